@@ -1,5 +1,8 @@
+import * as net from "net";
 import {
   ExtensionContext,
+  StatusBarAlignment,
+  window,
   workspace,
 } from "vscode";
 
@@ -12,7 +15,7 @@ import {
 
 let client: LanguageClient;
 
-export async function activate(_context: ExtensionContext) {
+export async function activate(context: ExtensionContext) {
   let base_dir = __dirname.split("/").slice(0, -2).join("/");
   let command = undefined;
 
@@ -41,18 +44,25 @@ export async function activate(_context: ExtensionContext) {
     },
   };
 
+  const client_config = workspace.getConfiguration("fuzzyRubyServer");
+  const daemon: string = client_config.get("daemon") || "";
+
   // If the extension is launched in debug mode then the debug server options
-  // are used Otherwise the run options are used
-  const serverOptions: ServerOptions = {
+  // are used Otherwise the run options are used. With `daemon` set, a running
+  // `fuzzy daemon` is connected to instead.
+  const serverOptions: ServerOptions = daemon ? () => {
+    const socket = /^\d+$/.test(daemon) ? net.connect(Number(daemon), "127.0.0.1") : net.connect(daemon);
+
+    return Promise.resolve({ reader: socket, writer: socket });
+  } : {
     run,
     debug: run,
   };
 
-  const client_config = workspace.getConfiguration("fuzzyRubyServer");
-
   let clientOptions: LanguageClientOptions = {
     documentSelector: [
-      { scheme: "file", language: "ruby" }
+      { scheme: "file", language: "ruby" },
+      { scheme: "file", pattern: "**/*.erb" },
     ],
     synchronize: {
       // fileEvents: workspace.createFileSystemWatcher("**/.clientrc"),
@@ -61,11 +71,47 @@ export async function activate(_context: ExtensionContext) {
       allocationType: client_config.get("allocationType"),
       indexGems: client_config.get("indexGems"),
       reportDiagnostics: client_config.get("reportDiagnostics"),
+      diagnostics: client_config.get("diagnostics"),
+      symbolSearchLimit: client_config.get("symbolSearchLimit"),
+      indexRspecLet: client_config.get("indexRspecLet"),
+      indexDynamicSends: client_config.get("indexDynamicSends"),
+      lazyGemIndexing: client_config.get("lazyGemIndexing"),
+      indexStdlib: client_config.get("indexStdlib"),
+      undefinedNameDiagnostics: client_config.get("undefinedNameDiagnostics"),
+      linter: client_config.get("linter"),
+      linterUseBundler: client_config.get("linterUseBundler"),
+      testCommands: client_config.get("testCommands"),
+      relatedFiles: client_config.get("relatedFiles"),
+      features: client_config.get("features"),
+      logLevel: client_config.get("logLevel"),
+      logFile: client_config.get("logFile"),
+      logFormat: client_config.get("logFormat"),
+      telemetry: client_config.get("telemetry"),
+      snapshot: client_config.get("snapshot"),
     },
   };
 
   // Create the language client and start the client.
   client = new LanguageClient("fuzzy-ruby-server", "Fuzzy Ruby Server", serverOptions, clientOptions);
+
+  // Shows what's being indexed, so a server still indexing gems doesn't
+  // look broken
+  const status = window.createStatusBarItem(StatusBarAlignment.Left);
+  context.subscriptions.push(status);
+
+  client.onNotification("fuzzy/indexingStatus", (params) => {
+    if (params.state == "queued") {
+      status.text = "$(sync~spin) Fuzzy: queued";
+    } else if (params.state == "indexing") {
+      status.text = `$(sync~spin) Fuzzy: indexing ${params.phase} ${params.indexed}/${params.total}`;
+    } else {
+      status.text = "$(check) Fuzzy";
+      status.tooltip = `Indexed ${params.indexedFiles.workspace} workspace and ${params.indexedFiles.gems} gem files`;
+    }
+
+    status.show();
+  });
+
   client.start();
 }
 
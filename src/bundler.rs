@@ -0,0 +1,257 @@
+use lib_ruby_parser::{nodes::*, Node, Parser, ParserOptions};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+
+// The gems in a `Gemfile.lock` that are only needed by Bundler groups other
+// than `groups`, e.g. everything `rspec-rails` pulls in when only `default`
+// and `development` are indexed. Groups are only declared in the `Gemfile`,
+// so the gems it lists pass theirs on to the gems they depend on. Gems the
+// `Gemfile` doesn't lead to, like a `gemspec`'s, are never excluded.
+pub fn excluded_gems(gemfile: &str, gemfile_lock: &str, groups: &[String]) -> HashSet<String> {
+    let gem_groups = gemfile_groups(gemfile);
+    let dependencies = locked_dependencies(gemfile_lock);
+
+    let in_groups =
+        |gem_groups: &Vec<String>| gem_groups.iter().any(|group| groups.contains(group));
+
+    let included_gems: Vec<&String> = gem_groups
+        .iter()
+        .filter(|(_name, gem_groups)| in_groups(gem_groups))
+        .map(|(name, _groups)| name)
+        .collect();
+    let excluded_gems: Vec<&String> = gem_groups
+        .iter()
+        .filter(|(_name, gem_groups)| !in_groups(gem_groups))
+        .map(|(name, _groups)| name)
+        .collect();
+
+    let included_gems = reachable_gems(&included_gems, &dependencies);
+
+    reachable_gems(&excluded_gems, &dependencies)
+        .into_iter()
+        .filter(|name| !included_gems.contains(name))
+        .collect()
+}
+
+// The groups of each gem listed in a `Gemfile`, from the `group` blocks
+// around it and its `group:` or `groups:` option, or `default`
+fn gemfile_groups(gemfile: &str) -> HashMap<String, Vec<String>> {
+    let options = ParserOptions {
+        buffer_name: "Gemfile".to_string(),
+        record_tokens: false,
+        ..Default::default()
+    };
+    let mut gem_groups = HashMap::new();
+
+    if let Some(ast) = Parser::new(gemfile.to_string(), options).do_parse().ast {
+        collect_gem_groups(&ast, &[], &mut gem_groups);
+    }
+
+    gem_groups
+}
+
+fn collect_gem_groups(
+    node: &Node,
+    groups: &[String],
+    gem_groups: &mut HashMap<String, Vec<String>>,
+) {
+    match node {
+        Node::Begin(Begin { statements, .. }) => {
+            for statement in statements {
+                collect_gem_groups(statement, groups, gem_groups);
+            }
+        }
+        // e.g. `if ENV["CI"]`, where the gems could be in either branch
+        Node::If(If {
+            if_true, if_false, ..
+        }) => {
+            for branch in [if_true, if_false].into_iter().flatten() {
+                collect_gem_groups(branch, groups, gem_groups);
+            }
+        }
+        Node::Block(Block { call, body, .. }) => {
+            let mut block_groups = groups.to_vec();
+
+            if let Node::Send(Send {
+                recv: None,
+                method_name,
+                args,
+                ..
+            }) = call.as_ref()
+            {
+                if method_name == "group" {
+                    block_groups.extend(args.iter().flat_map(group_names));
+                }
+            }
+
+            if let Some(body) = body {
+                collect_gem_groups(body, &block_groups, gem_groups);
+            }
+        }
+        Node::Send(Send {
+            recv: None,
+            method_name,
+            args,
+            ..
+        }) if method_name == "gem" => {
+            let name = match args.first() {
+                Some(Node::Str(Str { value, .. })) => value.to_string_lossy(),
+                _ => return,
+            };
+
+            let mut groups = groups.to_vec();
+
+            for arg in args {
+                let (Node::Hash(Hash { pairs, .. }) | Node::Kwargs(Kwargs { pairs, .. })) = arg
+                else {
+                    continue;
+                };
+
+                for pair in pairs {
+                    if let Node::Pair(Pair { key, value, .. }) = pair {
+                        if let Node::Sym(Sym { name, .. }) = key.as_ref() {
+                            if matches!(name.to_string_lossy().as_str(), "group" | "groups") {
+                                groups.extend(group_names(value));
+                            }
+                        }
+                    }
+                }
+            }
+
+            if groups.is_empty() {
+                groups.push("default".to_string());
+            }
+
+            gem_groups.entry(name).or_default().extend(groups);
+        }
+        _ => {}
+    }
+}
+
+// `:test`, `"test"`, or `[:development, :test]`
+fn group_names(node: &Node) -> Vec<String> {
+    match node {
+        Node::Sym(Sym { name, .. }) => vec![name.to_string_lossy()],
+        Node::Str(Str { value, .. }) => vec![value.to_string_lossy()],
+        Node::Array(Array { elements, .. }) => elements.iter().flat_map(group_names).collect(),
+        _ => vec![],
+    }
+}
+
+// The gems each locked gem depends on, from the lines indented under it in
+// the `specs:` of a `Gemfile.lock`
+fn locked_dependencies(gemfile_lock: &str) -> HashMap<String, Vec<String>> {
+    let spec = Regex::new(r"^ {4}([a-zA-Z\d\.\-_]+) \(").unwrap();
+    let dependency = Regex::new(r"^ {6}([a-zA-Z\d\.\-_]+)").unwrap();
+
+    let mut dependencies: HashMap<String, Vec<String>> = HashMap::new();
+    let mut locked_gem: Option<String> = None;
+
+    for line in gemfile_lock.lines() {
+        if let Some(captures) = spec.captures(line) {
+            let name = captures[1].to_string();
+            dependencies.entry(name.clone()).or_default();
+            locked_gem = Some(name);
+        } else if let (Some(captures), Some(locked_gem)) = (dependency.captures(line), &locked_gem)
+        {
+            if let Some(gem_dependencies) = dependencies.get_mut(locked_gem) {
+                gem_dependencies.push(captures[1].to_string());
+            }
+        } else {
+            locked_gem = None;
+        }
+    }
+
+    dependencies
+}
+
+fn reachable_gems(
+    names: &[&String],
+    dependencies: &HashMap<String, Vec<String>>,
+) -> HashSet<String> {
+    let mut reachable = HashSet::new();
+    let mut unvisited: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+
+    while let Some(name) = unvisited.pop() {
+        if !reachable.insert(name.clone()) {
+            continue;
+        }
+
+        if let Some(gem_dependencies) = dependencies.get(&name) {
+            unvisited.extend(gem_dependencies.iter().cloned());
+        }
+    }
+
+    reachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GEMFILE: &str = r#"source "https://rubygems.org"
+
+gem "rails"
+gem "pry", group: :development
+gem "capybara", groups: [:test, :system]
+
+group :development, :test do
+  gem "rspec-rails"
+end
+
+if ENV["CI"]
+  group :test do
+    gem "simplecov"
+  end
+end
+"#;
+
+    const GEMFILE_LOCK: &str = "GEM
+  remote: https://rubygems.org/
+  specs:
+    actionpack (7.1.0)
+      rack
+    capybara (3.39.2)
+      rack
+      xpath
+    pry (0.14.2)
+      coderay
+    coderay (1.1.3)
+    rack (3.0.8)
+    rails (7.1.0)
+      actionpack
+    rspec-rails (6.1.0)
+      actionpack
+      rspec-core
+    rspec-core (3.12.2)
+    simplecov (0.22.0)
+    xpath (3.2.0)
+
+PLATFORMS
+  ruby
+";
+
+    #[test]
+    fn gems_get_the_groups_around_them_or_default() {
+        let gem_groups = gemfile_groups(GEMFILE);
+        let groups = |name: &str| gem_groups[name].join(" ");
+
+        assert_eq!(groups("rails"), "default");
+        assert_eq!(groups("pry"), "development");
+        assert_eq!(groups("capybara"), "test system");
+        assert_eq!(groups("rspec-rails"), "development test");
+        assert_eq!(groups("simplecov"), "test");
+    }
+
+    #[test]
+    fn dependencies_of_included_gems_are_kept() {
+        let groups = vec!["default".to_string(), "development".to_string()];
+        let mut excluded: Vec<String> = excluded_gems(GEMFILE, GEMFILE_LOCK, &groups)
+            .into_iter()
+            .collect();
+        excluded.sort();
+
+        // `rack` is also needed by `rails`
+        assert_eq!(excluded, vec!["capybara", "simplecov", "xpath"]);
+    }
+}
@@ -0,0 +1,261 @@
+use fuzzy::config::DEFAULT_SNAPSHOT_PATH;
+use fuzzy::harness::{TestServer, TestWorkspace};
+use fuzzy::{logging, lsif, persistence, Indexer};
+use serde_json::json;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Instant;
+
+const INDEX_USAGE: &str = "Usage: fuzzy index <path> [--json]";
+const LSIF_USAGE: &str = "Usage: fuzzy lsif <path> [--output <file>]";
+const SNAPSHOT_USAGE: &str = "Usage: fuzzy snapshot <path> [--output <file>]";
+
+// `fuzzy index <path> [--json]` indexes a workspace the way the language
+// server does, using its `.fuzzy.toml` or `fuzzy.yml`, then prints every
+// definition found, one per line. Handy for CI checks and for finding out why
+// something isn't indexed.
+pub async fn index(args: &[String]) -> Result<(), String> {
+    let mut workspace_path = None;
+    let mut json = false;
+
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            _ if arg.starts_with("--") => {
+                return Err(format!("Unknown argument `{}`\n{}", arg, INDEX_USAGE))
+            }
+            _ => workspace_path = Some(arg),
+        }
+    }
+
+    let workspace_path = workspace_path.ok_or(INDEX_USAGE)?;
+    let indexer = index_workspace(workspace_path).await?;
+
+    let definitions = indexer.definitions().map_err(|error| error.to_string())?;
+    let mut stdout = io::stdout().lock();
+
+    for definition in definitions {
+        let line = if json {
+            definition.to_string()
+        } else {
+            format!(
+                "{}:{}\t{}\t{}\t{}",
+                definition["file"].as_str().unwrap_or_default(),
+                definition["line"],
+                definition["kind"].as_str().unwrap_or_default(),
+                definition["scope"].as_str().unwrap_or_default(),
+                definition["name"].as_str().unwrap_or_default(),
+            )
+        };
+
+        // Stops quietly when piped into e.g. `head`
+        if writeln!(stdout, "{}", line).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// `fuzzy lsif <path> [--output <file>]` indexes a workspace and writes an
+// LSIF dump of its definitions and references, to stdout by default
+pub async fn lsif(args: &[String]) -> Result<(), String> {
+    let mut workspace_path = None;
+    let mut output_path = None;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" => output_path = Some(args.next().ok_or(LSIF_USAGE)?),
+            _ if arg.starts_with("--") => {
+                return Err(format!("Unknown argument `{}`\n{}", arg, LSIF_USAGE))
+            }
+            _ => workspace_path = Some(arg),
+        }
+    }
+
+    let workspace_path = workspace_path.ok_or(LSIF_USAGE)?;
+    let indexer = index_workspace(workspace_path).await?;
+    let persistence = indexer.persistence();
+
+    let definitions = persistence
+        .definition_locations()
+        .map_err(|error| error.to_string())?;
+    let usages = persistence
+        .usage_definitions()
+        .map_err(|error| error.to_string())?;
+
+    let mut contents = String::new();
+    for element in lsif::dump(indexer.root_uri(), definitions, usages) {
+        contents.push_str(&element.to_string());
+        contents.push('\n');
+    }
+
+    match output_path {
+        Some(output_path) => fs::write(output_path, contents)
+            .map_err(|error| format!("Couldn't write {}: {}", output_path, error)),
+        None => {
+            let _ = io::stdout().lock().write_all(contents.as_bytes());
+            Ok(())
+        }
+    }
+}
+
+// `fuzzy snapshot <path> [--output <file>]` indexes a workspace and saves
+// the index, by default where the server looks for it on startup. Files that
+// are unchanged since aren't indexed again, so a snapshot made in CI or by a
+// teammate makes the first start quick.
+pub async fn snapshot(args: &[String]) -> Result<(), String> {
+    let mut workspace_path = None;
+    let mut output_path = None;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" => output_path = Some(args.next().ok_or(SNAPSHOT_USAGE)?.into()),
+            _ if arg.starts_with("--") => {
+                return Err(format!("Unknown argument `{}`\n{}", arg, SNAPSHOT_USAGE))
+            }
+            _ => workspace_path = Some(arg),
+        }
+    }
+
+    let workspace_path = workspace_path.ok_or(SNAPSHOT_USAGE)?;
+    let mut indexer = index_workspace(workspace_path).await?;
+
+    let output_path: PathBuf = match output_path {
+        Some(output_path) => output_path,
+        None => {
+            let snapshot = indexer.config().snapshot;
+            let snapshot = if snapshot.is_empty() {
+                DEFAULT_SNAPSHOT_PATH.to_string()
+            } else {
+                snapshot
+            };
+
+            Path::new(&indexer.root_path()).join(snapshot)
+        }
+    };
+
+    indexer
+        .persistence()
+        .export_snapshot(&output_path)
+        .map_err(|error| format!("Couldn't write {}: {}", output_path.display(), error))?;
+
+    eprintln!("Saved {}", output_path.display());
+
+    Ok(())
+}
+
+pub fn version() -> String {
+    format!(
+        "fuzzy {} ({} {})",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    )
+}
+
+// `fuzzy --health` prints what the server would find from the current
+// directory and checks that it can index and answer a request, for working
+// out why the server isn't starting or finding anything
+pub async fn health() -> Result<(), String> {
+    println!("{}", version());
+    println!("ruby: {}", command_output("ruby", &["--version"]));
+    println!(
+        "stdlib: {}",
+        persistence::stdlib_path().unwrap_or_else(|| "not found".to_string())
+    );
+    println!(
+        "gem home: {}",
+        command_output("gem", &["environment", "home"])
+    );
+
+    let timer = Instant::now();
+
+    match index_self_test().await {
+        Ok(()) => {
+            println!("index: ok ({:?})", timer.elapsed());
+            Ok(())
+        }
+        Err(message) => {
+            println!("index: failed");
+            Err(message)
+        }
+    }
+}
+
+fn command_output(program: &str, args: &[&str]) -> String {
+    match Command::new(program).args(args).output() {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        Ok(output) => format!(
+            "`{} {}` failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(error) => format!("not found ({})", error),
+    }
+}
+
+// Starts the server on a one-file workspace in a temporary directory and
+// looks up a definition in it, like an editor would
+async fn index_self_test() -> Result<(), String> {
+    let workspace = TestWorkspace::new(&[(
+        "health.rb",
+        "class Health\n  def check\n  end\nend\n\nHealth.new.check\n",
+    )])
+    .map_err(|error| format!("Couldn't create a workspace: {}", error))?;
+
+    let mut server = TestServer::start(workspace.path(), json!({ "indexGems": false }))
+        .await
+        .map_err(|error| error.to_string())?;
+    server
+        .wait_for_indexing()
+        .await
+        .map_err(|error| error.to_string())?;
+
+    let definitions = server
+        .request(
+            "textDocument/definition",
+            json!({
+                "textDocument": { "uri": server.uri("health.rb") },
+                "position": { "line": 5, "character": 12 },
+            }),
+        )
+        .await
+        .map_err(|error| error.to_string())?;
+    let _ = server.shutdown().await;
+
+    match definitions[0]["range"]["start"]["line"].as_u64() {
+        Some(1) => Ok(()),
+        _ => Err(format!(
+            "Expected `Health#check` to be defined on line 2, found {}",
+            definitions
+        )),
+    }
+}
+
+// Indexes a workspace the way the language server does on startup, and
+// waits until it can be searched
+async fn index_workspace(workspace_path: &str) -> Result<Indexer, String> {
+    let mut indexer = Indexer::new(workspace_path)?;
+    let config = indexer.config();
+    let mut errors = indexer.take_config_errors();
+
+    if let Err(message) = logging::init(&config, &indexer.root_path()) {
+        errors.push(message);
+    }
+
+    for message in errors {
+        eprintln!("{}", message);
+    }
+
+    indexer.index().await?;
+
+    Ok(indexer)
+}
@@ -0,0 +1,432 @@
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use tower_lsp::lsp_types::DiagnosticSeverity;
+
+// The settings from `initializationOptions` and the workspace config file.
+// Each one is read on its own, so a typo only resets that setting to its
+// default and is reported back to the user.
+pub struct Config {
+    pub allocation_type: AllocationType,
+    pub include_dirs: Vec<IncludeDir>,
+    pub excludes: Vec<String>,
+    pub index_gems: bool,
+    pub lazy_gem_indexing: bool,
+    pub index_gem_groups: Option<Vec<String>>,
+    pub index_stdlib: Option<bool>,
+    pub index_rspec_let: bool,
+    pub index_dynamic_sends: bool,
+    pub report_diagnostics: bool,
+    pub diagnostics: DiagnosticsConfig,
+    pub undefined_name_diagnostics: bool,
+    pub symbol_search_limit: usize,
+    pub linter: LinterName,
+    pub linter_use_bundler: bool,
+    pub test_commands: TestCommands,
+    pub related_files: Vec<RelatedFiles>,
+    pub autoload_paths: Vec<String>,
+    pub ruby_version: Option<String>,
+    pub features: Features,
+    pub log_level: Option<LogLevel>,
+    pub log_file: Option<String>,
+    pub log_format: LogFormat,
+    pub telemetry: bool,
+    pub snapshot: String,
+    // The settings the index is built and searched with, see
+    // `INDEX_SETTING_NAMES`
+    pub index_key: String,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AllocationType {
+    #[default]
+    Ram,
+    Tempdir,
+}
+
+#[derive(Deserialize)]
+pub struct IncludeDir {
+    pub path: String,
+    #[serde(default = "default_interface_only")]
+    pub interface_only: bool,
+}
+
+// e.g. `{ "syntax": "error", "warnings": "info", "unusedLocals": "hint" }`,
+// where a missing kind keeps its default severity
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DiagnosticsConfig {
+    pub syntax: Option<Severity>,
+    pub warnings: Option<Severity>,
+    pub unused_locals: Option<Severity>,
+    pub undefined_names: Option<Severity>,
+    pub missing_translations: Option<Severity>,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+    Hint,
+    Off,
+}
+
+impl Severity {
+    pub fn diagnostic_severity(self) -> Option<DiagnosticSeverity> {
+        match self {
+            Severity::Error => Some(DiagnosticSeverity::ERROR),
+            Severity::Warning => Some(DiagnosticSeverity::WARNING),
+            Severity::Info => Some(DiagnosticSeverity::INFORMATION),
+            Severity::Hint => Some(DiagnosticSeverity::HINT),
+            Severity::Off => None,
+        }
+    }
+}
+
+// The commands the test code lenses run for files under `spec/` and `test/`.
+// `{file}` is replaced with the file's path in the workspace, `{line}` with
+// the test's line, and `{name}` with its name.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TestCommands {
+    pub spec: String,
+    pub test: String,
+}
+
+impl Default for TestCommands {
+    fn default() -> Self {
+        Self {
+            spec: "bundle exec rspec {file}:{line}".to_string(),
+            test: "bundle exec ruby -Itest {file} --name {name}".to_string(),
+        }
+    }
+}
+
+// A convention pairing implementation files with their tests for
+// `fuzzy/relatedFiles`, e.g. `app/{path}.rb` with `spec/{path}_spec.rb`
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelatedFiles {
+    pub source: String,
+    pub test: String,
+}
+
+fn default_related_files() -> Vec<RelatedFiles> {
+    [
+        ("app/{path}.rb", "spec/{path}_spec.rb"),
+        ("app/{path}.rb", "test/{path}_test.rb"),
+        ("lib/{path}.rb", "spec/lib/{path}_spec.rb"),
+        ("lib/{path}.rb", "spec/{path}_spec.rb"),
+        ("lib/{path}.rb", "test/lib/{path}_test.rb"),
+        ("lib/{path}.rb", "test/{path}_test.rb"),
+    ]
+    .iter()
+    .map(|(source, test)| RelatedFiles {
+        source: source.to_string(),
+        test: test.to_string(),
+    })
+    .collect()
+}
+
+// The directories Rails autoloads constants from, relative to the workspace,
+// where `*` is any one directory, e.g. `app/*` for `app/models`
+fn default_autoload_paths() -> Vec<String> {
+    ["app/*", "app/*/concerns", "lib"]
+        .iter()
+        .map(|path| path.to_string())
+        .collect()
+}
+
+// Which capabilities are advertised and answered, so overlapping features can
+// be left to another Ruby language server. Everything is on by default.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct Features {
+    pub definitions: bool,
+    pub references: bool,
+    pub rename: bool,
+    pub highlights: bool,
+    pub symbols: bool,
+    pub diagnostics: bool,
+    pub code_actions: bool,
+    pub document_links: bool,
+    pub completion: bool,
+    pub hover: bool,
+    pub signature_help: bool,
+    pub code_lens: bool,
+    pub monikers: bool,
+    pub document_symbols: bool,
+    pub folding_ranges: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            definitions: true,
+            references: true,
+            rename: true,
+            highlights: true,
+            symbols: true,
+            diagnostics: true,
+            code_actions: true,
+            document_links: true,
+            completion: true,
+            hover: true,
+            signature_help: true,
+            code_lens: true,
+            monikers: true,
+            document_symbols: true,
+            folding_ranges: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LinterName {
+    #[default]
+    Off,
+    Rubocop,
+    Standardrb,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(log_level: LogLevel) -> Self {
+        match log_level {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+// Relative to the workspace
+pub const DEFAULT_SNAPSHOT_PATH: &str = ".fuzzy-snapshot";
+
+const SETTING_NAMES: [&str; 25] = [
+    "allocationType",
+    "includeDirs",
+    "excludes",
+    "indexGems",
+    "lazyGemIndexing",
+    "indexGemGroups",
+    "indexStdlib",
+    "indexRspecLet",
+    "indexDynamicSends",
+    "reportDiagnostics",
+    "diagnostics",
+    "undefinedNameDiagnostics",
+    "symbolSearchLimit",
+    "linter",
+    "linterUseBundler",
+    "testCommands",
+    "relatedFiles",
+    "autoloadPaths",
+    "rubyVersion",
+    "features",
+    "logLevel",
+    "logFile",
+    "logFormat",
+    "telemetry",
+    "snapshot",
+];
+
+// What a daemon's sessions have to agree on to share an index, since the
+// index is built and searched with them
+const INDEX_SETTING_NAMES: [&str; 17] = [
+    "allocationType",
+    "includeDirs",
+    "excludes",
+    "indexGems",
+    "lazyGemIndexing",
+    "indexGemGroups",
+    "indexStdlib",
+    "indexRspecLet",
+    "indexDynamicSends",
+    "reportDiagnostics",
+    "diagnostics",
+    "undefinedNameDiagnostics",
+    "symbolSearchLimit",
+    "relatedFiles",
+    "autoloadPaths",
+    "rubyVersion",
+    "snapshot",
+];
+
+impl Config {
+    // Returns the config along with a message for each setting that couldn't
+    // be read
+    pub fn from_value(user_config: &Value) -> (Config, Vec<String>) {
+        let mut errors = vec![];
+
+        if let Some(user_config) = user_config.as_object() {
+            for name in user_config.keys() {
+                if !SETTING_NAMES.contains(&name.as_str()) {
+                    errors.push(format!("Unknown setting `{}`", name));
+                }
+            }
+        } else if !user_config.is_null() {
+            errors.push("Settings should be an object".to_string());
+        }
+
+        let config = Config {
+            allocation_type: setting(user_config, "allocationType", &mut errors)
+                .unwrap_or_default(),
+            include_dirs: setting(user_config, "includeDirs", &mut errors).unwrap_or_default(),
+            excludes: setting(user_config, "excludes", &mut errors).unwrap_or_default(),
+            index_gems: setting(user_config, "indexGems", &mut errors).unwrap_or(true),
+            lazy_gem_indexing: setting(user_config, "lazyGemIndexing", &mut errors)
+                .unwrap_or(false),
+            index_gem_groups: setting(user_config, "indexGemGroups", &mut errors),
+            index_stdlib: setting(user_config, "indexStdlib", &mut errors),
+            index_rspec_let: setting(user_config, "indexRspecLet", &mut errors).unwrap_or(false),
+            index_dynamic_sends: setting(user_config, "indexDynamicSends", &mut errors)
+                .unwrap_or(false),
+            report_diagnostics: setting(user_config, "reportDiagnostics", &mut errors)
+                .unwrap_or(true),
+            diagnostics: setting(user_config, "diagnostics", &mut errors).unwrap_or_default(),
+            undefined_name_diagnostics: setting(
+                user_config,
+                "undefinedNameDiagnostics",
+                &mut errors,
+            )
+            .unwrap_or(false),
+            symbol_search_limit: setting(user_config, "symbolSearchLimit", &mut errors)
+                .unwrap_or(100),
+            linter: setting(user_config, "linter", &mut errors).unwrap_or_default(),
+            linter_use_bundler: setting(user_config, "linterUseBundler", &mut errors)
+                .unwrap_or(false),
+            test_commands: setting(user_config, "testCommands", &mut errors).unwrap_or_default(),
+            related_files: setting(user_config, "relatedFiles", &mut errors)
+                .unwrap_or_else(default_related_files),
+            autoload_paths: setting(user_config, "autoloadPaths", &mut errors)
+                .unwrap_or_else(default_autoload_paths),
+            ruby_version: setting(user_config, "rubyVersion", &mut errors),
+            features: setting(user_config, "features", &mut errors).unwrap_or_default(),
+            log_level: setting(user_config, "logLevel", &mut errors),
+            log_file: setting(user_config, "logFile", &mut errors),
+            log_format: setting(user_config, "logFormat", &mut errors).unwrap_or_default(),
+            telemetry: setting(user_config, "telemetry", &mut errors).unwrap_or(false),
+            snapshot: setting(user_config, "snapshot", &mut errors)
+                .unwrap_or_else(|| DEFAULT_SNAPSHOT_PATH.to_string()),
+            index_key: index_key(user_config),
+        };
+
+        (config, errors)
+    }
+}
+
+// The index settings as they were given, along with whether diagnostics are
+// reported at all
+fn index_key(user_config: &Value) -> String {
+    let mut index_settings: Vec<&Value> = INDEX_SETTING_NAMES
+        .iter()
+        .map(|name| user_config.get(name).unwrap_or(&Value::Null))
+        .collect();
+    index_settings.push(
+        user_config
+            .get("features")
+            .and_then(|features| features.get("diagnostics"))
+            .unwrap_or(&Value::Null),
+    );
+
+    serde_json::to_string(&index_settings).unwrap_or_default()
+}
+
+// `None` when the setting is missing, `null`, or invalid, in which case the
+// reason is added to `errors`
+fn setting<T: DeserializeOwned>(
+    user_config: &Value,
+    name: &str,
+    errors: &mut Vec<String>,
+) -> Option<T> {
+    let value = user_config.get(name).filter(|value| !value.is_null())?;
+
+    match serde_json::from_value(value.clone()) {
+        Ok(setting) => Some(setting),
+        Err(error) => {
+            errors.push(format!(
+                "Invalid `{}` setting, using the default: {}",
+                name, error
+            ));
+            None
+        }
+    }
+}
+
+fn default_interface_only() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn invalid_settings_are_reported_and_reset() {
+        let (config, errors) = Config::from_value(&json!({
+            "indexGems": "yes",
+            "symbolSearchLimit": 20,
+            "indexRSpecLet": true,
+        }));
+
+        assert!(config.index_gems);
+        assert_eq!(config.symbol_search_limit, 20);
+        assert!(!config.index_rspec_let);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0], "Unknown setting `indexRSpecLet`");
+        assert!(errors[1].starts_with("Invalid `indexGems` setting, using the default: "));
+    }
+
+    #[test]
+    fn settings_can_be_missing_but_not_another_type() {
+        assert!(Config::from_value(&Value::Null).1.is_empty());
+        assert_eq!(
+            Config::from_value(&json!(["indexGems"])).1,
+            vec!["Settings should be an object".to_string()]
+        );
+    }
+
+    #[test]
+    fn index_key_only_changes_with_index_settings() {
+        let index_key = |user_config: Value| Config::from_value(&user_config).0.index_key;
+        let default_key = index_key(json!({}));
+
+        assert_eq!(index_key(json!({ "logLevel": "debug" })), default_key);
+        assert_ne!(index_key(json!({ "indexGems": false })), default_key);
+        assert_ne!(
+            index_key(json!({ "features": { "diagnostics": false } })),
+            default_key
+        );
+        assert_eq!(
+            index_key(json!({ "features": { "hover": false } })),
+            default_key
+        );
+    }
+}
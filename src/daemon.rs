@@ -0,0 +1,97 @@
+use log::info;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+use tower_lsp::lsp_types::{InitializeParams, Url};
+use tower_lsp::{ClientSocket, LspService, Server};
+
+use crate::config::Config;
+use crate::persistence::Persistence;
+use crate::server::{language_service, Backend};
+use crate::session::SessionService;
+use crate::transport::Listener;
+
+const DAEMON_USAGE: &str = "Usage: fuzzy daemon --socket <port or path>";
+
+// The indexes of a daemon's workspaces, shared by every session opened with
+// the same root and index settings. They're kept after the last session
+// closes, so reopening the editor doesn't reindex either.
+#[derive(Clone, Default)]
+pub struct Workspaces {
+    persistences: Arc<Mutex<HashMap<WorkspaceKey, Arc<RwLock<Persistence>>>>>,
+}
+
+// The workspace's root and `Config::index_key`
+type WorkspaceKey = (Url, String);
+
+impl Workspaces {
+    // The workspace's index, and whether it was created for this session, in
+    // which case the session starts indexing it and is the one that configures
+    // it. Sessions with other index settings get an index of their own.
+    pub async fn persistence(
+        &self,
+        params: &InitializeParams,
+        config: &Config,
+    ) -> Option<(Arc<RwLock<Persistence>>, bool)> {
+        let root_uri = params.root_uri.as_ref()?;
+        let key = (root_uri.clone(), config.index_key.clone());
+        let mut persistences = self.persistences.lock().await;
+
+        if let Some(persistence) = persistences.get(&key) {
+            info!("Sharing the index of {}", root_uri);
+            return Some((Arc::clone(persistence), false));
+        }
+
+        let mut persistence = Persistence::new().ok()?;
+        persistence.initialize(params, config);
+
+        let persistence = Arc::new(RwLock::new(persistence));
+        persistences.insert(key, Arc::clone(&persistence));
+
+        Some((persistence, true))
+    }
+}
+
+// The language server for one of the daemon's editor sessions
+pub fn session_service(
+    workspaces: &Workspaces,
+) -> (SessionService<LspService<Backend>>, ClientSocket) {
+    let (service, socket) = language_service(Some(workspaces.clone()));
+
+    (SessionService::new(service), socket)
+}
+
+// `fuzzy daemon --socket <port or path>` accepts any number of editors, each
+// as its own LSP session. Sessions with the same workspace root and index
+// settings share its index, while open documents, the linter, test commands,
+// and the client stay per session.
+pub async fn serve(args: &[String]) -> Result<(), String> {
+    let address = match args {
+        [flag, address] if flag == "--socket" => address.clone(),
+        [arg] if arg.starts_with("--socket=") => arg["--socket=".len()..].to_string(),
+        _ => return Err(DAEMON_USAGE.to_string()),
+    };
+
+    let listener = Listener::bind(&address)
+        .await
+        .map_err(|error| format!("Couldn't listen on {}: {}", address, error))?;
+    let workspaces = Workspaces::default();
+
+    eprintln!("Listening on {}", address);
+
+    loop {
+        let (input, output) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                info!("Couldn't accept a connection: {}", error);
+                continue;
+            }
+        };
+
+        let (service, socket) = session_service(&workspaces);
+
+        tokio::spawn(async move {
+            Server::new(input, output, socket).serve(service).await;
+        });
+    }
+}
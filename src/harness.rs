@@ -0,0 +1,292 @@
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf, WriteHalf,
+};
+use tower::Service;
+use tower_lsp::jsonrpc::{Request, Response};
+use tower_lsp::lsp_types::Url;
+use tower_lsp::{ClientSocket, Server};
+
+use crate::daemon::{session_service, Workspaces};
+use crate::server::language_service;
+
+// How long `wait_for_indexing` waits before giving up, so a server that never
+// finishes fails the test instead of hanging it
+const INDEXING_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Runs the language server in-process over an in-memory stream, so tests can
+// drive it the way an editor would:
+//
+//     let workspace = TestWorkspace::new(&[("a.rb", "class A\n  def one\n  end\nend\nA.new.one\n")])?;
+//     let mut server = TestServer::start(workspace.path(), json!({ "indexGems": false })).await?;
+//     server.wait_for_indexing().await?;
+//     server.open("a.rb").await?;
+//
+//     let definitions = server.request("textDocument/definition", json!({
+//         "textDocument": { "uri": server.uri("a.rb") },
+//         "position": { "line": 4, "character": 7 },
+//     })).await?;
+pub struct TestServer {
+    input: WriteHalf<DuplexStream>,
+    output: BufReader<ReadHalf<DuplexStream>>,
+    root_uri: Url,
+    next_id: u64,
+    notifications: Vec<Value>,
+}
+
+impl TestServer {
+    // Sends `initialize` and `initialized` for a workspace
+    pub async fn start(
+        workspace_path: &Path,
+        initialization_options: Value,
+    ) -> io::Result<TestServer> {
+        let (service, socket) = language_service(None);
+
+        TestServer::serve(service, socket, workspace_path, initialization_options).await
+    }
+
+    // Like `start`, as a session of a daemon sharing its indexes with the
+    // other sessions started with the same `workspaces`
+    pub async fn start_session(
+        workspaces: &Workspaces,
+        workspace_path: &Path,
+        initialization_options: Value,
+    ) -> io::Result<TestServer> {
+        let (service, socket) = session_service(workspaces);
+
+        TestServer::serve(service, socket, workspace_path, initialization_options).await
+    }
+
+    async fn serve<S>(
+        service: S,
+        socket: ClientSocket,
+        workspace_path: &Path,
+        initialization_options: Value,
+    ) -> io::Result<TestServer>
+    where
+        S: Service<Request, Response = Option<Response>> + Send + 'static,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        S::Future: Send,
+    {
+        let root_uri = Url::from_directory_path(workspace_path)
+            .map_err(|_| invalid_data(format!("{} isn't absolute", workspace_path.display())))?;
+
+        let (client, server) = tokio::io::duplex(1 << 20);
+        let (server_input, server_output) = tokio::io::split(server);
+
+        tokio::spawn(Server::new(server_input, server_output, socket).serve(service));
+
+        let (output, input) = tokio::io::split(client);
+        let mut test_server = TestServer {
+            input,
+            output: BufReader::new(output),
+            root_uri,
+            next_id: 0,
+            notifications: vec![],
+        };
+
+        test_server
+            .request(
+                "initialize",
+                json!({
+                    "processId": std::process::id(),
+                    "rootUri": test_server.root_uri,
+                    "capabilities": {},
+                    "initializationOptions": initialization_options,
+                }),
+            )
+            .await?;
+        test_server.notify("initialized", json!({})).await?;
+
+        Ok(test_server)
+    }
+
+    // The URI of a file in the workspace
+    pub fn uri(&self, relative_path: &str) -> Url {
+        self.root_uri.join(relative_path).unwrap()
+    }
+
+    // The request's `result`, or an error with the response's `error`
+    pub async fn request(&mut self, method: &str, params: Value) -> io::Result<Value> {
+        self.next_id += 1;
+        let id = self.next_id;
+
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+            .await?;
+
+        loop {
+            let message = self.receive().await?;
+
+            if message.get("method").is_some() {
+                self.handle_server_message(message).await?;
+            } else if message["id"] == id {
+                return match message.get("error") {
+                    Some(error) => Err(invalid_data(error.to_string())),
+                    None => Ok(message["result"].clone()),
+                };
+            }
+        }
+    }
+
+    pub async fn notify(&mut self, method: &str, params: Value) -> io::Result<()> {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+            .await
+    }
+
+    // Sends `textDocument/didOpen` with the file's contents on disk
+    pub async fn open(&mut self, relative_path: &str) -> io::Result<()> {
+        let uri = self.uri(relative_path);
+        let text = fs::read_to_string(uri.to_file_path().unwrap())?;
+
+        self.notify(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": { "uri": uri, "languageId": "ruby", "version": 1, "text": text },
+            }),
+        )
+        .await
+    }
+
+    // Polls `fuzzy/indexStats` until the workspace has been indexed and can be
+    // searched, or errors after `INDEXING_TIMEOUT`
+    pub async fn wait_for_indexing(&mut self) -> io::Result<()> {
+        let indexed = async {
+            loop {
+                let stats = self.request("fuzzy/indexStats", Value::Null).await?;
+
+                if stats["lastReindexDurationsMs"].get("workspace").is_some() {
+                    return Ok(());
+                }
+
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        tokio::time::timeout(INDEXING_TIMEOUT, indexed)
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Not indexed after {:?}", INDEXING_TIMEOUT),
+                ))
+            })
+    }
+
+    // Notifications from the server so far, like `textDocument/publishDiagnostics`
+    pub fn notifications(&self) -> &[Value] {
+        &self.notifications
+    }
+
+    pub async fn shutdown(mut self) -> io::Result<()> {
+        self.request("shutdown", Value::Null).await?;
+        self.notify("exit", Value::Null).await
+    }
+
+    // Requests from the server, like `window/workDoneProgress/create`, are
+    // answered with `null`
+    async fn handle_server_message(&mut self, message: Value) -> io::Result<()> {
+        match message.get("id") {
+            Some(id) => {
+                self.send(json!({ "jsonrpc": "2.0", "id": id, "result": null }))
+                    .await
+            }
+            None => {
+                self.notifications.push(message);
+                Ok(())
+            }
+        }
+    }
+
+    async fn send(&mut self, mut message: Value) -> io::Result<()> {
+        if message["params"].is_null() {
+            if let Some(message) = message.as_object_mut() {
+                message.remove("params");
+            }
+        }
+
+        let body = message.to_string();
+        let header = format!("Content-Length: {}\r\n\r\n", body.len());
+
+        self.input.write_all(header.as_bytes()).await?;
+        self.input.write_all(body.as_bytes()).await
+    }
+
+    async fn receive(&mut self) -> io::Result<Value> {
+        let mut content_length = 0;
+
+        loop {
+            let mut line = String::new();
+            if self.output.read_line(&mut line).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+
+            if let Some(length) = line.strip_prefix("Content-Length: ") {
+                content_length = length
+                    .parse()
+                    .map_err(|_| invalid_data(format!("Bad header `{}`", line)))?;
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        self.output.read_exact(&mut body).await?;
+
+        serde_json::from_slice(&body).map_err(|error| invalid_data(error.to_string()))
+    }
+}
+
+// A workspace of Ruby files in a temporary directory, removed when dropped
+pub struct TestWorkspace {
+    path: PathBuf,
+}
+
+impl TestWorkspace {
+    pub fn new(files: &[(&str, &str)]) -> io::Result<TestWorkspace> {
+        static NEXT_WORKSPACE: AtomicUsize = AtomicUsize::new(0);
+
+        // Canonical so URIs match the paths found walking the workspace, e.g.
+        // under `/private/var` on macOS
+        let path = fs::canonicalize(std::env::temp_dir())?.join(format!(
+            "fuzzy-test-{}-{}",
+            std::process::id(),
+            NEXT_WORKSPACE.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path)?;
+
+        let workspace = TestWorkspace { path };
+
+        for (relative_path, contents) in files {
+            let file_path = workspace.path.join(relative_path);
+
+            if let Some(parent) = file_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(file_path, contents)?;
+        }
+
+        Ok(workspace)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TestWorkspace {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
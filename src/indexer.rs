@@ -0,0 +1,149 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tower_lsp::lsp_types::{
+    InitializeParams, Location, Position, TextDocumentIdentifier, TextDocumentPositionParams, Url,
+};
+
+use crate::config::Config;
+use crate::persistence::{NodeHandler, Persistence};
+use crate::workspace_config::WorkspaceConfig;
+use crate::workspace_path::uri_path;
+
+// Indexes a directory the way the language server does, using its
+// `.fuzzy.toml` or `fuzzy.yml`, and answers the same queries without an editor:
+//
+//     let mut indexer = Indexer::new("path/to/app")?;
+//     indexer.index().await?;
+//
+//     let query = SymbolQuery::new("app/models/user.rb", 4, 10);
+//     let definitions = indexer.find_definitions(&query)?;
+pub struct Indexer {
+    persistence: Persistence,
+    workspace_config: WorkspaceConfig,
+    root_uri: Url,
+}
+
+// A position in a file, like the cursor in an editor. The path can be
+// relative to the indexed directory, and lines and characters start at 0.
+pub struct SymbolQuery {
+    path: PathBuf,
+    position: Position,
+}
+
+impl SymbolQuery {
+    pub fn new(path: impl AsRef<Path>, line: u32, character: u32) -> SymbolQuery {
+        SymbolQuery {
+            path: path.as_ref().to_path_buf(),
+            position: Position::new(line, character),
+        }
+    }
+}
+
+impl Indexer {
+    // Reads the directory's config without indexing anything yet
+    pub fn new(path: impl AsRef<Path>) -> Result<Indexer, String> {
+        let path = path.as_ref();
+        let workspace_path = fs::canonicalize(path)
+            .map_err(|error| format!("Couldn't read {}: {}", path.display(), error))?;
+        let root_uri = Url::from_directory_path(&workspace_path)
+            .map_err(|_| format!("Couldn't index {}", workspace_path.display()))?;
+
+        let params = InitializeParams {
+            root_uri: Some(root_uri.clone()),
+            ..InitializeParams::default()
+        };
+
+        let mut workspace_config = WorkspaceConfig::default();
+        workspace_config.initialize(&params);
+
+        let mut persistence = Persistence::new().map_err(|error| error.to_string())?;
+        persistence.initialize(&params, &workspace_config.config());
+
+        Ok(Indexer {
+            persistence,
+            workspace_config,
+            root_uri,
+        })
+    }
+
+    pub fn root_uri(&self) -> &Url {
+        &self.root_uri
+    }
+
+    pub fn root_path(&self) -> String {
+        uri_path(&self.root_uri)
+    }
+
+    pub fn config(&mut self) -> Config {
+        self.workspace_config.config()
+    }
+
+    // Problems reading the config, where each setting that couldn't be read
+    // uses its default
+    pub fn take_config_errors(&mut self) -> Vec<String> {
+        self.workspace_config.take_errors()
+    }
+
+    // Indexes what `node_handler` finds too, like the definitions made by a
+    // library's DSL. Add handlers before calling `index`.
+    pub fn add_node_handler(&mut self, node_handler: impl NodeHandler + 'static) {
+        self.persistence.add_node_handler(Arc::new(node_handler));
+    }
+
+    // Indexes the directory, its `includeDirs`, its gems, and the standard
+    // library the same way the server does, returning once they can be
+    // searched
+    pub async fn index(&mut self) -> Result<(), String> {
+        let mut indexer = self
+            .persistence
+            .indexer()
+            .map_err(|error| error.to_string())?;
+        let indexer = tokio::task::spawn_blocking(move || {
+            indexer.index_all();
+            indexer
+        })
+        .await
+        .map_err(|error| error.to_string())?;
+
+        self.persistence.sync_indexing_status(&indexer);
+        self.persistence.wait_for_commit().await;
+
+        Ok(())
+    }
+
+    // Every definition, as `{ name, kind, file, line, scope }` sorted by file
+    // and line. Files in the directory are relative to it and lines start at 1.
+    pub fn definitions(&self) -> tantivy::Result<Vec<serde_json::Value>> {
+        self.persistence.definitions()
+    }
+
+    pub fn find_definitions(&self, query: &SymbolQuery) -> tantivy::Result<Vec<Location>> {
+        self.persistence
+            .find_definitions(self.position_params(query))
+    }
+
+    // Includes the definitions themselves
+    pub fn find_references(&self, query: &SymbolQuery) -> tantivy::Result<Vec<Location>> {
+        let params = self.position_params(query);
+        let path = uri_path(&params.text_document.uri);
+        let documents = self.persistence.find_references(params)?;
+
+        Ok(self.persistence.documents_to_locations(&path, documents))
+    }
+
+    // For queries `Indexer` doesn't cover
+    pub fn persistence(&self) -> &Persistence {
+        &self.persistence
+    }
+
+    fn position_params(&self, query: &SymbolQuery) -> TextDocumentPositionParams {
+        let path = Path::new(&self.root_path()).join(&query.path);
+        let uri = Url::from_file_path(&path).unwrap_or_else(|_| self.root_uri.clone());
+
+        TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier { uri },
+            position: query.position,
+        }
+    }
+}
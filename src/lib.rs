@@ -0,0 +1,29 @@
+// The Ruby indexer behind the language server, for tools that want to find
+// definitions and references without speaking LSP. `Indexer` and
+// `SymbolQuery` are the place to start; the modules are what the server is
+// built from.
+mod bundler;
+pub mod config;
+pub mod daemon;
+pub mod harness;
+mod indexer;
+pub mod linter;
+pub mod locales;
+pub mod logging;
+pub mod lsif;
+pub mod persistence;
+mod ruby_version;
+pub mod server;
+pub mod session;
+pub mod snapshot;
+pub mod telemetry;
+pub mod test_runner;
+pub mod transport;
+pub mod workspace_config;
+pub mod workspace_path;
+mod write_queue;
+
+pub use indexer::{Indexer, SymbolQuery};
+pub use lib_ruby_parser;
+pub use persistence::{NodeContext, NodeHandler};
+pub use tower_lsp::lsp_types::Location;
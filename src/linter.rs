@@ -0,0 +1,311 @@
+use log::info;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tower_lsp::lsp_types::{
+    CodeAction, CodeActionKind, CodeActionParams, Diagnostic, DiagnosticSeverity, InitializeParams,
+    NumberOrString, Position, Range, TextEdit, Url, WorkspaceEdit,
+};
+
+use crate::config::{Config, LinterName};
+use crate::workspace_path::uri_path;
+
+// Runs RuboCop or Standard on open and save. Offenses are published along
+// with the parser diagnostics, and autocorrectable ones are offered as quick
+// fixes.
+#[derive(Default)]
+pub struct Linter {
+    command: LinterCommand,
+    texts: HashMap<Url, String>,
+    offenses: HashMap<Url, Vec<Diagnostic>>,
+}
+
+// How the linter is run. Runs use a copy, so the linter's state isn't locked
+// while they wait on it.
+#[derive(Clone, Default)]
+pub struct LinterCommand {
+    linter: Option<String>,
+    use_bundler: bool,
+    workspace_path: PathBuf,
+}
+
+impl Linter {
+    pub fn initialize(&mut self, params: &InitializeParams, config: &Config) {
+        if let Some(uri) = &params.root_uri {
+            self.open_workspace(Path::new(&uri_path(uri)));
+        }
+
+        self.configure(config);
+    }
+
+    // The linter runs from here to pick up the project's configuration
+    pub fn open_workspace(&mut self, workspace_path: &Path) {
+        self.command.workspace_path = workspace_path.to_path_buf();
+    }
+
+    pub fn configure(&mut self, config: &Config) {
+        self.command.linter = match config.linter {
+            LinterName::Rubocop => Some("rubocop".to_string()),
+            LinterName::Standardrb => Some("standardrb".to_string()),
+            LinterName::Off => None,
+        };
+        self.command.use_bundler = config.linter_use_bundler;
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.command.linter.is_some()
+    }
+
+    pub fn command(&self) -> LinterCommand {
+        self.command.clone()
+    }
+
+    // Keeps the latest text of each open file for quick fixes
+    pub fn update_text(&mut self, uri: &Url, text: &str) {
+        if self.enabled() {
+            self.texts.insert(uri.clone(), text.to_string());
+        }
+    }
+
+    pub fn text(&self, uri: &Url) -> Option<String> {
+        self.texts.get(uri).cloned()
+    }
+
+    // The text a quick fix from `code_actions` corrects
+    pub fn code_action_text(&self, code_action: &CodeAction) -> Option<String> {
+        let uri: Url = serde_json::from_value(code_action.data.as_ref()?["uri"].clone()).ok()?;
+
+        self.text(&uri)
+    }
+
+    // The offenses from the last time `uri` was linted
+    pub fn offenses(&self, uri: &Url) -> Vec<Diagnostic> {
+        self.offenses.get(uri).cloned().unwrap_or_default()
+    }
+
+    pub fn set_offenses(&mut self, uri: &Url, offenses: Vec<Diagnostic>) {
+        self.offenses.insert(uri.clone(), offenses);
+    }
+
+    // A quick fix for each autocorrectable offense. Their edits are only made
+    // once one is picked, see `LinterCommand::resolve_code_action`.
+    pub fn code_actions(&self, params: &CodeActionParams) -> Vec<CodeAction> {
+        let linter = match &self.command.linter {
+            Some(linter) => linter,
+            None => return vec![],
+        };
+
+        let uri = &params.text_document.uri;
+
+        params
+            .context
+            .diagnostics
+            .iter()
+            .filter_map(|diagnostic| {
+                let correctable = diagnostic.source.as_ref() == Some(linter)
+                    && diagnostic
+                        .data
+                        .as_ref()
+                        .and_then(|data| data["correctable"].as_bool())
+                        .unwrap_or(false);
+
+                let cop_name = match &diagnostic.code {
+                    Some(NumberOrString::String(cop_name)) if correctable => cop_name,
+                    _ => return None,
+                };
+
+                Some(CodeAction {
+                    title: format!("Autocorrect {}", cop_name),
+                    kind: Some(CodeActionKind::QUICKFIX),
+                    diagnostics: Some(vec![diagnostic.clone()]),
+                    is_preferred: Some(true),
+                    data: Some(json!({ "uri": uri, "cop": cop_name })),
+                    ..CodeAction::default()
+                })
+            })
+            .collect()
+    }
+}
+
+impl LinterCommand {
+    pub async fn lint(&self, uri: &Url, text: &str) -> Vec<Diagnostic> {
+        let linter = match &self.linter {
+            Some(linter) => linter,
+            None => return vec![],
+        };
+
+        let path = uri_path(uri);
+
+        // Templates aren't Ruby until they're compiled
+        if !path.ends_with(".rb") {
+            return vec![];
+        }
+        let args = vec!["--format", "json", "--force-exclusion", "--stdin", &path];
+        let output = match self.run(&args, text).await {
+            Some(output) => output,
+            None => return vec![],
+        };
+
+        let report: Value = match serde_json::from_str(&output) {
+            Ok(report) => report,
+            Err(_) => {
+                info!("Couldn't parse {} output: {}", linter, output);
+                return vec![];
+            }
+        };
+
+        let mut offenses = vec![];
+
+        let files = report["files"].as_array().cloned().unwrap_or_default();
+
+        for file in files {
+            let file_offenses = file["offenses"].as_array().cloned().unwrap_or_default();
+
+            for offense in file_offenses {
+                if let Some(diagnostic) = offense_diagnostic(linter, &offense) {
+                    offenses.push(diagnostic);
+                }
+            }
+        }
+
+        offenses
+    }
+
+    // Adds the edit to a quick fix from `Linter::code_actions`, made by
+    // autocorrecting only its cop in `text`. Without one there's nothing to
+    // fix, so it's `None`.
+    pub async fn resolve_code_action(
+        &self,
+        mut code_action: CodeAction,
+        text: &str,
+    ) -> Option<CodeAction> {
+        let linter = self.linter.as_ref()?;
+        let data = code_action.data.as_ref()?;
+        let uri: Url = serde_json::from_value(data["uri"].clone()).ok()?;
+        let cop_name = data["cop"].as_str()?;
+
+        let path = uri_path(&uri);
+        let autocorrect_flag = if linter == "standardrb" {
+            "--fix"
+        } else {
+            "--autocorrect"
+        };
+
+        // With `--stderr` only the corrected source is written to stdout
+        let args = vec![
+            autocorrect_flag,
+            "--only",
+            cop_name,
+            "--stderr",
+            "--format",
+            "quiet",
+            "--stdin",
+            &path,
+        ];
+
+        let corrected_text = match self.run(&args, text).await {
+            Some(corrected_text) if !corrected_text.is_empty() && corrected_text != text => {
+                corrected_text
+            }
+            _ => return None,
+        };
+
+        let line_count = text.matches('\n').count() as u32 + 1;
+        let text_edit = TextEdit::new(
+            Range::new(Position::new(0, 0), Position::new(line_count, 0)),
+            corrected_text,
+        );
+
+        let mut changes = HashMap::new();
+        changes.insert(uri, vec![text_edit]);
+
+        code_action.edit = Some(WorkspaceEdit::new(changes));
+
+        Some(code_action)
+    }
+
+    // Runs the linter in the workspace with `text` as stdin, returning stdout.
+    // Offenses make the linter exit with a failure, so the status is ignored.
+    async fn run(&self, args: &[&str], text: &str) -> Option<String> {
+        let linter = self.linter.as_ref()?;
+
+        let mut command = if self.use_bundler {
+            let mut command = Command::new("bundle");
+            command.arg("exec").arg(linter);
+            command
+        } else {
+            Command::new(linter)
+        };
+
+        if !self.workspace_path.as_os_str().is_empty() {
+            command.current_dir(&self.workspace_path);
+        }
+
+        let child = command
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+
+        let mut child = match child {
+            Ok(child) => child,
+            Err(error) => {
+                info!("Couldn't run {}: {}", linter, error);
+                return None;
+            }
+        };
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes()).await.ok()?;
+        }
+
+        let output = child.wait_with_output().await.ok()?;
+
+        String::from_utf8(output.stdout).ok()
+    }
+}
+
+fn offense_diagnostic(linter: &str, offense: &Value) -> Option<Diagnostic> {
+    let cop_name = offense["cop_name"].as_str()?;
+    let location = &offense["location"];
+
+    // Lines and columns are 1-based, and `last_column` is inclusive
+    let start_line = location["start_line"].as_u64()?.saturating_sub(1) as u32;
+    let start_column = location["start_column"].as_u64()?.saturating_sub(1) as u32;
+    let last_line = location["last_line"].as_u64()?.saturating_sub(1) as u32;
+    let last_column = location["last_column"].as_u64()? as u32;
+
+    let severity = match offense["severity"].as_str() {
+        Some("fatal") | Some("error") => DiagnosticSeverity::ERROR,
+        Some("warning") => DiagnosticSeverity::WARNING,
+        Some("convention") => DiagnosticSeverity::INFORMATION,
+        _ => DiagnosticSeverity::HINT,
+    };
+
+    let message = offense["message"].as_str()?;
+    let message = message
+        .strip_prefix(&format!("{}: ", cop_name))
+        .unwrap_or(message);
+
+    let mut diagnostic = Diagnostic::new(
+        Range::new(
+            Position::new(start_line, start_column),
+            Position::new(last_line, last_column),
+        ),
+        Some(severity),
+        Some(NumberOrString::String(cop_name.to_string())),
+        Some(linter.to_string()),
+        message.to_string(),
+        None,
+        None,
+    );
+    diagnostic.data = Some(json!({
+        "correctable": offense["correctable"].as_bool().unwrap_or(false),
+    }));
+
+    Some(diagnostic)
+}
@@ -0,0 +1,162 @@
+use jwalk::WalkDir;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Where a translation key is defined in a locale file, e.g. `users.show.title`
+// on the `title:` line of
+//
+//     en:
+//       users:
+//         show:
+//           title: Profile
+#[derive(Clone)]
+pub struct LocaleKey {
+    pub locale: String,
+    pub path: String,
+    pub line: usize,
+    pub start_column: usize,
+    pub end_column: usize,
+}
+
+// The keys defined in `config/locales`, by their key without the locale. Keys
+// with nested keys are included too, since `t("users.show")` returns them all.
+#[derive(Default)]
+pub struct Locales {
+    keys: HashMap<String, Vec<LocaleKey>>,
+}
+
+impl Locales {
+    pub fn load(workspace_path: &Path) -> Locales {
+        let mut locales = Locales::default();
+        let locales_path = workspace_path.join("config").join("locales");
+
+        if !locales_path.is_dir() {
+            return locales;
+        }
+
+        let mut paths: Vec<String> = WalkDir::new(locales_path)
+            .into_iter()
+            .flatten()
+            .map(|entry| entry.path().to_string_lossy().to_string())
+            .filter(|path| path.ends_with(".yml") || path.ends_with(".yaml"))
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            if let Ok(contents) = fs::read_to_string(&path) {
+                for (key, locale_key) in locale_keys(&path, &contents) {
+                    locales.keys.entry(key).or_default().push(locale_key);
+                }
+            }
+        }
+
+        locales
+    }
+
+    pub fn get(&self, key: &str) -> &[LocaleKey] {
+        self.keys.get(key).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+// Walks the mappings of a locale file by indentation, which is all Rails
+// locale files use. Lists, flow mappings, and multiline values are skipped.
+fn locale_keys(path: &str, contents: &str) -> Vec<(String, LocaleKey)> {
+    let mut locale_keys = vec![];
+    // The indentation and name of the keys the current line is nested in
+    let mut parents: Vec<(usize, String)> = vec![];
+    // Lines indented past a key with a value are part of that value
+    let mut value_indent: Option<usize> = None;
+
+    for (line, line_text) in contents.lines().enumerate() {
+        let text = line_text.trim_start();
+        let indent = line_text.len() - text.len();
+
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+
+        match value_indent {
+            Some(value_indent) if indent > value_indent => continue,
+            _ => value_indent = None,
+        }
+
+        let (name, name_start, name_end, value) = match yaml_key(text) {
+            Some(yaml_key) => yaml_key,
+            None => continue,
+        };
+
+        parents.retain(|(parent_indent, _name)| *parent_indent < indent);
+
+        // e.g. `defaults: &defaults` nests keys like an empty value does
+        let value = value.trim();
+        let anchor = value.starts_with('&') && !value.contains(char::is_whitespace);
+        if !value.is_empty() && !value.starts_with('#') && !anchor {
+            value_indent = Some(indent);
+        }
+
+        if name == "<<" {
+            continue;
+        }
+
+        if let Some((_indent, locale)) = parents.first() {
+            let mut key: Vec<&str> = parents[1..]
+                .iter()
+                .map(|(_indent, name)| name.as_str())
+                .collect();
+            key.push(&name);
+
+            locale_keys.push((
+                key.join("."),
+                LocaleKey {
+                    locale: locale.clone(),
+                    path: path.to_string(),
+                    line,
+                    start_column: indent + name_start,
+                    end_column: indent + name_end,
+                },
+            ));
+        }
+
+        parents.push((indent, name));
+    }
+
+    locale_keys
+}
+
+// The key of a `key: value` line with where its name starts and ends, and the
+// rest of the line after the `:`
+fn yaml_key(text: &str) -> Option<(String, usize, usize, &str)> {
+    if text.starts_with('-') || text.starts_with('{') || text.starts_with('[') {
+        return None;
+    }
+
+    if let Some(quote) = text.chars().next().filter(|c| *c == '"' || *c == '\'') {
+        let name_end = text[1..].find(quote)? + 1;
+        let value = text[name_end + 1..].strip_prefix(':')?;
+
+        if !value.is_empty() && !value.starts_with(char::is_whitespace) {
+            return None;
+        }
+
+        return Some((text[1..name_end].to_string(), 1, name_end, value));
+    }
+
+    let name_end = text
+        .match_indices(':')
+        .map(|(index, _)| index)
+        .find(|index| {
+            text[index + 1..].is_empty() || text[index + 1..].starts_with(char::is_whitespace)
+        })?;
+    let name = text[..name_end].trim_end();
+
+    if name.is_empty() || name.contains(" #") {
+        return None;
+    }
+
+    Some((name.to_string(), 0, name.len(), &text[name_end + 1..]))
+}
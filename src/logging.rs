@@ -0,0 +1,59 @@
+use env_logger::{Builder, Env, Target};
+use serde_json::json;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use crate::config::{Config, LogFormat};
+
+// Stdout carries the LSP messages, so logs go to stderr unless `logFile` is
+// set. `RUST_LOG` is used when `logLevel` isn't.
+pub fn init(config: &Config, workspace_path: &str) -> Result<(), String> {
+    let mut builder = Builder::from_env(Env::default());
+    let mut result = Ok(());
+
+    if let Some(log_level) = config.log_level {
+        builder.filter_level(log_level.into());
+    }
+
+    if let Some(log_file) = &config.log_file {
+        // Relative to the workspace, or the working directory without one
+        let log_file_path = Path::new(workspace_path).join(log_file);
+
+        match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_file_path)
+        {
+            Ok(file) => {
+                builder.target(Target::Pipe(Box::new(file)));
+            }
+            Err(error) => {
+                result = Err(format!(
+                    "Couldn't open log file {}: {}",
+                    log_file_path.display(),
+                    error
+                ));
+            }
+        }
+    }
+
+    // One object per line, e.g.
+    // `{"timestamp":"...","level":"INFO","target":"fuzzy::persistence","message":"..."}`
+    if config.log_format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let entry = json!({
+                "timestamp": buf.timestamp_millis().to_string(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+
+            writeln!(buf, "{}", entry)
+        });
+    }
+
+    let _ = builder.try_init();
+
+    result
+}
@@ -0,0 +1,365 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use tower_lsp::lsp_types::{Location, Range, Url};
+
+// An LSIF dump of the definitions and references in the index, for code
+// intelligence platforms like Sourcegraph. Usages resolved to a single
+// definition share its result set, so finding references from either one
+// lists them all. Every vertex comes before the edges pointing at it.
+//
+// https://microsoft.github.io/language-server-protocol/specifications/lsif/0.4.0/specification/
+pub fn dump(
+    project_root: &Url,
+    definitions: Vec<Location>,
+    usages: Vec<(Location, Vec<Location>)>,
+) -> Vec<Value> {
+    let mut dump = Dump::default();
+
+    dump.vertex(
+        "metaData",
+        json!({
+            "version": "0.4.3",
+            "projectRoot": project_root,
+            "positionEncoding": "utf-16",
+            "toolInfo": { "name": "fuzzy", "version": env!("CARGO_PKG_VERSION") },
+        }),
+    );
+    let project = dump.vertex("project", json!({ "kind": "ruby" }));
+
+    for definition in &definitions {
+        dump.definition(definition);
+    }
+
+    for (usage, usage_definitions) in &usages {
+        if dump.definitions.contains_key(&location_key(usage)) {
+            continue;
+        }
+
+        let (document, range) = dump.range(usage);
+        let definition_indexes: Vec<usize> = usage_definitions
+            .iter()
+            .map(|definition| dump.definition(definition))
+            .collect();
+
+        if let [definition_index] = definition_indexes[..] {
+            let result_set = dump.result_sets[definition_index].id;
+            dump.edge("next", json!({ "outV": range, "inV": result_set }));
+        } else {
+            // Ambiguous usages get their own result listing every candidate
+            let definition_result = dump.vertex("definitionResult", json!({}));
+            dump.edge(
+                "textDocument/definition",
+                json!({ "outV": range, "inV": definition_result }),
+            );
+
+            for definition_index in &definition_indexes {
+                let result_set = &dump.result_sets[*definition_index];
+                let item = json!({
+                    "outV": definition_result,
+                    "inVs": [result_set.range],
+                    "document": result_set.document,
+                });
+                dump.edge("item", item);
+            }
+        }
+
+        for definition_index in definition_indexes {
+            dump.result_sets[definition_index]
+                .references
+                .entry(document)
+                .or_default()
+                .push(range);
+        }
+    }
+
+    for result_set in std::mem::take(&mut dump.result_sets) {
+        let definition_result = dump.vertex("definitionResult", json!({}));
+        dump.edge(
+            "textDocument/definition",
+            json!({ "outV": result_set.id, "inV": definition_result }),
+        );
+        dump.edge(
+            "item",
+            json!({
+                "outV": definition_result,
+                "inVs": [result_set.range],
+                "document": result_set.document,
+            }),
+        );
+
+        let reference_result = dump.vertex("referenceResult", json!({}));
+        dump.edge(
+            "textDocument/references",
+            json!({ "outV": result_set.id, "inV": reference_result }),
+        );
+        dump.edge(
+            "item",
+            json!({
+                "outV": reference_result,
+                "inVs": [result_set.range],
+                "document": result_set.document,
+                "property": "definitions",
+            }),
+        );
+
+        let mut references: Vec<(u64, Vec<u64>)> = result_set.references.into_iter().collect();
+        references.sort();
+
+        for (document, ranges) in references {
+            dump.edge(
+                "item",
+                json!({
+                    "outV": reference_result,
+                    "inVs": ranges,
+                    "document": document,
+                    "property": "references",
+                }),
+            );
+        }
+    }
+
+    let mut documents: Vec<(u64, Vec<u64>)> = dump.documents.values().cloned().collect();
+    documents.sort();
+
+    for (document, ranges) in &documents {
+        dump.edge("contains", json!({ "outV": document, "inVs": ranges }));
+    }
+
+    let document_ids: Vec<u64> = documents.iter().map(|(document, _)| *document).collect();
+    dump.edge("contains", json!({ "outV": project, "inVs": document_ids }));
+
+    dump.vertices.append(&mut dump.edges);
+    dump.vertices
+}
+
+// `Location` isn't `Hash`
+type LocationKey = (Url, u32, u32, u32, u32);
+
+fn location_key(location: &Location) -> LocationKey {
+    let Range { start, end } = location.range;
+
+    (
+        location.uri.clone(),
+        start.line,
+        start.character,
+        end.line,
+        end.character,
+    )
+}
+
+struct ResultSet {
+    id: u64,
+    document: u64,
+    range: u64,
+    // Usage ranges by document
+    references: HashMap<u64, Vec<u64>>,
+}
+
+#[derive(Default)]
+struct Dump {
+    next_id: u64,
+    vertices: Vec<Value>,
+    edges: Vec<Value>,
+    // Document id and the ids of the ranges in it, by URI
+    documents: HashMap<Url, (u64, Vec<u64>)>,
+    ranges: HashMap<LocationKey, (u64, u64)>,
+    // Indexes into `result_sets`
+    definitions: HashMap<LocationKey, usize>,
+    result_sets: Vec<ResultSet>,
+}
+
+impl Dump {
+    fn vertex(&mut self, label: &str, properties: Value) -> u64 {
+        self.element("vertex", label, properties)
+    }
+
+    fn edge(&mut self, label: &str, properties: Value) -> u64 {
+        self.element("edge", label, properties)
+    }
+
+    fn element(&mut self, element_type: &str, label: &str, properties: Value) -> u64 {
+        self.next_id += 1;
+
+        let mut element = json!({ "id": self.next_id, "type": element_type, "label": label });
+        if let (Some(element), Value::Object(properties)) = (element.as_object_mut(), properties) {
+            element.extend(properties);
+        }
+
+        if element_type == "vertex" {
+            self.vertices.push(element);
+        } else {
+            self.edges.push(element);
+        }
+
+        self.next_id
+    }
+
+    // The document and range ids for a location, added the first time it's seen
+    fn range(&mut self, location: &Location) -> (u64, u64) {
+        if let Some(ids) = self.ranges.get(&location_key(location)) {
+            return *ids;
+        }
+
+        let document = match self.documents.get(&location.uri) {
+            Some((document, _)) => *document,
+            None => {
+                let document = self.vertex(
+                    "document",
+                    json!({ "uri": location.uri, "languageId": "ruby" }),
+                );
+                self.documents
+                    .insert(location.uri.clone(), (document, vec![]));
+                document
+            }
+        };
+
+        let range = self.vertex(
+            "range",
+            json!({ "start": location.range.start, "end": location.range.end }),
+        );
+
+        if let Some((_, ranges)) = self.documents.get_mut(&location.uri) {
+            ranges.push(range);
+        }
+        self.ranges
+            .insert(location_key(location), (document, range));
+
+        (document, range)
+    }
+
+    // The index of a definition's result set, added the first time it's seen
+    fn definition(&mut self, location: &Location) -> usize {
+        if let Some(definition_index) = self.definitions.get(&location_key(location)) {
+            return *definition_index;
+        }
+
+        let (document, range) = self.range(location);
+        let id = self.vertex("resultSet", json!({}));
+        self.edge("next", json!({ "outV": range, "inV": id }));
+
+        self.definitions
+            .insert(location_key(location), self.result_sets.len());
+        self.result_sets.push(ResultSet {
+            id,
+            document,
+            range,
+            references: HashMap::new(),
+        });
+
+        self.result_sets.len() - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower_lsp::lsp_types::Position;
+
+    fn location(path: &str, line: u32) -> Location {
+        Location::new(
+            Url::parse(&format!("file:///app/{}", path)).unwrap(),
+            Range::new(Position::new(line, 2), Position::new(line, 7)),
+        )
+    }
+
+    fn elements<'a>(dump: &'a [Value], label: &str) -> Vec<&'a Value> {
+        dump.iter()
+            .filter(|element| element["label"] == label)
+            .collect()
+    }
+
+    // The ids an edge points at
+    fn in_vertices(edge: &Value) -> Vec<u64> {
+        match edge["inVs"].as_array() {
+            Some(in_vertices) => in_vertices.iter().flat_map(Value::as_u64).collect(),
+            None => edge["inV"].as_u64().into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn vertices_come_before_the_edges_pointing_at_them() {
+        let project_root = Url::parse("file:///app").unwrap();
+        let dump = dump(
+            &project_root,
+            vec![location("user.rb", 1)],
+            vec![(location("greeter.rb", 2), vec![location("user.rb", 1)])],
+        );
+
+        assert_eq!(dump[0]["label"], "metaData");
+
+        let mut seen = vec![];
+
+        for element in &dump {
+            let id = element["id"].as_u64().unwrap();
+
+            if element["type"] == "edge" {
+                assert!(seen.contains(&element["outV"].as_u64().unwrap()));
+                assert!(in_vertices(element).iter().all(|id| seen.contains(id)));
+            }
+
+            assert!(!seen.contains(&id));
+            seen.push(id);
+        }
+    }
+
+    #[test]
+    fn usages_share_their_definitions_result_set() {
+        let project_root = Url::parse("file:///app").unwrap();
+        let dump = dump(
+            &project_root,
+            vec![location("user.rb", 1)],
+            vec![(location("greeter.rb", 2), vec![location("user.rb", 1)])],
+        );
+
+        let ranges = elements(&dump, "range");
+        let result_sets = elements(&dump, "resultSet");
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(result_sets.len(), 1);
+
+        // Both ranges go on to the one result set
+        let next_edges = elements(&dump, "next");
+        assert_eq!(next_edges.len(), 2);
+        assert!(next_edges
+            .iter()
+            .all(|edge| edge["inV"] == result_sets[0]["id"]));
+
+        let references = elements(&dump, "item")
+            .into_iter()
+            .find(|edge| edge["property"] == "references")
+            .unwrap();
+        let usage_range = ranges
+            .iter()
+            .find(|range| range["start"]["line"] == 2)
+            .unwrap();
+        assert_eq!(
+            in_vertices(references),
+            vec![usage_range["id"].as_u64().unwrap()]
+        );
+    }
+
+    #[test]
+    fn ambiguous_usages_list_every_definition() {
+        let project_root = Url::parse("file:///app").unwrap();
+        let dump = dump(
+            &project_root,
+            vec![],
+            vec![(
+                location("greeter.rb", 2),
+                vec![location("user.rb", 1), location("admin.rb", 4)],
+            )],
+        );
+
+        let usage_range = elements(&dump, "range")[0]["id"].clone();
+        let definition_edge = elements(&dump, "textDocument/definition")
+            .into_iter()
+            .find(|edge| edge["outV"] == usage_range)
+            .unwrap();
+        let definition_items: Vec<&Value> = elements(&dump, "item")
+            .into_iter()
+            .filter(|edge| edge["outV"] == definition_edge["inV"])
+            .collect();
+
+        assert_eq!(definition_items.len(), 2);
+        assert_eq!(elements(&dump, "document").len(), 3);
+    }
+}
@@ -1,727 +1,838 @@
+mod code_actions;
+mod completion;
+mod hover;
+mod indexer;
+mod query;
+mod rename;
+mod schema;
+mod signature_help;
+mod symbols;
+mod workspace;
+
 use filetime::FileTime;
-use jwalk::WalkDirGeneric;
-use lib_ruby_parser::source::DecodedInput;
-use lib_ruby_parser::{nodes::*, Loc, Node, Parser, ParserOptions};
+use levenshtein_automata::{Distance, LevenshteinAutomatonBuilder, DFA, SINK_STATE};
 use log::info;
-use phf::phf_map;
-use regex::Regex;
+use once_cell::sync::Lazy;
+use phf::phf_set;
 use serde_json::json;
 use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::process::Command;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 use std::str;
-use tantivy::collector::TopDocs;
-use tantivy::query::{BooleanQuery, BoostQuery, Occur, Query, RegexQuery, TermQuery};
-use tantivy::{schema::*, ReloadPolicy, Document};
-use tantivy::{Index, IndexWriter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tantivy::collector::{Count, DocSetCollector, TopDocs};
+use tantivy::directory::{Directory, MmapDirectory, RamDirectory};
+use tantivy::query::{BooleanQuery, Occur, Query, RangeQuery, TermQuery};
+use tantivy::{schema::*, Document, ReloadPolicy};
+use tantivy::{Index, IndexReader, Searcher};
+use tantivy_fst::Automaton;
+use tokio::sync::Notify;
 use tower_lsp::lsp_types::InitializeParams;
 use tower_lsp::lsp_types::{
-    DocumentHighlight, DocumentHighlightKind, Location, Position, Range, SymbolInformation,
-    SymbolKind, TextDocumentPositionParams, TextEdit, Url, WorkspaceEdit,
+    CodeLens, DiagnosticSeverity, DiagnosticTag, DocumentHighlight, DocumentHighlightKind,
+    DocumentLink, Location, Moniker, MonikerKind, Position, Range, TextDocumentIdentifier,
+    TextDocumentPositionParams, UniquenessLevel, Url,
 };
 use tower_lsp::Client;
 
-static USAGE_TYPE_RESTRICTIONS: phf::Map<&'static str, &[&str]> = phf_map! {
-    "Alias" => &[
-        "Alias", "Def", "Defs",
-        "CSend", "Send", "Super", "ZSuper",
-    ],
-    "Const" => &[
-        "Casgn", "Class", "Module",
-        "Const"
-    ],
-    "CSend" => &[
-        "Alias", "Def", "Defs",
-        "CSend", "Send", "Super", "ZSuper",
-    ],
-    "Cvar" => &[
-        "Cvasgn",
-        "Cvar"
-    ],
-    "Gvar" => &[
-        "Gvasgn",
-        "Gvar"
-    ],
-    "Ivar" => &[
-        "Ivasgn",
-        "Ivar"
-    ],
-    "Lvar" => &[
-        "Arg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg", "Shadowarg",
-        "Lvar"
-    ],
-    "Send" => &[
-        "Alias", "Def", "Defs",
-        "CSend", "Send", "Super", "ZSuper",
-    ],
-    "Super" => &[
-        "Alias", "Def", "Defs",
-        "CSend", "Send", "Super", "ZSuper",
-    ],
-    "ZSuper" => &[
-        "Alias", "Def", "Defs",
-        "CSend", "Send", "Super", "ZSuper",
-    ],
+use crate::config::{AllocationType, Config, RelatedFiles, Severity};
+use crate::locales::Locales;
+use crate::ruby_version::{self, RubyVersion};
+use crate::session::{self, SessionId};
+use crate::snapshot::{self, SnapshotMetadata, SNAPSHOT_VERSION};
+use crate::telemetry::MeasuredSearcher;
+use crate::workspace_path::{self, uri_path, WorkspacePath};
+use crate::write_queue::WriteQueue;
+use indexer::{FuzzyNode, ParseFindings, RenderedView, RequiredPath, ScopeSpan};
+use schema::{name_lowercase_term, SchemaFields};
+use workspace::{
+    bundle_dirs, bundle_path, gem_homes, gem_name_and_version, IndexableDir, WatchedFiles,
 };
 
-static ASSIGNMENT_TYPE_RESTRICTIONS: phf::Map<&'static str, &[&str]> = phf_map! {
-    "Alias" => &[
-        "Alias", "CSend", "Send", "Super", "ZSuper",
-        "Def", "Defs"
-    ],
-    "Arg" => &[
-        "Lvar",
-        "Arg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg", "Shadowarg"
-    ],
-    "Casgn" => &[
-        "Const",
-        "Casgn", "Class", "Module"
-    ],
-    "Class" => &[
-        "Const",
-        "Casgn", "Class", "Module"
-    ],
-    "Cvasgn" => &[
-        "Cvar",
-        "Cvasgn"
-    ],
-    "Def" => &[
-        "Alias", "CSend", "Send", "Super", "ZSuper",
-        "Def"
-    ],
-    "Defs" => &[
-        "Alias", "CSend", "Send", "Super", "ZSuper",
-        "Defs"
-    ],
-    "Gvasgn" => &[
-        "Gvar",
-        "Gvasgn"
-    ],
-    "Ivasgn" => &[
-        "Ivar",
-        "Ivasgn"
-    ],
-    "Kwarg" => &[
-        "Lvar",
-        "Arg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg", "Shadowarg"
-    ],
-    "Kwoptarg" => &[
-        "Lvar",
-        "Arg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg", "Shadowarg"
-    ],
-    "Kwrestarg" => &[
-        "Lvar",
-        "Arg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg", "Shadowarg"
-    ],
-    "Lvasgn" => &[
-        "Lvar",
-        "Arg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg", "Shadowarg"
-    ],
-    "MatchVar" => &[
-        "Lvar",
-        "Arg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg", "Shadowarg"
-    ],
-    "Module" => &[
-        "Const",
-        "Casgn", "Class", "Module"
-    ],
-    "Optarg" => &[
-        "Lvar",
-        "Arg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg", "Shadowarg"
-    ],
-    "Restarg" => &[
-        "Lvar",
-        "Arg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg", "Shadowarg"
-    ],
-    "Shadowarg" => &[
-        "Lvar",
-        "Arg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg", "Shadowarg"
-    ],
+pub use indexer::{NodeContext, NodeHandler};
+pub use workspace::stdlib_path;
+
+// Methods that Ruby calls implicitly, so they're never unused
+static IMPLICITLY_CALLED_METHODS: phf::Set<&'static str> = phf_set! {
+    "call", "coerce", "each", "eql?", "extended", "hash", "included", "inherited",
+    "initialize", "initialize_copy", "inspect", "method_added", "method_missing",
+    "prepended", "respond_to_missing?", "to_a", "to_ary", "to_h", "to_hash", "to_proc",
+    "to_s", "to_str",
 };
 
-#[derive(Clone)]
-pub struct IndexableDir {
-    path: String,
-    interface_only: bool,
+// Stored in a Const usage's class scope when it's prefixed with `::`
+const TOP_LEVEL_SCOPE: &str = "::";
+
+// Stored in a Send usage's class scope when the receiver is a constant, e.g.
+// `User.find`, so class methods are preferred over instance methods
+const SINGLETON_RECEIVER_SCOPE: &str = "self.";
+
+// Starts the documentation of constants indexed from an `autoload`, which
+// are found along with the class or module they load
+const AUTOLOAD_DOCUMENTATION: &str = "Autoloaded from ";
+
+// Hovering an ambiguous call lists at most this many definitions
+const HOVER_DEFINITION_LIMIT: usize = 5;
+
+// Builders for the Levenshtein automata that find the names one and two edits
+// away from an undefined one. They take a while to make, so they're reused.
+static LEVENSHTEIN_BUILDERS: Lazy<[LevenshteinAutomatonBuilder; 2]> = Lazy::new(|| {
+    [
+        LevenshteinAutomatonBuilder::new(1, false),
+        LevenshteinAutomatonBuilder::new(2, false),
+    ]
+});
+
+// How far the current bulk indexing phase has got, shared with the task that
+// reports progress to the editor
+#[derive(Default)]
+pub struct IndexingProgress {
+    phase: Mutex<&'static str>,
+    indexed: AtomicUsize,
+    total: AtomicUsize,
+}
+
+impl IndexingProgress {
+    fn start(&self, phase: &'static str, total: usize) {
+        *self.phase.lock().unwrap() = phase;
+        self.indexed.store(0, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    // Returns how many files have been indexed in this phase
+    fn advance(&self) -> usize {
+        self.indexed.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // e.g. ("gems", 40)
+    pub fn status(&self) -> (&'static str, u32) {
+        let (phase, indexed, total) = self.counts();
+        let total = total.max(1);
+
+        (phase, (indexed.min(total) * 100 / total) as u32)
+    }
+
+    // The phase with how many of its files have been indexed so far and how
+    // many there are, e.g. ("gems", 120, 4000)
+    pub fn counts(&self) -> (&'static str, usize, usize) {
+        (
+            *self.phase.lock().unwrap(),
+            self.indexed.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+        )
+    }
 }
 
 pub struct Persistence {
     schema: Schema,
     schema_fields: SchemaFields,
     index: Option<Index>,
-    workspace_path: String,
+    reader: Option<IndexReader>,
+    write_queue: Option<WriteQueue>,
+    indexing_progress: Arc<IndexingProgress>,
+    watched_files: Arc<Mutex<WatchedFiles>>,
+    // Wakes the background indexer before its next periodic reindex
+    reindex_requests: Arc<Notify>,
+    workspace_path: WorkspacePath,
     last_reindex_time: i64,
     indexed_file_paths: HashSet<String>,
+    // Content hashes from a loaded snapshot, until the workspace is walked
+    snapshot_file_hashes: Arc<HashMap<String, String>>,
     process_id: Option<u32>,
     no_workspace: bool,
+    single_file: bool,
     gems_indexed: bool,
+    stdlib_indexed: bool,
+    // Where the standard library is, once it's been found
+    stdlib_path: Option<String>,
     include_dirs_indexed: bool,
     index_interface_only: bool,
-    class_scope: Vec<String>,
+    // What's indexed and reported, from the settings and shared with the
+    // background indexer
+    settings: Arc<IndexSettings>,
+    include_dir_file_count: usize,
+    gem_file_count: usize,
+    reindex_durations: HashMap<&'static str, Duration>,
+    unindexed_gems: HashMap<String, String>,
+    // Every gem's folder, indexed or not, for resolving `require`
+    gem_paths: Arc<Vec<String>>,
+    // The workspace and its engines with a `Gemfile.lock`
+    bundle_dirs: Vec<String>,
+    // The keys in `config/locales`, loaded again on each reindex
+    locales: Arc<Locales>,
+    // What the last parsed file has besides its definitions and usages
+    findings: ParseFindings,
+    // The open files of each editor session
+    open_documents: HashMap<SessionId, HashMap<Url, ParsedDocument>>,
+}
+
+// The last parse of an open file, reused until its text changes
+#[derive(Clone)]
+struct ParsedDocument {
+    content_hash: blake3::Hash,
+    parser_diagnostics: Vec<Option<tower_lsp::lsp_types::Diagnostic>>,
+    parsed: bool,
+    documents: Vec<FuzzyNode<'static>>,
+    text: String,
+    findings: ParseFindings,
+    indexed: bool,
+}
+
+// The settings that decide what's indexed and reported. The background
+// indexer shares them, so they're only copied when they change while it runs.
+#[derive(Clone)]
+struct IndexSettings {
+    node_handlers: Vec<Arc<dyn NodeHandler>>,
+    // Whether the Ruby that gems are installed for has its standard library
+    // indexed along with them
+    index_stdlib: bool,
     include_dirs: Vec<IndexableDir>,
-    pub report_diagnostics: bool,
+    // Directories in the workspace that aren't indexed, from `excludes`
+    excluded_dirs: Vec<PathBuf>,
+    symbol_search_limit: usize,
+    related_files: Vec<RelatedFiles>,
+    autoload_paths: Vec<String>,
+    index_rspec_let: bool,
+    index_dynamic_sends: bool,
+    undefined_name_diagnostics: bool,
+    lazy_gem_indexing: bool,
+    index_gem_groups: Option<Vec<String>>,
+    // From `rubyVersion` or the project's `.ruby-version`, for what syntax is
+    // parsed
+    ruby_version: Option<RubyVersion>,
+    report_diagnostics: bool,
+    diagnostic_severities: DiagnosticSeverities,
+}
+
+impl Default for IndexSettings {
+    fn default() -> Self {
+        Self {
+            node_handlers: indexer::node_handlers(),
+            index_stdlib: true,
+            include_dirs: Vec::new(),
+            excluded_dirs: Vec::new(),
+            symbol_search_limit: 100,
+            related_files: vec![],
+            autoload_paths: vec![],
+            index_rspec_let: false,
+            index_dynamic_sends: false,
+            undefined_name_diagnostics: false,
+            lazy_gem_indexing: false,
+            index_gem_groups: None,
+            ruby_version: None,
+            report_diagnostics: true,
+            diagnostic_severities: DiagnosticSeverities::default(),
+        }
+    }
 }
 
-struct SchemaFields {
-    file_path_id: Field,
-    file_path: Field,
-    category_field: Field,
-    fuzzy_ruby_scope_field: Field,
-    class_scope_field: Field,
-    name_field: Field,
-    node_type_field: Field,
-    line_field: Field,
-    start_column_field: Field,
-    end_column_field: Field,
-    columns_field: Field,
-    user_space_field: Field,
+// The severity of each kind of diagnostic, `None` when it's turned off
+#[derive(Clone)]
+struct DiagnosticSeverities {
+    syntax: Option<DiagnosticSeverity>,
+    warnings: Option<DiagnosticSeverity>,
+    unused_locals: Option<DiagnosticSeverity>,
+    undefined_names: Option<DiagnosticSeverity>,
+    missing_translations: Option<DiagnosticSeverity>,
 }
 
-#[derive(Debug)]
-struct FuzzyNode<'a> {
-    category: &'a str,
-    fuzzy_ruby_scope: Vec<String>,
-    class_scope: Vec<String>,
-    name: String,
-    node_type: &'a str,
-    line: usize,
-    start_column: usize,
-    end_column: usize,
+impl Default for DiagnosticSeverities {
+    fn default() -> Self {
+        Self {
+            syntax: Some(DiagnosticSeverity::ERROR),
+            warnings: Some(DiagnosticSeverity::WARNING),
+            unused_locals: None,
+            undefined_names: Some(DiagnosticSeverity::WARNING),
+            missing_translations: None,
+        }
+    }
 }
 
 impl Persistence {
     pub fn new() -> tantivy::Result<Persistence> {
-        let mut schema_builder = Schema::builder();
-        let schema_fields = SchemaFields {
-            file_path_id: schema_builder.add_text_field(
-                "file_path_id",
-                TextOptions::default()
-                    .set_indexing_options(
-                        TextFieldIndexing::default()
-                            .set_tokenizer("raw")
-                            .set_index_option(IndexRecordOption::Basic),
-                    )
-                    .set_stored(),
-            ),
-            file_path: schema_builder.add_text_field(
-                "file_path",
-                TextOptions::default()
-                    .set_indexing_options(
-                        TextFieldIndexing::default()
-                            .set_tokenizer("raw")
-                            .set_index_option(IndexRecordOption::Basic),
-                    )
-                    .set_stored(),
-            ),
-            category_field: schema_builder.add_text_field(
-                "category",
-                TextOptions::default()
-                    .set_indexing_options(
-                        TextFieldIndexing::default()
-                            .set_tokenizer("raw")
-                            .set_index_option(IndexRecordOption::Basic),
-                    )
-                    .set_stored(),
-            ),
-            fuzzy_ruby_scope_field: schema_builder.add_text_field(
-                "fuzzy_ruby_scope",
-                TextOptions::default()
-                    .set_indexing_options(
-                        TextFieldIndexing::default()
-                            .set_tokenizer("raw")
-                            .set_index_option(IndexRecordOption::Basic),
-                    )
-                    .set_stored(),
-            ),
-            class_scope_field: schema_builder.add_text_field(
-                "class_scope",
-                TextOptions::default()
-                    .set_indexing_options(
-                        TextFieldIndexing::default()
-                            .set_tokenizer("raw")
-                            .set_index_option(IndexRecordOption::Basic),
-                    )
-                    .set_stored(),
-            ),
-            name_field: schema_builder.add_text_field(
-                "name",
-                TextOptions::default()
-                    .set_indexing_options(
-                        TextFieldIndexing::default()
-                            .set_tokenizer("raw")
-                            .set_index_option(IndexRecordOption::Basic),
-                    )
-                    .set_stored(),
-            ),
-            node_type_field: schema_builder.add_text_field(
-                "node_type",
-                TextOptions::default()
-                    .set_indexing_options(
-                        TextFieldIndexing::default()
-                            .set_tokenizer("raw")
-                            .set_index_option(IndexRecordOption::Basic),
-                    )
-                    .set_stored(),
-            ),
-            line_field: schema_builder.add_u64_field("line", INDEXED | STORED),
-            start_column_field: schema_builder.add_u64_field("start_column", INDEXED | STORED),
-            end_column_field: schema_builder.add_u64_field("end_column", INDEXED | STORED),
-            columns_field: schema_builder.add_u64_field("columns", INDEXED | STORED),
-            user_space_field: schema_builder.add_bool_field("user_space", INDEXED | STORED),
-        };
-
-        let schema = schema_builder.build();
+        let (schema, schema_fields) = SchemaFields::build();
         let index = None;
-        let workspace_path = "unset".to_string();
+        let reader = None;
+        let write_queue = None;
+        let indexing_progress = Arc::new(IndexingProgress::default());
+        let watched_files = Arc::new(Mutex::new(WatchedFiles::default()));
+        let reindex_requests = Arc::new(Notify::new());
+        let workspace_path = WorkspacePath::default();
         let last_reindex_time = FileTime::from_unix_time(0, 0).seconds();
         let indexed_file_paths = HashSet::new();
+        let snapshot_file_hashes = Arc::default();
         let process_id: Option<u32> = None;
-        let no_workspace = false;
+        let no_workspace = true;
+        let single_file = false;
         let gems_indexed = false;
+        let stdlib_indexed = true;
+        let stdlib_path = None;
         let index_interface_only = false;
-        let class_scope = vec![];
-        let report_diagnostics = true;
-        let include_dirs = Vec::new();
+        let settings = Arc::default();
         let include_dirs_indexed = false;
+        let include_dir_file_count = 0;
+        let gem_file_count = 0;
+        let reindex_durations = HashMap::new();
+        let unindexed_gems = HashMap::new();
+        let gem_paths = Arc::default();
+        let bundle_dirs = vec![];
+        let locales = Arc::default();
+        let findings = ParseFindings::default();
+        let open_documents = HashMap::new();
 
         Ok(Self {
             schema,
             schema_fields,
             index,
+            reader,
+            write_queue,
+            indexing_progress,
+            watched_files,
+            reindex_requests,
             workspace_path,
             last_reindex_time,
             indexed_file_paths,
+            snapshot_file_hashes,
             process_id,
             no_workspace,
+            single_file,
             gems_indexed,
+            stdlib_indexed,
+            stdlib_path,
             index_interface_only,
-            class_scope,
-            report_diagnostics,
-            include_dirs,
+            settings,
             include_dirs_indexed,
+            include_dir_file_count,
+            gem_file_count,
+            reindex_durations,
+            unindexed_gems,
+            gem_paths,
+            bundle_dirs,
+            locales,
+            findings,
+            open_documents,
         })
     }
 
-    pub fn initialize(&mut self, params: &InitializeParams) {
-        let uri = params.root_uri.as_ref().unwrap_or_else(|| {
-            info!("root_uri wasn't given to initialize, exiting.");
-            quit::with_code(1);
+    pub fn initialize(&mut self, params: &InitializeParams, config: &Config) {
+        self.index = self
+            .load_snapshot(params, config)
+            .or_else(|| match config.allocation_type {
+                AllocationType::Ram => Some(Index::create_in_ram(self.schema.clone())),
+                AllocationType::Tempdir => {
+                    Some(Index::create_from_tempdir(self.schema.clone()).unwrap())
+                }
+            });
+
+        // Shared by every search and reloaded after each commit, rather than
+        // opening the segments again for every request
+        self.reader = self.index.as_ref().map(|index| {
+            index
+                .reader_builder()
+                .reload_policy(ReloadPolicy::Manual)
+                .try_into()
+                .unwrap()
         });
 
-        self.workspace_path = uri.path().to_string();
+        if let (Some(index), Some(reader)) = (&self.index, &self.reader) {
+            self.write_queue = Some(WriteQueue::spawn(index, reader.clone()).unwrap());
+        }
+
+        if !config.index_gems {
+            self.gems_indexed = true;
+        }
 
-        let default_user_config = json!({});
-        let default_allocation_type = json!("ram");
+        let settings = Arc::make_mut(&mut self.settings);
+        settings.index_rspec_let = config.index_rspec_let;
+        settings.index_dynamic_sends = config.index_dynamic_sends;
+        settings.lazy_gem_indexing = config.lazy_gem_indexing;
+        settings.index_gem_groups = config.index_gem_groups.clone();
 
-        let user_config = &params
-            .initialization_options
-            .as_ref()
-            .unwrap_or(&default_user_config)
-            .as_object()
-            .unwrap();
-        let allocation_type = user_config
-            .get("allocationType")
-            .unwrap_or(&default_allocation_type)
-            .as_str()
-            .unwrap();
-
-        self.index = match allocation_type {
-            "ram" => Some(Index::create_in_ram(self.schema.clone())),
-            "tempdir" => Some(Index::create_from_tempdir(self.schema.clone()).unwrap()),
-            _ => {
-                info!("Unknown allocation_type, defaulting to tempdir");
-                Some(Index::create_from_tempdir(self.schema.clone()).unwrap())
-            }
+        match &params.root_uri {
+            Some(uri) => self.open_workspace(&WorkspacePath::from_uri(uri), config),
+            None => info!("No workspace folder was given, waiting for a file to be opened"),
+        }
+
+        self.configure(config);
+    }
+
+    // Starts from the workspace's snapshot when it was made with the same
+    // schema and `Gemfile.lock`, so only files that changed since are indexed
+    fn load_snapshot(&mut self, params: &InitializeParams, config: &Config) -> Option<Index> {
+        let workspace_path = WorkspacePath::from_uri(params.root_uri.as_ref()?);
+
+        if config.snapshot.is_empty() {
+            return None;
+        }
+
+        let snapshot_path = if Path::new(&config.snapshot).is_absolute() {
+            config.snapshot.to_string()
+        } else {
+            workspace_path.absolute_path(&config.snapshot)
         };
+        let snapshot_path = Path::new(&snapshot_path);
+        let metadata = snapshot::read_metadata(snapshot_path).ok()?;
+        let bundle_dirs = bundle_dirs(workspace_path.as_path());
+        let gemfile_lock_hash = snapshot::gemfile_lock_hash(&bundle_dirs);
+
+        if metadata.version != SNAPSHOT_VERSION
+            || metadata.schema_hash != snapshot::schema_hash(&self.schema)
+        {
+            info!(
+                "Ignoring {}, it was made by another version",
+                snapshot_path.display()
+            );
+            return None;
+        }
 
-        if let Some(included_dirs) = user_config.get("includeDirs") {
-            if let Some(dirs) = included_dirs.as_array() {
-                let dirs = dirs
-                    .iter()
-                    .map(|v| {
-                        // v.as_str().unwrap().to_string()
-                        let dir_params = v.as_object().unwrap();
-                        let dir_path = dir_params.get("path").unwrap().as_str().unwrap();
-                        let interface_only = {
-                            let param = dir_params.get("interface_only");
-                            match param {
-                                Some(val) => val.as_bool().unwrap(),
-                                None => true,
-                            }
-                        };
-
-                        let dir_path = dir_path.to_string();
-                        let absolute_dir_path = if dir_path.starts_with("/") {
-                            dir_path
-                        } else {
-                            format!("{}/{}", &self.workspace_path, dir_path)
-                        };
-
-                        IndexableDir {
-                            path: absolute_dir_path,
-                            interface_only,
-                        }
-                    })
-                    .collect();
+        if metadata.gemfile_lock_hash != gemfile_lock_hash {
+            info!(
+                "Ignoring {}, Gemfile.lock has changed since",
+                snapshot_path.display()
+            );
+            return None;
+        }
 
-                self.include_dirs = dirs;
-            };
+        // Gems are indexed by where they're installed, so they're only found
+        // again when they're still there
+        if config.index_gems
+            && (metadata.gem_homes != gem_homes(&bundle_dirs)
+                || !metadata
+                    .gem_paths
+                    .iter()
+                    .all(|gem_path| gem_path.is_empty() || Path::new(gem_path).exists()))
+        {
+            info!(
+                "Ignoring {}, its gems are installed elsewhere",
+                snapshot_path.display()
+            );
+            return None;
         }
 
-        let default_index_gems = json!(true);
-        let skip_indexing_gems = !user_config
-            .get("indexGems")
-            .unwrap_or(&default_index_gems)
-            .as_bool()
-            .unwrap();
-        if skip_indexing_gems {
-            self.gems_indexed = true;
+        let directory: Box<dyn Directory> = match config.allocation_type {
+            AllocationType::Ram => Box::new(RamDirectory::create()),
+            AllocationType::Tempdir => Box::new(MmapDirectory::create_from_tempdir().ok()?),
+        };
+
+        if let Err(error) = snapshot::read_index(snapshot_path, directory.as_ref()) {
+            info!("Couldn't load {}: {}", snapshot_path.display(), error);
+            return None;
         }
 
-        let default_report_diagnostics = json!(true);
-        let report_diagnostics = user_config
-            .get("reportDiagnostics")
-            .unwrap_or(&default_report_diagnostics)
-            .as_bool()
-            .unwrap();
-        if !report_diagnostics {
-            self.report_diagnostics = false;
-        }
-    }
-
-    pub fn reindex_modified_files(&mut self) -> tantivy::Result<()> {
-        let start_time = FileTime::from_unix_time(FileTime::now().unix_seconds(), 0).seconds() - 1;
-        let last_reindex_time = self.last_reindex_time.clone();
-
-        let walk_dir = WalkDirGeneric::<(usize, bool)>::new(&self.workspace_path).process_read_dir(
-            move |_depth, _path, _read_dir_state, children| {
-                children.retain(|dir_entry_result| {
-                    dir_entry_result
-                        .as_ref()
-                        .map(|dir_entry| {
-                            if let Some(file_name) = dir_entry.file_name.to_str() {
-                                let ruby_file = file_name.ends_with(".rb");
-                                dir_entry.file_type.is_dir() || ruby_file
-                            } else {
-                                false
-                            }
-                        })
-                        .unwrap_or(false)
-                });
-
-                children.iter_mut().for_each(|dir_entry_result| {
-                    if let Ok(dir_entry) = dir_entry_result {
-                        if let Some(file_name) = dir_entry.file_name.to_str() {
-                            if file_name.contains("node_modules")
-                                || file_name.contains("tmp")
-                                || file_name.contains(".git")
-                            {
-                                dir_entry.read_children_path = None;
-                            }
-                        }
-                    }
-                });
-            },
+        // Opening a reader checks every segment
+        let index = match Index::open(directory).and_then(|index| {
+            index.reader()?;
+            Ok(index)
+        }) {
+            Ok(index) => index,
+            Err(error) => {
+                info!("Couldn't load {}: {}", snapshot_path.display(), error);
+                return None;
+            }
+        };
+
+        info!(
+            "Loaded {} with {} workspace files",
+            snapshot_path.display(),
+            metadata.file_hashes.len()
         );
 
-        let mut new_indexable_file_paths = HashSet::new();
-        let mut indexed_file_paths = HashSet::new();
+        self.snapshot_file_hashes = Arc::new(metadata.file_hashes);
+        self.gems_indexed = true;
+        self.unindexed_gems = metadata.unindexed_gems;
+        self.gem_paths = Arc::new(metadata.gem_paths);
+        self.gem_file_count = metadata.gem_file_count;
 
-        for entry in walk_dir {
-            let path = entry.unwrap().path();
-            let path = path.to_str().unwrap();
-            let ruby_file = path.ends_with(".rb");
+        Some(index)
+    }
 
-            if ruby_file {
-                indexed_file_paths.insert(path.to_string());
-                self.indexed_file_paths.remove(path);
+    // For `fuzzy snapshot`, once everything has been indexed and committed
+    pub fn export_snapshot(&self, path: &Path) -> tantivy::Result<()> {
+        let index = match &self.index {
+            Some(index) => index,
+            None => return Ok(()),
+        };
 
-                let metadata = fs::metadata(path).unwrap();
+        let file_hashes = self
+            .indexed_file_paths
+            .iter()
+            .filter_map(|path| {
+                let file_hash = snapshot::file_hash(Path::new(path))?;
+
+                Some((self.workspace_path.relative_path(path), file_hash))
+            })
+            .collect();
+
+        let metadata = SnapshotMetadata {
+            version: SNAPSHOT_VERSION,
+            schema_hash: snapshot::schema_hash(&self.schema),
+            gemfile_lock_hash: snapshot::gemfile_lock_hash(&self.bundle_dirs),
+            file_hashes,
+            gem_homes: gem_homes(&self.bundle_dirs),
+            unindexed_gems: self.unindexed_gems.clone(),
+            gem_paths: self.gem_paths.to_vec(),
+            gem_file_count: self.gem_file_count,
+        };
 
-                let mtime = FileTime::from_last_modification_time(&metadata);
-                let recently_modified = mtime.seconds() >= last_reindex_time;
+        snapshot::write(path, &metadata, index)
+    }
 
-                if recently_modified {
-                    new_indexable_file_paths.insert(path.to_string());
-                }
-            }
-        }
+    // Sets the directory that's indexed. Until there is one, only open files
+    // are parsed, so features work within them but not across files.
+    pub fn open_workspace(&mut self, workspace_path: &WorkspacePath, config: &Config) {
+        self.workspace_path = workspace_path.clone();
+        self.no_workspace = false;
+        self.bundle_dirs = bundle_dirs(workspace_path.as_path());
+        Arc::make_mut(&mut self.settings).index_stdlib = config.index_stdlib.unwrap_or(true);
+        self.detect_ruby_version(config);
+
+        // Vendored gems are indexed with the other gems instead
+        self.workspace_path.exclude(
+            self.bundle_dirs
+                .iter()
+                .filter_map(|bundle_dir| bundle_path(Path::new(bundle_dir)))
+                .collect(),
+        );
 
-        if let Some(index) = &self.index {
-            let files_added = new_indexable_file_paths.len() > 0;
-            let files_deleted = self.indexed_file_paths.len() > 0;
+        let include_dirs = config
+            .include_dirs
+            .iter()
+            .map(|include_dir| {
+                let absolute_dir_path = if Path::new(&include_dir.path).is_absolute() {
+                    include_dir.path.to_string()
+                } else {
+                    self.workspace_path.absolute_path(&include_dir.path)
+                };
 
-            if files_added || files_deleted {
-                let mut index_writer = index.writer(256_000_000).unwrap();
+                IndexableDir {
+                    path: absolute_dir_path,
+                    interface_only: include_dir.interface_only,
+                }
+            })
+            .collect();
+
+        let excluded_dirs = config
+            .excludes
+            .iter()
+            .map(|excluded_dir| {
+                workspace_path
+                    .as_path()
+                    .join(excluded_dir.trim_matches('/'))
+            })
+            .collect();
+
+        let settings = Arc::make_mut(&mut self.settings);
+        settings.include_dirs = include_dirs;
+        settings.excluded_dirs = excluded_dirs;
+    }
 
-                for path in &self.indexed_file_paths {
-                    let relative_path = path.replace(&self.workspace_path, "");
+    // For a lone script outside of any project. Only open files are indexed,
+    // along with the standard library when `indexStdlib` is enabled.
+    pub fn open_single_file(&mut self, config: &Config) {
+        self.single_file = true;
+        self.stdlib_indexed = !config.index_stdlib.unwrap_or(false);
+    }
 
-                    let file_path_id = blake3::hash(&relative_path.as_bytes());
-                    let path_term = Term::from_field_text(
-                        self.schema_fields.file_path_id,
-                        &file_path_id.to_string(),
-                    );
+    // From the `rubyVersion` setting, or else the workspace's own files
+    fn detect_ruby_version(&mut self, config: &Config) {
+        let ruby_version = config
+            .ruby_version
+            .as_deref()
+            .and_then(RubyVersion::parse)
+            .or_else(|| RubyVersion::detect(self.workspace_path.as_path()));
 
-                    index_writer.delete_term(path_term);
-                }
+        Arc::make_mut(&mut self.settings).ruby_version = ruby_version;
+    }
 
-                for path in &new_indexable_file_paths {
-                    let text = fs::read_to_string(&path).unwrap();
-                    let uri = Url::from_file_path(&path).unwrap();
-                    let relative_path = uri.path().replace(&self.workspace_path, "");
-
-                    self.reindex_modified_file_without_commit(
-                        &text,
-                        relative_path,
-                        &index_writer,
-                        true,
-                    );
-                }
+    // `.ruby-version` and the other files at the workspace root that the Ruby
+    // version is detected from
+    pub fn ruby_version_file(&self, uri: &Url) -> bool {
+        let path = PathBuf::from(uri_path(uri));
 
-                index_writer.commit().unwrap();
-                info!("Indexing workspace complete!");
-            } else {
-                info!("No file changes, skipping periodic reindexing.")
-            }
-        }
+        !self.no_workspace
+            && path.parent() == Some(self.workspace_path.as_path())
+            && path.file_name().is_some_and(|file_name| {
+                ruby_version::VERSION_FILES
+                    .iter()
+                    .any(|name| file_name == *name)
+            })
+    }
 
-        self.last_reindex_time = start_time;
-        self.indexed_file_paths = indexed_file_paths;
+    pub fn report_diagnostics(&self) -> bool {
+        self.settings.report_diagnostics
+    }
 
-        Ok(())
+    pub fn no_workspace(&self) -> bool {
+        self.no_workspace
     }
 
-    pub fn index_included_dirs_once(&mut self) -> tantivy::Result<()> {
-        if self.include_dirs_indexed {
-            return Ok(());
-        }
+    // Neither a workspace nor single-file mode has been picked yet
+    pub fn awaiting_workspace(&self) -> bool {
+        self.no_workspace && !self.single_file
+    }
 
-        self.index_interface_only = true;
+    // Applies the settings that can change without reindexing, on startup and
+    // whenever the workspace config file changes
+    pub fn configure(&mut self, config: &Config) {
+        let settings = Arc::make_mut(&mut self.settings);
+        settings.report_diagnostics = config.report_diagnostics && config.features.diagnostics;
 
-        if self.include_dirs.len() > 0 {
-            let index = match &self.index {
-                Some(index) => index,
-                None => {
-                    info!("missing index");
-                    quit::with_code(1);
-                }
-            };
+        let defaults = DiagnosticSeverities::default();
+        let severity = |configured: Option<Severity>, default| {
+            configured.map_or(default, Severity::diagnostic_severity)
+        };
 
-            let mut index_writer = index.writer(256_000_000).unwrap();
-
-            for indexable_dir in self.include_dirs.clone() {
-                let walk_dir = WalkDirGeneric::<(usize, bool)>::new(indexable_dir.path.clone())
-                    .process_read_dir(move |_depth, _path, _read_dir_state, children| {
-                        children.retain(|dir_entry_result| {
-                            dir_entry_result
-                                .as_ref()
-                                .map(|dir_entry| {
-                                    if let Some(file_name) = dir_entry.file_name.to_str() {
-                                        let ruby_file = file_name.ends_with(".rb");
-                                        dir_entry.file_type.is_dir() || ruby_file
-                                    } else {
-                                        false
-                                    }
-                                })
-                                .unwrap_or(false)
-                        });
-
-                        children.iter_mut().for_each(|dir_entry_result| {
-                            if let Ok(dir_entry) = dir_entry_result {
-                                if let Some(file_name) = dir_entry.file_name.to_str() {
-                                    if file_name.contains("node_modules")
-                                        || file_name.contains("vendor")
-                                        || file_name.contains("tmp")
-                                        || file_name.contains(".git")
-                                    {
-                                        dir_entry.read_children_path = None;
-                                    }
-                                }
-                            }
-                        });
-                    });
-
-                let mut indexable_file_paths = Vec::new();
-
-                for entry in walk_dir {
-                    let path = entry.unwrap().path();
-                    let path = path.to_str().unwrap();
-                    let ruby_file = path.ends_with(".rb");
-
-                    if ruby_file {
-                        indexable_file_paths.push(path.to_string());
-                    }
-                }
+        settings.diagnostic_severities = DiagnosticSeverities {
+            syntax: severity(config.diagnostics.syntax, defaults.syntax),
+            warnings: severity(config.diagnostics.warnings, defaults.warnings),
+            unused_locals: severity(config.diagnostics.unused_locals, defaults.unused_locals),
+            undefined_names: severity(config.diagnostics.undefined_names, defaults.undefined_names),
+            missing_translations: severity(
+                config.diagnostics.missing_translations,
+                defaults.missing_translations,
+            ),
+        };
 
-                self.index_interface_only = indexable_dir.interface_only;
+        settings.symbol_search_limit = config.symbol_search_limit;
+        settings.undefined_name_diagnostics = config.undefined_name_diagnostics;
+        settings.related_files = config.related_files.clone();
+        settings.autoload_paths = config.autoload_paths.clone();
 
-                for path in &indexable_file_paths {
-                    if let Ok(text) = fs::read_to_string(&path) {
-                        let uri = Url::from_file_path(&path).unwrap();
-                        let relative_path = uri.path().replace(&self.workspace_path, "");
+        if !self.no_workspace {
+            let ruby_version = self.settings.ruby_version;
+            self.detect_ruby_version(config);
 
-                        self.reindex_modified_file_without_commit(
-                            &text,
-                            relative_path,
-                            &index_writer,
-                            false,
-                        );
-                    }
-                }
+            // Files parsed as the old version are parsed again right away
+            if self.settings.ruby_version != ruby_version {
+                self.reindex_requests.notify_one();
             }
+        }
+    }
+
+    // Indexes what a `NodeHandler` finds along with the built in handlers,
+    // from the next file indexed on. Files indexed before keep what they had
+    // until they change or the workspace is reindexed.
+    pub fn add_node_handler(&mut self, node_handler: Arc<dyn NodeHandler>) {
+        Arc::make_mut(&mut self.settings)
+            .node_handlers
+            .push(node_handler);
+    }
+
+    // A copy for the background indexing task. It shares the index, reader,
+    // and write queue, but has its own parser state, so queries never wait on
+    // indexing.
+    pub fn indexer(&self) -> tantivy::Result<Persistence> {
+        let mut indexer = Persistence::new()?;
+
+        indexer.schema = self.schema.clone();
+        indexer.schema_fields = self.schema_fields.clone();
+        indexer.index = self.index.clone();
+        indexer.reader = self.reader.clone();
+        indexer.write_queue = self.write_queue.clone();
+        indexer.indexing_progress = Arc::clone(&self.indexing_progress);
+        indexer.watched_files = Arc::clone(&self.watched_files);
+        indexer.reindex_requests = Arc::clone(&self.reindex_requests);
+        indexer.workspace_path = self.workspace_path.clone();
+        indexer.snapshot_file_hashes = Arc::clone(&self.snapshot_file_hashes);
+        indexer.settings = Arc::clone(&self.settings);
+        indexer.gem_paths = Arc::clone(&self.gem_paths);
+        indexer.bundle_dirs = self.bundle_dirs.clone();
+        indexer.no_workspace = self.no_workspace;
+        indexer.single_file = self.single_file;
+        indexer.gems_indexed = self.gems_indexed;
+        indexer.stdlib_indexed = self.stdlib_indexed;
+        indexer.stdlib_path = self.stdlib_path.clone();
+
+        Ok(indexer)
+    }
 
-            index_writer.commit().unwrap();
+    // Gives the background indexer the settings changed since it was copied.
+    // After a change of Ruby version, the workspace and `includeDirs` are
+    // indexed again. Gems are left as they are, since they're written for the
+    // Rubies they support rather than the project's.
+    pub fn refresh_indexer(&self, indexer: &mut Persistence) {
+        if indexer.settings.ruby_version != self.settings.ruby_version {
+            indexer.last_reindex_time = FileTime::from_unix_time(0, 0).seconds();
+            indexer.include_dirs_indexed = false;
         }
 
-        self.include_dirs_indexed = true;
-        self.index_interface_only = false;
+        indexer.settings = Arc::clone(&self.settings);
+    }
 
-        Ok(())
+    // Notified when the background indexer should reindex before its next
+    // periodic reindex, like when the Ruby version changes
+    pub fn reindex_requests(&self) -> Arc<Notify> {
+        Arc::clone(&self.reindex_requests)
     }
 
-    pub fn index_gems_once(&mut self) -> tantivy::Result<()> {
-        if self.gems_indexed {
-            return Ok(());
+    // Copies the progress of the background indexer for `fuzzy/indexStats`
+    // and checks that depend on what's been indexed
+    pub fn sync_indexing_status(&mut self, indexer: &Persistence) {
+        self.indexed_file_paths = indexer.indexed_file_paths.clone();
+        self.snapshot_file_hashes = Arc::clone(&indexer.snapshot_file_hashes);
+        self.gem_paths = Arc::clone(&indexer.gem_paths);
+        self.locales = Arc::clone(&indexer.locales);
+        self.last_reindex_time = indexer.last_reindex_time;
+        // Gems indexed on demand are counted and removed here afterwards, so
+        // these are only taken once
+        if !self.gems_indexed && indexer.gems_indexed {
+            self.unindexed_gems = indexer.unindexed_gems.clone();
+            self.gem_file_count = indexer.gem_file_count;
         }
 
-        self.index_interface_only = true;
+        self.gems_indexed = indexer.gems_indexed;
+        self.stdlib_indexed = indexer.stdlib_indexed;
+        self.stdlib_path = indexer.stdlib_path.clone();
+        self.include_dirs_indexed = indexer.include_dirs_indexed;
+        self.include_dir_file_count = indexer.include_dir_file_count;
+        self.reindex_durations = indexer.reindex_durations.clone();
+    }
 
-        // Four leading spaces dictates that it's a gem version
-        // https://github.com/rubygems/bundler/blob/v2.1.4/lib/bundler/lockfile_parser.rb#L174-L181
-        let gem_version = Regex::new(r"^\s{4}([a-zA-Z\d\.\-_]+)\s\(([\d\w\.\-_]+)\)").unwrap();
-        let gemfile_path = format!("{}/{}", &self.workspace_path, "Gemfile.lock");
+    // Counts the files of the gems an indexer copy indexed on demand
+    pub fn sync_claimed_gems(&mut self, indexer: &Persistence) {
+        self.gem_file_count += indexer.gem_file_count;
+    }
 
-        if let Ok(gemfile_contents) = fs::read_to_string(gemfile_path) {
-            let mut gem_paths = vec![];
-            let mut base_gem_path = "unset";
+    // Matches documents spanning a column, i.e. starting at or before it and
+    // ending at or after it
+    fn column_query(&self, column: u32) -> Box<dyn Query> {
+        let start_column_query = RangeQuery::new_u64_bounds(
+            self.schema
+                .get_field_name(self.schema_fields.start_column_field)
+                .to_string(),
+            Bound::Unbounded,
+            Bound::Included(column.into()),
+        );
+        let end_column_query = RangeQuery::new_u64_bounds(
+            self.schema
+                .get_field_name(self.schema_fields.end_column_field)
+                .to_string(),
+            Bound::Included(column.into()),
+            Bound::Unbounded,
+        );
 
-            let gem_home_path_result = Command::new("sh")
-                .arg("-c")
-                // .arg(format!("eval \"$(/usr/local/bin/rbenv init -)\" && cd {} && gem environment home", &self.workspace_path))
-                .arg(format!(
-                    "cd {} && gem environment home",
-                    &self.workspace_path
-                ))
-                .output();
+        Box::new(BooleanQuery::new(vec![
+            (Occur::Must, Box::new(start_column_query) as Box<dyn Query>),
+            (Occur::Must, Box::new(end_column_query)),
+        ]))
+    }
 
-            if let Ok(gem_home_path) = gem_home_path_result {
-                if let Ok(gem_home_path) = str::from_utf8(gem_home_path.stdout.as_slice()) {
-                    base_gem_path = gem_home_path;
-                }
+    fn add_full_range(&self, fuzzy_doc: &mut Document, full_range: Range) {
+        fuzzy_doc.add_u64(
+            self.schema_fields.full_start_line_field,
+            full_range.start.line.into(),
+        );
+        fuzzy_doc.add_u64(
+            self.schema_fields.full_start_column_field,
+            full_range.start.character.into(),
+        );
+        fuzzy_doc.add_u64(
+            self.schema_fields.full_end_line_field,
+            full_range.end.line.into(),
+        );
+        fuzzy_doc.add_u64(
+            self.schema_fields.full_end_column_field,
+            full_range.end.character.into(),
+        );
+    }
 
-                // Index Ruby
-                let ruby_source_path = base_gem_path.replace("gems/", "").replace("\n", "");
+    // The constants in the usage at a position: its name, namespaces, and
+    // qualifiers for a constant, or its receiver's class for a method call
+    fn usage_constant_names(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> tantivy::Result<Vec<String>> {
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(vec![]),
+        };
 
-                info!("Added Ruby source path: {}", ruby_source_path);
-                gem_paths.push(ruby_source_path);
+        let searcher = reader.searcher();
+        let file_path_id = self
+            .workspace_path
+            .file_path_id(&uri_path(&params.text_document.uri));
 
-                // Index Gems
-                for line in gemfile_contents.lines() {
-                    if let Some(captures) = gem_version.captures(line) {
-                        let name = captures[1].to_string();
-                        let version = captures[2].to_string();
-                        let gem_folder_name =
-                            format!("{}/gems/{}-{}", base_gem_path, name, version);
-                        // Not 100% sure where this newline is coming from. `gemfile_contents.lines()` I think.
-                        let gem_folder_name = gem_folder_name.replace("\n", "");
+        let query = BooleanQuery::new(vec![
+            (
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(self.schema_fields.file_path_id, &file_path_id),
+                    IndexRecordOption::Basic,
+                )) as Box<dyn Query>,
+            ),
+            (
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(self.schema_fields.category_field, "usage"),
+                    IndexRecordOption::Basic,
+                )),
+            ),
+            (
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_u64(
+                        self.schema_fields.line_field,
+                        params.position.line.into(),
+                    ),
+                    IndexRecordOption::Basic,
+                )),
+            ),
+            (Occur::Must, self.column_query(params.position.character)),
+        ]);
 
-                        info!("gem folder name: {}", gem_folder_name);
+        let mut constant_names = vec![];
 
-                        gem_paths.push(gem_folder_name)
-                    }
-                }
-            }
+        for (_score, doc_address) in searcher.measured_search(&query, &TopDocs::with_limit(1))? {
+            let usage_doc = searcher.measured_doc(doc_address)?;
+            let node_type = usage_doc
+                .get_first(self.schema_fields.node_type_field)
+                .and_then(|value| value.as_text());
 
-            let index = match &self.index {
-                Some(index) => index,
-                None => {
-                    info!("missing index");
-                    quit::with_code(1);
-                }
-            };
+            let mut scope_names: Vec<_> = usage_doc
+                .get_all(self.schema_fields.class_scope_field)
+                .collect();
 
-            let mut index_writer = index.writer(256_000_000).unwrap();
-
-            for gem_path in gem_paths {
-                let walk_dir = WalkDirGeneric::<(usize, bool)>::new(gem_path.clone())
-                    .process_read_dir(move |_depth, _path, _read_dir_state, children| {
-                        children.retain(|dir_entry_result| {
-                            dir_entry_result
-                                .as_ref()
-                                .map(|dir_entry| {
-                                    if let Some(file_name) = dir_entry.file_name.to_str() {
-                                        let ruby_file = file_name.ends_with(".rb");
-                                        dir_entry.file_type.is_dir() || ruby_file
-                                    } else {
-                                        false
-                                    }
-                                })
-                                .unwrap_or(false)
-                        });
-
-                        children.iter_mut().for_each(|dir_entry_result| {
-                            if let Ok(dir_entry) = dir_entry_result {
-                                if let Some(file_name) = dir_entry.file_name.to_str() {
-                                    if file_name.contains("node_modules")
-                                        || file_name.contains("vendor")
-                                        || file_name.contains("tmp")
-                                        || file_name.contains(".git")
-                                    {
-                                        dir_entry.read_children_path = None;
-                                    }
-                                }
-                            }
-                        });
-                    });
-
-                let mut indexable_file_paths = Vec::new();
-
-                for entry in walk_dir {
-                    let path = entry.unwrap().path();
-                    let path = path.to_str().unwrap();
-                    let ruby_file = path.ends_with(".rb");
-
-                    if ruby_file {
-                        indexable_file_paths.push(path.to_string());
-                    }
-                }
+            // A constant's own name and the namespaces it's used in, as well
+            // as the ones qualifying it
+            if node_type == Some("Const") {
+                scope_names.extend(usage_doc.get_all(self.schema_fields.name_field));
+                scope_names.extend(usage_doc.get_all(self.schema_fields.fuzzy_ruby_scope_field));
+            }
 
-                for path in &indexable_file_paths {
-                    if let Ok(text) = fs::read_to_string(&path) {
-                        let uri = Url::from_file_path(&path).unwrap();
-                        let relative_path = uri.path().replace(&self.workspace_path, "");
-
-                        self.reindex_modified_file_without_commit(
-                            &text,
-                            relative_path,
-                            &index_writer,
-                            false,
-                        );
-                    }
+            for scope_name in scope_names {
+                if let Some(scope_name) = scope_name.as_text() {
+                    constant_names.push(scope_name.to_string());
                 }
             }
-
-            index_writer.commit().unwrap();
-        } else {
-            info!("Gemfile not found, skipping indexing workspace gems.");
         }
 
-        self.gems_indexed = true;
-        self.index_interface_only = false;
+        Ok(constant_names)
+    }
 
-        Ok(())
+    pub fn indexing_progress(&self) -> Arc<IndexingProgress> {
+        Arc::clone(&self.indexing_progress)
     }
 
     pub fn reindex_modified_file_without_commit(
         &mut self,
         text: &String,
         relative_path: String,
-        index_writer: &IndexWriter,
         user_space: bool,
     ) -> tantivy::Result<Vec<Option<tower_lsp::lsp_types::Diagnostic>>> {
-        if let Some(_) = &self.index {
+        if let Some(write_queue) = self.write_queue.clone() {
             let mut documents = Vec::new();
+            let mut fuzzy_docs = Vec::new();
+
+            let template_source;
+            let text = if template(&relative_path) {
+                template_source = erb_ruby_source(text);
+                &template_source
+            } else {
+                text
+            };
 
             let diagnostics = match self.parse(text, &mut documents) {
                 Ok(diagnostics) => diagnostics,
@@ -732,12 +843,13 @@ impl Persistence {
                 }
             };
 
-            let file_path_id = blake3::hash(&relative_path.as_bytes());
+            let file_path_id = workspace_path::file_path_id(&relative_path);
+            let stdlib = !user_space && self.stdlib_file(&relative_path);
 
             for document in documents {
                 let mut fuzzy_doc = Document::default();
 
-                fuzzy_doc.add_text(self.schema_fields.file_path_id, &file_path_id.to_string());
+                fuzzy_doc.add_text(self.schema_fields.file_path_id, &file_path_id);
 
                 for path_part in relative_path.split("/") {
                     if path_part.len() > 0 {
@@ -757,6 +869,10 @@ impl Persistence {
                     self.schema_fields.category_field,
                     document.category.to_string(),
                 );
+                fuzzy_doc.add_text(
+                    self.schema_fields.name_lowercase_field,
+                    name_lowercase_term(&document.name),
+                );
                 fuzzy_doc.add_text(self.schema_fields.name_field, document.name);
                 fuzzy_doc.add_text(self.schema_fields.node_type_field, document.node_type);
                 fuzzy_doc.add_u64(
@@ -772,46 +888,85 @@ impl Persistence {
                     document.end_column.try_into().unwrap(),
                 );
                 fuzzy_doc.add_bool(self.schema_fields.user_space_field, user_space);
+                fuzzy_doc.add_bool(self.schema_fields.stdlib_field, stdlib);
+
+                if let Some(full_range) = document.full_range {
+                    self.add_full_range(&mut fuzzy_doc, full_range);
+                }
 
-                let start_col = document.start_column;
-                let end_col = document.end_column;
-                let col_range = start_col..(end_col + 1);
-                for col in col_range {
-                    fuzzy_doc.add_u64(self.schema_fields.columns_field, col as u64);
+                if let Some(superclass) = document.superclass {
+                    fuzzy_doc.add_text(self.schema_fields.superclass_field, superclass);
                 }
 
-                index_writer.add_document(fuzzy_doc)?;
+                if let Some(visibility) = document.visibility {
+                    fuzzy_doc.add_text(self.schema_fields.visibility_field, visibility);
+                }
+
+                if let Some(documentation) = document.documentation {
+                    fuzzy_doc.add_text(self.schema_fields.documentation_field, documentation);
+                }
+
+                if let Some(parameters) = document.parameters {
+                    fuzzy_doc.add_text(self.schema_fields.parameters_field, parameters);
+                }
+
+                if let Some(parameter_types) = document.parameter_types {
+                    fuzzy_doc.add_text(self.schema_fields.parameter_types_field, parameter_types);
+                }
+
+                if let Some(return_type) = document.return_type {
+                    fuzzy_doc.add_text(self.schema_fields.return_type_field, return_type);
+                }
+
+                if let Some(refinement) = document.refinement {
+                    fuzzy_doc.add_text(self.schema_fields.refinement_field, refinement);
+                }
+
+                fuzzy_docs.push(fuzzy_doc);
             }
 
+            // Workspace files are reindexed when they change, so their previous
+            // documents are replaced
+            let delete_term = if user_space {
+                Some(Term::from_field_text(
+                    self.schema_fields.file_path_id,
+                    &file_path_id,
+                ))
+            } else {
+                None
+            };
+
+            write_queue.replace(delete_term, fuzzy_docs);
+
             Ok(diagnostics)
         } else {
             Ok(vec![])
         }
     }
 
-    pub async fn reindex_modified_file(&mut self, client: &Client, text: &String, uri: &Url) {
-        let mut documents = Vec::new();
-        let diagnostics = match self.parse(text, &mut documents) {
-            Ok(diagnostics) => diagnostics,
-            Err(diagnostics) => {
-                // Return early so existing documents are not deleted when
-                // there is a syntax error
-                // return Ok(diagnostics);
-                diagnostics
-            }
-        };
-
-        if self.report_diagnostics {
-            let mut reported_diagnostics = vec![];
-
-            for diagnostic in &diagnostics {
-                for unwrapped_diagnostic in diagnostic {
-                    reported_diagnostics.push(unwrapped_diagnostic.clone());
-                }
-            }
-
+    pub async fn reindex_modified_file(
+        &mut self,
+        client: &Client,
+        text: &String,
+        uri: &Url,
+        linter_diagnostics: Vec<tower_lsp::lsp_types::Diagnostic>,
+    ) {
+        let parsed_document = self.parse_open_document(text, uri);
+        let documents = parsed_document.documents;
+        let diagnostics = parsed_document.parser_diagnostics;
+
+        let mut reported_diagnostics: Vec<tower_lsp::lsp_types::Diagnostic> =
+            diagnostics.iter().flatten().cloned().collect();
+        reported_diagnostics.extend(self.unused_locals(&documents));
+        reported_diagnostics.extend(self.missing_translations(&uri_path(uri)));
+        reported_diagnostics.extend(linter_diagnostics);
+
+        // Undefined names are checked once the file is indexed below
+        if self.settings.report_diagnostics
+            && (!diagnostics.is_empty() || !self.settings.undefined_name_diagnostics)
+        {
             client
-                .publish_diagnostics(uri.clone(), reported_diagnostics, None)
+                .publish_diagnostics(uri.clone(), reported_diagnostics.clone(), None)
                 .await;
             // .await;
         }
@@ -820,31 +975,43 @@ impl Persistence {
             return;
         }
 
-        if let Some(index) = &self.index {
-            let mut index_writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
-
-            let user_space: bool;
-            let relative_path: String;
+        // Saving right after a change sends the same text again
+        if parsed_document.indexed {
+            if self.settings.report_diagnostics && self.settings.undefined_name_diagnostics {
+                reported_diagnostics.extend(self.undefined_names(&documents).unwrap_or_default());
 
-            if uri.path().contains(&self.workspace_path) {
-                user_space = true;
-                relative_path = uri.path().replace(&self.workspace_path, "");
-            } else {
-                user_space = false;
-                relative_path = uri.path().to_string();
+                client
+                    .publish_diagnostics(uri.clone(), reported_diagnostics, None)
+                    .await;
             }
 
-            let file_path_id = blake3::hash(&relative_path.as_bytes());
+            return;
+        }
+
+        if let Some(parsed_document) = self
+            .open_documents
+            .get_mut(&session::current())
+            .and_then(|open_documents| open_documents.get_mut(uri))
+        {
+            parsed_document.indexed = true;
+        }
+
+        if let Some(write_queue) = self.write_queue.clone() {
+            let path = uri_path(uri);
+            let user_space = self.workspace_path.contains(&path);
+            let relative_path = self.workspace_path.relative_path(&path);
+            let file_path_id = workspace_path::file_path_id(&relative_path);
+            let stdlib = !user_space && self.stdlib_file(&path);
 
             let file_path_id_term =
-                Term::from_field_text(self.schema_fields.file_path_id, &file_path_id.to_string());
+                Term::from_field_text(self.schema_fields.file_path_id, &file_path_id);
 
-            index_writer.delete_term(file_path_id_term);
+            let mut fuzzy_docs = Vec::new();
 
             for document in documents {
                 let mut fuzzy_doc = Document::default();
 
-                fuzzy_doc.add_text(self.schema_fields.file_path_id, &file_path_id.to_string());
+                fuzzy_doc.add_text(self.schema_fields.file_path_id, &file_path_id);
 
                 for path_part in relative_path.split("/") {
                     if path_part.len() > 0 {
@@ -864,6 +1031,10 @@ impl Persistence {
                     self.schema_fields.category_field,
                     document.category.to_string(),
                 );
+                fuzzy_doc.add_text(
+                    self.schema_fields.name_lowercase_field,
+                    name_lowercase_term(&document.name),
+                );
                 fuzzy_doc.add_text(self.schema_fields.name_field, document.name);
                 fuzzy_doc.add_text(self.schema_fields.node_type_field, document.node_type);
                 fuzzy_doc.add_u64(
@@ -879,2361 +1050,1941 @@ impl Persistence {
                     document.end_column.try_into().unwrap(),
                 );
                 fuzzy_doc.add_bool(self.schema_fields.user_space_field, user_space);
+                fuzzy_doc.add_bool(self.schema_fields.stdlib_field, stdlib);
+
+                if let Some(full_range) = document.full_range {
+                    self.add_full_range(&mut fuzzy_doc, full_range);
+                }
+
+                if let Some(superclass) = document.superclass {
+                    fuzzy_doc.add_text(self.schema_fields.superclass_field, superclass);
+                }
+
+                if let Some(visibility) = document.visibility {
+                    fuzzy_doc.add_text(self.schema_fields.visibility_field, visibility);
+                }
+
+                if let Some(documentation) = document.documentation {
+                    fuzzy_doc.add_text(self.schema_fields.documentation_field, documentation);
+                }
+
+                if let Some(parameters) = document.parameters {
+                    fuzzy_doc.add_text(self.schema_fields.parameters_field, parameters);
+                }
 
-                let start_col = document.start_column;
-                let end_col = document.end_column;
-                let col_range = start_col..(end_col + 1);
-                for col in col_range {
-                    fuzzy_doc.add_u64(self.schema_fields.columns_field, col as u64);
+                if let Some(parameter_types) = document.parameter_types {
+                    fuzzy_doc.add_text(self.schema_fields.parameter_types_field, parameter_types);
                 }
 
-                index_writer.add_document(fuzzy_doc).unwrap();
+                if let Some(return_type) = document.return_type {
+                    fuzzy_doc.add_text(self.schema_fields.return_type_field, return_type);
+                }
+
+                if let Some(refinement) = document.refinement {
+                    fuzzy_doc.add_text(self.schema_fields.refinement_field, refinement);
+                }
+
+                fuzzy_docs.push(fuzzy_doc);
             }
 
-            index_writer.commit().unwrap();
+            write_queue.replace(Some(file_path_id_term), fuzzy_docs);
+
+            // Undefined names are checked against the index, so they can't
+            // wait for the debounced commit
+            if self.settings.report_diagnostics && self.settings.undefined_name_diagnostics {
+                write_queue.commit_and_wait().await;
+            }
+        }
+
+        if self.settings.report_diagnostics && self.settings.undefined_name_diagnostics {
+            reported_diagnostics.extend(self.undefined_names(&[]).unwrap_or_default());
+
+            client
+                .publish_diagnostics(uri.clone(), reported_diagnostics, None)
+                .await;
         }
     }
 
     pub fn diagnostics(
         &mut self,
         text: &String,
-        _uri: &Url,
+        uri: &Url,
     ) -> tantivy::Result<Vec<Option<tower_lsp::lsp_types::Diagnostic>>> {
-        let mut documents = Vec::new();
-        match self.parse(text, &mut documents) {
-            Ok(diagnostics) => Ok(diagnostics),
-            Err(diagnostics) => Ok(diagnostics),
+        let parsed_document = self.parse_open_document(text, uri);
+        let documents = parsed_document.documents;
+        let mut diagnostics = parsed_document.parser_diagnostics;
+
+        if parsed_document.parsed {
+            diagnostics.extend(self.unused_locals(&documents).into_iter().map(Some));
+            diagnostics.extend(
+                self.missing_translations(&uri_path(uri))
+                    .into_iter()
+                    .map(Some),
+            );
+
+            if self.settings.undefined_name_diagnostics {
+                diagnostics.extend(self.undefined_names(&documents)?.into_iter().map(Some));
+            }
         }
+
+        Ok(diagnostics)
     }
 
-    pub fn find_definitions(
-        &self,
-        params: TextDocumentPositionParams,
-    ) -> tantivy::Result<Vec<Location>> {
-        let path = params.text_document.uri.path();
-        let relative_path = path.replace(&self.workspace_path, "");
+    // Parses an open file, or reuses its last parse when the text hasn't
+    // changed, restoring the parser state the diagnostics depend on
+    fn parse_open_document(&mut self, text: &String, uri: &Url) -> ParsedDocument {
+        let content_hash = blake3::hash(text.as_bytes());
 
-        let position = params.position;
+        let reused_document = self
+            .open_document(uri)
+            .filter(|parsed_document| parsed_document.content_hash == content_hash)
+            .cloned();
 
-        if let Some(index) = &self.index {
-            let reader = index
-                .reader_builder()
-                .reload_policy(ReloadPolicy::OnCommit)
-                .try_into()?;
+        if let Some(parsed_document) = reused_document {
+            self.findings = parsed_document.findings.clone();
 
-            let searcher = reader.searcher();
-            let character_position = position.character;
-            let character_line = position.line;
-            let file_path_id = blake3::hash(&relative_path.as_bytes());
+            return parsed_document;
+        }
 
-            let file_path_query: Box<dyn Query> = Box::new(TermQuery::new(
-                Term::from_field_text(self.schema_fields.file_path_id, &file_path_id.to_string()),
-                IndexRecordOption::Basic,
-            ));
-            let category_query: Box<dyn Query> = Box::new(TermQuery::new(
-                Term::from_field_text(self.schema_fields.category_field, "usage"),
-                IndexRecordOption::Basic,
-            ));
-            let line_query: Box<dyn Query> = Box::new(TermQuery::new(
-                Term::from_field_u64(self.schema_fields.line_field, character_line.into()),
-                IndexRecordOption::Basic,
-            ));
-            let column_query: Box<dyn Query> = Box::new(TermQuery::new(
-                Term::from_field_u64(self.schema_fields.columns_field, character_position.into()),
-                IndexRecordOption::Basic,
-            ));
+        let mut documents = Vec::new();
+        let source = if template(&uri_path(uri)) {
+            erb_ruby_source(text)
+        } else {
+            text.to_string()
+        };
+        let (parser_diagnostics, parsed) = match self.parse(&source, &mut documents) {
+            Ok(diagnostics) => (diagnostics, true),
+            Err(diagnostics) => (diagnostics, false),
+        };
 
-            let query = BooleanQuery::new(vec![
-                (Occur::Must, file_path_query),
-                (Occur::Must, category_query),
-                (Occur::Must, line_query),
-                (Occur::Must, column_query),
-            ]);
+        let parsed_document = ParsedDocument {
+            content_hash,
+            parser_diagnostics,
+            parsed,
+            documents,
+            text: text.to_string(),
+            findings: self.findings.clone(),
+            indexed: false,
+        };
 
-            let usage_top_docs = searcher.search(&query, &TopDocs::with_limit(1))?;
+        self.open_documents
+            .entry(session::current())
+            .or_default()
+            .insert(uri.clone(), parsed_document.clone());
 
-            let mut locations = Vec::new();
+        parsed_document
+    }
 
-            if usage_top_docs.len() == 0 {
-                info!("No usages docs found");
-                return Ok(locations);
-            }
+    // The last parse of a file the current session has open
+    fn open_document(&self, uri: &Url) -> Option<&ParsedDocument> {
+        self.open_documents.get(&session::current())?.get(uri)
+    }
 
-            let doc_address = usage_top_docs[0].1;
-            let retrieved_doc = searcher.doc(doc_address)?;
+    pub fn close_document(&mut self, uri: &Url) {
+        if let Some(open_documents) = self.open_documents.get_mut(&session::current()) {
+            open_documents.remove(uri);
+        }
+    }
 
-            let category_query: Box<dyn Query> = Box::new(TermQuery::new(
-                Term::from_field_text(self.schema_fields.category_field, "assignment"),
-                IndexRecordOption::Basic,
-            ));
+    // Closes everything the current session has open, when it shuts down
+    pub fn close_session(&mut self) {
+        self.open_documents.remove(&session::current());
+    }
 
-            let usage_name = retrieved_doc
-                .get_first(self.schema_fields.name_field)
-                .unwrap()
-                .as_text()
-                .unwrap();
-            let usage_type = retrieved_doc
-                .get_first(self.schema_fields.node_type_field)
-                .unwrap()
-                .as_text()
-                .unwrap();
+    // Local variables assigned inside a method but never read in the same
+    // scope. Names starting with `_` are skipped like Ruby does, and so is any
+    // file that calls `binding` since it can read locals indirectly.
+    fn unused_locals(&self, documents: &[FuzzyNode]) -> Vec<tower_lsp::lsp_types::Diagnostic> {
+        let severity = match self.settings.diagnostic_severities.unused_locals {
+            Some(severity) => severity,
+            None => return vec![],
+        };
 
-            let name_query: Box<dyn Query> = Box::new(TermQuery::new(
-                Term::from_field_text(self.schema_fields.name_field, usage_name),
-                IndexRecordOption::Basic,
-            ));
+        let calls_binding = documents
+            .iter()
+            .any(|document| document.node_type == "Send" && document.name == "binding");
 
-            let mut assignment_type_queries = vec![];
+        if calls_binding {
+            return vec![];
+        }
 
-            for possible_assignment_type in USAGE_TYPE_RESTRICTIONS.get(usage_type).unwrap().iter()
-            {
-                let assignment_type_query: Box<dyn Query> = Box::new(TermQuery::new(
-                    Term::from_field_text(
-                        self.schema_fields.node_type_field,
-                        possible_assignment_type,
-                    ),
-                    IndexRecordOption::Basic,
-                ));
+        let mut unused_locals = vec![];
 
-                assignment_type_queries.push((Occur::Should, assignment_type_query));
+        for assignment in documents {
+            if assignment.node_type != "Lvasgn"
+                || assignment.name.starts_with('_')
+                || self.findings.op_assigned_locals.contains(&assignment.name)
+            {
+                continue;
             }
 
-            let assignment_type_query = BooleanQuery::new(assignment_type_queries);
+            let inside_method = assignment
+                .fuzzy_ruby_scope
+                .iter()
+                .any(|scope_name| !scope_name.starts_with(char::is_uppercase));
 
-            let mut queries = vec![
-                (Occur::Must, category_query),
-                (Occur::Must, name_query),
-                (Occur::Must, Box::new(assignment_type_query)),
-            ];
+            if !inside_method {
+                continue;
+            }
 
-            let usage_fuzzy_scope =
-                retrieved_doc.get_all(self.schema_fields.fuzzy_ruby_scope_field);
-
-            match usage_type {
-                // "Alias" => {},
-                "Const" => {
-                    for scope_name in usage_fuzzy_scope {
-                        let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
-                            Term::from_field_text(
-                                self.schema_fields.fuzzy_ruby_scope_field,
-                                scope_name.as_text().unwrap(),
-                            ),
-                            IndexRecordOption::Basic,
-                        ));
-
-                        queries.push((Occur::Should, scope_query));
-                    }
+            let read = documents.iter().any(|usage| {
+                usage.node_type == "Lvar"
+                    && usage.name == assignment.name
+                    && (usage
+                        .fuzzy_ruby_scope
+                        .starts_with(&assignment.fuzzy_ruby_scope)
+                        || assignment
+                            .fuzzy_ruby_scope
+                            .starts_with(&usage.fuzzy_ruby_scope))
+            });
 
-                    let class_scope = retrieved_doc.get_all(self.schema_fields.class_scope_field);
+            if read {
+                continue;
+            }
 
-                    for scope_name in class_scope {
-                        let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
-                            Term::from_field_text(
-                                self.schema_fields.fuzzy_ruby_scope_field,
-                                scope_name.as_text().unwrap(),
-                            ),
-                            IndexRecordOption::Basic,
-                        ));
+            let range = Range::new(
+                Position::new(assignment.line as u32, assignment.start_column as u32),
+                Position::new(assignment.line as u32, assignment.end_column as u32),
+            );
 
-                        queries.push((Occur::Must, scope_query));
-                    }
-                }
-                // "CSend" => {},
-                // todo: improved indexed scopes so there is a separate class scope, etc
-                // "Cvar" => {},
-                // "Gvar" => {},
-                // todo: improved indexed scopes so there is a separate class scope, etc
-                // "Ivar" => {},
-                // todo: improved to be more accurate
-                "Arg" | "Kwarg" | "Kwoptarg" | "Kwrestarg" | "Lvasgn" | "MatchVar" | "Optarg"
-                | "Restarg" | "Shadowarg" | "Lvar" => {
-                    for scope_name in usage_fuzzy_scope {
-                        let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
-                            Term::from_field_text(
-                                self.schema_fields.fuzzy_ruby_scope_field,
-                                scope_name.as_text().unwrap(),
-                            ),
-                            IndexRecordOption::Basic,
-                        ));
-
-                        queries.push((Occur::Must, scope_query));
-                    }
-                }
-                //
-                "Send" => {
-                    let class_scope = retrieved_doc.get_all(self.schema_fields.class_scope_field);
+            let mut diagnostic = tower_lsp::lsp_types::Diagnostic::new_simple(
+                range,
+                format!("Assigned but unused variable `{}`", assignment.name),
+            );
+            diagnostic.severity = Some(severity);
+            diagnostic.tags = Some(vec![DiagnosticTag::UNNECESSARY]);
 
-                    let mut usage_scope_fallback = true;
+            unused_locals.push(diagnostic);
+        }
 
-                    for scope_name in class_scope {
-                        usage_scope_fallback = false;
+        unused_locals
+    }
 
-                        let scope_query = Box::new(TermQuery::new(
-                            Term::from_field_text(
-                                self.schema_fields.fuzzy_ruby_scope_field,
-                                scope_name.as_text().unwrap(),
-                            ),
-                            IndexRecordOption::Basic,
-                        ));
+    // Keys passed to `t` in the last parsed file that no locale file
+    // translates. Off by default, and skipped in workspaces without locale
+    // files or when the call has a `default:`.
+    fn missing_translations(&self, path: &str) -> Vec<tower_lsp::lsp_types::Diagnostic> {
+        let severity = match self.settings.diagnostic_severities.missing_translations {
+            Some(severity) => severity,
+            None => return vec![],
+        };
 
-                        let boosted_scope_query: Box<dyn Query> =
-                            Box::new(BoostQuery::new(scope_query, 10000.0));
+        if self.locales.is_empty() {
+            return vec![];
+        }
 
-                        // queries.push((Occur::Should, scope_query));
-                        // queries.push((Occur::Should, boosted_scope_query));
+        let relative_path = self.workspace_path.relative_path(path);
+        let mut missing_translations = vec![];
 
-                        // This probably would be better as just a boosted
-                        // query, but it's not working for some reason.
-                        queries.push((Occur::Must, boosted_scope_query));
-                    }
+        for translation_key in &self.findings.translation_keys {
+            if translation_key.has_default {
+                continue;
+            }
 
-                    if usage_scope_fallback {
-                        for scope_name in usage_fuzzy_scope {
-                            let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
-                                Term::from_field_text(
-                                    self.schema_fields.fuzzy_ruby_scope_field,
-                                    scope_name.as_text().unwrap(),
-                                ),
-                                IndexRecordOption::Basic,
-                            ));
-
-                            queries.push((Occur::Should, scope_query));
-                        }
-                    }
-                }
-                // "Super" => {},
-                // "ZSuper" => {},
-                _ => {
-                    for scope_name in usage_fuzzy_scope {
-                        let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
-                            Term::from_field_text(
-                                self.schema_fields.fuzzy_ruby_scope_field,
-                                scope_name.as_text().unwrap(),
-                            ),
-                            IndexRecordOption::Basic,
-                        ));
-
-                        queries.push((Occur::Should, scope_query));
-                    }
-                }
+            let key = match full_translation_key(&relative_path, &translation_key.key) {
+                Some(key) => key,
+                None => continue,
             };
 
-            let query = BooleanQuery::new(queries);
-            let assignments_top_docs = searcher.search(&query, &TopDocs::with_limit(50))?;
+            if !self.locales.get(&key).is_empty() {
+                continue;
+            }
 
-            for (_score, doc_address) in assignments_top_docs {
-                let retrieved_doc = searcher.doc(doc_address)?;
+            let range = Range::new(
+                Position::new(
+                    translation_key.line as u32,
+                    translation_key.start_column as u32,
+                ),
+                Position::new(
+                    translation_key.line as u32,
+                    translation_key.end_column as u32,
+                ),
+            );
 
-                let file_path: String = retrieved_doc
-                    .get_all(self.schema_fields.file_path)
-                    .flat_map(Value::as_text)
-                    .collect::<Vec<&str>>()
-                    .join("/");
+            let mut diagnostic = tower_lsp::lsp_types::Diagnostic::new_simple(
+                range,
+                format!("Missing translation `{}`", key),
+            );
+            diagnostic.severity = Some(severity);
 
-                let absolute_file_path: String;
+            missing_translations.push(diagnostic);
+        }
 
-                let user_space = retrieved_doc
-                    .get_first(self.schema_fields.user_space_field)
-                    .unwrap()
-                    .as_bool()
-                    .unwrap() as bool;
+        missing_translations
+    }
 
-                if user_space {
-                    absolute_file_path = format!("{}/{}", &self.workspace_path, &file_path);
-                } else {
-                    absolute_file_path = format!("/{}", &file_path);
-                }
+    // Checks the calls and constants from the last parsed file against the
+    // index, plus `documents` when the file hasn't been indexed yet.
+    // Conservative: calls are skipped in classes that define `method_missing`
+    // or inherit from or mix in anything that isn't indexed.
+    fn undefined_names(
+        &self,
+        documents: &[FuzzyNode],
+    ) -> tantivy::Result<Vec<tower_lsp::lsp_types::Diagnostic>> {
+        let mut undefined_names = vec![];
 
-                let doc_uri = Url::from_file_path(&absolute_file_path).unwrap();
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(undefined_names),
+        };
 
-                let start_line = retrieved_doc
-                    .get_first(self.schema_fields.line_field)
-                    .unwrap()
-                    .as_u64()
-                    .unwrap() as u32;
-                let start_column = retrieved_doc
-                    .get_first(self.schema_fields.start_column_field)
-                    .unwrap()
-                    .as_u64()
-                    .unwrap() as u32;
-                let start_position = Position::new(start_line, start_column);
-                let end_column = retrieved_doc
-                    .get_first(self.schema_fields.end_column_field)
-                    .unwrap()
-                    .as_u64()
-                    .unwrap() as u32;
-                let end_position = Position::new(start_line, end_column);
+        let severity = match self.settings.diagnostic_severities.undefined_names {
+            Some(severity) => severity,
+            None => return Ok(undefined_names),
+        };
 
-                let doc_range = Range::new(start_position, end_position);
-                let location = Location::new(doc_uri, doc_range);
+        let searcher = reader.searcher();
 
-                locations.push(location);
-            }
+        // Without gems, constants from dependencies can't be told apart from
+        // typos
+        let check_constants = self.gem_file_count > 0 && self.unindexed_gems.is_empty();
+        let mut checkable_classes: HashMap<String, bool> = HashMap::new();
 
-            Ok(locations)
-        } else {
-            Ok(vec![])
-        }
-    }
+        for name_check in &self.findings.name_checks {
+            let assignment_types: &[&str] = match name_check.node_type {
+                "Const" if check_constants => &["Casgn", "Class", "Module"],
+                "Const" => continue,
+                _ => &["Alias", "Def", "Defs"],
+            };
 
-    pub fn find_highlights(
-        &self,
-        params: TextDocumentPositionParams,
-    ) -> tantivy::Result<Vec<DocumentHighlight>> {
-        if let Ok(search_results) = self.find_references(params) {
-            let mut highlights = Vec::new();
+            if name_check.node_type == "Send" {
+                if self.findings.defines_method_missing {
+                    continue;
+                }
 
-            for search_result in &search_results {
-                let start_line = search_result
-                    .get_first(self.schema_fields.line_field)
-                    .unwrap()
-                    .as_u64()
-                    .unwrap() as u32;
-                let start_column = search_result
-                    .get_first(self.schema_fields.start_column_field)
-                    .unwrap()
-                    .as_u64()
-                    .unwrap() as u32;
-                let start_position = Position::new(start_line, start_column);
-                let end_column = search_result
-                    .get_first(self.schema_fields.end_column_field)
-                    .unwrap()
-                    .as_u64()
-                    .unwrap() as u32;
-                let end_position = Position::new(start_line, end_column);
+                if let Some(enclosing_class) = &name_check.enclosing_class {
+                    let checkable = match checkable_classes.get(enclosing_class) {
+                        Some(checkable) => *checkable,
+                        None => {
+                            let checkable =
+                                self.fully_indexed_ancestors(&searcher, enclosing_class)?;
+                            checkable_classes.insert(enclosing_class.to_string(), checkable);
+                            checkable
+                        }
+                    };
 
-                let range = Range::new(start_position, end_position);
+                    if !checkable {
+                        continue;
+                    }
+                }
+            }
 
-                let category = search_result
-                    .get_first(self.schema_fields.category_field)
-                    .unwrap()
-                    .as_text()
-                    .unwrap();
+            let defined_in_file = documents.iter().any(|document| {
+                document.category == "assignment"
+                    && document.name == name_check.name
+                    && assignment_types.contains(&document.node_type)
+            });
 
-                let kind = if category == "assignment" {
-                    Some(DocumentHighlightKind::WRITE)
-                } else {
-                    Some(DocumentHighlightKind::READ)
-                };
+            if defined_in_file {
+                continue;
+            }
 
-                let document_highlight = DocumentHighlight { range, kind };
+            let query = self.definition_query(&name_check.name, assignment_types, None);
 
-                highlights.push(document_highlight);
+            if searcher.measured_search(&query, &Count)? > 0 {
+                continue;
             }
 
-            Ok(highlights)
-        } else {
-            Ok(Vec::new())
+            let kind = if name_check.node_type == "Const" {
+                "constant"
+            } else {
+                "method"
+            };
+            let mut message = format!("Undefined {} `{}`", kind, name_check.name);
+
+            if let Some(suggestion) =
+                self.nearest_name(&searcher, &name_check.name, assignment_types)?
+            {
+                message.push_str(&format!(", did you mean `{}`?", suggestion));
+            }
+
+            let range = Range::new(
+                Position::new(name_check.line as u32, name_check.start_column as u32),
+                Position::new(name_check.line as u32, name_check.end_column as u32),
+            );
+
+            let mut diagnostic = tower_lsp::lsp_types::Diagnostic::new_simple(range, message);
+            diagnostic.severity = Some(severity);
+
+            undefined_names.push(diagnostic);
         }
+
+        Ok(undefined_names)
     }
 
-    pub fn find_references(
+    // True when every ancestor of `class_name` is defined in the index, so a
+    // method missing from all of them is really undefined
+    fn fully_indexed_ancestors(
         &self,
-        params: TextDocumentPositionParams,
-    ) -> tantivy::Result<Vec<Document>> {
-        let path = params.text_document.uri.path();
-        let relative_path = path.replace(&self.workspace_path, "");
+        searcher: &Searcher,
+        class_name: &str,
+    ) -> tantivy::Result<bool> {
+        let mut class_names = self.find_ancestors(searcher, class_name)?;
+        class_names.push(class_name.to_string());
 
-        let position = params.position;
+        for ancestor_name in class_names {
+            let ancestor_query = self.definition_query(&ancestor_name, &["Class", "Module"], None);
 
-        if let Some(index) = &self.index {
-            let reader = index
-                .reader_builder()
-                .reload_policy(ReloadPolicy::OnCommit)
-                .try_into()?;
+            if searcher.measured_search(&ancestor_query, &Count)? == 0 {
+                return Ok(false);
+            }
+        }
 
-            let searcher = reader.searcher();
-            let character_position = position.character;
-            let character_line = position.line;
-            let file_path_id = blake3::hash(&relative_path.as_bytes());
+        Ok(true)
+    }
 
-            let file_path_query: Box<dyn Query> = Box::new(TermQuery::new(
-                Term::from_field_text(self.schema_fields.file_path_id, &file_path_id.to_string()),
-                IndexRecordOption::Basic,
-            ));
-            let line_query: Box<dyn Query> = Box::new(TermQuery::new(
-                Term::from_field_u64(self.schema_fields.line_field, character_line.into()),
-                IndexRecordOption::Basic,
-            ));
-            let column_query: Box<dyn Query> = Box::new(TermQuery::new(
-                Term::from_field_u64(self.schema_fields.columns_field, character_position.into()),
-                IndexRecordOption::Basic,
-            ));
+    // The closest defined name by edit distance, for did-you-mean suggestions.
+    // Names within the distance are found with a Levenshtein automaton over
+    // each segment's name terms, rather than comparing every name.
+    fn nearest_name(
+        &self,
+        searcher: &Searcher,
+        name: &str,
+        assignment_types: &[&str],
+    ) -> tantivy::Result<Option<String>> {
+        let max_distance = if name.chars().count() < 6 { 1 } else { 2 };
+        let automaton = LevenshteinDfa(LEVENSHTEIN_BUILDERS[max_distance - 1].build_dfa(name));
+        let mut candidates = HashSet::new();
+
+        for segment_reader in searcher.segment_readers() {
+            let inverted_index = segment_reader.inverted_index(self.schema_fields.name_field)?;
+            let mut terms = inverted_index.terms().search(&automaton).into_stream()?;
+
+            while terms.advance() {
+                let candidate = match str::from_utf8(terms.key()) {
+                    Ok(candidate) => candidate,
+                    Err(_) => continue,
+                };
 
-            let query = BooleanQuery::new(vec![
-                (Occur::Must, file_path_query),
-                (Occur::Must, line_query),
-                (Occur::Must, column_query),
-            ]);
+                if let Distance::Exact(distance) = automaton.0.eval(candidate) {
+                    candidates.insert((distance, candidate.to_string()));
+                }
+            }
+        }
+
+        let mut candidates: Vec<(u8, String)> = candidates.into_iter().collect();
+        candidates.sort();
 
-            let usage_top_docs = searcher.search(&query, &TopDocs::with_limit(1))?;
+        for (_distance, candidate) in candidates {
+            let query = self.definition_query(&candidate, assignment_types, None);
 
-            if usage_top_docs.len() == 0 {
-                info!("No highlight usages docs found");
-                return Ok(Vec::new());
+            if searcher.measured_search(&query, &Count)? > 0 {
+                return Ok(Some(candidate));
             }
+        }
 
-            let doc_address = usage_top_docs[0].1;
-            let retrieved_doc = searcher.doc(doc_address)?;
+        Ok(None)
+    }
 
-            let usage_name = retrieved_doc
-                .get_first(self.schema_fields.name_field)
-                .unwrap()
-                .as_text()
-                .unwrap();
-            let token_type = retrieved_doc
-                .get_first(self.schema_fields.node_type_field)
-                .unwrap()
-                .as_text()
-                .unwrap();
+    // Waits until searches can see everything indexed so far
+    pub async fn wait_for_commit(&self) {
+        if let Some(write_queue) = &self.write_queue {
+            write_queue.commit_and_wait().await;
+        }
+    }
 
-            let file_path_query: Box<dyn Query> = Box::new(TermQuery::new(
-                Term::from_field_text(self.schema_fields.file_path_id, &file_path_id.to_string()),
-                IndexRecordOption::Basic,
-            ));
+    // Every definition in the index, sorted by file and line. Files in the
+    // workspace are relative to it.
+    pub fn definitions(&self) -> tantivy::Result<Vec<serde_json::Value>> {
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(vec![]),
+        };
 
-            let name_query: Box<dyn Query> = Box::new(TermQuery::new(
-                Term::from_field_text(self.schema_fields.name_field, usage_name),
-                IndexRecordOption::Basic,
-            ));
+        let searcher = reader.searcher();
+        let definitions_query = TermQuery::new(
+            Term::from_field_text(self.schema_fields.category_field, "assignment"),
+            IndexRecordOption::Basic,
+        );
 
-            let mut highlight_token_queries = vec![];
+        let mut definitions = vec![];
 
-            for possible_assignment_type in USAGE_TYPE_RESTRICTIONS
-                .get(token_type)
-                .unwrap_or(&[].as_slice())
-                .iter()
-            {
-                let assignment_type_query: Box<dyn Query> = Box::new(TermQuery::new(
-                    Term::from_field_text(
-                        self.schema_fields.node_type_field,
-                        possible_assignment_type,
-                    ),
-                    IndexRecordOption::Basic,
-                ));
+        for doc_address in searcher.measured_search(&definitions_query, &DocSetCollector)? {
+            let definition_doc = searcher.measured_doc(doc_address)?;
 
-                highlight_token_queries.push((Occur::Should, assignment_type_query));
-            }
-            for possible_usage_type in ASSIGNMENT_TYPE_RESTRICTIONS
-                .get(token_type)
-                .unwrap_or(&[].as_slice())
-                .iter()
-            {
-                let usage_type_query: Box<dyn Query> = Box::new(TermQuery::new(
-                    Term::from_field_text(self.schema_fields.node_type_field, possible_usage_type),
-                    IndexRecordOption::Basic,
-                ));
+            let file_path: String = definition_doc
+                .get_all(self.schema_fields.file_path)
+                .flat_map(Value::as_text)
+                .collect::<Vec<&str>>()
+                .join("/");
+            let user_space = definition_doc
+                .get_first(self.schema_fields.user_space_field)
+                .and_then(Value::as_bool)
+                .unwrap_or(false);
+            let file = if user_space {
+                file_path
+            } else {
+                workspace_path::absolute_path(&file_path)
+            };
+            let line = definition_doc
+                .get_first(self.schema_fields.line_field)
+                .and_then(Value::as_u64)
+                .unwrap_or(0)
+                + 1;
+            let kind = definition_doc
+                .get_first(self.schema_fields.node_type_field)
+                .and_then(Value::as_text)
+                .unwrap_or_default();
+            let mut definition = json!({
+                "name": self.definition_name(&definition_doc),
+                "kind": kind,
+                "file": file,
+                "line": line,
+                "scope": self.definition_container(&definition_doc),
+            });
 
-                highlight_token_queries.push((Occur::Should, usage_type_query));
+            if let Some(full_range) = self.definition_full_range(&definition_doc) {
+                definition["endLine"] = json!(full_range.end.line + 1);
             }
 
-            let token_type_query = BooleanQuery::new(highlight_token_queries);
+            definitions.push((file.clone(), line, definition));
+        }
 
-            let mut queries = vec![
-                (Occur::Must, file_path_query),
-                (Occur::Must, name_query),
-                (Occur::Must, Box::new(token_type_query)),
-            ];
+        definitions.sort_by(|(file, line, _), (other_file, other_line, _)| {
+            (file, line).cmp(&(other_file, other_line))
+        });
 
-            let usage_fuzzy_scope =
-                retrieved_doc.get_all(self.schema_fields.fuzzy_ruby_scope_field);
-
-            match token_type {
-                // "Alias" => {},
-                // "Const" => {},
-                // "CSend" => {},
-                // todo: improved indexed scopes so there is a separate class scope, etc
-                // "Cvar" => {},
-                // "Gvar" => {},
-                // todo: improved indexed scopes so there is a separate class scope, etc
-                // "Ivar" => {},
-                // todo: improved to be more accurate
-
-                // same values as local assignment type restrictions, for
-                // example "Lvasgn" in ASSIGNMENT_TYPE_RESTRICTIONS
-                "Arg" | "Kwarg" | "Kwoptarg" | "Kwrestarg" | "Lvasgn" | "MatchVar" | "Optarg"
-                | "Restarg" | "Shadowarg" | "Lvar" => {
-                    for scope_name in usage_fuzzy_scope {
-                        let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
-                            Term::from_field_text(
-                                self.schema_fields.fuzzy_ruby_scope_field,
-                                scope_name.as_text().unwrap(),
-                            ),
-                            IndexRecordOption::Basic,
-                        ));
-
-                        queries.push((Occur::Must, scope_query));
-                    }
-                }
-                // "Send" => {},
-                // "Super" => {},
-                // "ZSuper" => {},
-                _ => {
-                    for scope_name in usage_fuzzy_scope {
-                        let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
-                            Term::from_field_text(
-                                self.schema_fields.fuzzy_ruby_scope_field,
-                                scope_name.as_text().unwrap(),
-                            ),
-                            IndexRecordOption::Basic,
-                        ));
-
-                        queries.push((Occur::Should, scope_query));
-                    }
-                }
-            };
+        Ok(definitions
+            .into_iter()
+            .map(|(_file, _line, definition)| definition)
+            .collect())
+    }
+
+    // Where every user-space definition is, for exporting the index
+    pub fn definition_locations(&self) -> tantivy::Result<Vec<Location>> {
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(vec![]),
+        };
 
-            let results =
-                searcher.search(&BooleanQuery::new(queries), &TopDocs::with_limit(100))?;
+        let searcher = reader.searcher();
 
-            let mut documents = Vec::new();
+        Ok(self
+            .user_space_category_documents(&searcher, "assignment")?
+            .iter()
+            .map(|definition_doc| self.definition_location(definition_doc))
+            .collect())
+    }
 
-            for (_score, doc_address) in results {
-                documents.push(searcher.doc(doc_address).unwrap())
-            }
+    // Each user-space usage with the definitions going to its definition
+    // finds, for exporting the index
+    pub fn usage_definitions(&self) -> tantivy::Result<Vec<(Location, Vec<Location>)>> {
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(vec![]),
+        };
 
-            Ok(documents)
-        } else {
-            Ok(Vec::new())
+        let searcher = reader.searcher();
+        let mut usage_definitions = vec![];
+
+        for usage_doc in self.user_space_category_documents(&searcher, "usage")? {
+            let usage_location = self.definition_location(&usage_doc);
+            let definitions = self.find_definitions(TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier {
+                    uri: usage_location.uri.clone(),
+                },
+                position: usage_location.range.start,
+            })?;
+
+            if !definitions.is_empty() {
+                usage_definitions.push((usage_location, definitions));
+            }
         }
+
+        Ok(usage_definitions)
     }
 
-    pub fn find_references_in_workspace(
+    fn user_space_category_documents(
         &self,
-        query: String,
+        searcher: &Searcher,
+        category: &str,
     ) -> tantivy::Result<Vec<Document>> {
-        if let Some(index) = &self.index {
-            let reader = index
-                .reader_builder()
-                .reload_policy(ReloadPolicy::OnCommit)
-                .try_into()?;
-
-            let searcher = reader.searcher();
+        let documents_query = BooleanQuery::new(vec![
+            (
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_bool(self.schema_fields.user_space_field, true),
+                    IndexRecordOption::Basic,
+                )) as Box<dyn Query>,
+            ),
+            (
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(self.schema_fields.category_field, category),
+                    IndexRecordOption::Basic,
+                )),
+            ),
+        ]);
 
-            let user_space_query: Box<dyn Query> = Box::new(TermQuery::new(
-                Term::from_field_bool(self.schema_fields.user_space_field, true),
-                IndexRecordOption::Basic,
-            ));
+        let mut documents = vec![];
 
-            let name_query: Box<dyn Query> = Box::new(RegexQuery::from_pattern(
-                format!("{}.*", query).as_str(),
-                self.schema_fields.name_field,
-            )?);
+        for doc_address in searcher.measured_search(&documents_query, &DocSetCollector)? {
+            documents.push(searcher.measured_doc(doc_address)?);
+        }
 
-            let mut allowed_type_queries = vec![];
-            let allowed_types = ["Alias", "Casgn", "Class", "Def", "Defs", "Gvasgn", "Module"];
+        Ok(documents)
+    }
 
-            for allowed_type in allowed_types {
-                let assignment_type_query: Box<dyn Query> = Box::new(TermQuery::new(
-                    Term::from_field_text(self.schema_fields.node_type_field, allowed_type),
-                    IndexRecordOption::Basic,
-                ));
+    pub fn index_stats(&self) -> tantivy::Result<serde_json::Value> {
+        let mut node_type_counts = serde_json::Map::new();
+        let mut segment_count = 0;
+        let mut index_size = 0;
 
-                allowed_type_queries.push((Occur::Should, assignment_type_query));
-            }
+        if let Some(reader) = &self.reader {
+            let searcher = reader.searcher();
+            let mut node_types = HashSet::new();
 
-            let allowed_types_query = BooleanQuery::new(allowed_type_queries);
+            for segment_reader in searcher.segment_readers() {
+                let inverted_index =
+                    segment_reader.inverted_index(self.schema_fields.node_type_field)?;
+                let mut terms = inverted_index.terms().stream()?;
 
-            let queries = vec![
-                (Occur::Must, user_space_query),
-                (Occur::Must, name_query),
-                (Occur::Must, Box::new(allowed_types_query)),
-            ];
+                while terms.advance() {
+                    if let Ok(node_type) = str::from_utf8(terms.key()) {
+                        node_types.insert(node_type.to_string());
+                    }
+                }
+            }
 
-            let results =
-                searcher.search(&BooleanQuery::new(queries), &TopDocs::with_limit(100))?;
+            for node_type in node_types {
+                let node_type_query = TermQuery::new(
+                    Term::from_field_text(self.schema_fields.node_type_field, &node_type),
+                    IndexRecordOption::Basic,
+                );
 
-            let mut documents = Vec::new();
+                // Term dictionaries still contain terms for deleted documents
+                let count = searcher.measured_search(&node_type_query, &Count)?;
 
-            for (_score, doc_address) in results {
-                documents.push(searcher.doc(doc_address).unwrap())
+                if count > 0 {
+                    node_type_counts.insert(node_type, json!(count));
+                }
             }
 
-            Ok(documents)
-        } else {
-            Ok(Vec::new())
+            segment_count = searcher.segment_readers().len();
+            index_size = searcher.space_usage()?.total().get_bytes();
         }
-    }
 
-    pub fn documents_to_locations(
-        &self,
-        path: &str,
-        documents: Vec<Document>,
-    ) -> Vec<Location> {
-        let mut locations = Vec::new();
+        let reindex_durations: serde_json::Map<String, serde_json::Value> = self
+            .reindex_durations
+            .iter()
+            .map(|(name, duration)| (name.to_string(), json!(duration.as_millis() as u64)))
+            .collect();
+
+        Ok(json!({
+            "documentCounts": node_type_counts,
+            "indexedFiles": self.indexed_file_counts(),
+            "gemsIndexed": self.gems_indexed,
+            "includeDirsIndexed": self.include_dirs_indexed,
+            "segmentCount": segment_count,
+            "indexSizeBytes": index_size,
+            "lastReindexDurationsMs": reindex_durations,
+        }))
+    }
 
-        for document in documents {
-            let doc_uri = Url::from_file_path(path).unwrap();
+    pub fn indexed_file_counts(&self) -> serde_json::Value {
+        json!({
+            "workspace": self.indexed_file_paths.len(),
+            "includeDirs": self.include_dir_file_count,
+            "gems": self.gem_file_count,
+        })
+    }
 
-            let start_line = document
-                .get_first(self.schema_fields.line_field)
-                .unwrap()
-                .as_u64()
-                .unwrap() as u32;
-            let start_column = document
-                .get_first(self.schema_fields.start_column_field)
-                .unwrap()
-                .as_u64()
-                .unwrap() as u32;
-            let start_position = Position::new(start_line, start_column);
-            let end_column = document
-                .get_first(self.schema_fields.end_column_field)
-                .unwrap()
-                .as_u64()
-                .unwrap() as u32;
-            let end_position = Position::new(start_line, end_column);
+    // User-space method definitions whose name is never used anywhere in the
+    // index, sorted by file. Methods Ruby calls implicitly are skipped.
+    pub fn unused_methods(&self) -> tantivy::Result<Vec<serde_json::Value>> {
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(vec![]),
+        };
 
-            let doc_range = Range::new(start_position, end_position);
-            let location = Location::new(doc_uri, doc_range);
+        let searcher = reader.searcher();
 
-            locations.push(location);
-        }
+        let unused_definitions =
+            self.unused_definitions(&searcher, &["Def", "Defs"], |name, usage_count| {
+                Ok(IMPLICITLY_CALLED_METHODS.contains(name)
+                    || !name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+                    || usage_count(name)? > 0)
+            })?;
 
-        locations
-    }
+        let mut unused_methods = vec![];
 
-    pub fn rename_tokens(
-        &self,
-        path: &str,
-        documents: Vec<Document>,
-        new_name: &String,
-    ) -> WorkspaceEdit {
-        let mut edits = Vec::new();
-
-        for document in documents {
-            let start_line = document
-                .get_first(self.schema_fields.line_field)
-                .unwrap()
-                .as_u64()
-                .unwrap() as u32;
-            let start_column = document
-                .get_first(self.schema_fields.start_column_field)
-                .unwrap()
-                .as_u64()
-                .unwrap() as u32;
-            let start_position = Position::new(start_line, start_column);
-            let end_column = document
-                .get_first(self.schema_fields.end_column_field)
-                .unwrap()
-                .as_u64()
-                .unwrap() as u32;
-            let end_position = Position::new(start_line, end_column);
+        for definition_doc in unused_definitions {
+            let separator = match definition_doc
+                .get_first(self.schema_fields.node_type_field)
+                .and_then(Value::as_text)
+            {
+                Some("Defs") => ".",
+                _ => "#",
+            };
 
-            edits.push(TextEdit::new(
-                Range::new(start_position, end_position),
-                new_name.clone(),
-            ));
+            unused_methods.push(json!({
+                "name": format!(
+                    "{}{}{}",
+                    self.definition_container(&definition_doc),
+                    separator,
+                    self.definition_name(&definition_doc),
+                ),
+                "location": self.definition_location(&definition_doc),
+            }));
         }
 
-        let mut map = HashMap::new();
-        let uri = Url::from_file_path(&path).unwrap();
-
-        map.insert(uri, edits);
+        Ok(unused_methods)
+    }
 
-        let workspace_edit = WorkspaceEdit::new(map);
+    // User-space constant and instance variable assignments that are never
+    // read, grouped by file. Instance variables with a reader method of the
+    // same name count as read.
+    pub fn unused_symbols(&self) -> tantivy::Result<Vec<serde_json::Value>> {
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(vec![]),
+        };
 
-        workspace_edit
-    }
+        let searcher = reader.searcher();
 
-    pub fn documents_to_symbol_information(
-        &self,
-        documents: Vec<Document>,
-    ) -> Vec<SymbolInformation> {
-        let mut symbol_infos = Vec::new();
+        let unused_definitions =
+            self.unused_definitions(&searcher, &["Casgn", "Ivasgn"], |name, usage_count| {
+                if usage_count(name)? > 0 {
+                    return Ok(true);
+                }
 
-        for document in documents {
-            let doc_path: Vec<&str> = document
-                .get_all(self.schema_fields.file_path)
-                .map(|v| v.as_text().unwrap())
-                .collect();
-            let doc_path = doc_path.join("/");
-            let absolute_file_path = format!("{}/{}", &self.workspace_path, &doc_path);
-            let doc_uri = Url::from_file_path(absolute_file_path).unwrap();
+                match name.strip_prefix('@') {
+                    Some(reader_name) => {
+                        let reader_query = self.definition_query(reader_name, &["Def"], None);
 
-            let name = document
-                .get_first(self.schema_fields.name_field)
-                .unwrap()
-                .as_text()
-                .unwrap();
+                        Ok(searcher.measured_search(&reader_query, &Count)? > 0)
+                    }
+                    None => Ok(false),
+                }
+            })?;
 
-            let start_line = document
-                .get_first(self.schema_fields.line_field)
-                .unwrap()
-                .as_u64()
-                .unwrap() as u32;
-            let start_column = document
-                .get_first(self.schema_fields.start_column_field)
-                .unwrap()
-                .as_u64()
-                .unwrap() as u32;
-            let start_position = Position::new(start_line, start_column);
-            let end_column = document
-                .get_first(self.schema_fields.end_column_field)
-                .unwrap()
-                .as_u64()
-                .unwrap() as u32;
-            let end_position = Position::new(start_line, end_column);
+        let mut files: Vec<(Location, Vec<serde_json::Value>)> = vec![];
 
-            let doc_type = document
+        for definition_doc in unused_definitions {
+            let location = self.definition_location(&definition_doc);
+            let kind = match definition_doc
                 .get_first(self.schema_fields.node_type_field)
-                .unwrap()
-                .as_text()
-                .unwrap();
-
-            let symbol_kind = match doc_type {
-                "Alias" => SymbolKind::METHOD,
-                "Casgn" => SymbolKind::CLASS,
-                "Class" => SymbolKind::CLASS,
-                "Def" => SymbolKind::METHOD,
-                "Defs" => SymbolKind::METHOD,
-                "Gvasgn" => SymbolKind::VARIABLE,
-                "Module" => SymbolKind::MODULE,
-                _ => SymbolKind::VARIABLE,
+                .and_then(Value::as_text)
+            {
+                Some("Casgn") => "constant",
+                _ => "instanceVariable",
             };
 
-            let doc_range = Range::new(start_position, end_position);
-            let symbol_location = Location::new(doc_uri, doc_range);
-
-            let symbol_info = SymbolInformation {
-                name: name.to_string(),
-                kind: symbol_kind,
-                tags: None,
-                deprecated: None,
-                location: symbol_location,
-                container_name: None,
-            };
+            let symbol = json!({
+                "name": self.definition_name(&definition_doc),
+                "kind": kind,
+                "scope": self.definition_container(&definition_doc),
+                "range": location.range,
+            });
 
-            symbol_infos.push(symbol_info);
+            match files.last_mut() {
+                Some((file_location, symbols)) if file_location.uri == location.uri => {
+                    symbols.push(symbol)
+                }
+                _ => files.push((location, vec![symbol])),
+            }
         }
 
-        symbol_infos
+        Ok(files
+            .into_iter()
+            .map(|(location, symbols)| json!({ "uri": location.uri, "symbols": symbols }))
+            .collect())
     }
 
-    fn parse(
-        &mut self,
-        contents: &String,
-        documents: &mut Vec<FuzzyNode>,
-    ) -> Result<
-        Vec<Option<tower_lsp::lsp_types::Diagnostic>>,
-        Vec<Option<tower_lsp::lsp_types::Diagnostic>>,
-    > {
-        let options = ParserOptions {
-            buffer_name: "(eval)".to_string(),
-            record_tokens: false,
-            ..Default::default()
+    // Methods defined in the workspace and the calls between them, as DOT or
+    // JSON. Calls are attributed to the method they're made in and only kept
+    // when the called method can be told apart from others with the same
+    // name. `namespace` limits the graph to calls made from that namespace.
+    pub fn export_call_graph(
+        &self,
+        options: &serde_json::Value,
+    ) -> tantivy::Result<serde_json::Value> {
+        let format = options["format"].as_str().unwrap_or("json");
+        let namespace = options["namespace"].as_str();
+        let output_path = options["outputPath"].as_str();
+
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(json!(null)),
         };
-        let parser = Parser::new(contents.to_string(), options);
-        let parser_result = parser.do_parse();
-        let input = parser_result.input;
 
-        let mut diagnostics = vec![];
+        let searcher = reader.searcher();
 
-        for parser_diagnostic in parser_result.diagnostics {
-            diagnostics.push(self.lsp_diagnostic(parser_diagnostic, &input));
-        }
+        // (id, container, name, singleton, location)
+        let mut methods: Vec<(String, String, String, bool, Location)> = vec![];
+        let mut methods_by_name: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut method_ids = HashSet::new();
 
-        let ast = match parser_result.ast {
-            Some(a) => *a,
-            None => return Err(diagnostics),
-        };
-
-        let mut scope = Vec::new();
-
-        self.serialize(&ast, documents, &mut scope, &input);
-
-        Ok(diagnostics)
-    }
-
-    fn lsp_diagnostic(
-        &mut self,
-        parser_diagnostic: lib_ruby_parser::Diagnostic,
-        input: &DecodedInput,
-    ) -> Option<tower_lsp::lsp_types::Diagnostic> {
-        let diagnostic = || -> Option<tower_lsp::lsp_types::Diagnostic> {
-            let (begin_lineno, start_column) =
-                input.line_col_for_pos(parser_diagnostic.loc.begin).unwrap();
-            let (end_lineno, end_column) =
-                input.line_col_for_pos(parser_diagnostic.loc.end).unwrap();
-            let start_position = Position::new(
-                begin_lineno.try_into().unwrap(),
-                start_column.try_into().unwrap(),
-            );
-            let end_position = Position::new(
-                end_lineno.try_into().unwrap(),
-                end_column.try_into().unwrap(),
-            );
-
-            Some(tower_lsp::lsp_types::Diagnostic::new_simple(
-                Range::new(start_position, end_position),
-                parser_diagnostic.message.render(),
-            ))
-        }();
-
-        diagnostic
-    }
-
-    fn serialize(
-        &mut self,
-        node: &Node,
-        documents: &mut Vec<FuzzyNode>,
-        fuzzy_scope: &mut Vec<String>,
-        input: &DecodedInput,
-    ) {
-        match &node {
-            Node::Alias(Alias { to, from, .. }) => {
-                if let Node::Sym(sym) = *to.to_owned() {
-                    let (lineno, begin_pos) =
-                        input.line_col_for_pos(sym.expression_l.begin).unwrap();
-                    let (_lineno, end_pos) = input.line_col_for_pos(sym.expression_l.end).unwrap();
-
-                    documents.push(FuzzyNode {
-                        category: "assignment",
-                        fuzzy_ruby_scope: fuzzy_scope.clone(),
-                        class_scope: vec![],
-                        name: sym.name.to_string_lossy(),
-                        node_type: "Alias",
-                        line: lineno,
-                        start_column: begin_pos,
-                        end_column: end_pos,
-                    });
-                }
-
-                if let Node::Sym(sym) = *from.to_owned() {
-                    let (lineno, begin_pos) =
-                        input.line_col_for_pos(sym.expression_l.begin).unwrap();
-                    let (_lineno, end_pos) = input.line_col_for_pos(sym.expression_l.end).unwrap();
-
-                    documents.push(FuzzyNode {
-                        category: "usage",
-                        fuzzy_ruby_scope: fuzzy_scope.clone(),
-                        class_scope: vec![],
-                        name: sym.name.to_string_lossy(),
-                        node_type: "Alias",
-                        line: lineno,
-                        start_column: begin_pos,
-                        end_column: end_pos,
-                    });
-                }
-            }
-
-            Node::And(And { lhs, rhs, .. }) => {
-                self.serialize(lhs, documents, fuzzy_scope, input);
-                self.serialize(rhs, documents, fuzzy_scope, input);
-            }
+        for definition_doc in self.user_space_documents(&searcher, &["Def", "Defs"])? {
+            let container = self.definition_container(&definition_doc);
+            let name = self.definition_name(&definition_doc);
+            let singleton = definition_doc
+                .get_first(self.schema_fields.node_type_field)
+                .and_then(Value::as_text)
+                == Some("Defs");
+            let id = format!("{}{}{}", container, if singleton { "." } else { "#" }, name);
 
-            Node::AndAsgn(AndAsgn { recv, value, .. }) => {
-                self.serialize(recv, documents, fuzzy_scope, input);
-                self.serialize(value, documents, fuzzy_scope, input);
+            if !method_ids.insert(id.to_string()) {
+                continue;
             }
 
-            Node::Arg(Arg { name, expression_l }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(expression_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(expression_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "assignment",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "Arg",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-            }
+            methods_by_name
+                .entry(name.to_string())
+                .or_default()
+                .push(methods.len());
+            methods.push((
+                id,
+                container,
+                name,
+                singleton,
+                self.definition_location(&definition_doc),
+            ));
+        }
 
-            Node::Args(Args { args, .. }) => {
-                if self.index_interface_only {
-                    return;
-                }
+        let mut edges: Vec<(usize, usize)> = vec![];
+        let mut edge_set = HashSet::new();
 
-                for node in args {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+        for call_doc in self.user_space_documents(&searcher, &["Send"])? {
+            let fuzzy_scope: Vec<&str> = call_doc
+                .get_all(self.schema_fields.fuzzy_ruby_scope_field)
+                .flat_map(Value::as_text)
+                .collect();
+            let caller_container = fuzzy_scope
+                .iter()
+                .filter(|scope_name| scope_name.starts_with(char::is_uppercase))
+                .copied()
+                .collect::<Vec<&str>>()
+                .join("::");
 
-            Node::Array(Array { elements, .. }) => {
-                for node in elements {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+            if let Some(namespace) = namespace {
+                let in_namespace = caller_container == namespace
+                    || caller_container.starts_with(&format!("{}::", namespace));
 
-            Node::ArrayPattern(ArrayPattern { elements, .. }) => {
-                for node in elements {
-                    self.serialize(node, documents, fuzzy_scope, input);
+                if !in_namespace {
+                    continue;
                 }
             }
 
-            Node::ArrayPatternWithTail(ArrayPatternWithTail { elements, .. }) => {
-                for node in elements {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+            let caller_scope = match fuzzy_scope
+                .iter()
+                .rev()
+                .find(|scope_name| !scope_name.starts_with(char::is_uppercase))
+            {
+                Some(caller_scope) => *caller_scope,
+                None => continue,
+            };
+            let caller_name = caller_scope
+                .strip_prefix(SINGLETON_RECEIVER_SCOPE)
+                .unwrap_or(caller_scope);
 
-            // Node::BackRef(BackRef { .. }) => {}
-            Node::Begin(Begin { statements, .. }) => {
-                for child_node in statements {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+            let caller = methods_by_name.get(caller_name).and_then(|candidates| {
+                candidates
+                    .iter()
+                    .find(|index| methods[**index].1 == caller_container)
+                    .copied()
+            });
+            let caller = match caller {
+                Some(caller) => caller,
+                None => continue,
+            };
 
-            Node::Block(Block {
-                call, args, body, ..
-            }) => {
-                if self.index_interface_only {
-                    return;
-                }
+            let name = self.definition_name(&call_doc);
+            let candidates = match methods_by_name.get(&name) {
+                Some(candidates) => candidates,
+                None => continue,
+            };
 
-                self.serialize(call, documents, fuzzy_scope, input);
+            let class_scope: Vec<&str> = call_doc
+                .get_all(self.schema_fields.class_scope_field)
+                .flat_map(Value::as_text)
+                .collect();
+            let singleton_call = self.class_method_call(&call_doc);
 
-                for child_node in args {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+            let mut callees: Vec<usize> = match class_scope.first() {
+                // A constant receiver or an inferred local variable class
+                Some(receiver_class) => candidates
+                    .iter()
+                    .filter(|index| {
+                        let container = &methods[**index].1;
 
-                if let Some(child_node) = body {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
+                        container == receiver_class
+                            || container.ends_with(&format!("::{}", receiver_class))
+                    })
+                    .copied()
+                    .collect(),
+                None => {
+                    let same_class: Vec<usize> = candidates
+                        .iter()
+                        .filter(|index| methods[**index].1 == caller_container)
+                        .copied()
+                        .collect();
+
+                    if same_class.is_empty() && candidates.len() == 1 {
+                        candidates.to_vec()
+                    } else {
+                        same_class
+                    }
                 }
-            }
+            };
 
-            // Node::Blockarg(Blockarg { .. }) => {}
-            Node::BlockPass(BlockPass { value, .. }) => {
-                if let Some(child_node) = value {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+            if callees
+                .iter()
+                .any(|index| methods[*index].3 == singleton_call)
+            {
+                callees.retain(|index| methods[*index].3 == singleton_call);
             }
 
-            Node::Break(Break { args, .. }) => {
-                for child_node in args {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
+            for callee in callees {
+                if edge_set.insert((caller, callee)) {
+                    edges.push((caller, callee));
                 }
             }
+        }
 
-            Node::Case(Case {
-                expr,
-                when_bodies,
-                else_body,
-                ..
-            }) => {
-                if let Some(child_node) = expr {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+        let graph = match format {
+            "dot" => {
+                let mut dot = String::from("digraph calls {\n  rankdir=LR;\n");
 
-                for child_node in when_bodies {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
+                for (caller, callee) in &edges {
+                    dot.push_str(&format!(
+                        "  {:?} -> {:?};\n",
+                        methods[*caller].0, methods[*callee].0
+                    ));
                 }
 
-                if let Some(child_node) = else_body {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
-
-            Node::CaseMatch(CaseMatch {
-                expr,
-                in_bodies,
-                else_body,
-                ..
-            }) => {
-                self.serialize(expr, documents, fuzzy_scope, input);
-
-                for child_node in in_bodies {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+                dot.push_str("}\n");
 
-                if let Some(child_node) = else_body {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+                json!(dot)
             }
-
-            Node::Casgn(Casgn {
-                scope,
-                name,
-                value,
-                name_l,
-                ..
-            }) => {
-                let const_node = Const {
-                    scope: scope.to_owned(),
-                    name: "".to_string(),
-                    double_colon_l: None,
-                    name_l: Loc { begin: 0, end: 0 },
-                    expression_l: Loc { begin: 0, end: 0 },
-                };
-                let node_class_scope = self.build_class_scope(&const_node);
-
-                let (lineno, begin_pos) = input.line_col_for_pos(name_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(name_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "assignment",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: node_class_scope,
-                    name: name.to_string(),
-                    node_type: "Casgn",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-
-                if let Some(child_node) = scope {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-
-                if let Some(child_node) = value {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+            _ => {
+                let mut node_indexes: Vec<usize> = edges
+                    .iter()
+                    .flat_map(|(caller, callee)| [*caller, *callee])
+                    .collect();
+                node_indexes.sort();
+                node_indexes.dedup();
+
+                json!({
+                    "nodes": node_indexes
+                        .iter()
+                        .map(|index| json!({ "id": methods[*index].0, "location": methods[*index].4 }))
+                        .collect::<Vec<serde_json::Value>>(),
+                    "edges": edges
+                        .iter()
+                        .map(|(caller, callee)| json!({ "from": methods[*caller].0, "to": methods[*callee].0 }))
+                        .collect::<Vec<serde_json::Value>>(),
+                })
             }
+        };
 
-            // Node::Cbase(Cbase { .. }) => {}
-            Node::Class(Class {
-                name,
-                superclass,
-                body,
-                ..
-            }) => {
-                if let Node::Const(const_node) = *name.to_owned() {
-                    // loop over names and add to fuzzy/class_scope
-                    let node_class_scope = self.build_class_scope(&const_node);
-                    let class_scope_len = node_class_scope.len();
-
-                    for ancestor_name in node_class_scope {
-                        fuzzy_scope.push(ancestor_name);
-                    }
-
-                    let (lineno, begin_pos) = input
-                        .line_col_for_pos(const_node.expression_l.begin)
-                        .unwrap();
-                    let (_lineno, end_pos) =
-                        input.line_col_for_pos(const_node.expression_l.end).unwrap();
-                    let class_name = const_node.name.to_string();
-
-                    let document = FuzzyNode {
-                        category: "assignment",
-                        fuzzy_ruby_scope: fuzzy_scope.clone(),
-                        // class_scope: node_class_scope,
-                        class_scope: vec![],
-                        name: class_name.clone(),
-                        node_type: "Class",
-                        line: lineno,
-                        start_column: begin_pos,
-                        end_column: end_pos,
-                    };
-
-                    documents.push(document);
-
-                    fuzzy_scope.push(class_name.to_string());
-                    self.class_scope.push(class_name);
-
-                    if let Some(scope_node) = const_node.scope {
-                        self.serialize(&scope_node, documents, fuzzy_scope, input);
-                    }
-
-                    if let Some(superclass_node) = superclass {
-                        self.serialize(superclass_node, documents, fuzzy_scope, input);
-                    }
-
-                    for child_node in body {
-                        self.serialize(child_node, documents, fuzzy_scope, input);
-                    }
-
-                    for _ in 0..class_scope_len {
-                        fuzzy_scope.pop();
-                    }
+        if let Some(output_path) = output_path {
+            let contents = match &graph {
+                serde_json::Value::String(dot) => dot.to_string(),
+                _ => serde_json::to_string_pretty(&graph).unwrap_or_default(),
+            };
 
-                    fuzzy_scope.pop();
-                    self.class_scope.pop();
-                }
+            if let Err(error) = fs::write(output_path, contents) {
+                info!(
+                    "Couldn't write the call graph to {}: {}",
+                    output_path, error
+                );
+                return Ok(json!(null));
             }
 
-            // Node::Complex(Complex { .. }) => {}
-            Node::Const(Const {
-                scope,
-                name,
-                name_l,
-                ..
-            }) => {
-                let const_node = Const {
-                    scope: scope.to_owned(),
-                    name: "".to_string(),
-                    double_colon_l: None,
-                    name_l: Loc { begin: 0, end: 0 },
-                    expression_l: Loc { begin: 0, end: 0 },
-                };
-                let node_class_scope = self.build_class_scope(&const_node);
-
-                let (lineno, begin_pos) = input.line_col_for_pos(name_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(name_l.end).unwrap();
-
-                let document = FuzzyNode {
-                    category: "usage",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: node_class_scope,
-                    name: name.to_string(),
-                    node_type: "Const",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                };
+            return Ok(json!({ "path": output_path }));
+        }
 
-                documents.push(document);
+        Ok(graph)
+    }
 
-                if let Some(child_node) = scope {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+    // User-space definitions of `node_types` that `used` doesn't accept,
+    // sorted by file and line. `used` is given the definition's name and a
+    // function counting the usages of a name, which are cached.
+    fn unused_definitions(
+        &self,
+        searcher: &Searcher,
+        node_types: &[&str],
+        used: impl Fn(&str, &mut dyn FnMut(&str) -> tantivy::Result<usize>) -> tantivy::Result<bool>,
+    ) -> tantivy::Result<Vec<Document>> {
+        let mut usage_counts: HashMap<String, usize> = HashMap::new();
+        let mut usage_count = |name: &str| -> tantivy::Result<usize> {
+            if let Some(usage_count) = usage_counts.get(name) {
+                return Ok(*usage_count);
+            }
+
+            let usage_query = BooleanQuery::new(vec![
+                (
+                    Occur::Must,
+                    Box::new(TermQuery::new(
+                        Term::from_field_text(self.schema_fields.category_field, "usage"),
+                        IndexRecordOption::Basic,
+                    )) as Box<dyn Query>,
+                ),
+                (
+                    Occur::Must,
+                    Box::new(TermQuery::new(
+                        Term::from_field_text(self.schema_fields.name_field, name),
+                        IndexRecordOption::Basic,
+                    )),
+                ),
+            ]);
 
-            Node::ConstPattern(ConstPattern {
-                const_, pattern, ..
-            }) => {
-                self.serialize(const_, documents, fuzzy_scope, input);
-                self.serialize(pattern, documents, fuzzy_scope, input);
-            }
+            let count = searcher.measured_search(&usage_query, &Count)?;
+            usage_counts.insert(name.to_string(), count);
 
-            Node::CSend(CSend {
-                recv,
-                method_name,
-                args,
-                selector_l,
-                ..
-            }) => {
-                if let Some(loc) = selector_l {
-                    let (lineno, begin_pos) = input.line_col_for_pos(loc.begin).unwrap();
-                    let (_lineno, end_pos) = input.line_col_for_pos(loc.end).unwrap();
-
-                    documents.push(FuzzyNode {
-                        category: "usage",
-                        fuzzy_ruby_scope: fuzzy_scope.clone(),
-                        class_scope: vec![],
-                        name: method_name.to_string(),
-                        node_type: "CSend",
-                        line: lineno,
-                        start_column: begin_pos,
-                        end_column: end_pos,
-                    });
-                }
+            Ok(count)
+        };
 
-                self.serialize(recv, documents, fuzzy_scope, input);
+        let mut unused_definitions = vec![];
 
-                for child_node in args {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+        for definition_doc in self.user_space_documents(searcher, node_types)? {
+            let name = self.definition_name(&definition_doc);
 
-            Node::Cvar(Cvar { name, expression_l }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(expression_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(expression_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "usage",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "Cvar",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
+            if !used(&name, &mut usage_count)? {
+                unused_definitions.push(definition_doc);
             }
+        }
 
-            Node::Cvasgn(Cvasgn {
-                name,
-                value,
-                name_l,
-                ..
-            }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(name_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(name_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "assignment",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "Cvasgn",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-
-                if let Some(child_node) = value {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+        Ok(unused_definitions)
+    }
 
-            Node::Def(Def {
-                name,
-                args,
-                body,
-                name_l,
-                ..
-            }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(name_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(name_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "assignment",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "Def",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-
-                if self.index_interface_only {
-                    return;
-                }
+    // Every user-space document of `node_types`, sorted by file and line
+    fn user_space_documents(
+        &self,
+        searcher: &Searcher,
+        node_types: &[&str],
+    ) -> tantivy::Result<Vec<Document>> {
+        let mut node_type_queries = vec![];
 
-                fuzzy_scope.push(name.to_string());
+        for node_type in node_types {
+            let node_type_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(self.schema_fields.node_type_field, node_type),
+                IndexRecordOption::Basic,
+            ));
 
-                if let Some(child_node) = args {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+            node_type_queries.push((Occur::Should, node_type_query));
+        }
 
-                if let Some(child_node) = body {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+        let user_space_query: Box<dyn Query> = Box::new(TermQuery::new(
+            Term::from_field_bool(self.schema_fields.user_space_field, true),
+            IndexRecordOption::Basic,
+        ));
+        let documents_query = BooleanQuery::new(vec![
+            (Occur::Must, user_space_query),
+            (
+                Occur::Must,
+                Box::new(BooleanQuery::new(node_type_queries)) as Box<dyn Query>,
+            ),
+        ]);
 
-                fuzzy_scope.pop();
-            }
+        let mut documents = vec![];
 
-            Node::Defined(Defined { value, .. }) => {
-                self.serialize(value, documents, fuzzy_scope, input);
-            }
+        for doc_address in searcher.measured_search(&documents_query, &DocSetCollector)? {
+            documents.push(searcher.measured_doc(doc_address)?);
+        }
 
-            Node::Defs(Defs {
-                name,
-                args,
-                body,
-                name_l,
-                ..
-            }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(name_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(name_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "assignment",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "Defs",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-
-                if self.index_interface_only {
-                    return;
-                }
+        documents.sort_by_key(|document| {
+            let location = self.definition_location(document);
 
-                let mut scope_name = "self.".to_owned();
-                scope_name.push_str(name);
+            (location.uri.to_string(), location.range.start.line)
+        });
 
-                fuzzy_scope.push(scope_name);
+        Ok(documents)
+    }
 
-                if let Some(child_node) = args {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+    pub fn find_highlights(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> tantivy::Result<Vec<DocumentHighlight>> {
+        if let Ok(search_results) = self.find_references(params) {
+            let mut highlights = Vec::new();
 
-                if let Some(child_node) = body {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+            for search_result in &search_results {
+                let start_line = search_result
+                    .get_first(self.schema_fields.line_field)
+                    .unwrap()
+                    .as_u64()
+                    .unwrap() as u32;
+                let start_column = search_result
+                    .get_first(self.schema_fields.start_column_field)
+                    .unwrap()
+                    .as_u64()
+                    .unwrap() as u32;
+                let start_position = Position::new(start_line, start_column);
+                let end_column = search_result
+                    .get_first(self.schema_fields.end_column_field)
+                    .unwrap()
+                    .as_u64()
+                    .unwrap() as u32;
+                let end_position = Position::new(start_line, end_column);
 
-                fuzzy_scope.pop();
-            }
+                let range = Range::new(start_position, end_position);
 
-            Node::Dstr(Dstr { parts, .. }) => {
-                for child_node in parts {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+                let category = search_result
+                    .get_first(self.schema_fields.category_field)
+                    .unwrap()
+                    .as_text()
+                    .unwrap();
 
-            Node::Dsym(Dsym { parts, .. }) => {
-                for child_node in parts {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+                let kind = if category == "assignment" {
+                    Some(DocumentHighlightKind::WRITE)
+                } else {
+                    Some(DocumentHighlightKind::READ)
+                };
 
-            Node::EFlipFlop(EFlipFlop { left, right, .. }) => {
-                if let Some(child_node) = left {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+                let document_highlight = DocumentHighlight { range, kind };
 
-                if let Some(child_node) = right {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+                highlights.push(document_highlight);
             }
 
-            // Node::EmptyElse(EmptyElse { .. }) => {}
-            // Node::Encoding(Encoding { .. }) => {}
-            Node::Ensure(Ensure { body, ensure, .. }) => {
-                if let Some(child_node) = body {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+            Ok(highlights)
+        } else {
+            Ok(Vec::new())
+        }
+    }
 
-                if let Some(child_node) = ensure {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+    // Links from the paths an open file requires to the files they load.
+    // Paths that can't be found, like native extensions, aren't linked.
+    pub fn document_links(&self, uri: &Url) -> Vec<DocumentLink> {
+        let parsed_document = match self.open_document(uri) {
+            Some(parsed_document) => parsed_document,
+            None => return vec![],
+        };
 
-            Node::Erange(Erange { left, right, .. }) => {
-                if let Some(child_node) = left {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+        let file_path = uri_path(uri);
+        let mut links = vec![];
 
-                if let Some(child_node) = right {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+        for required_path in &parsed_document.findings.required_paths {
+            let target_path = self.required_file_path(&file_path, required_path);
 
-            // Node::False(False { .. }) => {}
-            // Node::File(File { .. }) => {}
-            Node::FindPattern(FindPattern { elements, .. }) => {
-                for child_node in elements {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+            let target = match target_path.and_then(|path| Url::from_file_path(path).ok()) {
+                Some(target) => target,
+                None => continue,
+            };
 
-            // Node::Float(Float { .. }) => {}
-            Node::For(For {
-                iterator,
-                iteratee,
-                body,
-                ..
-            }) => {
-                self.serialize(iterator, documents, fuzzy_scope, input);
-                self.serialize(iteratee, documents, fuzzy_scope, input);
-
-                for child_node in body {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+            links.push(DocumentLink {
+                range: Range::new(
+                    Position::new(required_path.line as u32, required_path.start_column as u32),
+                    Position::new(required_path.line as u32, required_path.end_column as u32),
+                ),
+                target: Some(target),
+                tooltip: None,
+                data: None,
+            });
+        }
 
-            // Node::ForwardArg(ForwardArg { .. }) => {}
-            // Node::ForwardedArgs(ForwardedArgs { .. }) => {}
-            Node::Gvar(Gvar { name, expression_l }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(expression_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(expression_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "usage",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "Gvar",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-            }
+        for rendered_view in &parsed_document.findings.rendered_views {
+            let target = self
+                .rendered_view_paths(&file_path, rendered_view)
+                .first()
+                .and_then(|path| Url::from_file_path(path).ok());
 
-            Node::Gvasgn(Gvasgn {
-                name,
-                value,
-                name_l,
-                ..
-            }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(name_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(name_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "assignment",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "Gvasgn",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-
-                if let Some(child_node) = value {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+            let target = match target {
+                Some(target) => target,
+                None => continue,
+            };
 
-            Node::Hash(Hash { pairs, .. }) => {
-                for child_node in pairs {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+            links.push(DocumentLink {
+                range: Range::new(
+                    Position::new(rendered_view.line as u32, rendered_view.start_column as u32),
+                    Position::new(rendered_view.line as u32, rendered_view.end_column as u32),
+                ),
+                target: Some(target),
+                tooltip: None,
+                data: None,
+            });
+        }
 
-            Node::HashPattern(HashPattern { elements, .. }) => {
-                for child_node in elements {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+        links
+    }
 
-            Node::Heredoc(Heredoc { parts, .. }) => {
-                for child_node in parts {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+    // The indexed templates a `render` could mean, e.g. `_header.html.erb` in
+    // `app/views/shared` for `render "shared/header"` and `_item.html.erb`
+    // next to the template for `render partial: "item"`
+    fn rendered_view_paths(&self, file_path: &str, rendered_view: &RenderedView) -> Vec<String> {
+        let relative_path = self.workspace_path.relative_path(file_path);
+        let partial = rendered_view.partial.unwrap_or(template(&relative_path));
+
+        let (directory, name) = match rendered_view.path.rsplit_once('/') {
+            Some((directory, name)) => (directory.to_string(), name),
+            None => match views_directory(&relative_path) {
+                Some(directory) => (directory, rendered_view.path.as_str()),
+                None => return vec![],
+            },
+        };
 
-            Node::If(If {
-                cond,
-                if_true,
-                if_false,
-                ..
-            }) => {
-                self.serialize(cond, documents, fuzzy_scope, input);
+        let prefix = format!(
+            "app/views/{}/{}{}",
+            directory,
+            if partial { "_" } else { "" },
+            name
+        );
+        let prefix = self.workspace_path.absolute_path(&prefix);
+
+        let mut paths: Vec<String> = self
+            .indexed_file_paths
+            .iter()
+            .filter(|path| {
+                path.strip_prefix(&prefix)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+            })
+            .cloned()
+            .collect();
+        paths.sort();
+
+        paths
+    }
 
-                if let Some(child_node) = if_true {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+    // The method the one around a position overrides, from the closest
+    // ancestor of its class that defines it
+    pub fn super_method(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> tantivy::Result<Option<Location>> {
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
 
-                if let Some(child_node) = if_false {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+        let parsed_document = match self.open_document(&params.text_document.uri) {
+            Some(parsed_document) => parsed_document,
+            None => return Ok(None),
+        };
 
-            Node::IfGuard(IfGuard { cond, .. }) => {
-                self.serialize(cond, documents, fuzzy_scope, input);
-            }
+        let position = params.position;
+        let scope_spans: Vec<&ScopeSpan> = parsed_document
+            .findings
+            .scope_spans
+            .iter()
+            .filter(|scope_span| {
+                scope_span.range.start <= position && position < scope_span.range.end
+            })
+            .collect();
+
+        let method = scope_spans
+            .iter()
+            .rev()
+            .find(|scope_span| matches!(scope_span.node_type, "Def" | "Defs"));
+        let class = scope_spans
+            .iter()
+            .rev()
+            .find(|scope_span| matches!(scope_span.node_type, "Class" | "Module"));
+
+        let (method, class) = match (method, class) {
+            (Some(method), Some(class)) => (method, class),
+            _ => return Ok(None),
+        };
 
-            Node::IFlipFlop(IFlipFlop { left, right, .. }) => {
-                if let Some(child_node) = left {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+        // Indexed by the last part of names like `Admin::User`
+        let class_name = class.name.rsplit("::").next().unwrap_or(&class.name);
+        let searcher = reader.searcher();
+        let super_top_docs = self.find_ancestor_definitions(
+            &searcher,
+            class_name,
+            &method.name,
+            &[method.node_type],
+        )?;
+
+        match super_top_docs.first() {
+            Some((_score, doc_address)) => {
+                let super_doc = searcher.measured_doc(*doc_address)?;
+                Ok(Some(self.definition_location(&super_doc)))
+            }
+            None => Ok(None),
+        }
+    }
 
-                if let Some(child_node) = right {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+    // Files in the standard library, including its default gems
+    fn stdlib_file(&self, path: &str) -> bool {
+        self.stdlib_path
+            .as_ref()
+            .is_some_and(|stdlib_path| Path::new(path).starts_with(stdlib_path))
+    }
 
-            Node::IfMod(IfMod {
-                cond,
-                if_true,
-                if_false,
-                ..
-            }) => {
-                self.serialize(cond, documents, fuzzy_scope, input);
+    // Where a gem from `Gemfile.lock` is installed, e.g. `gems/sidekiq-7.1.2`
+    // in the gem home
+    fn gem_path(&self, name: &str) -> Option<&String> {
+        self.gem_paths
+            .iter()
+            .chain(self.unindexed_gems.values())
+            .find(|gem_path| {
+                gem_name_and_version(&format!("{}/", gem_path))
+                    .is_some_and(|(gem_name, _version)| gem_name == name)
+            })
+    }
 
-                if let Some(child_node) = if_true {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+    // The main file of a gem from `Gemfile.lock`, like `lib/sidekiq.rb`
+    pub fn gem_location(&self, name: &str) -> Option<Location> {
+        let uri = Url::from_file_path(self.gem_main_file(name)?).ok()?;
 
-                if let Some(child_node) = if_false {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+        Some(Location::new(uri, Range::default()))
+    }
 
-            Node::IfTernary(IfTernary {
-                cond,
-                if_true,
-                if_false,
-                ..
-            }) => {
-                self.serialize(cond, documents, fuzzy_scope, input);
-                self.serialize(if_true, documents, fuzzy_scope, input);
-                self.serialize(if_false, documents, fuzzy_scope, input);
-            }
+    fn gem_main_file(&self, name: &str) -> Option<PathBuf> {
+        let gem_path = self.gem_path(name)?;
+
+        // `concurrent-ruby` is loaded from `lib/concurrent-ruby.rb`, while
+        // `rack-test` is loaded from `lib/rack/test.rb`
+        [name.to_string(), name.replace('-', "/")]
+            .iter()
+            .map(|main_path| {
+                Path::new(gem_path)
+                    .join("lib")
+                    .join(format!("{}.rb", main_path))
+            })
+            .find(|main_file| main_file.is_file())
+    }
 
-            Node::Index(lib_ruby_parser::nodes::Index { recv, indexes, .. }) => {
-                self.serialize(recv, documents, fuzzy_scope, input);
+    // The tests of an implementation file, or the implementation of a test,
+    // going by the `relatedFiles` conventions. Only files in the workspace are
+    // listed, unless none of them exist, in which case they all are so an
+    // editor can offer to create one.
+    pub fn related_files(&self, uri: &Url) -> Vec<serde_json::Value> {
+        if self.no_workspace {
+            return vec![];
+        }
 
-                for child_node in indexes {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+        let file_path = uri_path(uri);
+        if !self.workspace_path.contains(&file_path) {
+            return vec![];
+        }
 
-            Node::IndexAsgn(IndexAsgn {
-                recv,
-                indexes,
-                value,
-                ..
-            }) => {
-                self.serialize(recv, documents, fuzzy_scope, input);
+        let relative_path = self.workspace_path.relative_path(&file_path);
+        let relative_path = relative_path.trim_start_matches('/');
+        let mut candidates: Vec<String> = vec![];
 
-                for child_node in indexes {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+        for related_files in &self.settings.related_files {
+            let conventions = [
+                (&related_files.source, &related_files.test),
+                (&related_files.test, &related_files.source),
+            ];
 
-                if let Some(child_node) = value {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+            for (from, to) in conventions {
+                let (from_prefix, from_suffix) = match from.split_once("{path}") {
+                    Some(affixes) => affixes,
+                    None => continue,
+                };
 
-            Node::InPattern(InPattern {
-                pattern,
-                guard,
-                body,
-                ..
-            }) => {
-                self.serialize(pattern, documents, fuzzy_scope, input);
+                let path = relative_path
+                    .strip_prefix(from_prefix)
+                    .and_then(|path| path.strip_suffix(from_suffix))
+                    .filter(|path| !path.is_empty());
 
-                if let Some(child_node) = guard {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+                if let Some(path) = path {
+                    let candidate = to.replace("{path}", path);
 
-                if let Some(child_node) = body {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
+                    if !candidates.contains(&candidate) {
+                        candidates.push(candidate);
+                    }
                 }
             }
+        }
 
-            // Node::Int(Int { .. }) => {}
-            Node::Irange(Irange { left, right, .. }) => {
-                if let Some(child_node) = left {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
+        let candidates: Vec<(Url, bool)> = candidates
+            .iter()
+            .map(|candidate| self.workspace_path.absolute_path(candidate))
+            .filter_map(|path| {
+                let exists = self.indexed_file_paths.contains(&path);
+                Some((Url::from_file_path(path).ok()?, exists))
+            })
+            .collect();
+
+        let any_exist = candidates.iter().any(|(_uri, exists)| *exists);
+
+        candidates
+            .into_iter()
+            .filter(|(_uri, exists)| *exists || !any_exist)
+            .map(|(uri, exists)| json!({ "uri": uri, "exists": exists }))
+            .collect()
+    }
 
-                if let Some(child_node) = right {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+    // A lens running each test in a file under `spec/` or `test/`, with the
+    // file and line as the `fuzzy.runTest` command's arguments
+    pub fn code_lenses(&self, uri: &Url) -> Vec<CodeLens> {
+        let parsed_document = match self.open_document(uri) {
+            Some(parsed_document) => parsed_document,
+            None => return vec![],
+        };
 
-            Node::Ivar(Ivar { name, expression_l }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(expression_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(expression_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "usage",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "Ivar",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-            }
+        let framework = match self.test_framework(uri) {
+            Some(framework) => framework,
+            None => return vec![],
+        };
 
-            Node::Ivasgn(Ivasgn {
-                name,
-                value,
-                name_l,
-                ..
-            }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(name_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(name_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "assignment",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "Ivasgn",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-
-                if let Some(child_node) = value {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+        parsed_document
+            .findings
+            .test_blocks
+            .iter()
+            .filter(|test_block| test_block.framework == framework)
+            .map(|test_block| {
+                let position = Position::new(test_block.line as u32, 0);
+                let title = if test_block.group {
+                    "Run tests"
+                } else {
+                    "Run test"
+                };
 
-            Node::Kwarg(Kwarg { name, name_l, .. }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(name_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(name_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "assignment",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "Kwarg",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-            }
+                CodeLens {
+                    range: Range::new(position, position),
+                    command: Some(tower_lsp::lsp_types::Command::new(
+                        title.to_string(),
+                        "fuzzy.runTest".to_string(),
+                        Some(vec![json!(uri), json!(test_block.line)]),
+                    )),
+                    data: None,
+                }
+            })
+            .collect()
+    }
 
-            Node::Kwargs(Kwargs { pairs, .. }) => {
-                for node in pairs {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+    // The framework and name of the test starting on `line`, for running it
+    pub fn test_at(&self, uri: &Url, line: u32) -> Option<(&'static str, String)> {
+        let framework = self.test_framework(uri)?;
+        let test_block =
+            self.open_document(uri)?
+                .findings
+                .test_blocks
+                .iter()
+                .find(|test_block| {
+                    test_block.framework == framework && test_block.line == line as usize
+                })?;
 
-            Node::KwBegin(KwBegin { statements, .. }) => {
-                for node in statements {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+        Some((framework, test_block.name.clone()))
+    }
 
-            // Node::Kwnilarg(Kwnilarg { .. }) => {}
-            Node::Kwoptarg(Kwoptarg {
-                name,
-                default,
-                name_l,
-                ..
-            }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(name_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(name_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "assignment",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "Kwoptarg",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-
-                self.serialize(default, documents, fuzzy_scope, input);
-            }
+    // `spec` for files in a `spec` directory and `test` for ones in a `test`
+    // directory, including those of engines
+    fn test_framework(&self, uri: &Url) -> Option<&'static str> {
+        let file_path = uri_path(uri);
+        let relative_path = self.workspace_path.relative_path(&file_path);
+
+        Path::new(&relative_path)
+            .parent()?
+            .components()
+            .find_map(|component| match component.as_os_str().to_str() {
+                Some("spec") => Some("spec"),
+                Some("test") => Some("test"),
+                _ => None,
+            })
+    }
 
-            Node::Kwrestarg(Kwrestarg { name, name_l, .. }) => {
-                if let Some(node_name) = name {
-                    if let Some(loc) = name_l {
-                        let (lineno, begin_pos) = input.line_col_for_pos(loc.begin).unwrap();
-                        let (_lineno, end_pos) = input.line_col_for_pos(loc.end).unwrap();
-
-                        documents.push(FuzzyNode {
-                            category: "assignment",
-                            fuzzy_ruby_scope: fuzzy_scope.clone(),
-                            class_scope: vec![],
-                            name: node_name.to_string(),
-                            node_type: "Kwrestarg",
-                            line: lineno,
-                            start_column: begin_pos,
-                            end_column: end_pos,
-                        });
-                    }
-                }
-            }
+    // The file a `require`, `require_relative`, or `autoload` in `file_path`
+    // loads. `require_relative` is relative to the file, and the others look
+    // through the load path.
+    fn required_file_path(&self, file_path: &str, required_path: &RequiredPath) -> Option<PathBuf> {
+        if gem_dependency(&required_path.method_name) {
+            return self.gem_main_file(&required_path.path);
+        }
 
-            Node::Kwsplat(Kwsplat { value, .. }) => {
-                self.serialize(value, documents, fuzzy_scope, input);
-            }
+        if required_path.method_name == "require_relative" {
+            return Path::new(file_path)
+                .parent()
+                .and_then(|dir| required_file_path(dir, &required_path.path));
+        }
 
-            // Node::Lambda(Lambda { .. }) => {}
-            // Node::Line(Line { .. }) => {}
-            Node::Lvar(Lvar { name, expression_l }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(expression_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(expression_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "usage",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "Lvar",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-            }
+        let mut require_dirs = self.load_path();
 
-            Node::Lvasgn(Lvasgn {
-                name,
-                value,
-                name_l,
-                ..
-            }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(name_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(name_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "assignment",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "Lvasgn",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-
-                if let Some(child_node) = value {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
-                }
-            }
+        // Apps often require files from their root as well
+        if !self.no_workspace {
+            require_dirs.push(self.workspace_path.absolute_path(""));
+        }
 
-            Node::Masgn(Masgn { lhs, rhs, .. }) => {
-                self.serialize(lhs, documents, fuzzy_scope, input);
-                self.serialize(rhs, documents, fuzzy_scope, input);
-            }
+        require_dirs
+            .iter()
+            .find_map(|dir| required_file_path(Path::new(dir), &required_path.path))
+    }
 
-            Node::MatchAlt(MatchAlt { lhs, rhs, .. }) => {
-                self.serialize(lhs, documents, fuzzy_scope, input);
-                self.serialize(rhs, documents, fuzzy_scope, input);
-            }
+    // Monikers for the definition at a position, or the ones a usage there
+    // goes to, so indexes of other repositories can find the same symbols
+    pub fn monikers(&self, params: TextDocumentPositionParams) -> tantivy::Result<Vec<Moniker>> {
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(vec![]),
+        };
 
-            Node::MatchAs(MatchAs { value, as_, .. }) => {
-                self.serialize(value, documents, fuzzy_scope, input);
-                self.serialize(as_, documents, fuzzy_scope, input);
-            }
+        let searcher = reader.searcher();
+        let file_path_id = self
+            .workspace_path
+            .file_path_id(&uri_path(&params.text_document.uri));
 
-            Node::MatchCurrentLine(MatchCurrentLine { re, .. }) => {
-                self.serialize(re, documents, fuzzy_scope, input);
-            }
+        let query = BooleanQuery::new(vec![
+            (
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(self.schema_fields.file_path_id, &file_path_id),
+                    IndexRecordOption::Basic,
+                )) as Box<dyn Query>,
+            ),
+            (
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(self.schema_fields.category_field, "assignment"),
+                    IndexRecordOption::Basic,
+                )),
+            ),
+            (
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_u64(
+                        self.schema_fields.line_field,
+                        params.position.line.into(),
+                    ),
+                    IndexRecordOption::Basic,
+                )),
+            ),
+            (Occur::Must, self.column_query(params.position.character)),
+        ]);
 
-            // Node::MatchNilPattern(MatchNilPattern { .. }) => {}
-            Node::MatchPattern(MatchPattern { value, pattern, .. }) => {
-                self.serialize(value, documents, fuzzy_scope, input);
-                self.serialize(pattern, documents, fuzzy_scope, input);
-            }
+        let mut definition_docs = vec![];
+        for (_score, doc_address) in searcher.measured_search(&query, &TopDocs::with_limit(1))? {
+            definition_docs.push(searcher.measured_doc(doc_address)?);
+        }
 
-            Node::MatchPatternP(MatchPatternP { value, pattern, .. }) => {
-                self.serialize(value, documents, fuzzy_scope, input);
-                self.serialize(pattern, documents, fuzzy_scope, input);
-            }
+        if definition_docs.is_empty() {
+            for location in self.find_definitions(params)? {
+                let file_path_id = self.workspace_path.file_path_id(&uri_path(&location.uri));
 
-            Node::MatchRest(MatchRest { name, .. }) => {
-                if let Some(child_node) = name {
-                    self.serialize(child_node, documents, fuzzy_scope, input);
+                if let Some(definition_doc) = self.definition_doc_at(
+                    &searcher,
+                    &file_path_id,
+                    location.range.start.line.into(),
+                    location.range.start.character.into(),
+                )? {
+                    definition_docs.push(definition_doc);
                 }
             }
+        }
 
-            Node::MatchVar(MatchVar { name, name_l, .. }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(name_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(name_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "assignment",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "MatchVar",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-            }
-
-            Node::MatchWithLvasgn(MatchWithLvasgn { re, value, .. }) => {
-                self.serialize(re, documents, fuzzy_scope, input);
-                self.serialize(value, documents, fuzzy_scope, input);
-            }
-
-            Node::Mlhs(Mlhs { items, .. }) => {
-                for node in items {
-                    self.serialize(node, documents, fuzzy_scope, input);
+        let mut monikers = vec![];
+        for definition_doc in &definition_docs {
+            if let Some(moniker) = self.definition_moniker(definition_doc) {
+                if !monikers.contains(&moniker) {
+                    monikers.push(moniker);
                 }
             }
+        }
 
-            Node::Module(Module { name, body, .. }) => {
-                if let Node::Const(const_node) = *name.to_owned() {
-                    let node_class_scope = self.build_class_scope(&const_node);
-                    let class_scope_len = node_class_scope.len();
-
-                    for ancestor_name in node_class_scope {
-                        fuzzy_scope.push(ancestor_name);
-                    }
+        Ok(monikers)
+    }
 
-                    let (lineno, begin_pos) = input
-                        .line_col_for_pos(const_node.expression_l.begin)
-                        .unwrap();
-                    let (_lineno, end_pos) =
-                        input.line_col_for_pos(const_node.expression_l.end).unwrap();
-                    let class_name = const_node.name.to_string();
-
-                    documents.push(FuzzyNode {
-                        category: "assignment",
-                        fuzzy_ruby_scope: fuzzy_scope.clone(),
-                        // class_scope: node_class_scope,
-                        class_scope: vec![],
-                        name: class_name.clone(),
-                        node_type: "Module",
-                        line: lineno,
-                        start_column: begin_pos,
-                        end_column: end_pos,
-                    });
-
-                    fuzzy_scope.push(class_name.to_string());
-                    self.class_scope.push(class_name);
-
-                    for child_node in body {
-                        self.serialize(child_node, documents, fuzzy_scope, input);
-                    }
+    // `gem:<gem>:<name>` for a gem's classes, modules, constants, and methods,
+    // e.g. `gem:activerecord:ActiveRecord::Base#save`, `ruby:<name>` for the
+    // rest outside the workspace, and `workspace:<name>` for the workspace's
+    fn definition_moniker(&self, definition_doc: &Document) -> Option<Moniker> {
+        let name = self.definition_name(definition_doc);
+        let container = self.definition_container(definition_doc);
+        let node_type = definition_doc
+            .get_first(self.schema_fields.node_type_field)
+            .and_then(Value::as_text)?;
+
+        let separator = match node_type {
+            "Class" | "Module" | "Casgn" => "::",
+            "Def" | "Alias" => "#",
+            "Defs" => ".",
+            _ => return None,
+        };
+        let qualified_name = match (container.is_empty(), separator) {
+            (false, _) => format!("{}{}{}", container, separator, name),
+            // Top-level methods are private methods of `Object`
+            (true, "::") => name,
+            (true, _) => format!("Object{}{}", separator, name),
+        };
 
-                    for _ in 0..class_scope_len {
-                        fuzzy_scope.pop();
-                    }
+        let user_space = definition_doc
+            .get_first(self.schema_fields.user_space_field)
+            .and_then(Value::as_bool)
+            .unwrap_or(true);
+
+        if user_space {
+            return Some(Moniker {
+                scheme: "workspace".to_string(),
+                identifier: format!("workspace:{}", qualified_name),
+                unique: UniquenessLevel::Project,
+                kind: Some(MonikerKind::Export),
+            });
+        }
 
-                    fuzzy_scope.pop();
-                    self.class_scope.pop();
-                }
-            }
+        let file_path: String = definition_doc
+            .get_all(self.schema_fields.file_path)
+            .flat_map(Value::as_text)
+            .collect::<Vec<&str>>()
+            .join("/");
 
-            Node::Next(Next { args, .. }) => {
-                for node in args {
-                    self.serialize(node, documents, fuzzy_scope, input);
+        let (scheme, identifier) =
+            match gem_name_and_version(&workspace_path::absolute_path(&file_path)) {
+                Some((gem_name, _version)) => {
+                    ("gem", format!("gem:{}:{}", gem_name, qualified_name))
                 }
-            }
+                None => ("ruby", format!("ruby:{}", qualified_name)),
+            };
 
-            // Node::Nil(Nil { .. }) => {}
-            // Node::NthRef(NthRef { .. }) => {}
-            Node::Numblock(Numblock { call, body, .. }) => {
-                self.serialize(call, documents, fuzzy_scope, input);
-                self.serialize(body, documents, fuzzy_scope, input);
-            }
+        Some(Moniker {
+            scheme: scheme.to_string(),
+            identifier,
+            unique: UniquenessLevel::Scheme,
+            kind: Some(MonikerKind::Import),
+        })
+    }
 
-            Node::OpAsgn(OpAsgn { recv, value, .. }) => {
-                self.serialize(recv, documents, fuzzy_scope, input);
-                self.serialize(value, documents, fuzzy_scope, input);
-            }
+    // The text of an open file's line up to the cursor
+    fn line_prefix(&self, params: &TextDocumentPositionParams) -> Option<String> {
+        let parsed_document = self.open_document(&params.text_document.uri)?;
+        let line = parsed_document
+            .text
+            .lines()
+            .nth(params.position.line as usize)?;
+
+        Some(
+            line.chars()
+                .take(params.position.character as usize)
+                .collect(),
+        )
+    }
 
-            Node::Optarg(Optarg {
-                name,
-                default,
-                name_l,
-                ..
-            }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(name_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(name_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "assignment",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "Optarg",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-
-                self.serialize(default, documents, fuzzy_scope, input);
-            }
+    // Where `require` looks, in the order it's searched: the workspace's
+    // `lib`, its `includeDirs`, and then the gems and Ruby's standard library
+    fn load_path(&self) -> Vec<String> {
+        let mut dirs = vec![];
 
-            Node::Or(Or { lhs, rhs, .. }) => {
-                self.serialize(lhs, documents, fuzzy_scope, input);
-                self.serialize(rhs, documents, fuzzy_scope, input);
-            }
+        if !self.no_workspace {
+            dirs.push(self.workspace_path.absolute_path("lib"));
+        }
 
-            Node::OrAsgn(OrAsgn { recv, value, .. }) => {
-                self.serialize(recv, documents, fuzzy_scope, input);
-                self.serialize(value, documents, fuzzy_scope, input);
+        // Engines require their own files from their `lib`
+        for bundle_dir in &self.bundle_dirs {
+            if Path::new(bundle_dir) != self.workspace_path.as_path() {
+                dirs.push(format!("{}/lib", bundle_dir));
             }
+        }
 
-            Node::Pair(Pair { key, value, .. }) => {
-                self.serialize(key, documents, fuzzy_scope, input);
-                self.serialize(value, documents, fuzzy_scope, input);
-            }
+        let include_dir_paths = self
+            .settings
+            .include_dirs
+            .iter()
+            .map(|include_dir| &include_dir.path);
+        let gem_paths = self.gem_paths.iter().chain(self.unindexed_gems.values());
 
-            Node::Pin(Pin { var, .. }) => {
-                self.serialize(var, documents, fuzzy_scope, input);
-            }
+        // Gems and engines are required from their `lib`, while the standard
+        // library and other directories are required from directly
+        for dir in include_dir_paths.chain(gem_paths) {
+            let lib_dir = format!("{}/lib", dir);
 
-            Node::Postexe(Postexe { body, .. }) => {
-                for node in body {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
+            if Path::new(&lib_dir).is_dir() {
+                dirs.push(lib_dir);
+            } else {
+                dirs.push(dir.to_string());
             }
+        }
 
-            Node::Preexe(Preexe { body, .. }) => {
-                for node in body {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+        dirs
+    }
+}
 
-            Node::Procarg0(Procarg0 { args, .. }) => {
-                for node in args {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+// The classes and modules around a position in a parsed file, going by the
+// closest document before it. Right after a `class` or `module` line, that's
+// the class or module itself.
+fn lexical_scope_at(documents: &[FuzzyNode], position: Position) -> Vec<String> {
+    let cursor = (position.line as usize, position.character as usize);
+    let closest_document = documents
+        .iter()
+        .filter(|document| (document.line, document.start_column) <= cursor)
+        .max_by_key(|document| (document.line, document.start_column));
+
+    let closest_document = match closest_document {
+        Some(closest_document) => closest_document,
+        None => return vec![],
+    };
+
+    let mut lexical_scope: Vec<String> = closest_document
+        .fuzzy_ruby_scope
+        .iter()
+        .filter(|scope_name| scope_name.starts_with(char::is_uppercase))
+        .cloned()
+        .collect();
+
+    let opens_scope = closest_document.category == "assignment"
+        && matches!(closest_document.node_type, "Class" | "Module");
+
+    if opens_scope && closest_document.line < cursor.0 {
+        lexical_scope.push(closest_document.name.clone());
+    }
 
-            // Node::Rational(Rational { .. }) => {}
-            // Node::Redo(Redo { .. }) => {}
-            Node::Regexp(Regexp { parts, options, .. }) => {
-                for node in parts {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
+    lexical_scope
+}
 
-                for node in options {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+// Scores `candidate` as a case-insensitive subsequence match of `query`,
+// rewarding characters that start a word (`_`, `::`, or camelCase boundaries)
+// and consecutive runs while penalizing gaps. Returns `None` when `query`
+// isn't a subsequence.
+fn fuzzy_match_score(query: &str, candidate: &str) -> Option<i64> {
+    let candidate_chars: Vec<char> = candidate.chars().collect();
+    let mut candidate_index = 0;
+    let mut previous_match_index: Option<usize> = None;
+    let mut score: i64 = 0;
+
+    for query_char in query.chars() {
+        let match_index = candidate_chars[candidate_index..]
+            .iter()
+            .position(|candidate_char| chars_eq_ignore_case(*candidate_char, query_char))
+            .map(|offset| candidate_index + offset)?;
+
+        score += 1;
+
+        if candidate_chars[match_index] == query_char {
+            score += 1;
+        }
 
-            // Node::RegOpt(RegOpt { .. }) => {}
-            Node::Rescue(Rescue {
-                body,
-                rescue_bodies,
-                ..
-            }) => {
-                for node in body {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
+        if match_index == 0 {
+            score += 8;
+        } else {
+            let previous_char = candidate_chars[match_index - 1];
+            let word_start = previous_char == '_'
+                || previous_char == ':'
+                || (previous_char.is_lowercase() && candidate_chars[match_index].is_uppercase());
 
-                for node in rescue_bodies {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
+            if word_start {
+                score += 6;
             }
+        }
 
-            Node::RescueBody(RescueBody {
-                exc_list,
-                exc_var,
-                body,
-                ..
-            }) => {
-                for node in exc_list {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-
-                for node in exc_var {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
+        match previous_match_index {
+            Some(previous_index) if previous_index + 1 == match_index => score += 4,
+            Some(previous_index) => score -= (match_index - previous_index - 1).min(3) as i64,
+            None => {}
+        }
 
-                for node in body {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+        previous_match_index = Some(match_index);
+        candidate_index = match_index + 1;
+    }
 
-            Node::Restarg(Restarg { name, name_l, .. }) => {
-                if let Some(name_str) = name {
-                    if let Some(loc) = name_l {
-                        let (lineno, begin_pos) = input.line_col_for_pos(loc.begin).unwrap();
-                        let (_lineno, end_pos) = input.line_col_for_pos(loc.end).unwrap();
-
-                        documents.push(FuzzyNode {
-                            category: "assignment",
-                            fuzzy_ruby_scope: fuzzy_scope.clone(),
-                            class_scope: vec![],
-                            name: name_str.to_string(),
-                            node_type: "Restarg",
-                            line: lineno,
-                            start_column: begin_pos,
-                            end_column: end_pos,
-                        });
-                    }
-                }
-            }
+    // Prefer shorter names when matches are otherwise equal
+    score -= (candidate_chars.len() / 8) as i64;
 
-            // Node::Retry(Retry { .. }) => {}
-            Node::Return(Return { args, .. }) => {
-                for node in args {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+    Some(score)
+}
 
-            Node::SClass(SClass { expr, body, .. }) => {
-                self.serialize(expr, documents, fuzzy_scope, input);
+fn chars_eq_ignore_case(a: char, b: char) -> bool {
+    a == b || a.to_lowercase().eq(b.to_lowercase())
+}
 
-                for node in body {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+// e.g. `Admin::UsersController` for `admin/users`
+fn controller_class_name(controller_path: &str) -> String {
+    format!("{}Controller", camelize(controller_path))
+}
 
-            // Node::Self_(Self_ { .. }) => {}
-            Node::Send(Send {
-                recv,
-                method_name,
-                args,
-                selector_l,
-                ..
-            }) => {
-                let class_scope = if let Some(recv_node) = recv {
-                    self.serialize(recv_node, documents, fuzzy_scope, input);
-
-                    match recv_node.as_ref() {
-                        Node::Const(const_node) => {
-                            let mut full_class_scope = vec![const_node.name.to_string()];
-                            full_class_scope.append(self.build_class_scope(&const_node).as_mut());
-                            full_class_scope
-                        }
-                        _ => vec![],
+// e.g. `Admin::LineItem` for `admin/line_item`
+fn camelize(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            segment
+                .split('_')
+                .map(|word| {
+                    let mut chars = word.chars();
+                    match chars.next() {
+                        Some(first) => first.to_uppercase().chain(chars).collect(),
+                        None => String::new(),
                     }
-                } else {
-                    vec![]
-                };
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<String>>()
+        .join("::")
+}
 
-                if let Some(loc) = selector_l {
-                    let (lineno, begin_pos) = input.line_col_for_pos(loc.begin).unwrap();
-                    let (_lineno, end_pos) = input.line_col_for_pos(loc.end).unwrap();
-
-                    documents.push(FuzzyNode {
-                        category: "usage",
-                        fuzzy_ruby_scope: fuzzy_scope.clone(),
-                        class_scope: class_scope.clone(),
-                        name: method_name.to_string(),
-                        node_type: "Send",
-                        line: lineno,
-                        start_column: begin_pos,
-                        end_column: end_pos,
-                    });
-                }
+// The full key of a translation, where keys starting with `.` are relative to
+// the template they're in, e.g. `users.show.title` for `t(".title")` in
+// `app/views/users/show.html.erb` and `users.item.title` in `_item.html.erb`
+fn full_translation_key(relative_path: &str, key: &str) -> Option<String> {
+    if !key.starts_with('.') {
+        return Some(key.to_string());
+    }
 
-                for node in args {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
+    let view_path = relative_path
+        .trim_start_matches('/')
+        .strip_prefix("app/views/")?;
+    let (directory, file_name) = view_path.rsplit_once('/').unwrap_or(("", view_path));
+    let name = file_name.split('.').next()?.trim_start_matches('_');
 
-                match method_name.as_str() {
-                    // Ruby
-                    "attr_accessor" => {
-                        for node in args {
-                            match node {
-                                Node::Sym(Sym {
-                                    name, expression_l, ..
-                                }) => {
-                                    let (lineno, begin_pos) =
-                                        input.line_col_for_pos(expression_l.begin).unwrap();
-                                    let (_lineno, end_pos) =
-                                        input.line_col_for_pos(expression_l.end).unwrap();
-
-                                    documents.push(FuzzyNode {
-                                        category: "assignment",
-                                        fuzzy_ruby_scope: fuzzy_scope.clone(),
-                                        class_scope: class_scope.clone(),
-                                        name: name.to_string_lossy(),
-                                        node_type: "Def",
-                                        line: lineno,
-                                        start_column: begin_pos,
-                                        end_column: end_pos,
-                                    });
-
-                                    documents.push(FuzzyNode {
-                                        category: "assignment",
-                                        fuzzy_ruby_scope: fuzzy_scope.clone(),
-                                        class_scope: class_scope.clone(),
-                                        name: format!("{}=", name.to_string_lossy()),
-                                        node_type: "Def",
-                                        line: lineno,
-                                        start_column: begin_pos,
-                                        end_column: end_pos,
-                                    });
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                    "attr_writer" => {
-                        for node in args {
-                            match node {
-                                Node::Sym(Sym {
-                                    name, expression_l, ..
-                                }) => {
-                                    let (lineno, begin_pos) =
-                                        input.line_col_for_pos(expression_l.begin).unwrap();
-                                    let (_lineno, end_pos) =
-                                        input.line_col_for_pos(expression_l.end).unwrap();
-
-                                    documents.push(FuzzyNode {
-                                        category: "assignment",
-                                        fuzzy_ruby_scope: fuzzy_scope.clone(),
-                                        class_scope: class_scope.clone(),
-                                        name: format!("{}=", name.to_string_lossy()),
-                                        node_type: "Def",
-                                        line: lineno,
-                                        start_column: begin_pos,
-                                        end_column: end_pos,
-                                    });
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                    "attr_reader" => {
-                        for node in args {
-                            match node {
-                                Node::Sym(Sym {
-                                    name, expression_l, ..
-                                }) => {
-                                    let (lineno, begin_pos) =
-                                        input.line_col_for_pos(expression_l.begin).unwrap();
-                                    let (_lineno, end_pos) =
-                                        input.line_col_for_pos(expression_l.end).unwrap();
-
-                                    documents.push(FuzzyNode {
-                                        category: "assignment",
-                                        fuzzy_ruby_scope: fuzzy_scope.clone(),
-                                        class_scope: class_scope.clone(),
-                                        name: name.to_string_lossy(),
-                                        node_type: "Def",
-                                        line: lineno,
-                                        start_column: begin_pos,
-                                        end_column: end_pos,
-                                    });
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                    "alias_method" => {
-                        if let Some(node) = args.first() {
-                            match node {
-                                Node::Sym(Sym {
-                                    name, expression_l, ..
-                                }) => {
-                                    let (lineno, begin_pos) =
-                                        input.line_col_for_pos(expression_l.begin).unwrap();
-                                    let (_lineno, end_pos) =
-                                        input.line_col_for_pos(expression_l.end).unwrap();
-
-                                    documents.push(FuzzyNode {
-                                        category: "assignment",
-                                        fuzzy_ruby_scope: fuzzy_scope.clone(),
-                                        class_scope: class_scope.clone(),
-                                        name: name.to_string_lossy(),
-                                        node_type: "Def",
-                                        line: lineno,
-                                        start_column: begin_pos,
-                                        end_column: end_pos,
-                                    });
-                                }
-                                Node::Str(Str {
-                                    value,
-                                    expression_l,
-                                    ..
-                                }) => {
-                                    let (lineno, begin_pos) =
-                                        input.line_col_for_pos(expression_l.begin).unwrap();
-                                    let (_lineno, end_pos) =
-                                        input.line_col_for_pos(expression_l.end).unwrap();
-
-                                    documents.push(FuzzyNode {
-                                        category: "assignment",
-                                        fuzzy_ruby_scope: fuzzy_scope.clone(),
-                                        class_scope: class_scope.clone(),
-                                        name: value.to_string_lossy(),
-                                        node_type: "Def",
-                                        line: lineno,
-                                        start_column: begin_pos,
-                                        end_column: end_pos,
-                                    });
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
+    let scope: Vec<&str> = directory
+        .split('/')
+        .chain([name])
+        .filter(|part| !part.is_empty())
+        .collect();
 
-                    // Rails
-                    "belongs_to" | "has_one" | "has_many" | "has_and_belongs_to_many" => {
-                        if let Some(node) = args.first() {
-                            match node {
-                                Node::Sym(Sym {
-                                    name, expression_l, ..
-                                }) => {
-                                    let (lineno, begin_pos) =
-                                        input.line_col_for_pos(expression_l.begin).unwrap();
-                                    let (_lineno, end_pos) =
-                                        input.line_col_for_pos(expression_l.end).unwrap();
-
-                                    documents.push(FuzzyNode {
-                                        category: "assignment",
-                                        fuzzy_ruby_scope: fuzzy_scope.clone(),
-                                        class_scope: class_scope.clone(),
-                                        name: name.to_string_lossy(),
-                                        node_type: "Def",
-                                        line: lineno,
-                                        start_column: begin_pos,
-                                        end_column: end_pos,
-                                    });
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                    _ => {} // todo: the code below works, but it will pollute searches too
-                            // much unless filtering is added when searching
-
-                            // Rspec
-                            // "let!" | "let" => {
-                            //     if let Some(arg) = args.first() {
-                            //         match node {
-                            //             Node::Sym(Sym { name, expression_l, .. }) => {
-                            //                 let (lineno, begin_pos) = input.line_col_for_pos(expression_l.begin).unwrap();
-                            //                 let (_lineno, end_pos) = input.line_col_for_pos(expression_l.end).unwrap();
-
-                            //                 documents.push(FuzzyNode {
-                            //                     category: "assignment",
-                            //                     fuzzy_ruby_scope: fuzzy_scope.clone(),
-                            // class_scope: vec![],
-                            //                     name: name.to_string_lossy(),
-                            //                     node_type: "Def",
-                            //                     line: lineno,
-                            //                     start_column: begin_pos,
-                            //                     end_column: end_pos,
-                            //                 });
-                            //             },
-                            //             _ => {}
-                            //         }
-                            //     }
-                            // },
-                            // _ => {}
-                }
-            }
+    Some(format!("{}{}", scope.join("."), key))
+}
 
-            Node::Shadowarg(Shadowarg { name, expression_l }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(expression_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(expression_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "assignment",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string(),
-                    node_type: "Shadowarg",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-            }
+// The directory under `app/views` of a template, or of the views of a
+// controller, e.g. `admin/users` for `app/controllers/admin/users_controller.rb`
+fn views_directory(relative_path: &str) -> Option<String> {
+    let relative_path = relative_path.trim_start_matches('/');
 
-            Node::Splat(Splat { value, .. }) => {
-                for node in value {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+    if let Some(view_path) = relative_path.strip_prefix("app/views/") {
+        return view_path
+            .rsplit_once('/')
+            .map(|(directory, _file_name)| directory.to_string());
+    }
 
-            // Node::Str(Str { .. }) => {}
-            Node::Super(Super {
-                args, keyword_l, ..
-            }) => {
-                if let Some(last_scope_name) = fuzzy_scope.last() {
-                    let (lineno, begin_pos) = input.line_col_for_pos(keyword_l.begin).unwrap();
-                    let (_lineno, end_pos) = input.line_col_for_pos(keyword_l.end).unwrap();
-
-                    documents.push(FuzzyNode {
-                        category: "usage",
-                        fuzzy_ruby_scope: fuzzy_scope.clone(),
-                        class_scope: vec![],
-                        name: last_scope_name.to_string(),
-                        node_type: "Super",
-                        line: lineno,
-                        start_column: begin_pos,
-                        end_column: end_pos,
-                    });
-                }
+    relative_path
+        .strip_prefix("app/controllers/")?
+        .strip_suffix("_controller.rb")
+        .map(str::to_string)
+}
 
-                for node in args {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+// ERB templates like `app/views/users/show.html.erb`
+fn template(path: &str) -> bool {
+    path.ends_with(".erb")
+}
 
-            Node::Sym(Sym {
-                name, expression_l, ..
-            }) => {
-                let (lineno, begin_pos) = input.line_col_for_pos(expression_l.begin).unwrap();
-                let (_lineno, end_pos) = input.line_col_for_pos(expression_l.end).unwrap();
-
-                documents.push(FuzzyNode {
-                    category: "usage",
-                    fuzzy_ruby_scope: fuzzy_scope.clone(),
-                    class_scope: vec![],
-                    name: name.to_string_lossy(),
-                    node_type: "Send",
-                    line: lineno,
-                    start_column: begin_pos,
-                    end_column: end_pos,
-                });
-            }
+// Whether a `RequiredPath` names a gem, like `gem "sidekiq"` in a `Gemfile`
+// or `spec.add_dependency "sidekiq"` in a gemspec
+fn gem_dependency(method_name: &str) -> bool {
+    matches!(
+        method_name,
+        "gem" | "add_dependency" | "add_runtime_dependency" | "add_development_dependency"
+    )
+}
 
-            // Node::True(True { .. }) => {}
-            Node::Undef(Undef { names, .. }) => {
-                for node in names {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+// The Ruby in an ERB template at the same lines and columns. Text outside of
+// tags and comments are blanked out, and each tag ends with `;` so tags on
+// the same line stay separate statements.
+fn erb_ruby_source(template: &str) -> String {
+    let blank = |char| if char == '\n' { '\n' } else { ' ' };
+    let mut source = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(tag_start) = rest.find("<%") {
+        source.extend(rest[..tag_start].chars().map(blank));
+
+        let tag_end = rest[tag_start..]
+            .find("%>")
+            .map(|tag_end| tag_start + tag_end + 2)
+            .unwrap_or(rest.len());
+        let tag = &rest[tag_start..tag_end];
+        rest = &rest[tag_end..];
+
+        let opening = ["<%==", "<%=", "<%-", "<%"]
+            .iter()
+            .find(|opening| tag.starts_with(*opening))
+            .map_or(2, |opening| opening.len());
+        let closing = ["-%>", "%>"]
+            .iter()
+            .find(|closing| tag.ends_with(*closing))
+            .map_or(0, |closing| closing.len());
+        let code = tag.get(opening..tag.len() - closing).unwrap_or_default();
+
+        // `<%%` is a literal `<%`
+        if tag.starts_with("<%%") || code.trim_start().starts_with('#') {
+            source.extend(tag.chars().map(blank));
+            continue;
+        }
 
-            Node::UnlessGuard(UnlessGuard { cond, .. }) => {
-                self.serialize(cond, documents, fuzzy_scope, input);
-            }
+        source.extend(tag[..opening].chars().map(blank));
+        source.push_str(code);
 
-            Node::Until(Until { cond, body, .. }) => {
-                self.serialize(cond, documents, fuzzy_scope, input);
+        if closing > 0 {
+            source.push(';');
+            source.extend(tag[tag.len() - closing + 1..].chars().map(blank));
+        }
+    }
 
-                for node in body {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+    source.extend(rest.chars().map(blank));
+    source
+}
 
-            Node::UntilPost(UntilPost { cond, body, .. }) => {
-                self.serialize(cond, documents, fuzzy_scope, input);
-                self.serialize(body, documents, fuzzy_scope, input);
-            }
+// Method names that can have a setter, e.g. `name` but not `valid?` or `<=>`
+fn identifier_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
 
-            Node::When(When { patterns, body, .. }) => {
-                for node in patterns {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
+// `name` for setters like `name=`, but not operators like `==` or `[]=`
+fn setter_reader_name(name: &str) -> Option<&str> {
+    name.strip_suffix('=')
+        .filter(|reader_name| identifier_name(reader_name))
+}
 
-                for node in body {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+// Matches the terms within a Levenshtein distance of a name, for searching a
+// term dictionary with
+struct LevenshteinDfa(DFA);
 
-            Node::While(While { cond, body, .. }) => {
-                self.serialize(cond, documents, fuzzy_scope, input);
+impl Automaton for LevenshteinDfa {
+    type State = u32;
 
-                for node in body {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+    fn start(&self) -> u32 {
+        self.0.initial_state()
+    }
 
-            Node::WhilePost(WhilePost { cond, body, .. }) => {
-                self.serialize(cond, documents, fuzzy_scope, input);
-                self.serialize(body, documents, fuzzy_scope, input);
-            }
+    fn is_match(&self, state: &u32) -> bool {
+        matches!(self.0.distance(*state), Distance::Exact(_))
+    }
 
-            Node::XHeredoc(XHeredoc { parts, .. }) => {
-                for node in parts {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+    fn can_match(&self, state: &u32) -> bool {
+        *state != SINK_STATE
+    }
 
-            Node::Xstr(Xstr { parts, .. }) => {
-                for node in parts {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+    fn accept(&self, state: &u32, byte: u8) -> u32 {
+        self.0.transition(*state, byte)
+    }
+}
 
-            Node::Yield(Yield { args, .. }) => {
-                for node in args {
-                    self.serialize(node, documents, fuzzy_scope, input);
-                }
-            }
+// The file a required path loads from a directory, adding the `.rb` that's
+// usually left off
+fn required_file_path(dir: &Path, required_path: &str) -> Option<PathBuf> {
+    let file_path = if required_path.ends_with(".rb") {
+        dir.join(required_path)
+    } else {
+        dir.join(format!("{}.rb", required_path))
+    };
+
+    if file_path.is_file() {
+        fs::canonicalize(file_path).ok()
+    } else {
+        None
+    }
+}
 
-            Node::ZSuper(ZSuper { expression_l, .. }) => {
-                if let Some(last_scope_name) = fuzzy_scope.last() {
-                    let (lineno, begin_pos) = input.line_col_for_pos(expression_l.begin).unwrap();
-                    let (_lineno, end_pos) = input.line_col_for_pos(expression_l.end).unwrap();
-
-                    documents.push(FuzzyNode {
-                        category: "usage",
-                        fuzzy_ruby_scope: fuzzy_scope.clone(),
-                        class_scope: vec![],
-                        name: last_scope_name.to_string(),
-                        node_type: "ZSuper",
-                        line: lineno,
-                        start_column: begin_pos,
-                        end_column: end_pos,
-                    });
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            _ => {}
-        };
+    #[test]
+    fn fuzzy_match_score_needs_a_subsequence() {
+        assert!(fuzzy_match_score("usr", "user").is_some());
+        assert!(fuzzy_match_score("urs", "user").is_none());
+        assert!(fuzzy_match_score("users", "user").is_none());
     }
 
-    fn build_class_scope(&self, const_node: &Const) -> Vec<String> {
-        let mut node_class_scope = vec![];
-        let mut current_node = &const_node.scope;
+    #[test]
+    fn fuzzy_match_score_prefers_consecutive_matches() {
+        assert!(
+            fuzzy_match_score("user", "username").unwrap()
+                > fuzzy_match_score("user", "unsaved_error").unwrap()
+        );
+    }
 
-        loop {
-            match current_node {
-                Some(node) => {
-                    match node.as_ref() {
-                        Node::Const(Const { name, scope, .. }) => {
-                            node_class_scope.push(name.to_string());
-                            current_node = scope;
-                        }
-                        Node::Cbase(Cbase { .. }) => {
-                            // let mut root_prefixed_scope = vec!["^^^".to_string()];
-                            // root_prefixed_scope.append(&mut node_class_scope);
+    #[test]
+    fn fuzzy_match_score_ignores_case_but_prefers_matching_it() {
+        assert!(fuzzy_match_score("user", "User").is_some());
+        assert!(
+            fuzzy_match_score("User", "User").unwrap() > fuzzy_match_score("User", "user").unwrap()
+        );
+    }
 
-                            // node_class_scope = root_prefixed_scope;
-                            break;
-                        }
-                        Node::Send(Send { .. }) => break,
-                        Node::Self_(Self_ { expression_l: _ }) => break,
-                        _ => {
-                            info!("unknown node in build_class_scope");
-                            info!("{:#?}", node);
-                            break;
-                        }
-                    }
-                }
-                None => {
-                    // node_class_scope.should = self.class_scope.clone();
-                    break;
-                }
-            }
-        }
+    #[test]
+    fn fuzzy_match_score_prefers_word_starts() {
+        let score = |candidate: &str| fuzzy_match_score("fb", candidate).unwrap();
 
-        node_class_scope
+        assert!(score("foo_bar") > score("fabric"));
+        assert!(score("fooBar") > score("fabric"));
+        assert!(score("Foo::Bar") > score("fabric"));
+    }
+
+    #[test]
+    fn fuzzy_match_score_prefers_shorter_names() {
+        assert!(
+            fuzzy_match_score("save", "save").unwrap()
+                > fuzzy_match_score("save", "save_and_notify_followers").unwrap()
+        );
     }
 }
@@ -0,0 +1,329 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use tantivy::collector::{Count, TopDocs};
+use tantivy::schema::*;
+use tower_lsp::lsp_types::{
+    CodeAction, CodeActionKind, CodeActionParams, Position, Range, TextEdit, Url, WorkspaceEdit,
+};
+
+use super::indexer::RequiredPath;
+use super::workspace::gem_name_and_version;
+use super::{identifier_name, setter_reader_name, Persistence};
+use crate::telemetry::MeasuredSearcher;
+use crate::workspace_path::uri_path;
+
+// A line in the block of requires at the top of a file, with an optional
+// trailing comment
+static REQUIRE_LINE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"^\s*(require|require_relative)\s*\(?\s*(["'])[^"']*["']\s*\)?\s*(#.*)?$"#)
+        .unwrap()
+});
+
+impl Persistence {
+    // Code actions for the cursor: generating a missing method, and sorting
+    // the file's requires
+    pub fn code_actions(&self, params: &CodeActionParams) -> tantivy::Result<Vec<CodeAction>> {
+        let mut code_actions = vec![];
+
+        // Clients can ask for only some kinds, like `source.organizeImports`
+        // when saving
+        let requested = |kind: &CodeActionKind| match &params.context.only {
+            Some(only) => only
+                .iter()
+                .any(|only| kind.as_str().starts_with(only.as_str())),
+            None => true,
+        };
+
+        if requested(&CodeActionKind::QUICKFIX) {
+            code_actions.extend(self.method_stub_action(params)?);
+        }
+
+        if requested(&CodeActionKind::SOURCE_ORGANIZE_IMPORTS) {
+            code_actions.extend(self.sort_requires_action(&params.text_document.uri));
+        }
+
+        Ok(code_actions)
+    }
+
+    // A method the receiver's class and its ancestors don't define can be
+    // generated as an empty stub at the bottom of the class
+    fn method_stub_action(&self, params: &CodeActionParams) -> tantivy::Result<Option<CodeAction>> {
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
+
+        let parsed_document = match self.open_document(&params.text_document.uri) {
+            Some(parsed_document) => parsed_document,
+            None => return Ok(None),
+        };
+
+        if parsed_document.findings.defines_method_missing {
+            return Ok(None);
+        }
+
+        let position = params.range.start;
+        let method_call = parsed_document
+            .findings
+            .method_calls
+            .iter()
+            .find(|method_call| {
+                method_call.line == position.line as usize
+                    && method_call.start_column <= position.character as usize
+                    && position.character as usize <= method_call.end_column
+            });
+        let method_call = match method_call {
+            Some(method_call) => method_call,
+            None => return Ok(None),
+        };
+
+        // Operators and the like aren't worth stubbing
+        let name = &method_call.name;
+        let reader_name = setter_reader_name(name).unwrap_or(name);
+        let reader_name = reader_name.strip_suffix(['?', '!']).unwrap_or(reader_name);
+
+        if !identifier_name(reader_name) {
+            return Ok(None);
+        }
+
+        let receiver_class = &method_call.receiver_class;
+        let assignment_types = &["Alias", "Def", "Defs"];
+
+        let defined_in_file = parsed_document.documents.iter().any(|document| {
+            document.category == "assignment"
+                && document.name == *name
+                && assignment_types.contains(&document.node_type)
+                && document.fuzzy_ruby_scope.contains(receiver_class)
+        });
+
+        if defined_in_file {
+            return Ok(None);
+        }
+
+        let searcher = reader.searcher();
+        let method_query = self.definition_query(name, assignment_types, Some(receiver_class));
+
+        if searcher.measured_search(&method_query, &Count)? > 0
+            || !self
+                .find_ancestor_definitions(&searcher, receiver_class, name, assignment_types)?
+                .is_empty()
+        {
+            return Ok(None);
+        }
+
+        // The class's file in the workspace, preferring the current file
+        let class_query = self.definition_query(receiver_class, &["Class", "Module"], None);
+        let mut class_locations = vec![];
+
+        for (_score, doc_address) in
+            searcher.measured_search(&class_query, &TopDocs::with_limit(50))?
+        {
+            let class_doc = searcher.measured_doc(doc_address)?;
+            let user_space = class_doc
+                .get_first(self.schema_fields.user_space_field)
+                .and_then(Value::as_bool)
+                .unwrap_or(false);
+
+            if user_space {
+                class_locations.push(self.definition_location(&class_doc));
+            }
+        }
+
+        class_locations.sort_by_key(|location| location.uri != params.text_document.uri);
+
+        let class_location = match class_locations.first() {
+            Some(class_location) => class_location,
+            None => return Ok(None),
+        };
+
+        let text = match self.open_document(&class_location.uri) {
+            Some(parsed_document) => parsed_document.text.clone(),
+            None => match class_location.uri.to_file_path() {
+                Ok(file_path) => fs::read_to_string(file_path).unwrap_or_default(),
+                Err(_) => return Ok(None),
+            },
+        };
+
+        // The `end` lined up with the class, which one-line classes don't have
+        let lines: Vec<&str> = text.lines().collect();
+        let class_line = class_location.range.start.line as usize;
+        let class_text = match lines.get(class_line) {
+            Some(class_text) => class_text,
+            None => return Ok(None),
+        };
+        let indentation = &class_text[..class_text.len() - class_text.trim_start().len()];
+        let end_line = lines
+            .iter()
+            .enumerate()
+            .skip(class_line + 1)
+            .find(|(_, line)| {
+                line.strip_prefix(indentation)
+                    .is_some_and(|line| line == "end" || line.starts_with("end "))
+            })
+            .map(|(end_line, _)| end_line);
+        let end_line = match end_line {
+            Some(end_line) => end_line,
+            None => return Ok(None),
+        };
+
+        let receiver = if method_call.class_method {
+            "self."
+        } else {
+            ""
+        };
+        let mut stub = format!(
+            "{indentation}  def {}{}{}\n{indentation}  end\n",
+            receiver, name, method_call.parameters
+        );
+
+        // Separated from whatever the class ends with
+        if end_line > class_line + 1 && !lines[end_line - 1].trim().is_empty() {
+            stub.insert(0, '\n');
+        }
+
+        let insert_position = Position::new(end_line as u32, 0);
+        let mut changes = HashMap::new();
+        changes.insert(
+            class_location.uri.clone(),
+            vec![TextEdit::new(
+                Range::new(insert_position, insert_position),
+                stub,
+            )],
+        );
+
+        let separator = if method_call.class_method { "." } else { "#" };
+
+        Ok(Some(CodeAction {
+            title: format!("Generate method `{}{}{}`", receiver_class, separator, name),
+            kind: Some(CodeActionKind::QUICKFIX),
+            edit: Some(WorkspaceEdit::new(changes)),
+            ..CodeAction::default()
+        }))
+    }
+
+    // Sorts the block of `require` and `require_relative` lines at the top of
+    // a file and removes duplicates, grouped into the standard library, gems,
+    // then the workspace's own files
+    fn sort_requires_action(&self, uri: &Url) -> Option<CodeAction> {
+        let parsed_document = self.open_document(uri)?;
+        let lines: Vec<&str> = parsed_document.text.lines().collect();
+
+        let required_paths: HashMap<usize, &RequiredPath> = parsed_document
+            .findings
+            .required_paths
+            .iter()
+            .filter(|required_path| {
+                matches!(
+                    required_path.method_name.as_str(),
+                    "require" | "require_relative"
+                )
+            })
+            .map(|required_path| (required_path.line, required_path))
+            .collect();
+
+        // Only comments and blank lines can come before the block
+        let first_line = lines.iter().position(|line| {
+            let line = line.trim();
+            !line.is_empty() && !line.starts_with('#')
+        })?;
+
+        let mut last_line = first_line;
+        let mut requires = vec![];
+
+        for (lineno, line) in lines.iter().enumerate().skip(first_line) {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let required_path = match required_paths.get(&lineno) {
+                Some(required_path) if REQUIRE_LINE.is_match(line) => required_path,
+                _ => break,
+            };
+
+            last_line = lineno;
+            requires.push((*required_path, line.trim()));
+        }
+
+        if requires.is_empty() {
+            return None;
+        }
+
+        let file_path = uri_path(uri);
+        let workspace_dir = self.workspace_path.absolute_path("");
+        let mut groups: [Vec<(&str, &str, &str)>; 3] = [vec![], vec![], vec![]];
+
+        for (required_path, line) in requires {
+            let group = if required_path.method_name == "require_relative" {
+                2
+            } else {
+                match self.required_file_path(&file_path, required_path) {
+                    Some(path) if gem_name_and_version(&path.to_string_lossy()).is_some() => 1,
+                    Some(path)
+                        if !self.no_workspace && path.starts_with(&workspace_dir)
+                            || self
+                                .settings
+                                .include_dirs
+                                .iter()
+                                .any(|include_dir| path.starts_with(&include_dir.path)) =>
+                    {
+                        2
+                    }
+                    // Including requires that can't be found, like gems
+                    // before they're indexed
+                    _ => 0,
+                }
+            };
+
+            let require = (
+                required_path.method_name.as_str(),
+                required_path.path.as_str(),
+                line,
+            );
+
+            if !groups[group]
+                .iter()
+                .any(|(method_name, path, _)| (*method_name, *path) == (require.0, require.1))
+            {
+                groups[group].push(require);
+            }
+        }
+
+        let sorted_requires = groups
+            .iter_mut()
+            .filter(|group| !group.is_empty())
+            .map(|group| {
+                group.sort_by_key(|(method_name, path, _)| (*method_name, *path));
+                group
+                    .iter()
+                    .map(|(_, _, line)| format!("{}\n", line))
+                    .collect::<String>()
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let range = Range::new(
+            Position::new(first_line as u32, 0),
+            Position::new(last_line as u32 + 1, 0),
+        );
+        let current_requires: String = lines[first_line..=last_line]
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect();
+
+        if sorted_requires == current_requires {
+            return None;
+        }
+
+        let mut changes = HashMap::new();
+        changes.insert(uri.clone(), vec![TextEdit::new(range, sorted_requires)]);
+
+        Some(CodeAction {
+            title: "Sort requires".to_string(),
+            kind: Some(CodeActionKind::SOURCE_ORGANIZE_IMPORTS),
+            edit: Some(WorkspaceEdit::new(changes)),
+            ..CodeAction::default()
+        })
+    }
+}
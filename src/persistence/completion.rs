@@ -0,0 +1,689 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::str;
+use tantivy::collector::TopDocs;
+use tantivy::query::{BooleanQuery, Occur, Query, TermQuery};
+use tantivy::{schema::*, Document};
+use tower_lsp::lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionTextEdit, Documentation, InsertTextFormat,
+    MarkupContent, MarkupKind, Position, Range, TextDocumentPositionParams, TextEdit,
+};
+
+use super::{fuzzy_match_score, lexical_scope_at, Persistence};
+use crate::telemetry::MeasuredSearcher;
+use crate::workspace_path::uri_path;
+
+// Label, description, and LSP snippet of the constructs completed as snippets
+static SNIPPETS: &[(&str, &str, &str)] = &[
+    ("def", "def … end", "def ${1:method_name}\n  $0\nend"),
+    (
+        "defs",
+        "def self.… end",
+        "def self.${1:method_name}\n  $0\nend",
+    ),
+    ("class", "class … end", "class ${1:ClassName}\n  $0\nend"),
+    (
+        "module",
+        "module … end",
+        "module ${1:ModuleName}\n  $0\nend",
+    ),
+    ("do", "do |…| … end", "do |${1:args}|\n  $0\nend"),
+    ("%w", "%w[…]", "%w[${1}]$0"),
+];
+
+// The keyword or `%w` being typed, for snippets
+static TYPED_KEYWORD: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?:^|[^\w.@$:%])([a-z]\w*|%w?)$").unwrap());
+
+// The path being typed in a `require` or `require_relative` string
+static TYPED_REQUIRE_PATH: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?:^|[^\w.])(require|require_relative)\s*\(?\s*["']([^"'#]*)$"#).unwrap()
+});
+
+// The factory name being typed in a FactoryBot call, after any list count
+// and traits
+static TYPED_FACTORY: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?:^|[^\w.])(?:FactoryBot\.)?(?:create|build|build_stubbed|attributes_for|create_list|build_list|create_pair|build_pair)(?:\s*\(\s*|\s+)((?::\w+,\s*)?(?:\d+,\s*)?(?::\w+,\s*)*):(\w*)$",
+    )
+    .unwrap()
+});
+
+// e.g. `Us`, `Admin::Us`, `::Us`, or `Admin::`
+static TYPED_CONSTANT_PATH: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?:^|[^\w.:])(::)?((?:[A-Z]\w*::)*)([A-Z]\w*)?$").unwrap());
+
+// The instance, class, or global variable being typed
+static TYPED_VARIABLE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?:^|[^\w@$])((?:@@?|\$)\w*)$").unwrap());
+
+impl Persistence {
+    // Completions for whatever is being typed at the cursor: a `require`
+    // path, a variable, a constant, or a keyword when the client supports
+    // snippets
+    pub fn completions(
+        &self,
+        params: &TextDocumentPositionParams,
+        snippet_support: bool,
+    ) -> tantivy::Result<Vec<CompletionItem>> {
+        if let Some(completions) = self.require_completions(params) {
+            return Ok(completions);
+        }
+
+        if let Some(completions) = self.factory_completions(params)? {
+            return Ok(completions);
+        }
+
+        if let Some(completions) = self.variable_completions(params)? {
+            return Ok(completions);
+        }
+
+        if snippet_support {
+            if let Some(completions) = self.snippet_completions(params) {
+                return Ok(completions);
+            }
+        }
+
+        self.constant_completions(params)
+    }
+
+    // Snippets for `def`, `class`, and the other constructs starting with the
+    // word being typed. `None` when a lowercase word isn't being typed.
+    fn snippet_completions(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> Option<Vec<CompletionItem>> {
+        let line_prefix = self.line_prefix(params)?;
+
+        let captures = TYPED_KEYWORD.captures(&line_prefix)?;
+        let typed_word = &captures[1];
+
+        let range = Range::new(
+            Position::new(
+                params.position.line,
+                params.position.character - typed_word.chars().count() as u32,
+            ),
+            params.position,
+        );
+
+        let completions = SNIPPETS
+            .iter()
+            .filter(|(label, _detail, _snippet)| label.starts_with(typed_word))
+            .map(|(label, detail, snippet)| CompletionItem {
+                label: label.to_string(),
+                kind: Some(CompletionItemKind::SNIPPET),
+                detail: Some(detail.to_string()),
+                insert_text_format: Some(InsertTextFormat::SNIPPET),
+                text_edit: Some(CompletionTextEdit::Edit(TextEdit::new(
+                    range,
+                    snippet.to_string(),
+                ))),
+                ..CompletionItem::default()
+            })
+            .collect();
+
+        Some(completions)
+    }
+
+    // Completes the path in a `require` or `require_relative` string up to
+    // the cursor with the directories and Ruby files it could continue with.
+    // `None` when the cursor isn't in one.
+    fn require_completions(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> Option<Vec<CompletionItem>> {
+        let position = params.position;
+        let line_prefix = self.line_prefix(params)?;
+
+        let captures = TYPED_REQUIRE_PATH.captures(&line_prefix)?;
+
+        // The part after the last `/` is completed, and whatever comes before
+        // it picks the directory to list
+        let typed_path = &captures[2];
+        let (typed_dir, typed_name) = match typed_path.rfind('/') {
+            Some(index) => (&typed_path[..=index], &typed_path[index + 1..]),
+            None => ("", typed_path),
+        };
+
+        let search_dirs = if &captures[1] == "require_relative" {
+            let file_path = uri_path(&params.text_document.uri);
+
+            match Path::new(&file_path).parent() {
+                Some(dir) => vec![dir.to_string_lossy().to_string()],
+                None => return Some(vec![]),
+            }
+        } else {
+            self.load_path()
+        };
+
+        let typed_name_length = typed_name.chars().count() as u32;
+        let range = Range::new(
+            Position::new(position.line, position.character - typed_name_length),
+            position,
+        );
+
+        let mut labels = HashSet::new();
+        let mut completions = vec![];
+
+        for search_dir in search_dirs {
+            let entries = match fs::read_dir(Path::new(&search_dir).join(typed_dir)) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+
+            for entry in entries.flatten() {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+
+                if file_name.starts_with('.') || !file_name.starts_with(typed_name) {
+                    continue;
+                }
+
+                let (label, kind) = if entry.path().is_dir() {
+                    (format!("{}/", file_name), CompletionItemKind::FOLDER)
+                } else if let Some(name) = file_name.strip_suffix(".rb") {
+                    (name.to_string(), CompletionItemKind::FILE)
+                } else {
+                    continue;
+                };
+
+                if !labels.insert(label.clone()) {
+                    continue;
+                }
+
+                completions.push(CompletionItem {
+                    label: label.clone(),
+                    kind: Some(kind),
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit::new(range, label))),
+                    ..CompletionItem::default()
+                });
+            }
+        }
+
+        completions.sort_by(|a, b| a.label.cmp(&b.label));
+        Some(completions)
+    }
+
+    // Completes the factory name in `create(:` and the like, and trait names
+    // after it, e.g. `create(:user, :ad`. `None` when the cursor isn't in a
+    // symbol passed to one of them.
+    fn factory_completions(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> tantivy::Result<Option<Vec<CompletionItem>>> {
+        let (Some(line_prefix), Some(reader)) = (self.line_prefix(params), &self.reader) else {
+            return Ok(None);
+        };
+
+        let Some(captures) = TYPED_FACTORY.captures(&line_prefix) else {
+            return Ok(None);
+        };
+
+        let typed_name = &captures[2];
+        let factory = captures[1]
+            .split(',')
+            .next()
+            .and_then(|arg| arg.trim().strip_prefix(':'))
+            .map(str::to_string);
+
+        let searcher = reader.searcher();
+        let (definition_docs, kind) = match &factory {
+            Some(factory) => (
+                self.factory_traits(&searcher, factory)?,
+                CompletionItemKind::ENUM_MEMBER,
+            ),
+            None => (
+                self.factory_docs(&searcher, None)?,
+                CompletionItemKind::CONSTRUCTOR,
+            ),
+        };
+
+        let range = Range::new(
+            Position::new(
+                params.position.line,
+                params.position.character - typed_name.chars().count() as u32,
+            ),
+            params.position,
+        );
+
+        let mut labels = HashSet::new();
+        let mut completions = vec![];
+
+        for definition_doc in definition_docs {
+            let label = self.definition_name(&definition_doc);
+
+            if !label.starts_with(typed_name) || !labels.insert(label.clone()) {
+                continue;
+            }
+
+            completions.push(CompletionItem {
+                label: label.clone(),
+                kind: Some(kind),
+                // Traits stay closest first
+                sort_text: factory
+                    .is_some()
+                    .then(|| format!("{:04}", completions.len())),
+                text_edit: Some(CompletionTextEdit::Edit(TextEdit::new(range, label))),
+                ..CompletionItem::default()
+            });
+        }
+
+        if factory.is_none() {
+            completions.sort_by(|a, b| a.label.cmp(&b.label));
+        }
+
+        Ok(Some(completions))
+    }
+
+    // Completes the class, module, or constant name being typed. Constants
+    // visible from the cursor's nesting come first and are inserted as is,
+    // closest first, while the rest are inserted fully qualified.
+    fn constant_completions(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> tantivy::Result<Vec<CompletionItem>> {
+        let (parsed_document, line_prefix) = match (
+            self.open_document(&params.text_document.uri),
+            self.line_prefix(params),
+        ) {
+            (Some(parsed_document), Some(line_prefix)) => (parsed_document, line_prefix),
+            _ => return Ok(vec![]),
+        };
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(vec![]),
+        };
+
+        let captures = match TYPED_CONSTANT_PATH.captures(&line_prefix) {
+            Some(captures) => captures,
+            None => return Ok(vec![]),
+        };
+
+        let top_level = captures.get(1).is_some();
+        let namespace: Vec<&str> = captures[2]
+            .split("::")
+            .filter(|scope_name| !scope_name.is_empty())
+            .collect();
+        let typed_name = captures.get(3).map_or("", |typed_name| typed_name.as_str());
+
+        if typed_name.is_empty() && namespace.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let lexical_scope = if top_level {
+            vec![]
+        } else {
+            lexical_scope_at(&parsed_document.documents, params.position)
+        };
+
+        let searcher = reader.searcher();
+        let mut queries = vec![];
+
+        let category_query: Box<dyn Query> = Box::new(TermQuery::new(
+            Term::from_field_text(self.schema_fields.category_field, "assignment"),
+            IndexRecordOption::Basic,
+        ));
+        queries.push((Occur::Must, category_query));
+
+        let mut node_type_queries = vec![];
+
+        for node_type in ["Casgn", "Class", "Module"] {
+            let node_type_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(self.schema_fields.node_type_field, node_type),
+                IndexRecordOption::Basic,
+            ));
+
+            node_type_queries.push((Occur::Should, node_type_query));
+        }
+
+        queries.push((Occur::Must, Box::new(BooleanQuery::new(node_type_queries))));
+
+        if let Some(namespace_name) = namespace.last() {
+            let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(self.schema_fields.fuzzy_ruby_scope_field, namespace_name),
+                IndexRecordOption::Basic,
+            ));
+            queries.push((Occur::Must, scope_query));
+        }
+
+        // Only the names starting with what's typed are read from the term
+        // dictionary, and only the best of those are looked up, since a short
+        // prefix can match most constants in the gems
+        if !typed_name.is_empty() {
+            let mut name_scores = HashMap::new();
+
+            for segment_reader in searcher.segment_readers() {
+                let inverted_index =
+                    segment_reader.inverted_index(self.schema_fields.name_field)?;
+                let mut terms = inverted_index
+                    .terms()
+                    .range()
+                    .ge(typed_name)
+                    .into_stream()?;
+
+                while terms.advance() {
+                    if !terms.key().starts_with(typed_name.as_bytes()) {
+                        break;
+                    }
+
+                    if let Ok(name) = str::from_utf8(terms.key()) {
+                        if let Some(score) = fuzzy_match_score(typed_name, name) {
+                            name_scores.insert(name.to_string(), score);
+                        }
+                    }
+                }
+            }
+
+            let mut ranked_names: Vec<(String, i64)> = name_scores.into_iter().collect();
+            ranked_names.sort_by(|(a_name, a_score), (b_name, b_score)| {
+                b_score.cmp(a_score).then(a_name.cmp(b_name))
+            });
+            ranked_names.truncate(self.settings.symbol_search_limit);
+
+            let name_queries: Vec<(Occur, Box<dyn Query>)> = ranked_names
+                .iter()
+                .map(|(name, _score)| {
+                    let name_query: Box<dyn Query> = Box::new(TermQuery::new(
+                        Term::from_field_text(self.schema_fields.name_field, name),
+                        IndexRecordOption::Basic,
+                    ));
+
+                    (Occur::Should, name_query)
+                })
+                .collect();
+
+            if name_queries.is_empty() {
+                return Ok(vec![]);
+            }
+
+            queries.push((Occur::Must, Box::new(BooleanQuery::new(name_queries))));
+        }
+
+        let query = BooleanQuery::new(queries);
+        let typed_name_length = typed_name.chars().count() as u32;
+        let range = Range::new(
+            Position::new(
+                params.position.line,
+                params.position.character - typed_name_length,
+            ),
+            params.position,
+        );
+
+        let mut qualified_names = HashSet::new();
+        let mut candidates = vec![];
+
+        for (_score, doc_address) in searcher.measured_search(&query, &TopDocs::with_limit(500))? {
+            let definition_doc = searcher.measured_doc(doc_address)?;
+            let name = self.definition_name(&definition_doc);
+            let definition_scope: Vec<&str> = definition_doc
+                .get_all(self.schema_fields.fuzzy_ruby_scope_field)
+                .flat_map(Value::as_text)
+                .filter(|scope_name| scope_name.starts_with(char::is_uppercase))
+                .collect();
+
+            if !definition_scope.ends_with(&namespace) {
+                continue;
+            }
+
+            let mut qualified_name = definition_scope.join("::");
+            if !qualified_name.is_empty() {
+                qualified_name.push_str("::");
+            }
+            qualified_name.push_str(&name);
+
+            if !qualified_names.insert(qualified_name.clone()) {
+                continue;
+            }
+
+            // How deep in the cursor's nesting the constant is defined, like
+            // Ruby's lexical lookup. `::Name` only looks at the top level.
+            let outer_scope = &definition_scope[..definition_scope.len() - namespace.len()];
+            let visible_depth = (0..=lexical_scope.len())
+                .rev()
+                .find(|depth| lexical_scope[..*depth] == *outer_scope);
+
+            let kind = match definition_doc
+                .get_first(self.schema_fields.node_type_field)
+                .and_then(Value::as_text)
+            {
+                Some("Class") => CompletionItemKind::CLASS,
+                Some("Module") => CompletionItemKind::MODULE,
+                _ => CompletionItemKind::CONSTANT,
+            };
+
+            let data = self.completion_data(&definition_doc);
+            candidates.push((name, qualified_name, kind, visible_depth, data));
+        }
+
+        // A visible constant is shadowed by one with the same name deeper in
+        // the nesting
+        let mut closest_depths: HashMap<&str, usize> = HashMap::new();
+
+        for (name, _qualified_name, _kind, visible_depth, _data) in &candidates {
+            if let Some(depth) = visible_depth {
+                let closest_depth = closest_depths.entry(name.as_str()).or_default();
+                *closest_depth = (*closest_depth).max(*depth);
+            }
+        }
+
+        let mut completions = vec![];
+
+        for (name, qualified_name, kind, visible_depth, data) in candidates.iter() {
+            // Once a namespace is typed only the name after it is completed
+            let (sort_text, new_text) = match visible_depth {
+                Some(depth) if !namespace.is_empty() || closest_depths[name.as_str()] == *depth => {
+                    (format!("0{:03}{}", 999 - depth, name), name.clone())
+                }
+                Some(_) => (
+                    format!("1{}", qualified_name),
+                    format!("::{}", qualified_name),
+                ),
+                None if !namespace.is_empty() => (format!("1{}", name), name.clone()),
+                None => (format!("1{}", qualified_name), qualified_name.clone()),
+            };
+
+            completions.push(CompletionItem {
+                label: name.clone(),
+                kind: Some(*kind),
+                detail: Some(qualified_name.clone()),
+                sort_text: Some(sort_text),
+                filter_text: Some(name.clone()),
+                text_edit: Some(CompletionTextEdit::Edit(TextEdit::new(range, new_text))),
+                data: Some(data.clone()),
+                ..CompletionItem::default()
+            });
+        }
+
+        completions.sort_by(|a, b| a.sort_text.cmp(&b.sort_text));
+        completions.truncate(self.settings.symbol_search_limit);
+
+        Ok(completions)
+    }
+
+    // Completes `@ivars` and `@@cvars` assigned anywhere in the class around
+    // the cursor, including in other files reopening it, and `$gvars`
+    // assigned anywhere. `None` when a variable isn't being typed.
+    fn variable_completions(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> tantivy::Result<Option<Vec<CompletionItem>>> {
+        let (parsed_document, line_prefix) = match (
+            self.open_document(&params.text_document.uri),
+            self.line_prefix(params),
+        ) {
+            (Some(parsed_document), Some(line_prefix)) => (parsed_document, line_prefix),
+            _ => return Ok(None),
+        };
+
+        let typed_name = match TYPED_VARIABLE.captures(&line_prefix) {
+            Some(captures) => captures[1].to_string(),
+            None => return Ok(None),
+        };
+
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(Some(vec![])),
+        };
+
+        let (node_type, kind) = if typed_name.starts_with("@@") {
+            ("Cvasgn", CompletionItemKind::FIELD)
+        } else if typed_name.starts_with('@') {
+            ("Ivasgn", CompletionItemKind::FIELD)
+        } else {
+            ("Gvasgn", CompletionItemKind::VARIABLE)
+        };
+
+        let category_query: Box<dyn Query> = Box::new(TermQuery::new(
+            Term::from_field_text(self.schema_fields.category_field, "assignment"),
+            IndexRecordOption::Basic,
+        ));
+        let node_type_query: Box<dyn Query> = Box::new(TermQuery::new(
+            Term::from_field_text(self.schema_fields.node_type_field, node_type),
+            IndexRecordOption::Basic,
+        ));
+        let mut queries = vec![
+            (Occur::Must, category_query),
+            (Occur::Must, node_type_query),
+        ];
+
+        // Globals are the same everywhere, while the others need a class
+        let lexical_scope = lexical_scope_at(&parsed_document.documents, params.position);
+
+        if node_type != "Gvasgn" {
+            let class_name = match lexical_scope.last() {
+                Some(class_name) => class_name,
+                None => return Ok(Some(vec![])),
+            };
+            let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(self.schema_fields.fuzzy_ruby_scope_field, class_name),
+                IndexRecordOption::Basic,
+            ));
+
+            queries.push((Occur::Must, scope_query));
+        }
+
+        let searcher = reader.searcher();
+        let query = BooleanQuery::new(queries);
+        let range = Range::new(
+            Position::new(
+                params.position.line,
+                params.position.character - typed_name.chars().count() as u32,
+            ),
+            params.position,
+        );
+
+        let mut names = HashSet::new();
+        let mut completions = vec![];
+
+        for (_score, doc_address) in searcher.measured_search(&query, &TopDocs::with_limit(1000))? {
+            let assignment_doc = searcher.measured_doc(doc_address)?;
+            let name = self.definition_name(&assignment_doc);
+
+            if !name.starts_with(&typed_name) || names.contains(&name) {
+                continue;
+            }
+
+            // The scope term also matches classes nested in one with the same
+            // name, so the whole nesting has to match
+            if node_type != "Gvasgn" {
+                let assignment_scope: Vec<&str> = assignment_doc
+                    .get_all(self.schema_fields.fuzzy_ruby_scope_field)
+                    .flat_map(Value::as_text)
+                    .filter(|scope_name| scope_name.starts_with(char::is_uppercase))
+                    .collect();
+
+                if assignment_scope != lexical_scope {
+                    continue;
+                }
+            }
+
+            names.insert(name.clone());
+            completions.push(CompletionItem {
+                label: name.clone(),
+                kind: Some(kind),
+                detail: Some(self.definition_container(&assignment_doc))
+                    .filter(|container| !container.is_empty()),
+                filter_text: Some(name.clone()),
+                text_edit: Some(CompletionTextEdit::Edit(TextEdit::new(range, name))),
+                data: Some(self.completion_data(&assignment_doc)),
+                ..CompletionItem::default()
+            });
+        }
+
+        completions.sort_by(|a, b| a.label.cmp(&b.label));
+        completions.truncate(self.settings.symbol_search_limit);
+
+        Ok(Some(completions))
+    }
+
+    // Where a completion's definition is, so resolving it can look it up again
+    fn completion_data(&self, definition_doc: &Document) -> serde_json::Value {
+        json!({
+            "filePathId": definition_doc
+                .get_first(self.schema_fields.file_path_id)
+                .and_then(Value::as_text),
+            "line": definition_doc
+                .get_first(self.schema_fields.line_field)
+                .and_then(Value::as_u64),
+            "startColumn": definition_doc
+                .get_first(self.schema_fields.start_column_field)
+                .and_then(Value::as_u64),
+        })
+    }
+
+    // Adds the definition's signature, documentation comment, and location to
+    // a completion. These are only looked up for the completion the editor
+    // highlights, so listing completions stays fast.
+    pub fn resolve_completion(&self, mut item: CompletionItem) -> tantivy::Result<CompletionItem> {
+        let (reader, data) = match (&self.reader, &item.data) {
+            (Some(reader), Some(data)) => (reader, data),
+            _ => return Ok(item),
+        };
+
+        let (file_path_id, line, start_column) = match (
+            data["filePathId"].as_str(),
+            data["line"].as_u64(),
+            data["startColumn"].as_u64(),
+        ) {
+            (Some(file_path_id), Some(line), Some(start_column)) => {
+                (file_path_id, line, start_column)
+            }
+            _ => return Ok(item),
+        };
+
+        // The file may have changed since the completions were listed
+        let searcher = reader.searcher();
+        let definition_doc =
+            match self.definition_doc_at(&searcher, file_path_id, line, start_column)? {
+                Some(definition_doc) => definition_doc,
+                None => return Ok(item),
+            };
+
+        let mut documentation = vec![];
+        if let Some(comment) = definition_doc
+            .get_first(self.schema_fields.documentation_field)
+            .and_then(Value::as_text)
+        {
+            documentation.push(comment.to_string());
+        }
+        documentation.push(self.definition_origin(&definition_doc));
+
+        // Typed methods show their Sorbet `sig` above the signature, as in
+        // hovers
+        let detail = match self.definition_sig(&definition_doc) {
+            Some(sig) => format!("{}\n{}", sig, self.definition_signature(&definition_doc)),
+            None => self.definition_signature(&definition_doc),
+        };
+
+        item.detail = Some(detail);
+        item.documentation = Some(Documentation::MarkupContent(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: documentation.join("\n\n"),
+        }));
+
+        Ok(item)
+    }
+}
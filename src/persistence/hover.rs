@@ -0,0 +1,307 @@
+use std::fs;
+use tantivy::collector::TopDocs;
+use tantivy::query::{BooleanQuery, Occur, Query, TermQuery};
+use tantivy::{schema::*, Document, Searcher};
+use tower_lsp::lsp_types::{
+    Hover, HoverContents, Location, MarkupContent, MarkupKind, TextDocumentPositionParams,
+};
+
+use super::workspace::gem_name_and_version;
+use super::{Persistence, HOVER_DEFINITION_LIMIT};
+use crate::telemetry::MeasuredSearcher;
+use crate::workspace_path::{self, uri_path};
+
+// Longer definitions are cut off in the hover's source preview
+const HOVER_PREVIEW_LINES: usize = 10;
+
+impl Persistence {
+    // The signatures, documentation comments, and origins of the definitions
+    // under the cursor. Definitions in gems say which gem and version they're
+    // from, e.g. to find where a monkey-patched method actually lives.
+    pub fn hover(&self, params: TextDocumentPositionParams) -> tantivy::Result<Option<Hover>> {
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
+
+        if let Some(hover) = self.gem_hover(&params) {
+            return Ok(Some(hover));
+        }
+
+        let searcher = reader.searcher();
+        let mut sections = vec![];
+
+        for location in self
+            .find_definitions(params)?
+            .iter()
+            .take(HOVER_DEFINITION_LIMIT)
+        {
+            let file_path_id = self.workspace_path.file_path_id(&uri_path(&location.uri));
+            let definition_doc = match self.definition_doc_at(
+                &searcher,
+                &file_path_id,
+                location.range.start.line.into(),
+                location.range.start.character.into(),
+            )? {
+                Some(definition_doc) => definition_doc,
+                None => continue,
+            };
+
+            // The definition's source when the file can be read, which starts
+            // with the signature anyway
+            let mut code = self
+                .source_preview(location)
+                .unwrap_or_else(|| self.definition_signature(&definition_doc));
+
+            if let Some(sig) = self.definition_sig(&definition_doc) {
+                code = format!("{}\n{}", sig, code);
+            }
+
+            let mut section = vec![format!("```ruby\n{}\n```", code)];
+            if let Some(comment) = definition_doc
+                .get_first(self.schema_fields.documentation_field)
+                .and_then(Value::as_text)
+            {
+                section.push(comment.to_string());
+            }
+            section.push(self.definition_origin(&definition_doc));
+
+            sections.push(section.join("\n\n"));
+        }
+
+        if sections.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: sections.join("\n\n---\n\n"),
+            }),
+            range: None,
+        }))
+    }
+
+    // The version and install path of the gem named by a `Gemfile` or gemspec
+    // dependency, as locked in `Gemfile.lock`
+    fn gem_hover(&self, params: &TextDocumentPositionParams) -> Option<Hover> {
+        let gem_declaration = self.gem_declaration(params)?;
+        let parsed_document = self.open_document(&params.text_document.uri)?;
+        let declaration = parsed_document.text.lines().nth(gem_declaration.line)?;
+        let name = &gem_declaration.path;
+
+        let origin = match self.gem_path(name) {
+            Some(gem_path) => {
+                let version = gem_name_and_version(&format!("{}/", gem_path))
+                    .map(|(_name, version)| version)
+                    .unwrap_or_default();
+
+                format!("Gem `{}` ({})  \n`{}`", name, version, gem_path)
+            }
+            None => format!("Gem `{}` isn't one of the gems from `Gemfile.lock`", name),
+        };
+
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!("```ruby\n{}\n```\n\n{}", declaration.trim(), origin),
+            }),
+            range: None,
+        })
+    }
+
+    // The first lines of a definition, up to the `end` lined up with it, from
+    // the editor's text when the file is open and otherwise from disk
+    fn source_preview(&self, location: &Location) -> Option<String> {
+        let text = match self.open_document(&location.uri) {
+            Some(parsed_document) => parsed_document.text.clone(),
+            None => fs::read_to_string(location.uri.to_file_path().ok()?).ok()?,
+        };
+
+        let mut lines = text.lines().skip(location.range.start.line as usize);
+        let first_line = lines.next()?;
+        let indentation = first_line.len() - first_line.trim_start().len();
+        let mut preview_lines = vec![first_line];
+
+        for line in lines.take(HOVER_PREVIEW_LINES - 1) {
+            preview_lines.push(line);
+
+            let line_indentation = line.len() - line.trim_start().len();
+            if line_indentation == indentation && line.trim_start().starts_with("end") {
+                break;
+            }
+        }
+
+        // One-line definitions like constants don't need the lines after them
+        let keyword = first_line.trim_start();
+        if !["class ", "module ", "def "]
+            .iter()
+            .any(|prefix| keyword.starts_with(prefix))
+        {
+            preview_lines.truncate(1);
+        }
+
+        let preview = preview_lines
+            .iter()
+            .map(|line| line.get(indentation..).unwrap_or(line.trim_start()))
+            .collect::<Vec<&str>>()
+            .join("\n");
+
+        Some(preview)
+    }
+
+    // The definition starting at a position in a file, if it's still there
+    pub(super) fn definition_doc_at(
+        &self,
+        searcher: &Searcher,
+        file_path_id: &str,
+        line: u64,
+        start_column: u64,
+    ) -> tantivy::Result<Option<Document>> {
+        let query = BooleanQuery::new(vec![
+            (
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(self.schema_fields.file_path_id, file_path_id),
+                    IndexRecordOption::Basic,
+                )) as Box<dyn Query>,
+            ),
+            (
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(self.schema_fields.category_field, "assignment"),
+                    IndexRecordOption::Basic,
+                )),
+            ),
+            (
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_u64(self.schema_fields.line_field, line),
+                    IndexRecordOption::Basic,
+                )),
+            ),
+            (
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_u64(self.schema_fields.start_column_field, start_column),
+                    IndexRecordOption::Basic,
+                )),
+            ),
+        ]);
+
+        match searcher
+            .measured_search(&query, &TopDocs::with_limit(1))?
+            .first()
+        {
+            Some((_score, doc_address)) => Ok(Some(searcher.measured_doc(*doc_address)?)),
+            None => Ok(None),
+        }
+    }
+
+    // How a definition reads in Ruby, e.g. `class Admin::User < Base` or
+    // `def self.find(id)`
+    pub(super) fn definition_signature(&self, definition_doc: &Document) -> String {
+        let name = self.definition_name(definition_doc);
+        let container = self.definition_container(definition_doc);
+        let qualified_name = if container.is_empty() {
+            name.clone()
+        } else {
+            format!("{}::{}", container, name)
+        };
+        let text_field = |field| {
+            definition_doc
+                .get_first(field)
+                .and_then(Value::as_text)
+                .map(str::to_string)
+        };
+
+        match text_field(self.schema_fields.node_type_field).as_deref() {
+            Some("Class") => match text_field(self.schema_fields.superclass_field) {
+                Some(superclass) => format!("class {} < {}", qualified_name, superclass),
+                None => format!("class {}", qualified_name),
+            },
+            Some("Module") => format!("module {}", qualified_name),
+            Some(node_type @ ("Def" | "Defs")) => {
+                let receiver = if node_type == "Defs" { "self." } else { "" };
+
+                match text_field(self.schema_fields.parameters_field) {
+                    Some(parameters) if !parameters.is_empty() => {
+                        format!("def {}{}({})", receiver, name, parameters)
+                    }
+                    _ => format!("def {}{}", receiver, name),
+                }
+            }
+            Some("Ivasgn") | Some("Cvasgn") if !container.is_empty() => {
+                format!("{} in {}", name, container)
+            }
+            _ => qualified_name,
+        }
+    }
+
+    // The Sorbet `sig` of a method with types, e.g.
+    // `sig { params(name: String).returns(Integer) }`
+    pub(super) fn definition_sig(&self, definition_doc: &Document) -> Option<String> {
+        let text_field = |field| definition_doc.get_first(field).and_then(Value::as_text);
+        let parameter_types = text_field(self.schema_fields.parameter_types_field);
+        let return_type = text_field(self.schema_fields.return_type_field);
+
+        if parameter_types.is_none() && return_type.is_none() {
+            return None;
+        }
+
+        let mut calls = vec![];
+
+        if let Some(parameter_types) = parameter_types {
+            calls.push(format!("params({})", parameter_types));
+        }
+
+        match return_type {
+            Some("void") => calls.push("void".to_string()),
+            Some(return_type) => calls.push(format!("returns({})", return_type)),
+            None => {}
+        }
+
+        Some(format!("sig {{ {} }}", calls.join(".")))
+    }
+
+    // Where a definition is, e.g. `app/models/user.rb:12`, or for one in a
+    // gem its name and version followed by the full path
+    pub(super) fn definition_origin(&self, definition_doc: &Document) -> String {
+        let file_path: String = definition_doc
+            .get_all(self.schema_fields.file_path)
+            .flat_map(Value::as_text)
+            .collect::<Vec<&str>>()
+            .join("/");
+        let line = definition_doc
+            .get_first(self.schema_fields.line_field)
+            .and_then(Value::as_u64)
+            .unwrap_or(0)
+            + 1;
+        let user_space = definition_doc
+            .get_first(self.schema_fields.user_space_field)
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+
+        let origin = if user_space {
+            format!("`{}:{}`", file_path, line)
+        } else {
+            let absolute_file_path = workspace_path::absolute_path(&file_path);
+
+            match gem_name_and_version(&absolute_file_path) {
+                Some((name, version)) => format!(
+                    "From gem `{}` ({})  \n`{}:{}`",
+                    name, version, absolute_file_path, line
+                ),
+                None => format!("`{}:{}`", absolute_file_path, line),
+            }
+        };
+
+        match definition_doc
+            .get_first(self.schema_fields.refinement_field)
+            .and_then(Value::as_text)
+        {
+            Some(refinement) => format!("Refined by `{}`  \n{}", refinement, origin),
+            None => origin,
+        }
+    }
+}
@@ -0,0 +1,884 @@
+mod factory_bot;
+mod rails;
+mod rspec;
+mod visitor;
+
+use lib_ruby_parser::source::{Comment, CommentType, DecodedInput};
+use lib_ruby_parser::{nodes::*, Loc, Node, Parser};
+use phf::phf_set;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+use tower_lsp::lsp_types::{Position, Range};
+
+use super::{IndexSettings, Persistence, AUTOLOAD_DOCUMENTATION};
+use crate::ruby_version;
+use crate::telemetry;
+
+static RSPEC_EXAMPLE_GROUPS: phf::Set<&'static str> = phf_set! {
+    "describe", "context", "feature", "shared_context", "shared_examples",
+    "shared_examples_for",
+};
+
+#[derive(Clone, Debug, Default)]
+pub(super) struct FuzzyNode<'a> {
+    pub(super) category: &'a str,
+    pub(super) fuzzy_ruby_scope: Vec<String>,
+    pub(super) class_scope: Vec<String>,
+    pub(super) name: String,
+    pub(super) node_type: &'a str,
+    pub(super) line: usize,
+    pub(super) start_column: usize,
+    pub(super) end_column: usize,
+    // From `class`, `module`, or `def` to its `end`, where `line` and the
+    // columns are only its name
+    pub(super) full_range: Option<Range>,
+    pub(super) superclass: Option<String>,
+    pub(super) visibility: Option<&'a str>,
+    pub(super) documentation: Option<String>,
+    pub(super) parameters: Option<String>,
+    pub(super) parameter_types: Option<String>,
+    pub(super) return_type: Option<String>,
+    // The module whose `refine` block defines the method, if it's refined
+    pub(super) refinement: Option<String>,
+}
+
+// The types from a Sorbet `sig` for the method defined right after it
+#[derive(Clone, Default)]
+pub(super) struct SorbetSig {
+    // The line the `sig` starts on, which the method's comment is above
+    line: Option<usize>,
+    // What's passed to `params`, e.g. `name: String, count: Integer`
+    parameter_types: Option<String>,
+    // `void` for `.void`
+    return_type: Option<String>,
+}
+
+// A method call or constant from the last parsed file to check against the
+// index for undefined name diagnostics
+#[derive(Clone)]
+pub(super) struct NameCheck {
+    pub(super) name: String,
+    pub(super) node_type: &'static str,
+    pub(super) line: usize,
+    pub(super) start_column: usize,
+    pub(super) end_column: usize,
+    pub(super) enclosing_class: Option<String>,
+}
+
+// The string passed to `require`, `require_relative`, or `autoload`, or the
+// gem named by `gem` in a `Gemfile`, in the last parsed file, for document
+// links
+#[derive(Clone)]
+pub(super) struct RequiredPath {
+    pub(super) method_name: String,
+    pub(super) path: String,
+    pub(super) line: usize,
+    pub(super) start_column: usize,
+    pub(super) end_column: usize,
+}
+
+// A view passed to `render` in the last parsed file, for document links. It's
+// a partial for `partial:`, a template for `template:` and `action:`, and
+// otherwise depends on whether it's rendered from a template or a controller.
+#[derive(Clone)]
+pub(super) struct RenderedView {
+    pub(super) path: String,
+    pub(super) partial: Option<bool>,
+    pub(super) line: usize,
+    pub(super) start_column: usize,
+    pub(super) end_column: usize,
+}
+
+// A key passed to `t` in the last parsed file, for going to the locale files
+// that translate it and flagging keys none of them do
+#[derive(Clone)]
+pub(super) struct TranslationKey {
+    pub(super) key: String,
+    pub(super) has_default: bool,
+    pub(super) line: usize,
+    pub(super) start_column: usize,
+    pub(super) end_column: usize,
+}
+
+// A factory or trait symbol passed to FactoryBot in the last parsed file,
+// e.g. `:user` and `:admin` in `create(:user, :admin)`
+#[derive(Clone)]
+pub(super) struct FactoryReference {
+    pub(super) factory: String,
+    pub(super) trait_name: Option<String>,
+    pub(super) line: usize,
+    pub(super) start_column: usize,
+    pub(super) end_column: usize,
+}
+
+// A shared example group included in the last parsed file, e.g. `"an
+// auditable model"` in `it_behaves_like "an auditable model"`
+#[derive(Clone)]
+pub(super) struct SharedGroupReference {
+    pub(super) name: String,
+    pub(super) line: usize,
+    pub(super) start_column: usize,
+    pub(super) end_column: usize,
+}
+
+// A call in the last parsed file on a class it could be generated in, for
+// offering a stub when the method doesn't exist
+#[derive(Clone)]
+pub(super) struct MethodCall {
+    pub(super) name: String,
+    pub(super) receiver_class: String,
+    pub(super) class_method: bool,
+    // Named after the arguments, e.g. `(user, notify:)`
+    pub(super) parameters: String,
+    pub(super) line: usize,
+    pub(super) start_column: usize,
+    pub(super) end_column: usize,
+}
+
+// An RSpec example or group, or a Minitest test, in the last parsed file for
+// the code lenses that run it. `framework` is `spec` or `test`.
+#[derive(Clone)]
+pub(super) struct TestBlock {
+    pub(super) framework: &'static str,
+    pub(super) name: String,
+    pub(super) group: bool,
+    pub(super) line: usize,
+}
+
+// A class, module, or method in the last parsed file, from its keyword to
+// its `end`, for `fuzzy/whereAmI`, document symbols, and folding
+#[derive(Clone)]
+pub(super) struct ScopeSpan {
+    // As written, e.g. `Admin::UsersController`
+    pub(super) name: String,
+    pub(super) node_type: &'static str,
+    pub(super) range: Range,
+    // Only its name
+    pub(super) selection_range: Range,
+}
+
+// A controller, or an action in one, that a Rails route in the last parsed
+// file points at, e.g. `"users#show"` or the `:users` in `resources :users`
+#[derive(Clone)]
+pub(super) struct RouteTarget {
+    // e.g. `Admin::UsersController`
+    pub(super) controller: String,
+    pub(super) action: Option<String>,
+    pub(super) line: usize,
+    pub(super) start_column: usize,
+    pub(super) end_column: usize,
+}
+
+// What the last parsed file has besides its definitions and usages, for the
+// features that read it after parsing
+#[derive(Clone, Default)]
+pub(super) struct ParseFindings {
+    pub(super) name_checks: Vec<NameCheck>,
+    pub(super) required_paths: Vec<RequiredPath>,
+    pub(super) rendered_views: Vec<RenderedView>,
+    pub(super) translation_keys: Vec<TranslationKey>,
+    pub(super) factory_references: Vec<FactoryReference>,
+    pub(super) shared_group_references: Vec<SharedGroupReference>,
+    pub(super) method_calls: Vec<MethodCall>,
+    pub(super) test_blocks: Vec<TestBlock>,
+    pub(super) scope_spans: Vec<ScopeSpan>,
+    pub(super) route_targets: Vec<RouteTarget>,
+    pub(super) defines_method_missing: bool,
+    // Locals that are read by `x += 1`, where only an assignment is indexed
+    pub(super) op_assigned_locals: HashSet<String>,
+}
+
+// Walks the syntax tree of one file, serializing its nodes into documents.
+// Each parse has its own, so where it is in the file starts out empty.
+struct Visitor<'v> {
+    settings: &'v IndexSettings,
+    index_interface_only: bool,
+    // The source of the file, for finding the lines and columns of nodes
+    input: &'v DecodedInput,
+    comment_lines: HashMap<usize, String>,
+    class_scope: Vec<String>,
+    local_variable_classes: HashMap<String, Vec<String>>,
+    defining_class_methods: bool,
+    current_visibility: &'static str,
+    module_function_section: bool,
+    // The `sig` just before the method being parsed, if any
+    pending_sig: Option<SorbetSig>,
+    // The module whose `refine` block is being parsed, if any
+    refinement: Option<String>,
+    // The calls of the blocks being parsed, outermost first
+    block_calls: Vec<&'v Node>,
+    findings: ParseFindings,
+}
+
+// An extension point for indexing what isn't plain Ruby, like a library's
+// DSL. Each handler is shown every node before its children are serialized
+// and again after, and can add definitions of its own for it. The built in
+// ones, including the Rails, RSpec, and FactoryBot support, are in
+// `node_handlers`, and others are added with `Persistence::add_node_handler`.
+pub trait NodeHandler: std::marker::Send + Sync {
+    fn handle(&self, _node: &Node, _context: &mut NodeContext) {}
+
+    // Called once the node's children are serialized, so what they index
+    // comes first
+    fn handle_after(&self, _node: &Node, _context: &mut NodeContext) {}
+
+    // A scope for what's defined in the block of `call`, like the example
+    // group of a `describe`, which is added to the fuzzy scope inside it
+    fn block_scope(&self, _call: &Node, _context: &NodeContext) -> Option<String> {
+        None
+    }
+}
+
+// What a `NodeHandler` can read and add to while a node is serialized
+pub struct NodeContext<'a, 'v, 'd> {
+    visitor: &'a mut Visitor<'v>,
+    // The modules, classes, and methods the node is in, outermost first
+    fuzzy_scope: &'a [String],
+    documents: &'a mut Vec<FuzzyNode<'d>>,
+}
+
+impl<'v, 'd> NodeContext<'_, 'v, 'd> {
+    // The source of the file being indexed, for finding the lines and
+    // columns of nodes
+    pub fn input(&self) -> &DecodedInput {
+        self.visitor.input
+    }
+
+    pub fn fuzzy_scope(&self) -> &[String] {
+        self.fuzzy_scope
+    }
+
+    // The calls of the blocks the node is in, outermost first, like the
+    // `namespace :admin` of the routes inside it
+    pub fn block_calls(&self) -> &[&'v Node] {
+        &self.visitor.block_calls
+    }
+
+    // See `literal_span`
+    pub fn literal_span(&self, node: &Node) -> Option<(String, usize, usize, usize)> {
+        literal_span(node, self.visitor.input)
+    }
+
+    // The line and start and end columns of a location, e.g. a node's
+    // `expression_l`
+    pub fn loc_span(&self, loc: &Loc) -> Option<(usize, usize, usize)> {
+        let (line, start_column) = self.visitor.input.line_col_for_pos(loc.begin)?;
+        let (_line, end_column) = self.visitor.input.line_col_for_pos(loc.end)?;
+
+        Some((line, start_column, end_column))
+    }
+
+    // Indexes `name` as defined in the node's scope, e.g. as `"Casgn"` for a
+    // constant, where `span` is its line and start and end columns
+    pub fn push_definition(
+        &mut self,
+        name: String,
+        node_type: &'static str,
+        (line, start_column, end_column): (usize, usize, usize),
+        documentation: Option<String>,
+    ) {
+        self.documents.push(FuzzyNode {
+            category: "assignment",
+            fuzzy_ruby_scope: self.fuzzy_scope.to_vec(),
+            name,
+            node_type,
+            line,
+            start_column,
+            end_column,
+            documentation,
+            ..Default::default()
+        });
+    }
+
+    fn settings(&self) -> &IndexSettings {
+        self.visitor.settings
+    }
+
+    fn findings(&mut self) -> &mut ParseFindings {
+        &mut self.visitor.findings
+    }
+
+    fn documents(&mut self) -> &mut Vec<FuzzyNode<'d>> {
+        self.documents
+    }
+
+    // See `Visitor::receiver_class_scope`, which is empty without a receiver
+    fn receiver_class_scope(&self, recv: &Option<Box<Node>>) -> Vec<String> {
+        recv.as_deref()
+            .map(|recv_node| self.visitor.receiver_class_scope(recv_node))
+            .unwrap_or_default()
+    }
+}
+
+// `autoload :Parser, "my_gem/parser"` as an assignment of `Parser` in the
+// enclosing module, noting the file it's loaded from. The constant is defined
+// once it's first used, so it's indexed here too for when its file isn't.
+struct AutoloadHandler;
+
+impl NodeHandler for AutoloadHandler {
+    fn handle(&self, node: &Node, context: &mut NodeContext) {
+        let Node::Send(Send {
+            recv: None,
+            method_name,
+            args,
+            ..
+        }) = node
+        else {
+            return;
+        };
+
+        let (true, Some(name_arg), Some(path_arg)) =
+            (method_name == "autoload", args.first(), args.get(1))
+        else {
+            return;
+        };
+
+        let Some((name, line, start_column, end_column)) = context.literal_span(name_arg) else {
+            return;
+        };
+        let Some((path, ..)) = context.literal_span(path_arg) else {
+            return;
+        };
+
+        if !name.starts_with(char::is_uppercase) {
+            return;
+        }
+
+        context.push_definition(
+            name,
+            "Casgn",
+            (line, start_column, end_column),
+            Some(format!("{}`{}`", AUTOLOAD_DOCUMENTATION, path)),
+        );
+    }
+}
+
+impl Persistence {
+    pub(super) fn parse(
+        &mut self,
+        contents: &String,
+        documents: &mut Vec<FuzzyNode>,
+    ) -> Result<
+        Vec<Option<tower_lsp::lsp_types::Diagnostic>>,
+        Vec<Option<tower_lsp::lsp_types::Diagnostic>>,
+    > {
+        let options = ruby_version::parser_options("(eval)", self.settings.ruby_version);
+        let parser = Parser::new(contents.to_string(), options);
+        let timer = Instant::now();
+        let parser_result = parser.do_parse();
+        telemetry::record_parse(timer.elapsed());
+        let input = parser_result.input;
+
+        let mut diagnostics = vec![];
+
+        for parser_diagnostic in parser_result.diagnostics {
+            diagnostics.push(self.lsp_diagnostic(parser_diagnostic, &input));
+        }
+
+        let ast = match parser_result.ast {
+            Some(a) => *a,
+            None => {
+                self.findings = ParseFindings::default();
+
+                return Err(diagnostics);
+            }
+        };
+
+        let mut scope = Vec::new();
+        let comment_lines = full_line_comments(contents, &parser_result.comments, &input);
+        let mut visitor = Visitor::new(
+            &self.settings,
+            self.index_interface_only,
+            &input,
+            comment_lines,
+        );
+
+        visitor.serialize(&ast, documents, &mut scope);
+        self.findings = visitor.findings;
+
+        Ok(diagnostics)
+    }
+
+    fn lsp_diagnostic(
+        &mut self,
+        parser_diagnostic: lib_ruby_parser::Diagnostic,
+        input: &DecodedInput,
+    ) -> Option<tower_lsp::lsp_types::Diagnostic> {
+        let diagnostic = || -> Option<tower_lsp::lsp_types::Diagnostic> {
+            let (begin_lineno, start_column) =
+                input.line_col_for_pos(parser_diagnostic.loc.begin).unwrap();
+            let (end_lineno, end_column) =
+                input.line_col_for_pos(parser_diagnostic.loc.end).unwrap();
+            let start_position = Position::new(
+                begin_lineno.try_into().unwrap(),
+                start_column.try_into().unwrap(),
+            );
+            let end_position = Position::new(
+                end_lineno.try_into().unwrap(),
+                end_column.try_into().unwrap(),
+            );
+
+            let severity = match parser_diagnostic.level {
+                lib_ruby_parser::ErrorLevel::Error => self.settings.diagnostic_severities.syntax,
+                lib_ruby_parser::ErrorLevel::Warning => {
+                    self.settings.diagnostic_severities.warnings
+                }
+            };
+
+            let mut diagnostic = tower_lsp::lsp_types::Diagnostic::new_simple(
+                Range::new(start_position, end_position),
+                parser_diagnostic.message.render(),
+            );
+            diagnostic.severity = Some(severity?);
+
+            Some(diagnostic)
+        }();
+
+        diagnostic
+    }
+
+    pub(super) fn example_group_scope(&self, scope_name: &str) -> bool {
+        scope_name
+            .split_once(' ')
+            .is_some_and(|(method_name, _description)| RSPEC_EXAMPLE_GROUPS.contains(method_name))
+    }
+}
+
+// Maps line numbers to the text of `#` comments that are alone on their line,
+// so trailing comments aren't mistaken for documentation
+fn full_line_comments(
+    contents: &str,
+    comments: &[Comment],
+    input: &DecodedInput,
+) -> HashMap<usize, String> {
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut comment_lines = HashMap::new();
+
+    for comment in comments {
+        if comment.kind != CommentType::Inline {
+            continue;
+        }
+
+        let (lineno, begin_pos) = match input.line_col_for_pos(comment.location.begin) {
+            Some(line_col) => line_col,
+            None => continue,
+        };
+
+        let full_line = lines
+            .get(lineno)
+            .and_then(|line| line.get(..begin_pos))
+            .is_some_and(|before_comment| before_comment.trim().is_empty());
+
+        if !full_line {
+            continue;
+        }
+
+        if let Some(source) = comment.location.source(input) {
+            let text = source.trim_end().trim_start_matches('#');
+            let text = text.strip_prefix(' ').unwrap_or(text);
+
+            comment_lines.insert(lineno, text.to_string());
+        }
+    }
+
+    comment_lines
+}
+
+fn loc_range(loc: &Loc, input: &DecodedInput) -> Option<Range> {
+    let (start_line, start_column) = input.line_col_for_pos(loc.begin)?;
+    let (end_line, end_column) = input.line_col_for_pos(loc.end)?;
+
+    Some(Range::new(
+        Position::new(start_line as u32, start_column as u32),
+        Position::new(end_line as u32, end_column as u32),
+    ))
+}
+
+// The block of `ActiveRecord::Schema.define` or, in newer schemas,
+// `ActiveRecord::Schema[7.1].define`
+fn schema_definition(call: &Node) -> bool {
+    let Node::Send(Send {
+        recv: Some(recv),
+        method_name,
+        ..
+    }) = call
+    else {
+        return false;
+    };
+
+    let schema = match recv.as_ref() {
+        Node::Index(lib_ruby_parser::nodes::Index { recv, .. }) => recv.as_ref(),
+        recv => recv,
+    };
+
+    method_name == "define"
+        && matches!(schema, Node::Const(Const { name, scope: Some(scope), .. })
+            if name == "Schema"
+                && matches!(scope.as_ref(), Node::Const(Const { name, .. }) if name == "ActiveRecord"))
+}
+
+// The statements of a body, which is only wrapped in `Begin` when there's
+// more than one
+fn statements(body: &Node) -> &[Node] {
+    match body {
+        Node::Begin(Begin { statements, .. }) => statements,
+        node => std::slice::from_ref(node),
+    }
+}
+
+// The view path of `render` calls like `render "header"`, `render :edit`,
+// and `render partial: "item", locals: { ... }`
+fn rendered_view(args: &[Node], input: &DecodedInput) -> Option<RenderedView> {
+    let (path_node, partial) = match args.first()? {
+        Node::Hash(Hash { pairs, .. }) | Node::Kwargs(Kwargs { pairs, .. }) => {
+            pairs.iter().find_map(|pair| match pair {
+                Node::Pair(Pair { key, value, .. }) => match key.as_ref() {
+                    Node::Sym(Sym { name, .. }) => match name.to_string_lossy().as_str() {
+                        "partial" => Some((value.as_ref(), Some(true))),
+                        "template" | "action" => Some((value.as_ref(), Some(false))),
+                        _ => None,
+                    },
+                    _ => None,
+                },
+                _ => None,
+            })?
+        }
+        path_node => (path_node, None),
+    };
+
+    let (path, line, start_column, end_column) = literal_span(path_node, input)?;
+
+    Some(RenderedView {
+        path,
+        partial,
+        line,
+        start_column,
+        end_column,
+    })
+}
+
+// The class of `refine String do`
+fn refined_class(call: &Node) -> Option<&Const> {
+    match call {
+        Node::Send(Send {
+            recv: None,
+            method_name,
+            args,
+            ..
+        }) if method_name == "refine" => match args.first() {
+            Some(Node::Const(const_node)) => Some(const_node),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The class of `describe User do` or `RSpec.describe Admin::User do`
+fn described_class(call: &Node) -> Option<&Const> {
+    match call {
+        Node::Send(Send {
+            recv,
+            method_name,
+            args,
+            ..
+        }) if method_name == "describe" && (recv.is_none() || rspec_receiver(recv)) => {
+            match args.first() {
+                Some(Node::Const(const_node)) => Some(const_node),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn rspec_receiver(recv: &Option<Box<Node>>) -> bool {
+    matches!(
+        recv.as_deref(),
+        Some(Node::Const(Const { scope: None, name, .. })) if name == "RSpec"
+    )
+}
+
+fn i18n_receiver(recv: &Option<Box<Node>>) -> bool {
+    matches!(
+        recv.as_deref(),
+        Some(Node::Const(Const { scope: None, name, .. })) if name == "I18n"
+    )
+}
+
+// The key of `t` and `I18n.t` calls like `t("users.show.title")`, `t(:title)`,
+// and `t(".title", default: "Profile")`
+fn translation_key(args: &[Node], input: &DecodedInput) -> Option<TranslationKey> {
+    let (key, line, start_column, end_column) = literal_span(args.first()?, input)?;
+
+    let has_default = args[1..].iter().any(|arg| match arg {
+        Node::Hash(Hash { pairs, .. }) | Node::Kwargs(Kwargs { pairs, .. }) => {
+            pairs.iter().any(|pair| match pair {
+                Node::Pair(Pair { key, .. }) => matches!(
+                    key.as_ref(),
+                    Node::Sym(Sym { name, .. }) if name.to_string_lossy() == "default"
+                ),
+                _ => false,
+            })
+        }
+        _ => false,
+    });
+
+    Some(TranslationKey {
+        key,
+        has_default,
+        line,
+        start_column,
+        end_column,
+    })
+}
+
+// The handlers every file is indexed with
+pub(super) fn node_handlers() -> Vec<Arc<dyn NodeHandler>> {
+    vec![
+        Arc::new(AutoloadHandler),
+        Arc::new(rails::ScopeHandler),
+        Arc::new(rails::AssociationHandler),
+        Arc::new(rails::CallbackHandler),
+        Arc::new(rails::RouteHandler),
+        Arc::new(rspec::LetHandler),
+        Arc::new(rspec::SharedExampleHandler),
+        Arc::new(factory_bot::FactoryHandler),
+    ]
+}
+
+// The value of a string or symbol literal, with the line and columns of only
+// what's inside the quotes or after the `:`
+fn literal_span(node: &Node, input: &DecodedInput) -> Option<(String, usize, usize, usize)> {
+    let (value, begin, end) = match node {
+        Node::Str(Str {
+            value,
+            begin_l: Some(begin_l),
+            end_l: Some(end_l),
+            ..
+        }) => (value.to_string_lossy(), begin_l.end, end_l.begin),
+        Node::Sym(Sym {
+            name,
+            begin_l: Some(begin_l),
+            expression_l,
+            ..
+        }) => (name.to_string_lossy(), begin_l.end, expression_l.end),
+        _ => return None,
+    };
+
+    let (line, start_column) = input.line_col_for_pos(begin)?;
+    let (_line, end_column) = input.line_col_for_pos(end)?;
+
+    Some((value, line, start_column, end_column))
+}
+
+// Covers the common English plurals used for association names
+fn singularize(word: &str) -> String {
+    if let Some(stem) = word.strip_suffix("ies") {
+        format!("{}y", stem)
+    } else if ["sses", "shes", "ches", "xes", "zes"]
+        .iter()
+        .any(|suffix| word.ends_with(suffix))
+    {
+        word[..word.len() - 2].to_string()
+    } else if let Some(stem) = word.strip_suffix('s') {
+        if stem.ends_with('s') {
+            word.to_string()
+        } else {
+            stem.to_string()
+        }
+    } else {
+        word.to_string()
+    }
+}
+
+fn pluralize(word: &str) -> String {
+    if word.ends_with('y')
+        && !["ay", "ey", "oy", "uy"]
+            .iter()
+            .any(|suffix| word.ends_with(suffix))
+    {
+        format!("{}ies", &word[..word.len() - 1])
+    } else if ["s", "sh", "ch", "x", "z"]
+        .iter()
+        .any(|suffix| word.ends_with(suffix))
+    {
+        format!("{}es", word)
+    } else {
+        format!("{}s", word)
+    }
+}
+
+// A Sorbet `sig { ... }` block, which is read before the method after it
+fn sorbet_sig_block(node: &Node) -> bool {
+    let Node::Block(Block { call, .. }) = node else {
+        return false;
+    };
+
+    matches!(
+        call.as_ref(),
+        Node::Send(Send { recv: None, method_name, .. }) if method_name == "sig"
+    )
+}
+
+// The types in the chain of calls in a `sig` block, like
+// `override.params(name: String).returns(Integer)`
+fn sorbet_sig(body: &Node, input: &DecodedInput) -> SorbetSig {
+    let mut sig = SorbetSig::default();
+    let mut call = Some(body);
+
+    while let Some(Node::Send(Send {
+        recv,
+        method_name,
+        args,
+        ..
+    })) = call
+    {
+        let source = |node: &Node| {
+            node.expression()
+                .source(input)
+                .map(|source| source.split_whitespace().collect::<Vec<&str>>().join(" "))
+        };
+
+        match method_name.as_str() {
+            "params" => sig.parameter_types = args.first().and_then(source),
+            "returns" => sig.return_type = args.first().and_then(source),
+            "void" => sig.return_type = Some("void".to_string()),
+            _ => {}
+        }
+
+        call = recv.as_deref();
+    }
+
+    sig
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `(node type, scope, name)` of each definition indexed from `source`
+    fn definitions(source: &str) -> Vec<(String, String, String)> {
+        let mut persistence = Persistence::new().unwrap();
+        let mut documents = vec![];
+        persistence
+            .parse(&source.to_string(), &mut documents)
+            .unwrap();
+
+        documents
+            .iter()
+            .filter(|document| document.category == "assignment")
+            .map(|document| {
+                (
+                    document.node_type.to_string(),
+                    document.fuzzy_ruby_scope.join("::"),
+                    document.name.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn module_function_doesnt_apply_in_refine_blocks() {
+        let definitions = definitions(
+            "module Shouting\n  module_function\n\n  refine String do\n    def shout\n    end\n  end\n\n  def whisper\n  end\nend\n",
+        );
+        let definition = |node_type: &str, name: &str| {
+            definitions.iter().any(|(other_type, _scope, other_name)| {
+                other_type == node_type && other_name == name
+            })
+        };
+
+        assert!(definition("Def", "shout"));
+        assert!(!definition("Defs", "shout"));
+        assert!(definition("Def", "whisper"));
+        assert!(definition("Defs", "whisper"));
+    }
+
+    #[test]
+    fn rails_dsls_are_indexed_by_node_handlers() {
+        let definitions = definitions(
+            "class Post < ApplicationRecord\n  belongs_to :author\n  scope :published, -> { where(published: true) }\nend\n",
+        );
+        let definition = |node_type: &str, name: &str| {
+            definitions.contains(&(node_type.to_string(), "Post".to_string(), name.to_string()))
+        };
+
+        assert!(definition("Def", "author"));
+        assert!(definition("Def", "build_author"));
+        assert!(definition("Defs", "published"));
+    }
+
+    #[test]
+    fn factories_are_scoped_to_the_factories_they_are_in() {
+        let definitions = definitions(
+            "FactoryBot.define do\n  factory :user do\n    trait :banned do\n    end\n\n    factory :admin\n  end\nend\n",
+        );
+
+        assert_eq!(
+            definitions,
+            vec![
+                ("Factory".to_string(), "".to_string(), "user".to_string()),
+                (
+                    "Trait".to_string(),
+                    "user".to_string(),
+                    "banned".to_string()
+                ),
+                (
+                    "Factory".to_string(),
+                    "user".to_string(),
+                    "admin".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn routes_point_at_the_controllers_of_their_blocks() {
+        let mut persistence = Persistence::new().unwrap();
+        persistence
+            .parse(
+                &"Rails.application.routes.draw do\n  namespace :admin do\n    resources :users do\n      get :preview\n    end\n  end\nend\n".to_string(),
+                &mut vec![],
+            )
+            .unwrap();
+
+        let route_targets: Vec<(String, Option<String>)> = persistence
+            .findings
+            .route_targets
+            .iter()
+            .map(|route_target| (route_target.controller.clone(), route_target.action.clone()))
+            .collect();
+
+        assert_eq!(
+            route_targets,
+            vec![
+                ("Admin::UsersController".to_string(), None),
+                (
+                    "Admin::UsersController".to_string(),
+                    Some("preview".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn rspec_lets_are_scoped_to_their_example_group() {
+        let mut persistence = Persistence::new().unwrap();
+        Arc::make_mut(&mut persistence.settings).index_rspec_let = true;
+
+        let mut documents = vec![];
+        persistence
+            .parse(
+                &"RSpec.describe User do\n  let(:user) { User.new }\nend\n".to_string(),
+                &mut documents,
+            )
+            .unwrap();
+
+        assert!(documents.iter().any(|document| {
+            document.category == "assignment"
+                && document.name == "user"
+                && document.fuzzy_ruby_scope == vec!["describe User".to_string()]
+        }));
+    }
+}
@@ -0,0 +1,159 @@
+use lib_ruby_parser::source::DecodedInput;
+use lib_ruby_parser::{nodes::*, Loc, Node};
+
+use super::{literal_span, FactoryReference, FuzzyNode, NodeContext, NodeHandler};
+
+// FactoryBot's `factory :admin, parent: :user` and `trait :banned`, scoped to
+// the factories they're in, and the factories and traits used by calls like
+// `create(:user, :admin)`
+pub(super) struct FactoryHandler;
+
+impl NodeHandler for FactoryHandler {
+    fn handle_after(&self, node: &Node, context: &mut NodeContext) {
+        let Node::Send(Send {
+            recv,
+            method_name,
+            args,
+            ..
+        }) = node
+        else {
+            return;
+        };
+
+        match method_name.as_str() {
+            "factory" | "trait" if recv.is_none() => {
+                // Traits and nested factories belong to the factory they're in
+                let factory_names: Vec<String> = context
+                    .block_calls()
+                    .iter()
+                    .filter_map(|call| factory_definition(call).map(|(name, _parent, _loc)| name))
+                    .collect();
+
+                let definition = match method_name.as_str() {
+                    "factory" => factory_definition(node).map(|(name, parent, loc)| {
+                        // Nested factories inherit from the one they're in
+                        let parent = parent.or_else(|| factory_names.last().cloned());
+                        ("Factory", name, parent, loc)
+                    }),
+                    _ => match args.first() {
+                        Some(Node::Sym(Sym {
+                            name, expression_l, ..
+                        })) => Some(("Trait", name.to_string_lossy(), None, *expression_l)),
+                        _ => None,
+                    },
+                };
+
+                let Some((node_type, name, parent, expression_l)) = definition else {
+                    return;
+                };
+                let Some((line, start_column, end_column)) = context.loc_span(&expression_l) else {
+                    return;
+                };
+
+                context.documents().push(FuzzyNode {
+                    category: "assignment",
+                    fuzzy_ruby_scope: factory_names,
+                    class_scope: vec![],
+                    name,
+                    node_type,
+                    line,
+                    start_column,
+                    end_column,
+                    superclass: parent,
+                    ..Default::default()
+                });
+            }
+            "create" | "build" | "build_stubbed" | "attributes_for" | "create_list"
+            | "build_list" | "create_pair" | "build_pair"
+                if recv.is_none() || factory_bot_receiver(recv) =>
+            {
+                let factory_references = factory_references(args, context.input());
+
+                context
+                    .findings()
+                    .factory_references
+                    .extend(factory_references);
+            }
+            _ => {}
+        }
+    }
+}
+
+// The name, `parent:`, and location of the symbol of `factory :admin, parent:
+// :user`, with or without a block
+fn factory_definition(call: &Node) -> Option<(String, Option<String>, Loc)> {
+    let Node::Send(Send {
+        recv: None,
+        method_name,
+        args,
+        ..
+    }) = call
+    else {
+        return None;
+    };
+
+    let Some(Node::Sym(Sym {
+        name, expression_l, ..
+    })) = args.first()
+    else {
+        return None;
+    };
+
+    if method_name != "factory" {
+        return None;
+    }
+
+    let parent = args[1..].iter().find_map(|arg| match arg {
+        Node::Hash(Hash { pairs, .. }) | Node::Kwargs(Kwargs { pairs, .. }) => {
+            pairs.iter().find_map(|pair| match pair {
+                Node::Pair(Pair { key, value, .. }) => match (key.as_ref(), value.as_ref()) {
+                    (Node::Sym(Sym { name: key, .. }), Node::Sym(Sym { name: value, .. }))
+                        if key.to_string_lossy() == "parent" =>
+                    {
+                        Some(value.to_string_lossy())
+                    }
+                    _ => None,
+                },
+                _ => None,
+            })
+        }
+        _ => None,
+    });
+
+    Some((name.to_string_lossy(), parent, *expression_l))
+}
+
+// The factory and traits of calls like `create(:user, :admin)` and
+// `build_list(:user, 3, :admin)`, where the first symbol is the factory
+fn factory_references(args: &[Node], input: &DecodedInput) -> Vec<FactoryReference> {
+    let symbols: Vec<(String, usize, usize, usize)> = args
+        .iter()
+        .filter(|arg| matches!(arg, Node::Sym(_)))
+        .flat_map(|arg| literal_span(arg, input))
+        .collect();
+
+    let Some((factory, ..)) = symbols.first() else {
+        return vec![];
+    };
+
+    symbols
+        .iter()
+        .enumerate()
+        .map(
+            |(index, (name, line, start_column, end_column))| FactoryReference {
+                factory: factory.clone(),
+                trait_name: (index > 0).then(|| name.clone()),
+                line: *line,
+                start_column: *start_column,
+                end_column: *end_column,
+            },
+        )
+        .collect()
+}
+
+fn factory_bot_receiver(recv: &Option<Box<Node>>) -> bool {
+    matches!(
+        recv.as_deref(),
+        Some(Node::Const(Const { scope: None, name, .. })) if name == "FactoryBot"
+    )
+}
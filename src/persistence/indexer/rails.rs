@@ -0,0 +1,466 @@
+use lib_ruby_parser::{nodes::*, Node};
+use phf::phf_set;
+
+use super::{pluralize, singularize, FuzzyNode, NodeContext, NodeHandler, RouteTarget};
+use crate::persistence::controller_class_name;
+
+// Rails macros that take the names of methods to call
+static CALLBACK_MACROS: phf::Set<&'static str> = phf_set! {
+    // ActionController
+    "before_action", "after_action", "around_action", "prepend_before_action",
+    "prepend_after_action", "prepend_around_action", "skip_before_action",
+    "skip_after_action", "skip_around_action", "append_before_action",
+    "append_after_action", "append_around_action", "helper_method",
+    // ActiveRecord and ActiveModel
+    "before_validation", "after_validation", "before_save", "around_save", "after_save",
+    "before_create", "around_create", "after_create", "before_update", "around_update",
+    "after_update", "before_destroy", "around_destroy", "after_destroy", "after_commit",
+    "after_rollback", "after_create_commit", "after_update_commit", "after_destroy_commit",
+    "after_save_commit", "after_initialize", "after_find", "after_touch", "validate",
+    // ActiveJob
+    "before_enqueue", "around_enqueue", "after_enqueue", "before_perform", "around_perform",
+    "after_perform",
+};
+
+// `scope :published, -> { ... }` as a class method of the model
+pub(super) struct ScopeHandler;
+
+impl NodeHandler for ScopeHandler {
+    fn handle_after(&self, node: &Node, context: &mut NodeContext) {
+        let Node::Send(Send {
+            recv: None,
+            method_name,
+            args,
+            ..
+        }) = node
+        else {
+            return;
+        };
+
+        let (
+            true,
+            Some(Node::Sym(Sym {
+                name, expression_l, ..
+            })),
+        ) = (method_name == "scope", args.first())
+        else {
+            return;
+        };
+
+        if let Some(span) = context.loc_span(expression_l) {
+            context.push_definition(name.to_string_lossy(), "Defs", span, None);
+        }
+    }
+}
+
+// The methods `belongs_to`, `has_one`, `has_many`, and
+// `has_and_belongs_to_many` generate for an association
+pub(super) struct AssociationHandler;
+
+impl NodeHandler for AssociationHandler {
+    fn handle_after(&self, node: &Node, context: &mut NodeContext) {
+        let Node::Send(Send {
+            recv,
+            method_name,
+            args,
+            ..
+        }) = node
+        else {
+            return;
+        };
+
+        if !matches!(
+            method_name.as_str(),
+            "belongs_to" | "has_one" | "has_many" | "has_and_belongs_to_many"
+        ) {
+            return;
+        }
+
+        let Some(Node::Sym(Sym {
+            name, expression_l, ..
+        })) = args.first()
+        else {
+            return;
+        };
+        let Some((line, start_column, end_column)) = context.loc_span(expression_l) else {
+            return;
+        };
+
+        let class_scope = context.receiver_class_scope(recv);
+        let fuzzy_scope = context.fuzzy_scope().to_vec();
+
+        for generated_name in association_method_names(method_name, &name.to_string_lossy()) {
+            context.documents().push(FuzzyNode {
+                category: "assignment",
+                fuzzy_ruby_scope: fuzzy_scope.clone(),
+                class_scope: class_scope.clone(),
+                name: generated_name,
+                node_type: "Def",
+                line,
+                start_column,
+                end_column,
+                ..Default::default()
+            });
+        }
+    }
+}
+
+// The method names passed to callback macros like `before_action
+// :authenticate` are already usages, but their ranges include the `:`, which
+// breaks renaming the method
+pub(super) struct CallbackHandler;
+
+impl NodeHandler for CallbackHandler {
+    fn handle_after(&self, node: &Node, context: &mut NodeContext) {
+        let Node::Send(Send {
+            recv: None,
+            method_name,
+            args,
+            ..
+        }) = node
+        else {
+            return;
+        };
+
+        if !CALLBACK_MACROS.contains(method_name.as_str()) {
+            return;
+        }
+
+        for callback_sym in callback_symbols(args) {
+            let Some(begin_l) = callback_sym.begin_l else {
+                continue;
+            };
+
+            if callback_sym.end_l.is_some() {
+                continue;
+            }
+
+            let (lineno, begin_pos) = context
+                .input()
+                .line_col_for_pos(callback_sym.expression_l.begin)
+                .unwrap();
+            let (_lineno, name_begin_pos) = context.input().line_col_for_pos(begin_l.end).unwrap();
+
+            let usage_document = context.documents().iter_mut().rev().find(|document| {
+                document.category == "usage"
+                    && document.node_type == "Send"
+                    && document.line == lineno
+                    && document.start_column == begin_pos
+            });
+
+            if let Some(usage_document) = usage_document {
+                usage_document.start_column = name_begin_pos;
+            }
+        }
+    }
+}
+
+// The controllers and actions a Rails routing call points at: the
+// `"users#show"` in `get "profile" => "users#show"` or `to: "users#show"`,
+// the resources in `resources :users`, and `:preview` in
+// `member { get :preview }` inside of them
+pub(super) struct RouteHandler;
+
+impl NodeHandler for RouteHandler {
+    fn handle_after(&self, node: &Node, context: &mut NodeContext) {
+        let Node::Send(Send {
+            recv: None,
+            method_name,
+            args,
+            ..
+        }) = node
+        else {
+            return;
+        };
+
+        let route_scopes: Vec<RouteScope> = context
+            .block_calls()
+            .iter()
+            .filter_map(|call| route_scope(call))
+            .collect();
+        let route_targets = route_targets(method_name, args, &route_scopes, context);
+
+        context.findings().route_targets.extend(route_targets);
+    }
+}
+
+// What a routing block like `namespace :admin do` or `resources :users do`
+// applies to the routes in it
+struct RouteScope {
+    module: Option<String>,
+    controller: Option<String>,
+}
+
+fn route_targets(
+    method_name: &str,
+    args: &[Node],
+    route_scopes: &[RouteScope],
+    context: &NodeContext,
+) -> Vec<RouteTarget> {
+    let input = context.input();
+    let mut route_targets = vec![];
+
+    match method_name {
+        "get" | "post" | "put" | "patch" | "delete" | "match" | "root" => {
+            let mut endpoints = vec![];
+
+            for (index, arg) in args.iter().enumerate() {
+                match arg {
+                    Node::Str(endpoint) if method_name == "root" && index == 0 => {
+                        endpoints.push(endpoint)
+                    }
+                    Node::Hash(Hash { pairs, .. }) | Node::Kwargs(Kwargs { pairs, .. }) => {
+                        for pair in pairs {
+                            let Node::Pair(Pair { key, value, .. }) = pair else {
+                                continue;
+                            };
+
+                            match (key.as_ref(), value.as_ref()) {
+                                (Node::Sym(Sym { name, .. }), Node::Str(endpoint))
+                                    if name.to_string_lossy() == "to" =>
+                                {
+                                    endpoints.push(endpoint)
+                                }
+                                (Node::Str(_), Node::Str(endpoint)) => endpoints.push(endpoint),
+                                _ => {}
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            for endpoint in endpoints {
+                let Str {
+                    value,
+                    begin_l: Some(begin_l),
+                    end_l: Some(end_l),
+                    ..
+                } = endpoint
+                else {
+                    continue;
+                };
+                let value = value.to_string_lossy();
+                let Some((controller, action)) = value.split_once('#') else {
+                    continue;
+                };
+
+                let (lineno, begin_pos) = input.line_col_for_pos(begin_l.end).unwrap();
+                let (_lineno, end_pos) = input.line_col_for_pos(end_l.begin).unwrap();
+
+                route_targets.push(RouteTarget {
+                    controller: route_controller(controller, route_scopes),
+                    action: Some(action.to_string()),
+                    line: lineno,
+                    start_column: begin_pos,
+                    end_column: end_pos,
+                });
+            }
+
+            // `get :preview` goes to the action of the resource it's in
+            let resource_controller = route_scopes
+                .iter()
+                .rev()
+                .find_map(|route_scope| route_scope.controller.as_ref());
+
+            if let (Some(Node::Sym(sym)), Some(controller), true) =
+                (args.first(), resource_controller, route_targets.is_empty())
+            {
+                let (lineno, begin_pos) = input.line_col_for_pos(sym.expression_l.begin).unwrap();
+                let (_lineno, end_pos) = input.line_col_for_pos(sym.expression_l.end).unwrap();
+
+                route_targets.push(RouteTarget {
+                    controller: route_controller(controller, route_scopes),
+                    action: Some(sym.name.to_string_lossy()),
+                    line: lineno,
+                    start_column: begin_pos,
+                    end_column: end_pos,
+                });
+            }
+        }
+        "resources" | "resource" => {
+            for arg in args {
+                let Node::Sym(sym) = arg else {
+                    continue;
+                };
+
+                let controller = route_option(args, "controller")
+                    .unwrap_or_else(|| resource_controller(method_name, sym));
+                let (lineno, begin_pos) = input.line_col_for_pos(sym.expression_l.begin).unwrap();
+                let (_lineno, end_pos) = input.line_col_for_pos(sym.expression_l.end).unwrap();
+
+                route_targets.push(RouteTarget {
+                    controller: route_controller(&controller, route_scopes),
+                    action: None,
+                    line: lineno,
+                    start_column: begin_pos,
+                    end_column: end_pos,
+                });
+            }
+        }
+        _ => {}
+    }
+
+    route_targets
+}
+
+// The module or controller a routing block's routes are in
+fn route_scope(call: &Node) -> Option<RouteScope> {
+    let Node::Send(Send {
+        recv: None,
+        method_name,
+        args,
+        ..
+    }) = call
+    else {
+        return None;
+    };
+
+    let first_name = || match args.first() {
+        Some(Node::Sym(Sym { name, .. })) => Some(name.to_string_lossy()),
+        Some(Node::Str(Str { value, .. })) => Some(value.to_string_lossy()),
+        _ => None,
+    };
+
+    let (module, controller) = match method_name.as_str() {
+        "namespace" => (first_name(), None),
+        "scope" => (route_option(args, "module"), None),
+        "controller" => (None, first_name()),
+        "resources" | "resource" => {
+            let Some(Node::Sym(sym)) = args.first() else {
+                return None;
+            };
+
+            let controller = route_option(args, "controller")
+                .unwrap_or_else(|| resource_controller(method_name, sym));
+
+            (None, Some(controller))
+        }
+        _ => return None,
+    };
+
+    Some(RouteScope { module, controller })
+}
+
+// The class of a controller in a route, inside the modules of the blocks
+// it's in, e.g. `Admin::UsersController` for `users` in `namespace :admin`
+fn route_controller(controller: &str, route_scopes: &[RouteScope]) -> String {
+    let mut path: Vec<&str> = match controller.strip_prefix('/') {
+        Some(_) => vec![],
+        None => route_scopes
+            .iter()
+            .filter_map(|route_scope| route_scope.module.as_deref())
+            .collect(),
+    };
+    path.push(controller.trim_start_matches('/'));
+
+    controller_class_name(&path.join("/"))
+}
+
+// Method names passed to a callback macro, including `if:` and `unless:`
+// conditions, e.g. `before_action :authenticate, if: :signed_in?`
+fn callback_symbols(args: &[Node]) -> Vec<&Sym> {
+    let mut callback_symbols = vec![];
+
+    for node in args {
+        match node {
+            Node::Sym(sym) => callback_symbols.push(sym),
+            Node::Kwargs(Kwargs { pairs, .. }) | Node::Hash(Hash { pairs, .. }) => {
+                for pair in pairs {
+                    let Node::Pair(Pair { key, value, .. }) = pair else {
+                        continue;
+                    };
+
+                    let condition_key = matches!(
+                        key.as_ref(),
+                        Node::Sym(Sym { name, .. })
+                            if matches!(name.to_string_lossy().as_str(), "if" | "unless")
+                    );
+
+                    if !condition_key {
+                        continue;
+                    }
+
+                    match value.as_ref() {
+                        Node::Sym(sym) => callback_symbols.push(sym),
+                        Node::Array(Array { elements, .. }) => {
+                            for element in elements {
+                                if let Node::Sym(sym) = element {
+                                    callback_symbols.push(sym);
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    callback_symbols
+}
+
+// An option of a routing call, e.g. `"images"` in `controller: "images"`
+fn route_option(args: &[Node], option_name: &str) -> Option<String> {
+    let pairs = args.iter().find_map(|arg| match arg {
+        Node::Hash(Hash { pairs, .. }) | Node::Kwargs(Kwargs { pairs, .. }) => Some(pairs),
+        _ => None,
+    })?;
+
+    pairs.iter().find_map(|pair| match pair {
+        Node::Pair(Pair { key, value, .. }) => match (key.as_ref(), value.as_ref()) {
+            (Node::Sym(Sym { name, .. }), Node::Sym(Sym { name: value, .. }))
+                if name.to_string_lossy() == option_name =>
+            {
+                Some(value.to_string_lossy())
+            }
+            (Node::Sym(Sym { name, .. }), Node::Str(Str { value, .. }))
+                if name.to_string_lossy() == option_name =>
+            {
+                Some(value.to_string_lossy())
+            }
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+// The controller of `resources :users` is `users`, and of the singular
+// `resource :profile` it's still the plural `profiles`
+fn resource_controller(method_name: &str, sym: &Sym) -> String {
+    let name = sym.name.to_string_lossy();
+
+    if method_name == "resource" {
+        pluralize(&name)
+    } else {
+        name
+    }
+}
+
+// The instance methods Rails generates for an association macro, e.g.
+// `has_many :comments` defines `comments`, `comments=`, `comment_ids`, and
+// `comment_ids=`
+fn association_method_names(macro_name: &str, association_name: &str) -> Vec<String> {
+    let mut names = vec![
+        association_name.to_string(),
+        format!("{}=", association_name),
+    ];
+
+    match macro_name {
+        "belongs_to" | "has_one" => {
+            names.push(format!("build_{}", association_name));
+            names.push(format!("create_{}", association_name));
+            names.push(format!("create_{}!", association_name));
+            names.push(format!("reload_{}", association_name));
+        }
+        _ => {
+            let singular_name = singularize(association_name);
+
+            names.push(format!("{}_ids", singular_name));
+            names.push(format!("{}_ids=", singular_name));
+        }
+    }
+
+    names
+}
@@ -0,0 +1,141 @@
+use lib_ruby_parser::{nodes::*, Node};
+
+use super::{rspec_receiver, NodeContext, NodeHandler, SharedGroupReference, RSPEC_EXAMPLE_GROUPS};
+
+// `let(:user)` and `subject(:user)` as methods of the example group they're
+// in. Opt-in since they pollute searches in app code, and usages outside of
+// spec files ignore these definitions.
+pub(super) struct LetHandler;
+
+impl NodeHandler for LetHandler {
+    fn handle_after(&self, node: &Node, context: &mut NodeContext) {
+        let Node::Send(Send {
+            recv: None,
+            method_name,
+            args,
+            ..
+        }) = node
+        else {
+            return;
+        };
+
+        if !context.settings().index_rspec_let
+            || !matches!(
+                method_name.as_str(),
+                "let" | "let!" | "subject" | "subject!"
+            )
+        {
+            return;
+        }
+
+        let Some(Node::Sym(Sym {
+            name, expression_l, ..
+        })) = args.first()
+        else {
+            return;
+        };
+
+        if let Some(span) = context.loc_span(expression_l) {
+            context.push_definition(name.to_string_lossy(), "Def", span, None);
+        }
+    }
+
+    // So lets in different example groups don't look like the same method
+    fn block_scope(&self, call: &Node, context: &NodeContext) -> Option<String> {
+        if context.settings().index_rspec_let {
+            example_group_scope_name(call)
+        } else {
+            None
+        }
+    }
+}
+
+// `shared_examples "an auditable model"` and `shared_context`, and the
+// `it_behaves_like` and `include_context` calls that use them
+pub(super) struct SharedExampleHandler;
+
+impl NodeHandler for SharedExampleHandler {
+    fn handle_after(&self, node: &Node, context: &mut NodeContext) {
+        let Node::Send(Send {
+            recv,
+            method_name,
+            args,
+            ..
+        }) = node
+        else {
+            return;
+        };
+
+        let defines = matches!(
+            method_name.as_str(),
+            "shared_examples" | "shared_examples_for" | "shared_context"
+        );
+        let references = matches!(
+            method_name.as_str(),
+            "it_behaves_like" | "it_should_behave_like" | "include_examples" | "include_context"
+        );
+
+        if !defines && !references {
+            return;
+        }
+
+        let Some((name, line, start_column, end_column)) =
+            args.first().and_then(|arg| context.literal_span(arg))
+        else {
+            return;
+        };
+
+        if defines && (recv.is_none() || rspec_receiver(recv)) {
+            let node_type = if method_name == "shared_context" {
+                "SharedContext"
+            } else {
+                "SharedExamples"
+            };
+
+            context.push_definition(name, node_type, (line, start_column, end_column), None);
+        } else if references && recv.is_none() {
+            context
+                .findings()
+                .shared_group_references
+                .push(SharedGroupReference {
+                    name,
+                    line,
+                    start_column,
+                    end_column,
+                });
+        }
+    }
+}
+
+// Names `describe`/`context` blocks like `describe User` or
+// `context "when signed in"`. Lowercase so they aren't mistaken for a class.
+pub(super) fn example_group_scope_name(call: &Node) -> Option<String> {
+    match call {
+        Node::Send(Send {
+            recv,
+            method_name,
+            args,
+            ..
+        }) if RSPEC_EXAMPLE_GROUPS.contains(method_name.as_str()) => {
+            let rspec_receiver = match recv {
+                None => true,
+                Some(recv_node) => {
+                    matches!(recv_node.as_ref(), Node::Const(Const { name, .. }) if name == "RSpec")
+                }
+            };
+
+            if !rspec_receiver {
+                return None;
+            }
+
+            let description = match args.first() {
+                Some(Node::Const(const_node)) => const_node.name.to_string(),
+                Some(Node::Str(Str { value, .. })) => value.to_string_lossy(),
+                _ => String::new(),
+            };
+
+            Some(format!("{} {}", method_name, description))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,2629 @@
+use lib_ruby_parser::source::DecodedInput;
+use lib_ruby_parser::{nodes::*, Loc, Node};
+use log::info;
+use phf::{phf_map, phf_set};
+use std::collections::HashMap;
+use tower_lsp::lsp_types::Range;
+
+use super::{
+    described_class, i18n_receiver, literal_span, loc_range, refined_class, rendered_view,
+    schema_definition, singularize, sorbet_sig, sorbet_sig_block, statements, translation_key,
+    FuzzyNode, IndexSettings, MethodCall, NameCheck, NodeContext, ParseFindings, RequiredPath,
+    ScopeSpan, SorbetSig, TestBlock, Visitor,
+};
+use crate::persistence::{
+    camelize, identifier_name, setter_reader_name, SINGLETON_RECEIVER_SCOPE, TOP_LEVEL_SCOPE,
+};
+
+// Class methods that return an instance of the receiver, used to infer the
+// class of local variables
+static INSTANCE_RETURNING_METHODS: phf::Set<&'static str> = phf_set! {
+    "new", "find", "find_by", "find_by!", "find_or_create_by", "find_or_create_by!",
+    "find_or_initialize_by", "create", "create!", "first", "first!", "last", "last!",
+    "take", "take!",
+};
+
+// Methods from Kernel, Object, and Module that are commonly called without a
+// receiver and are never indexed
+static KERNEL_METHODS: phf::Set<&'static str> = phf_set! {
+    "Array", "Complex", "Float", "Hash", "Integer", "Rational", "String", "__method__",
+    "abort", "alias_method", "at_exit", "attr_accessor", "attr_reader", "attr_writer",
+    "autoload", "binding", "block_given?", "caller", "catch", "class", "clone",
+    "define_method", "define_singleton_method", "dup", "eval", "exit", "exit!", "extend",
+    "fail", "format", "freeze", "frozen?", "gets", "hash", "include", "inspect",
+    "instance_eval", "instance_exec", "instance_of?", "instance_variable_defined?",
+    "instance_variable_get", "instance_variable_set", "instance_variables", "is_a?",
+    "itself", "kind_of?", "lambda", "loop", "method", "methods", "module_function", "nil?",
+    "object_id", "p", "pp", "prepend", "print", "printf", "private", "private_class_method",
+    "proc", "protected", "public", "public_send", "puts", "raise", "rand", "require",
+    "require_relative", "respond_to?", "send", "singleton_class", "sleep", "sprintf",
+    "srand", "system", "tap", "then", "throw", "to_s", "warn", "yield_self", "__send__",
+};
+
+// Built-in constants that aren't defined in Ruby source, so they're never
+// indexed
+static RUBY_CORE_CONSTANTS: phf::Set<&'static str> = phf_set! {
+    "ARGF", "ARGV", "ArgumentError", "Array", "BasicObject", "Binding", "Class",
+    "Comparable", "Complex", "Data", "Dir", "ENV", "EOFError", "Encoding", "Enumerable",
+    "Enumerator", "Errno", "Exception", "FalseClass", "Fiber", "File", "FileTest", "Float",
+    "FloatDomainError", "FrozenError", "GC", "Hash", "IO", "IOError", "IndexError",
+    "Integer", "Interrupt", "Kernel", "KeyError", "LoadError", "LocalJumpError", "Marshal",
+    "MatchData", "Math", "Method", "Module", "NameError", "NilClass", "NoMatchingPatternError",
+    "NoMethodError", "NotImplementedError", "Numeric", "Object", "ObjectSpace", "Proc",
+    "Process", "RUBY_PLATFORM", "RUBY_VERSION", "Ractor", "Random", "Range", "RangeError",
+    "Rational", "Regexp", "RegexpError", "RuntimeError", "STDERR", "STDIN", "STDOUT",
+    "ScriptError", "SecurityError", "Signal", "SignalException", "StandardError",
+    "StopIteration", "String", "Struct", "Symbol", "SyntaxError", "SystemCallError",
+    "SystemExit", "Thread", "ThreadError", "Time", "TracePoint", "TrueClass", "TypeError",
+    "UnboundMethod", "ZeroDivisionError",
+};
+
+// The example groups and examples that can be run on their own
+static RSPEC_RUNNABLE_BLOCKS: phf::Map<&'static str, bool> = phf_map! {
+    "describe" => true, "context" => true, "feature" => true, "it" => false,
+    "specify" => false, "example" => false, "scenario" => false,
+};
+
+impl<'v> Visitor<'v> {
+    pub(super) fn new(
+        settings: &'v IndexSettings,
+        index_interface_only: bool,
+        input: &'v DecodedInput,
+        comment_lines: HashMap<usize, String>,
+    ) -> Visitor<'v> {
+        Visitor {
+            settings,
+            index_interface_only,
+            input,
+            comment_lines,
+            class_scope: vec![],
+            local_variable_classes: HashMap::new(),
+            defining_class_methods: false,
+            current_visibility: "public",
+            module_function_section: false,
+            pending_sig: None,
+            refinement: None,
+            block_calls: vec![],
+            findings: ParseFindings::default(),
+        }
+    }
+
+    // Shows the node to the handlers before and after serializing it
+    pub(super) fn serialize(
+        &mut self,
+        node: &'v Node,
+        documents: &mut Vec<FuzzyNode>,
+        fuzzy_scope: &mut Vec<String>,
+    ) {
+        let settings = self.settings;
+
+        let mut context = NodeContext {
+            visitor: self,
+            fuzzy_scope,
+            documents,
+        };
+
+        for node_handler in &settings.node_handlers {
+            node_handler.handle(node, &mut context);
+        }
+
+        self.serialize_node(node, documents, fuzzy_scope);
+
+        let mut context = NodeContext {
+            visitor: self,
+            fuzzy_scope,
+            documents,
+        };
+
+        for node_handler in &settings.node_handlers {
+            node_handler.handle_after(node, &mut context);
+        }
+    }
+
+    fn serialize_node(
+        &mut self,
+        node: &'v Node,
+        documents: &mut Vec<FuzzyNode>,
+        fuzzy_scope: &mut Vec<String>,
+    ) {
+        match node {
+            Node::Alias(Alias { to, from, .. }) => {
+                if let Node::Sym(sym) = to.as_ref() {
+                    let name = sym.name.to_string_lossy();
+                    documents.push(self.document(
+                        "assignment",
+                        "Alias",
+                        name,
+                        &sym.expression_l,
+                        fuzzy_scope,
+                    ));
+                }
+
+                if let Node::Sym(sym) = from.as_ref() {
+                    let name = sym.name.to_string_lossy();
+                    documents.push(self.document(
+                        "usage",
+                        "Alias",
+                        name,
+                        &sym.expression_l,
+                        fuzzy_scope,
+                    ));
+                }
+            }
+
+            Node::And(And { lhs, rhs, .. }) => {
+                self.serialize(lhs, documents, fuzzy_scope);
+                self.serialize(rhs, documents, fuzzy_scope);
+            }
+
+            Node::AndAsgn(AndAsgn { recv, value, .. }) => {
+                self.serialize(recv, documents, fuzzy_scope);
+                self.mark_setter_usage(recv, documents);
+                self.mark_op_assigned_local(recv);
+                self.serialize(value, documents, fuzzy_scope);
+            }
+
+            Node::Arg(Arg { name, expression_l }) => {
+                documents.push(self.document(
+                    "assignment",
+                    "Arg",
+                    name.to_string(),
+                    expression_l,
+                    fuzzy_scope,
+                ));
+            }
+
+            Node::Args(Args { args, .. }) => {
+                if self.index_interface_only {
+                    return;
+                }
+
+                for node in args {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Array(Array { elements, .. }) => {
+                for node in elements {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::ArrayPattern(ArrayPattern { elements, .. }) => {
+                for node in elements {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::ArrayPatternWithTail(ArrayPatternWithTail { elements, .. }) => {
+                for node in elements {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            // Node::BackRef(BackRef { .. }) => {}
+            Node::Begin(Begin { statements, .. }) => {
+                for child_node in statements {
+                    self.serialize(child_node, documents, fuzzy_scope);
+
+                    // A `sig` only describes the method right after it
+                    if !sorbet_sig_block(child_node) {
+                        self.pending_sig = None;
+                    }
+                }
+            }
+
+            Node::Block(block) => self.serialize_block(node, block, documents, fuzzy_scope),
+
+            // `&block`, but not an anonymous `&` that's only passed on
+            Node::Blockarg(Blockarg {
+                name: Some(name),
+                name_l: Some(name_l),
+                ..
+            }) => {
+                documents.push(self.document(
+                    "assignment",
+                    "Blockarg",
+                    name.to_string(),
+                    name_l,
+                    fuzzy_scope,
+                ));
+            }
+
+            Node::BlockPass(BlockPass { value, .. }) => {
+                if let Some(child_node) = value {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Break(Break { args, .. }) => {
+                for child_node in args {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Case(Case {
+                expr,
+                when_bodies,
+                else_body,
+                ..
+            }) => {
+                if let Some(child_node) = expr {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+
+                for child_node in when_bodies {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+
+                if let Some(child_node) = else_body {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::CaseMatch(CaseMatch {
+                expr,
+                in_bodies,
+                else_body,
+                ..
+            }) => {
+                self.serialize(expr, documents, fuzzy_scope);
+
+                for child_node in in_bodies {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+
+                if let Some(child_node) = else_body {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Casgn(Casgn {
+                scope,
+                name,
+                value,
+                name_l,
+                ..
+            }) => {
+                let const_node = Const {
+                    scope: scope.to_owned(),
+                    name: "".to_string(),
+                    double_colon_l: None,
+                    name_l: Loc { begin: 0, end: 0 },
+                    expression_l: Loc { begin: 0, end: 0 },
+                };
+
+                documents.push(FuzzyNode {
+                    class_scope: self.build_class_scope(&const_node),
+                    ..self.document("assignment", "Casgn", name.to_string(), name_l, fuzzy_scope)
+                });
+
+                if let Some(child_node) = scope {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+
+                if let Some(child_node) = value {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            // Node::Cbase(Cbase { .. }) => {}
+            Node::Class(class) => self.serialize_class(class, documents, fuzzy_scope),
+
+            // Node::Complex(Complex { .. }) => {}
+            Node::Const(constant) => self.serialize_const(constant, documents, fuzzy_scope),
+
+            Node::ConstPattern(ConstPattern {
+                const_, pattern, ..
+            }) => {
+                self.serialize(const_, documents, fuzzy_scope);
+                self.serialize(pattern, documents, fuzzy_scope);
+            }
+
+            Node::CSend(CSend {
+                recv,
+                method_name,
+                args,
+                selector_l,
+                ..
+            }) => {
+                if let Some(loc) = selector_l {
+                    documents.push(self.document(
+                        "usage",
+                        "CSend",
+                        method_name.to_string(),
+                        loc,
+                        fuzzy_scope,
+                    ));
+                }
+
+                self.serialize(recv, documents, fuzzy_scope);
+
+                for child_node in args {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Cvar(Cvar { name, expression_l }) => {
+                documents.push(self.document(
+                    "usage",
+                    "Cvar",
+                    name.to_string(),
+                    expression_l,
+                    fuzzy_scope,
+                ));
+            }
+
+            Node::Cvasgn(Cvasgn {
+                name,
+                value,
+                name_l,
+                ..
+            }) => {
+                documents.push(self.document(
+                    "assignment",
+                    "Cvasgn",
+                    name.to_string(),
+                    name_l,
+                    fuzzy_scope,
+                ));
+
+                if let Some(child_node) = value {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Def(def) => self.serialize_def(def, documents, fuzzy_scope),
+
+            Node::Defined(Defined { value, .. }) => {
+                self.serialize(value, documents, fuzzy_scope);
+            }
+
+            Node::Defs(defs) => self.serialize_defs(defs, documents, fuzzy_scope),
+
+            Node::Dstr(Dstr { parts, .. }) => {
+                for child_node in parts {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Dsym(Dsym { parts, .. }) => {
+                for child_node in parts {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::EFlipFlop(EFlipFlop { left, right, .. }) => {
+                if let Some(child_node) = left {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+
+                if let Some(child_node) = right {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            // Node::EmptyElse(EmptyElse { .. }) => {}
+            // Node::Encoding(Encoding { .. }) => {}
+            Node::Ensure(Ensure { body, ensure, .. }) => {
+                if let Some(child_node) = body {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+
+                if let Some(child_node) = ensure {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Erange(Erange { left, right, .. }) => {
+                if let Some(child_node) = left {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+
+                if let Some(child_node) = right {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            // Node::False(False { .. }) => {}
+            // Node::File(File { .. }) => {}
+            Node::FindPattern(FindPattern { elements, .. }) => {
+                for child_node in elements {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            // Node::Float(Float { .. }) => {}
+            Node::For(For {
+                iterator,
+                iteratee,
+                body,
+                ..
+            }) => {
+                self.serialize(iterator, documents, fuzzy_scope);
+                self.serialize(iteratee, documents, fuzzy_scope);
+
+                for child_node in body {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            // Node::ForwardArg(ForwardArg { .. }) => {}
+            // Node::ForwardedArgs(ForwardedArgs { .. }) => {}
+            Node::Gvar(Gvar { name, expression_l }) => {
+                documents.push(self.document(
+                    "usage",
+                    "Gvar",
+                    name.to_string(),
+                    expression_l,
+                    fuzzy_scope,
+                ));
+            }
+
+            Node::Gvasgn(Gvasgn {
+                name,
+                value,
+                name_l,
+                ..
+            }) => {
+                documents.push(self.document(
+                    "assignment",
+                    "Gvasgn",
+                    name.to_string(),
+                    name_l,
+                    fuzzy_scope,
+                ));
+
+                if let Some(child_node) = value {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Hash(Hash { pairs, .. }) => {
+                for child_node in pairs {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::HashPattern(HashPattern { elements, .. }) => {
+                for child_node in elements {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Heredoc(Heredoc { parts, .. }) => {
+                for child_node in parts {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::If(If {
+                cond,
+                if_true,
+                if_false,
+                ..
+            }) => {
+                self.serialize(cond, documents, fuzzy_scope);
+
+                if let Some(child_node) = if_true {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+
+                if let Some(child_node) = if_false {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::IfGuard(IfGuard { cond, .. }) => {
+                self.serialize(cond, documents, fuzzy_scope);
+            }
+
+            Node::IFlipFlop(IFlipFlop { left, right, .. }) => {
+                if let Some(child_node) = left {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+
+                if let Some(child_node) = right {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::IfMod(IfMod {
+                cond,
+                if_true,
+                if_false,
+                ..
+            }) => {
+                self.serialize(cond, documents, fuzzy_scope);
+
+                if let Some(child_node) = if_true {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+
+                if let Some(child_node) = if_false {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::IfTernary(IfTernary {
+                cond,
+                if_true,
+                if_false,
+                ..
+            }) => {
+                self.serialize(cond, documents, fuzzy_scope);
+                self.serialize(if_true, documents, fuzzy_scope);
+                self.serialize(if_false, documents, fuzzy_scope);
+            }
+
+            Node::Index(lib_ruby_parser::nodes::Index { recv, indexes, .. }) => {
+                self.serialize(recv, documents, fuzzy_scope);
+
+                for child_node in indexes {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::IndexAsgn(IndexAsgn {
+                recv,
+                indexes,
+                value,
+                ..
+            }) => {
+                self.serialize(recv, documents, fuzzy_scope);
+
+                for child_node in indexes {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+
+                if let Some(child_node) = value {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::InPattern(InPattern {
+                pattern,
+                guard,
+                body,
+                ..
+            }) => {
+                self.serialize(pattern, documents, fuzzy_scope);
+
+                if let Some(child_node) = guard {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+
+                if let Some(child_node) = body {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            // Node::Int(Int { .. }) => {}
+            Node::Irange(Irange { left, right, .. }) => {
+                if let Some(child_node) = left {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+
+                if let Some(child_node) = right {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Ivar(Ivar { name, expression_l }) => {
+                documents.push(self.document(
+                    "usage",
+                    "Ivar",
+                    name.to_string(),
+                    expression_l,
+                    fuzzy_scope,
+                ));
+            }
+
+            Node::Ivasgn(Ivasgn {
+                name,
+                value,
+                name_l,
+                ..
+            }) => {
+                documents.push(self.document(
+                    "assignment",
+                    "Ivasgn",
+                    name.to_string(),
+                    name_l,
+                    fuzzy_scope,
+                ));
+
+                if let Some(child_node) = value {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Kwarg(Kwarg { name, name_l, .. }) => {
+                documents.push(self.document(
+                    "assignment",
+                    "Kwarg",
+                    name.to_string(),
+                    name_l,
+                    fuzzy_scope,
+                ));
+            }
+
+            Node::Kwargs(Kwargs { pairs, .. }) => {
+                for node in pairs {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::KwBegin(KwBegin { statements, .. }) => {
+                for node in statements {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            // Node::Kwnilarg(Kwnilarg { .. }) => {}
+            Node::Kwoptarg(Kwoptarg {
+                name,
+                default,
+                name_l,
+                ..
+            }) => {
+                documents.push(self.document(
+                    "assignment",
+                    "Kwoptarg",
+                    name.to_string(),
+                    name_l,
+                    fuzzy_scope,
+                ));
+
+                self.serialize(default, documents, fuzzy_scope);
+            }
+
+            Node::Kwrestarg(Kwrestarg {
+                name: Some(name),
+                name_l: Some(name_l),
+                ..
+            }) => {
+                documents.push(self.document(
+                    "assignment",
+                    "Kwrestarg",
+                    name.to_string(),
+                    name_l,
+                    fuzzy_scope,
+                ));
+            }
+
+            Node::Kwsplat(Kwsplat { value, .. }) => {
+                self.serialize(value, documents, fuzzy_scope);
+            }
+
+            // Node::Lambda(Lambda { .. }) => {}
+            // Node::Line(Line { .. }) => {}
+            Node::Lvar(Lvar { name, expression_l }) => {
+                documents.push(self.document(
+                    "usage",
+                    "Lvar",
+                    name.to_string(),
+                    expression_l,
+                    fuzzy_scope,
+                ));
+            }
+
+            Node::Lvasgn(Lvasgn {
+                name,
+                value,
+                name_l,
+                ..
+            }) => {
+                documents.push(self.document(
+                    "assignment",
+                    "Lvasgn",
+                    name.to_string(),
+                    name_l,
+                    fuzzy_scope,
+                ));
+
+                if let Some(child_node) = value {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+
+                // Assigned after serializing the value so `user = user.reload`
+                // still uses the previous class
+                match value
+                    .as_deref()
+                    .and_then(|value| self.inferred_class_scope(value))
+                {
+                    Some(inferred_class_scope) => {
+                        self.local_variable_classes
+                            .insert(name.to_string(), inferred_class_scope);
+                    }
+                    None => {
+                        self.local_variable_classes.remove(name.as_str());
+                    }
+                }
+            }
+
+            Node::Masgn(Masgn { lhs, rhs, .. }) => {
+                self.serialize(lhs, documents, fuzzy_scope);
+                self.serialize(rhs, documents, fuzzy_scope);
+            }
+
+            Node::MatchAlt(MatchAlt { lhs, rhs, .. }) => {
+                self.serialize(lhs, documents, fuzzy_scope);
+                self.serialize(rhs, documents, fuzzy_scope);
+            }
+
+            Node::MatchAs(MatchAs { value, as_, .. }) => {
+                self.serialize(value, documents, fuzzy_scope);
+                self.serialize(as_, documents, fuzzy_scope);
+            }
+
+            Node::MatchCurrentLine(MatchCurrentLine { re, .. }) => {
+                self.serialize(re, documents, fuzzy_scope);
+            }
+
+            // Node::MatchNilPattern(MatchNilPattern { .. }) => {}
+            Node::MatchPattern(MatchPattern { value, pattern, .. }) => {
+                self.serialize(value, documents, fuzzy_scope);
+                self.serialize(pattern, documents, fuzzy_scope);
+            }
+
+            Node::MatchPatternP(MatchPatternP { value, pattern, .. }) => {
+                self.serialize(value, documents, fuzzy_scope);
+                self.serialize(pattern, documents, fuzzy_scope);
+            }
+
+            Node::MatchRest(MatchRest { name, .. }) => {
+                if let Some(child_node) = name {
+                    self.serialize(child_node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::MatchVar(MatchVar { name, name_l, .. }) => {
+                documents.push(self.document(
+                    "assignment",
+                    "MatchVar",
+                    name.to_string(),
+                    name_l,
+                    fuzzy_scope,
+                ));
+            }
+
+            Node::MatchWithLvasgn(MatchWithLvasgn { re, value, .. }) => {
+                self.serialize(re, documents, fuzzy_scope);
+                self.serialize(value, documents, fuzzy_scope);
+            }
+
+            Node::Mlhs(Mlhs { items, .. }) => {
+                for node in items {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Module(module) => self.serialize_module(module, documents, fuzzy_scope),
+
+            Node::Next(Next { args, .. }) => {
+                for node in args {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            // Node::Nil(Nil { .. }) => {}
+            // Node::NthRef(NthRef { .. }) => {}
+            Node::Numblock(Numblock { call, body, .. }) => {
+                self.serialize(call, documents, fuzzy_scope);
+                self.serialize(body, documents, fuzzy_scope);
+            }
+
+            Node::OpAsgn(OpAsgn { recv, value, .. }) => {
+                self.serialize(recv, documents, fuzzy_scope);
+                self.mark_setter_usage(recv, documents);
+                self.mark_op_assigned_local(recv);
+                self.serialize(value, documents, fuzzy_scope);
+            }
+
+            Node::Optarg(Optarg {
+                name,
+                default,
+                name_l,
+                ..
+            }) => {
+                documents.push(self.document(
+                    "assignment",
+                    "Optarg",
+                    name.to_string(),
+                    name_l,
+                    fuzzy_scope,
+                ));
+
+                self.serialize(default, documents, fuzzy_scope);
+            }
+
+            Node::Or(Or { lhs, rhs, .. }) => {
+                self.serialize(lhs, documents, fuzzy_scope);
+                self.serialize(rhs, documents, fuzzy_scope);
+            }
+
+            Node::OrAsgn(OrAsgn { recv, value, .. }) => {
+                self.serialize(recv, documents, fuzzy_scope);
+                self.mark_setter_usage(recv, documents);
+                self.mark_op_assigned_local(recv);
+                self.serialize(value, documents, fuzzy_scope);
+            }
+
+            Node::Pair(Pair { key, value, .. }) => {
+                // The key of a shorthand pair like `{ name: }` is also its
+                // value, which is what it refers to
+                if key.expression() != value.expression() {
+                    self.serialize(key, documents, fuzzy_scope);
+                }
+
+                self.serialize(value, documents, fuzzy_scope);
+            }
+
+            Node::Pin(Pin { var, .. }) => {
+                self.serialize(var, documents, fuzzy_scope);
+            }
+
+            Node::Postexe(Postexe { body, .. }) => {
+                for node in body {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Preexe(Preexe { body, .. }) => {
+                for node in body {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Procarg0(Procarg0 { args, .. }) => {
+                for node in args {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            // Node::Rational(Rational { .. }) => {}
+            // Node::Redo(Redo { .. }) => {}
+            Node::Regexp(Regexp { parts, options, .. }) => {
+                for node in parts {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+
+                for node in options {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            // Node::RegOpt(RegOpt { .. }) => {}
+            Node::Rescue(Rescue {
+                body,
+                rescue_bodies,
+                ..
+            }) => {
+                for node in body {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+
+                for node in rescue_bodies {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::RescueBody(RescueBody {
+                exc_list,
+                exc_var,
+                body,
+                ..
+            }) => {
+                for node in exc_list {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+
+                for node in exc_var {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+
+                for node in body {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Restarg(Restarg {
+                name: Some(name),
+                name_l: Some(name_l),
+                ..
+            }) => {
+                documents.push(self.document(
+                    "assignment",
+                    "Restarg",
+                    name.to_string(),
+                    name_l,
+                    fuzzy_scope,
+                ));
+            }
+
+            // Node::Retry(Retry { .. }) => {}
+            Node::Return(Return { args, .. }) => {
+                for node in args {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::SClass(SClass { expr, body, .. }) => {
+                self.serialize(expr, documents, fuzzy_scope);
+
+                let outer_visibility = std::mem::replace(&mut self.current_visibility, "public");
+                let outer_defining_class_methods = std::mem::replace(
+                    &mut self.defining_class_methods,
+                    matches!(expr.as_ref(), Node::Self_(_)),
+                );
+
+                for node in body {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+
+                self.defining_class_methods = outer_defining_class_methods;
+                self.current_visibility = outer_visibility;
+            }
+
+            // Node::Self_(Self_ { .. }) => {}
+            Node::Send(send) => self.serialize_send(send, documents, fuzzy_scope),
+
+            Node::Shadowarg(Shadowarg { name, expression_l }) => {
+                documents.push(self.document(
+                    "assignment",
+                    "Shadowarg",
+                    name.to_string(),
+                    expression_l,
+                    fuzzy_scope,
+                ));
+            }
+
+            Node::Splat(Splat { value, .. }) => {
+                for node in value {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            // Node::Str(Str { .. }) => {}
+            Node::Super(Super {
+                args, keyword_l, ..
+            }) => {
+                if let Some(last_scope_name) = fuzzy_scope.last() {
+                    let name = last_scope_name.to_string();
+                    documents.push(self.document("usage", "Super", name, keyword_l, fuzzy_scope));
+                }
+
+                for node in args {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Sym(Sym {
+                name, expression_l, ..
+            }) => {
+                documents.push(self.document(
+                    "usage",
+                    "Send",
+                    name.to_string_lossy(),
+                    expression_l,
+                    fuzzy_scope,
+                ));
+            }
+
+            // Node::True(True { .. }) => {}
+            Node::Undef(Undef { names, .. }) => {
+                for node in names {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::UnlessGuard(UnlessGuard { cond, .. }) => {
+                self.serialize(cond, documents, fuzzy_scope);
+            }
+
+            Node::Until(Until { cond, body, .. }) => {
+                self.serialize(cond, documents, fuzzy_scope);
+
+                for node in body {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::UntilPost(UntilPost { cond, body, .. }) => {
+                self.serialize(cond, documents, fuzzy_scope);
+                self.serialize(body, documents, fuzzy_scope);
+            }
+
+            Node::When(When { patterns, body, .. }) => {
+                for node in patterns {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+
+                for node in body {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::While(While { cond, body, .. }) => {
+                self.serialize(cond, documents, fuzzy_scope);
+
+                for node in body {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::WhilePost(WhilePost { cond, body, .. }) => {
+                self.serialize(cond, documents, fuzzy_scope);
+                self.serialize(body, documents, fuzzy_scope);
+            }
+
+            Node::XHeredoc(XHeredoc { parts, .. }) => {
+                for node in parts {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Xstr(Xstr { parts, .. }) => {
+                for node in parts {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::Yield(Yield { args, .. }) => {
+                for node in args {
+                    self.serialize(node, documents, fuzzy_scope);
+                }
+            }
+
+            Node::ZSuper(ZSuper { expression_l, .. }) => {
+                if let Some(last_scope_name) = fuzzy_scope.last() {
+                    let name = last_scope_name.to_string();
+                    documents.push(self.document(
+                        "usage",
+                        "ZSuper",
+                        name,
+                        expression_l,
+                        fuzzy_scope,
+                    ));
+                }
+            }
+
+            _ => {}
+        };
+    }
+
+    fn serialize_block(
+        &mut self,
+        node: &'v Node,
+        block: &'v Block,
+        documents: &mut Vec<FuzzyNode>,
+        fuzzy_scope: &mut Vec<String>,
+    ) {
+        let input = self.input;
+        let Block {
+            call, args, body, ..
+        } = block;
+
+        // Typed gems have them too, so they're read even when only
+        // their interface is indexed
+        if sorbet_sig_block(node) {
+            self.pending_sig = body.as_deref().map(|body| SorbetSig {
+                line: input
+                    .line_col_for_pos(node.expression().begin)
+                    .map(|(lineno, _column)| lineno),
+                ..sorbet_sig(body, input)
+            });
+        }
+
+        if self.index_interface_only {
+            return;
+        }
+
+        self.serialize(call, documents, fuzzy_scope);
+
+        if let Some(test_block) = self.test_block(call) {
+            self.findings.test_blocks.push(test_block);
+        }
+
+        // ActiveRecord generates column accessors at runtime, so the
+        // columns in `db/schema.rb` are indexed as their model's methods
+        if let (true, Some(body)) = (schema_definition(call), body) {
+            self.push_model_columns(body, documents);
+        }
+
+        // Handlers can scope what's defined in the block, like RSpec `let`
+        // definitions to the example group they're in
+        let block_scopes = self.block_scopes(call, documents, fuzzy_scope);
+        let block_scope_len = block_scopes.len();
+
+        fuzzy_scope.extend(block_scopes);
+        self.block_calls.push(call);
+
+        // `included do` bodies are evaluated in the including class,
+        // so they're indexed like the concern's own body. Only
+        // `class_methods do` changes what its methods are.
+        let outer_defining_class_methods = self.defining_class_methods;
+
+        if let Node::Send(Send {
+            recv: None,
+            method_name,
+            ..
+        }) = call.as_ref()
+        {
+            match method_name.as_str() {
+                "class_methods" => self.defining_class_methods = true,
+                "included" | "prepended" => self.defining_class_methods = false,
+                _ => {}
+            }
+        }
+
+        // Methods in `refine String do` are `String`'s, though only
+        // where the refinement is used. A `module_function` in the
+        // refining module doesn't apply to them.
+        let outer_refinement = refined_class(call).map(|refined_class| {
+            let mut refined_scope = self.build_class_scope(refined_class);
+            refined_scope.push(refined_class.name.to_string());
+
+            let outer_fuzzy_scope = std::mem::replace(fuzzy_scope, refined_scope);
+            let refinement = Some(outer_fuzzy_scope.join("::"));
+
+            (
+                outer_fuzzy_scope,
+                std::mem::replace(&mut self.refinement, refinement),
+                std::mem::replace(&mut self.module_function_section, false),
+            )
+        });
+
+        for child_node in args {
+            self.serialize(child_node, documents, fuzzy_scope);
+        }
+
+        if let Some(child_node) = body {
+            self.serialize(child_node, documents, fuzzy_scope);
+        }
+
+        if let Some((outer_fuzzy_scope, outer_refinement, outer_module_function_section)) =
+            outer_refinement
+        {
+            *fuzzy_scope = outer_fuzzy_scope;
+            self.refinement = outer_refinement;
+            self.module_function_section = outer_module_function_section;
+        }
+
+        self.defining_class_methods = outer_defining_class_methods;
+
+        self.block_calls.pop();
+
+        for _ in 0..block_scope_len {
+            fuzzy_scope.pop();
+        }
+    }
+
+    fn serialize_class(
+        &mut self,
+        class: &'v Class,
+        documents: &mut Vec<FuzzyNode>,
+        fuzzy_scope: &mut Vec<String>,
+    ) {
+        let input = self.input;
+        let Class {
+            name,
+            superclass,
+            body,
+            expression_l,
+            ..
+        } = class;
+
+        let full_range = self.push_scope_span(name.expression(), "Class", expression_l);
+
+        let Node::Const(const_node) = name.as_ref() else {
+            return;
+        };
+
+        // loop over names and add to fuzzy/class_scope
+        let node_class_scope = self.build_class_scope(const_node);
+        let class_scope_len = node_class_scope.len();
+
+        for ancestor_name in node_class_scope {
+            fuzzy_scope.push(ancestor_name);
+        }
+
+        let (lineno, begin_pos) = input
+            .line_col_for_pos(const_node.expression_l.begin)
+            .unwrap();
+        let (_lineno, end_pos) = input.line_col_for_pos(const_node.expression_l.end).unwrap();
+        let class_name = const_node.name.to_string();
+
+        let superclass_name = match superclass.as_deref() {
+            Some(Node::Const(superclass_const)) => Some(superclass_const.name.to_string()),
+            _ => None,
+        };
+
+        let document = FuzzyNode {
+            category: "assignment",
+            fuzzy_ruby_scope: fuzzy_scope.clone(),
+            // class_scope: node_class_scope,
+            class_scope: vec![],
+            name: class_name.clone(),
+            node_type: "Class",
+            line: lineno,
+            start_column: begin_pos,
+            end_column: end_pos,
+            full_range,
+            superclass: superclass_name,
+            visibility: None,
+            documentation: self.preceding_comment(lineno),
+            parameters: None,
+            parameter_types: None,
+            return_type: None,
+            refinement: None,
+        };
+
+        documents.push(document);
+
+        fuzzy_scope.push(class_name.to_string());
+        self.class_scope.push(class_name);
+
+        if let Some(scope_node) = &const_node.scope {
+            self.serialize(scope_node, documents, fuzzy_scope);
+        }
+
+        if let Some(superclass_node) = superclass {
+            self.serialize(superclass_node, documents, fuzzy_scope);
+        }
+
+        let outer_visibility = std::mem::replace(&mut self.current_visibility, "public");
+        let outer_defining_class_methods =
+            std::mem::replace(&mut self.defining_class_methods, false);
+        let outer_module_function_section =
+            std::mem::replace(&mut self.module_function_section, false);
+
+        for child_node in body {
+            self.serialize(child_node, documents, fuzzy_scope);
+        }
+
+        self.module_function_section = outer_module_function_section;
+        self.defining_class_methods = outer_defining_class_methods;
+        self.current_visibility = outer_visibility;
+
+        for _ in 0..class_scope_len {
+            fuzzy_scope.pop();
+        }
+
+        fuzzy_scope.pop();
+        self.class_scope.pop();
+    }
+
+    fn serialize_module(
+        &mut self,
+        module: &'v Module,
+        documents: &mut Vec<FuzzyNode>,
+        fuzzy_scope: &mut Vec<String>,
+    ) {
+        let input = self.input;
+        let Module {
+            name,
+            body,
+            expression_l,
+            ..
+        } = module;
+
+        let full_range = self.push_scope_span(name.expression(), "Module", expression_l);
+
+        let Node::Const(const_node) = name.as_ref() else {
+            return;
+        };
+
+        let node_class_scope = self.build_class_scope(const_node);
+        let class_scope_len = node_class_scope.len();
+
+        for ancestor_name in node_class_scope {
+            fuzzy_scope.push(ancestor_name);
+        }
+
+        let (lineno, begin_pos) = input
+            .line_col_for_pos(const_node.expression_l.begin)
+            .unwrap();
+        let (_lineno, end_pos) = input.line_col_for_pos(const_node.expression_l.end).unwrap();
+        let class_name = const_node.name.to_string();
+
+        documents.push(FuzzyNode {
+            category: "assignment",
+            fuzzy_ruby_scope: fuzzy_scope.clone(),
+            // class_scope: node_class_scope,
+            class_scope: vec![],
+            name: class_name.clone(),
+            node_type: "Module",
+            line: lineno,
+            start_column: begin_pos,
+            end_column: end_pos,
+            full_range,
+            documentation: self.preceding_comment(lineno),
+            ..Default::default()
+        });
+
+        fuzzy_scope.push(class_name.to_string());
+        self.class_scope.push(class_name);
+
+        let outer_visibility = std::mem::replace(&mut self.current_visibility, "public");
+        let outer_defining_class_methods =
+            std::mem::replace(&mut self.defining_class_methods, false);
+        let outer_module_function_section =
+            std::mem::replace(&mut self.module_function_section, false);
+
+        for child_node in body {
+            self.serialize(child_node, documents, fuzzy_scope);
+        }
+
+        self.module_function_section = outer_module_function_section;
+        self.defining_class_methods = outer_defining_class_methods;
+        self.current_visibility = outer_visibility;
+
+        for _ in 0..class_scope_len {
+            fuzzy_scope.pop();
+        }
+
+        fuzzy_scope.pop();
+        self.class_scope.pop();
+    }
+
+    fn serialize_const(
+        &mut self,
+        constant: &'v Const,
+        documents: &mut Vec<FuzzyNode>,
+        fuzzy_scope: &mut Vec<String>,
+    ) {
+        let input = self.input;
+        let Const {
+            scope,
+            name,
+            name_l,
+            ..
+        } = constant;
+
+        let const_node = Const {
+            scope: scope.to_owned(),
+            name: "".to_string(),
+            double_colon_l: None,
+            name_l: Loc { begin: 0, end: 0 },
+            expression_l: Loc { begin: 0, end: 0 },
+        };
+        let mut node_class_scope = self.build_class_scope(&const_node);
+
+        if self.top_level_const(&const_node) {
+            node_class_scope.push(TOP_LEVEL_SCOPE.to_string());
+        }
+
+        let (lineno, begin_pos) = input.line_col_for_pos(name_l.begin).unwrap();
+        let (_lineno, end_pos) = input.line_col_for_pos(name_l.end).unwrap();
+
+        // Only unqualified constants, since `Foo::Bar` is reported
+        // on `Foo` when it's undefined
+        if self.settings.undefined_name_diagnostics
+            && scope.is_none()
+            && !RUBY_CORE_CONSTANTS.contains(name.as_str())
+        {
+            self.findings.name_checks.push(NameCheck {
+                name: name.to_string(),
+                node_type: "Const",
+                line: lineno,
+                start_column: begin_pos,
+                end_column: end_pos,
+                enclosing_class: None,
+            });
+        }
+
+        let document = FuzzyNode {
+            category: "usage",
+            fuzzy_ruby_scope: fuzzy_scope.clone(),
+            class_scope: node_class_scope,
+            name: name.to_string(),
+            node_type: "Const",
+            line: lineno,
+            start_column: begin_pos,
+            end_column: end_pos,
+            ..Default::default()
+        };
+
+        documents.push(document);
+
+        if let Some(child_node) = scope {
+            self.serialize(child_node, documents, fuzzy_scope);
+        }
+    }
+
+    fn serialize_def(
+        &mut self,
+        def: &'v Def,
+        documents: &mut Vec<FuzzyNode>,
+        fuzzy_scope: &mut Vec<String>,
+    ) {
+        let input = self.input;
+        let Def {
+            name,
+            args,
+            body,
+            name_l,
+            expression_l,
+            ..
+        } = def;
+
+        let sig = self.pending_sig.take().unwrap_or_default();
+        let (lineno, begin_pos) = input.line_col_for_pos(name_l.begin).unwrap();
+        let (_lineno, mut end_pos) = input.line_col_for_pos(name_l.end).unwrap();
+
+        // Setter ranges stop before the `=` so renaming `name` keeps
+        // `def name=` in sync
+        if setter_reader_name(name).is_some() {
+            end_pos -= 1;
+        }
+
+        // Methods under `class << self` or in a concern's
+        // `class_methods do` block are class methods
+        let (node_type, scope_name) = if self.defining_class_methods {
+            ("Defs", format!("self.{}", name))
+        } else {
+            ("Def", name.to_string())
+        };
+
+        let full_range = self.push_scope_span(name_l, node_type, expression_l);
+
+        documents.push(FuzzyNode {
+            category: "assignment",
+            fuzzy_ruby_scope: fuzzy_scope.clone(),
+            class_scope: vec![],
+            name: name.to_string(),
+            node_type,
+            line: lineno,
+            start_column: begin_pos,
+            end_column: end_pos,
+            full_range,
+            visibility: Some(self.current_visibility),
+            documentation: self.preceding_comment(sig.line.unwrap_or(lineno)),
+            parameters: Some(self.parameter_signature(args)),
+            parameter_types: sig.parameter_types,
+            return_type: sig.return_type,
+            refinement: self.refinement.clone(),
+            ..Default::default()
+        });
+
+        if name.starts_with("test_") {
+            self.findings.test_blocks.push(TestBlock {
+                framework: "test",
+                name: name.to_string(),
+                group: false,
+                line: lineno,
+            });
+        }
+
+        if self.module_function_section && !self.defining_class_methods {
+            self.push_module_function(documents, documents.len() - 1);
+        }
+
+        if name == "method_missing" || name == "respond_to_missing?" {
+            self.findings.defines_method_missing = true;
+        }
+
+        if self.index_interface_only {
+            return;
+        }
+
+        fuzzy_scope.push(scope_name);
+
+        let outer_local_variable_classes = std::mem::take(&mut self.local_variable_classes);
+
+        if let Some(child_node) = args {
+            self.serialize(child_node, documents, fuzzy_scope);
+        }
+
+        if let Some(child_node) = body {
+            self.serialize(child_node, documents, fuzzy_scope);
+        }
+
+        self.local_variable_classes = outer_local_variable_classes;
+        fuzzy_scope.pop();
+    }
+
+    fn serialize_defs(
+        &mut self,
+        defs: &'v Defs,
+        documents: &mut Vec<FuzzyNode>,
+        fuzzy_scope: &mut Vec<String>,
+    ) {
+        let input = self.input;
+        let Defs {
+            name,
+            args,
+            body,
+            name_l,
+            expression_l,
+            ..
+        } = defs;
+
+        let sig = self.pending_sig.take().unwrap_or_default();
+        let (lineno, begin_pos) = input.line_col_for_pos(name_l.begin).unwrap();
+        let (_lineno, end_pos) = input.line_col_for_pos(name_l.end).unwrap();
+
+        let full_range = self.push_scope_span(name_l, "Defs", expression_l);
+
+        documents.push(FuzzyNode {
+            category: "assignment",
+            fuzzy_ruby_scope: fuzzy_scope.clone(),
+            class_scope: vec![],
+            name: name.to_string(),
+            node_type: "Defs",
+            line: lineno,
+            start_column: begin_pos,
+            end_column: end_pos,
+            full_range,
+            visibility: Some("public"),
+            documentation: self.preceding_comment(sig.line.unwrap_or(lineno)),
+            parameters: Some(self.parameter_signature(args)),
+            parameter_types: sig.parameter_types,
+            return_type: sig.return_type,
+            refinement: self.refinement.clone(),
+            ..Default::default()
+        });
+
+        if self.index_interface_only {
+            return;
+        }
+
+        let mut scope_name = "self.".to_owned();
+        scope_name.push_str(name);
+
+        fuzzy_scope.push(scope_name);
+
+        let outer_local_variable_classes = std::mem::take(&mut self.local_variable_classes);
+
+        if let Some(child_node) = args {
+            self.serialize(child_node, documents, fuzzy_scope);
+        }
+
+        if let Some(child_node) = body {
+            self.serialize(child_node, documents, fuzzy_scope);
+        }
+
+        self.local_variable_classes = outer_local_variable_classes;
+        fuzzy_scope.pop();
+    }
+
+    fn serialize_send(
+        &mut self,
+        send: &'v Send,
+        documents: &mut Vec<FuzzyNode>,
+        fuzzy_scope: &mut Vec<String>,
+    ) {
+        let Send {
+            recv,
+            args,
+            selector_l,
+            ..
+        } = send;
+
+        let class_scope = match recv {
+            Some(recv_node) => {
+                self.serialize(recv_node, documents, fuzzy_scope);
+                self.receiver_class_scope(recv_node)
+            }
+            None => vec![],
+        };
+
+        if let Some(loc) = selector_l {
+            self.push_call(send, loc, &class_scope, documents, fuzzy_scope);
+        }
+
+        for node in args {
+            self.serialize(node, documents, fuzzy_scope);
+        }
+
+        self.index_method_macro(send, &class_scope, documents, fuzzy_scope);
+    }
+
+    // The usage of a called method, and what's checked about it after
+    // parsing: whether it's defined, and where a stub for it would go
+    fn push_call(
+        &mut self,
+        send: &Send,
+        loc: &Loc,
+        class_scope: &[String],
+        documents: &mut Vec<FuzzyNode>,
+        fuzzy_scope: &[String],
+    ) {
+        let input = self.input;
+        let Send {
+            recv,
+            method_name,
+            args,
+            ..
+        } = send;
+
+        let (lineno, begin_pos) = input.line_col_for_pos(loc.begin).unwrap();
+        let (_lineno, end_pos) = input.line_col_for_pos(loc.end).unwrap();
+
+        // `described_class` is a usage of the class it stands for
+        let document = match (recv, self.described_class()) {
+            (None, Some(described_class)) if method_name == "described_class" => {
+                let mut described_class_scope = self.build_class_scope(described_class);
+
+                if self.top_level_const(described_class) {
+                    described_class_scope.push(TOP_LEVEL_SCOPE.to_string());
+                }
+
+                FuzzyNode {
+                    category: "usage",
+                    fuzzy_ruby_scope: fuzzy_scope.to_vec(),
+                    class_scope: described_class_scope,
+                    name: described_class.name.to_string(),
+                    node_type: "Const",
+                    line: lineno,
+                    start_column: begin_pos,
+                    end_column: end_pos,
+                    ..Default::default()
+                }
+            }
+            _ => FuzzyNode {
+                category: "usage",
+                fuzzy_ruby_scope: fuzzy_scope.to_vec(),
+                class_scope: class_scope.to_vec(),
+                name: method_name.to_string(),
+                node_type: "Send",
+                line: lineno,
+                start_column: begin_pos,
+                end_column: end_pos,
+                ..Default::default()
+            },
+        };
+
+        documents.push(document);
+
+        // Calls at the class level are usually DSLs, so only
+        // receiverless calls inside methods are checked
+        let inside_method = fuzzy_scope
+            .iter()
+            .any(|scope_name| !scope_name.starts_with(char::is_uppercase));
+
+        if self.settings.undefined_name_diagnostics
+            && recv.is_none()
+            && inside_method
+            && !KERNEL_METHODS.contains(method_name.as_str())
+        {
+            self.findings.name_checks.push(NameCheck {
+                name: method_name.to_string(),
+                node_type: "Send",
+                line: lineno,
+                start_column: begin_pos,
+                end_column: end_pos,
+                enclosing_class: fuzzy_scope
+                    .iter()
+                    .rev()
+                    .find(|scope_name| scope_name.starts_with(char::is_uppercase))
+                    .cloned(),
+            });
+        }
+
+        let enclosing_class = fuzzy_scope
+            .iter()
+            .rev()
+            .find(|scope_name| scope_name.starts_with(char::is_uppercase));
+
+        // The receiver's class when it's known, and whether the
+        // call is on the class itself
+        let receiver = match recv.as_deref() {
+            Some(recv_node) if self.receiver_const(recv_node).is_some() => {
+                class_scope.first().map(|name| (name, true))
+            }
+            Some(Node::Lvar(_)) => class_scope.first().map(|name| (name, false)),
+            None | Some(Node::Self_(_)) => enclosing_class.map(|name| {
+                let method_scope = fuzzy_scope
+                    .iter()
+                    .rev()
+                    .find(|scope_name| !scope_name.starts_with(char::is_uppercase));
+
+                (
+                    name,
+                    method_scope.is_some_and(|method_scope| {
+                        method_scope.starts_with(SINGLETON_RECEIVER_SCOPE)
+                    }),
+                )
+            }),
+            _ => None,
+        };
+
+        if let Some((receiver_class, class_method)) = receiver {
+            self.findings.method_calls.push(MethodCall {
+                name: method_name.to_string(),
+                receiver_class: receiver_class.to_string(),
+                class_method,
+                parameters: self.stub_parameters(args),
+                line: lineno,
+                start_column: begin_pos,
+                end_column: end_pos,
+            });
+        }
+    }
+
+    // Ruby's own macros like `attr_reader` and `private`, and calls that
+    // name other files or gems. DSLs like Rails' are in the node handlers.
+    fn index_method_macro(
+        &mut self,
+        send: &Send,
+        class_scope: &[String],
+        documents: &mut Vec<FuzzyNode>,
+        fuzzy_scope: &[String],
+    ) {
+        let input = self.input;
+        let Send {
+            recv,
+            method_name,
+            args,
+            ..
+        } = send;
+
+        match method_name.as_str() {
+            "attr_accessor" | "attr_reader" | "attr_writer" => {
+                for node in args {
+                    let Node::Sym(Sym {
+                        name, expression_l, ..
+                    }) = node
+                    else {
+                        continue;
+                    };
+
+                    let reader_name = name.to_string_lossy();
+                    let writer_name = format!("{}=", reader_name);
+                    let method_names = match method_name.as_str() {
+                        "attr_reader" => vec![reader_name],
+                        "attr_writer" => vec![writer_name],
+                        _ => vec![reader_name, writer_name],
+                    };
+
+                    for name in method_names {
+                        documents.push(FuzzyNode {
+                            class_scope: class_scope.to_vec(),
+                            ..self.document("assignment", "Def", name, expression_l, fuzzy_scope)
+                        });
+                    }
+                }
+            }
+            "include" | "extend" | "prepend" if recv.is_none() => {
+                let mixin_type = match method_name.as_str() {
+                    "include" => "Include",
+                    "extend" => "Extend",
+                    _ => "Prepend",
+                };
+
+                for node in args {
+                    if let Node::Const(const_node) = node {
+                        let (lineno, begin_pos) =
+                            input.line_col_for_pos(const_node.name_l.begin).unwrap();
+                        let (_lineno, end_pos) =
+                            input.line_col_for_pos(const_node.name_l.end).unwrap();
+
+                        documents.push(FuzzyNode {
+                            category: "mixin",
+                            fuzzy_ruby_scope: fuzzy_scope.to_vec(),
+                            class_scope: self.build_class_scope(const_node),
+                            name: const_node.name.to_string(),
+                            node_type: mixin_type,
+                            line: lineno,
+                            start_column: begin_pos,
+                            end_column: end_pos,
+                            ..Default::default()
+                        });
+                    }
+                }
+            }
+            "private" | "protected" | "public" if recv.is_none() => {
+                let visibility = match method_name.as_str() {
+                    "private" => "private",
+                    "protected" => "protected",
+                    _ => "public",
+                };
+
+                if args.is_empty() {
+                    // A bare `private` applies to the rest of the body
+                    self.current_visibility = visibility;
+                } else {
+                    // `private :foo` and `private def foo`
+                    self.set_visibility(documents, fuzzy_scope, args, "Def", visibility);
+                }
+            }
+            "render" if recv.is_none() => {
+                if let Some(rendered_view) = rendered_view(args, input) {
+                    self.findings.rendered_views.push(rendered_view);
+                }
+            }
+            "t" | "translate" if recv.is_none() || i18n_receiver(recv) => {
+                if let Some(translation_key) = translation_key(args, input) {
+                    self.findings.translation_keys.push(translation_key);
+                }
+            }
+            "add_dependency" | "add_runtime_dependency" | "add_development_dependency"
+                if recv.is_some() =>
+            {
+                if let Some(dependency) = args.first() {
+                    self.push_gem_dependency(method_name, dependency, documents);
+                }
+            }
+            "require" | "require_relative" | "autoload" | "gem" if recv.is_none() => {
+                // `autoload :Foo, "foo"` takes the path second
+                let path_arg = if method_name == "autoload" {
+                    args.get(1)
+                } else {
+                    args.first()
+                };
+
+                if let Some(Node::Str(Str {
+                    value,
+                    begin_l: Some(begin_l),
+                    end_l: Some(end_l),
+                    ..
+                })) = path_arg
+                {
+                    let (lineno, begin_pos) = input.line_col_for_pos(begin_l.end).unwrap();
+                    let (_lineno, end_pos) = input.line_col_for_pos(end_l.begin).unwrap();
+
+                    self.findings.required_paths.push(RequiredPath {
+                        method_name: method_name.to_string(),
+                        path: value.to_string_lossy(),
+                        line: lineno,
+                        start_column: begin_pos,
+                        end_column: end_pos,
+                    });
+                }
+
+                if method_name == "gem" {
+                    if let Some(dependency) = args.first() {
+                        self.push_gem_dependency(method_name, dependency, documents);
+                    }
+                }
+            }
+            // Opt-in since the method name is often not a literal
+            "send" | "public_send" | "__send__" if self.settings.index_dynamic_sends => {
+                self.index_dynamic_send(args, class_scope, documents, fuzzy_scope);
+            }
+            "module_function" if recv.is_none() => {
+                if args.is_empty() {
+                    // A bare `module_function` applies to the rest of the body
+                    self.module_function_section = true;
+                } else {
+                    // `module_function :foo` and `module_function def foo`
+                    for node in args {
+                        let Some(function_name) = self.named_method(node) else {
+                            continue;
+                        };
+
+                        let method_index = documents.iter().rposition(|document| {
+                            document.category == "assignment"
+                                && document.node_type == "Def"
+                                && document.name == function_name
+                                && document.fuzzy_ruby_scope == *fuzzy_scope
+                        });
+
+                        if let Some(method_index) = method_index {
+                            self.push_module_function(documents, method_index);
+                        }
+                    }
+                }
+            }
+            "private_class_method" | "public_class_method" if recv.is_none() => {
+                let visibility = if method_name == "private_class_method" {
+                    "private"
+                } else {
+                    "public"
+                };
+
+                self.set_visibility(documents, fuzzy_scope, args, "Defs", visibility);
+            }
+            "alias_method" => self.index_alias_method(args, class_scope, documents, fuzzy_scope),
+            _ => {}
+        }
+    }
+
+    // `send(:save)` and `public_send("save")` as calls of `save` on the
+    // receiver's class
+    fn index_dynamic_send(
+        &self,
+        args: &[Node],
+        class_scope: &[String],
+        documents: &mut Vec<FuzzyNode>,
+        fuzzy_scope: &[String],
+    ) {
+        let input = self.input;
+
+        match args.first() {
+            // Symbols are already usages, but without the
+            // receiver's class
+            Some(Node::Sym(Sym { expression_l, .. })) => {
+                let (lineno, begin_pos) = input.line_col_for_pos(expression_l.begin).unwrap();
+
+                let usage_document = documents.iter_mut().rev().find(|document| {
+                    document.category == "usage"
+                        && document.line == lineno
+                        && document.start_column == begin_pos
+                });
+
+                if let Some(usage_document) = usage_document {
+                    usage_document.class_scope = class_scope.to_vec();
+                }
+            }
+            Some(Node::Str(Str {
+                value,
+                begin_l: Some(begin_l),
+                end_l: Some(end_l),
+                ..
+            })) => {
+                // Only the name inside the quotes
+                let (lineno, begin_pos) = input.line_col_for_pos(begin_l.end).unwrap();
+                let (_lineno, end_pos) = input.line_col_for_pos(end_l.begin).unwrap();
+
+                documents.push(FuzzyNode {
+                    category: "usage",
+                    fuzzy_ruby_scope: fuzzy_scope.to_vec(),
+                    class_scope: class_scope.to_vec(),
+                    name: value.to_string_lossy(),
+                    node_type: "Send",
+                    line: lineno,
+                    start_column: begin_pos,
+                    end_column: end_pos,
+                    ..Default::default()
+                });
+            }
+            _ => {}
+        }
+    }
+
+    // Indexed like the `alias` keyword: the new name is an assignment and the
+    // old name is a usage. Symbol arguments are already usages from
+    // serializing `args`.
+    fn index_alias_method(
+        &self,
+        args: &[Node],
+        class_scope: &[String],
+        documents: &mut Vec<FuzzyNode>,
+        fuzzy_scope: &[String],
+    ) {
+        let input = self.input;
+
+        if let Some((name, expression_l)) =
+            args.first().and_then(|node| self.method_name_literal(node))
+        {
+            let (lineno, begin_pos) = input.line_col_for_pos(expression_l.begin).unwrap();
+            let (_lineno, end_pos) = input.line_col_for_pos(expression_l.end).unwrap();
+
+            documents.push(FuzzyNode {
+                category: "assignment",
+                fuzzy_ruby_scope: fuzzy_scope.to_vec(),
+                class_scope: class_scope.to_vec(),
+                name,
+                node_type: "Alias",
+                line: lineno,
+                start_column: begin_pos,
+                end_column: end_pos,
+                ..Default::default()
+            });
+        }
+
+        if let Some(Node::Str(Str {
+            value,
+            expression_l,
+            ..
+        })) = args.get(1)
+        {
+            let (lineno, begin_pos) = input.line_col_for_pos(expression_l.begin).unwrap();
+            let (_lineno, end_pos) = input.line_col_for_pos(expression_l.end).unwrap();
+
+            documents.push(FuzzyNode {
+                category: "usage",
+                fuzzy_ruby_scope: fuzzy_scope.to_vec(),
+                class_scope: class_scope.to_vec(),
+                name: value.to_string_lossy(),
+                node_type: "Alias",
+                line: lineno,
+                start_column: begin_pos,
+                end_column: end_pos,
+                ..Default::default()
+            });
+        }
+    }
+
+    // A definition or usage of `name` at `loc` in the current scope
+    fn document(
+        &self,
+        category: &'static str,
+        node_type: &'static str,
+        name: String,
+        loc: &Loc,
+        fuzzy_scope: &[String],
+    ) -> FuzzyNode<'static> {
+        let (lineno, begin_pos) = self.input.line_col_for_pos(loc.begin).unwrap();
+        let (_lineno, end_pos) = self.input.line_col_for_pos(loc.end).unwrap();
+
+        FuzzyNode {
+            category,
+            fuzzy_ruby_scope: fuzzy_scope.to_vec(),
+            class_scope: vec![],
+            name,
+            node_type,
+            line: lineno,
+            start_column: begin_pos,
+            end_column: end_pos,
+            ..Default::default()
+        }
+    }
+
+    // The scopes the handlers give the block of `call`
+    fn block_scopes(
+        &mut self,
+        call: &Node,
+        documents: &mut Vec<FuzzyNode>,
+        fuzzy_scope: &[String],
+    ) -> Vec<String> {
+        let settings = self.settings;
+
+        let context = NodeContext {
+            visitor: self,
+            fuzzy_scope,
+            documents,
+        };
+
+        settings
+            .node_handlers
+            .iter()
+            .filter_map(|node_handler| node_handler.block_scope(call, &context))
+            .collect()
+    }
+
+    // The class a call's receiver is known to be, in the format of a `Send`
+    // usage's class scope, e.g. `["User", "self."]` for `User.find`
+    pub(super) fn receiver_class_scope(&self, recv_node: &Node) -> Vec<String> {
+        match (self.receiver_const(recv_node), recv_node) {
+            (Some(const_node), _) => {
+                let mut full_class_scope = vec![const_node.name.to_string()];
+                full_class_scope.append(self.build_class_scope(const_node).as_mut());
+                full_class_scope.push(SINGLETON_RECEIVER_SCOPE.to_string());
+                full_class_scope
+            }
+            (None, Node::Lvar(Lvar { name, .. })) => self
+                .local_variable_classes
+                .get(name.as_str())
+                .cloned()
+                .unwrap_or_default(),
+            _ => vec![],
+        }
+    }
+
+    // The class of the innermost `describe` block being parsed
+    fn described_class(&self) -> Option<&Const> {
+        self.block_calls
+            .iter()
+            .rev()
+            .find_map(|call| described_class(call))
+    }
+
+    // The parameter list as written, e.g. `a, b = 1, *rest, c:, **opts, &block`,
+    // with whitespace in defaults collapsed so it fits on one line
+    fn parameter_signature(&self, args: &Option<Box<Node>>) -> String {
+        let input = self.input;
+        let parameters = match args.as_deref() {
+            Some(Node::Args(Args { args, .. })) => args,
+            _ => return String::new(),
+        };
+
+        parameters
+            .iter()
+            .flat_map(|parameter| parameter.expression().source(input))
+            .map(|source| source.split_whitespace().collect::<Vec<&str>>().join(" "))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    // Parameters for a generated method, named after the arguments it's called
+    // with where they're variables, e.g. `(user, arg2, notify:)`
+    fn stub_parameters(&self, args: &[Node]) -> String {
+        if args.is_empty() {
+            return String::new();
+        }
+
+        let mut parameters: Vec<String> = vec![];
+
+        for (index, arg) in args.iter().enumerate() {
+            let parameter = match arg {
+                Node::Lvar(Lvar { name, .. }) => name.to_string(),
+                Node::Ivar(Ivar { name, .. }) => name.trim_start_matches('@').to_string(),
+                Node::Send(Send {
+                    recv: None,
+                    method_name,
+                    args,
+                    ..
+                }) if args.is_empty() && identifier_name(method_name) => method_name.to_string(),
+                Node::Splat(_) => "*args".to_string(),
+                Node::BlockPass(_) => "&block".to_string(),
+                Node::Kwargs(Kwargs { pairs, .. }) => {
+                    for pair in pairs {
+                        if let Node::Pair(Pair { key, .. }) = pair {
+                            if let Node::Sym(Sym { name, .. }) = key.as_ref() {
+                                parameters.push(format!("{}:", name.to_string_lossy()));
+                            }
+                        }
+                    }
+
+                    continue;
+                }
+                _ => format!("arg{}", index + 1),
+            };
+
+            // Passing the same variable twice still needs distinct names
+            if parameters.contains(&parameter) {
+                parameters.push(format!("arg{}", index + 1));
+            } else {
+                parameters.push(parameter);
+            }
+        }
+
+        format!("({})", parameters.join(", "))
+    }
+
+    // The comment block directly above `lineno`, e.g. YARD docs for a method
+    fn preceding_comment(&self, lineno: usize) -> Option<String> {
+        let mut comment = vec![];
+        let mut comment_lineno = lineno;
+
+        while comment_lineno > 0 {
+            comment_lineno -= 1;
+
+            match self.comment_lines.get(&comment_lineno) {
+                Some(text) => comment.push(text.as_str()),
+                None => break,
+            }
+        }
+
+        if comment.is_empty() {
+            return None;
+        }
+
+        comment.reverse();
+
+        Some(comment.join("\n"))
+    }
+
+    // Updates methods named by `args` that were already defined in the
+    // current scope, since `private :foo` comes after `def foo`
+    fn set_visibility(
+        &self,
+        documents: &mut [FuzzyNode],
+        fuzzy_scope: &[String],
+        args: &[Node],
+        node_type: &str,
+        visibility: &'static str,
+    ) {
+        for node in args {
+            let Some(method_name) = self.named_method(node) else {
+                continue;
+            };
+
+            let method_document = documents.iter_mut().rev().find(|document| {
+                document.category == "assignment"
+                    && document.node_type == node_type
+                    && document.name == method_name
+                    && document.fuzzy_ruby_scope == fuzzy_scope
+            });
+
+            if let Some(method_document) = method_document {
+                method_document.visibility = Some(visibility);
+            }
+        }
+    }
+
+    // The method named by an argument like `:foo`, `"foo"`, or `def foo`
+    fn named_method(&self, node: &Node) -> Option<String> {
+        match node {
+            Node::Def(Def { name, .. }) | Node::Defs(Defs { name, .. }) => Some(name.to_string()),
+            _ => self
+                .method_name_literal(node)
+                .map(|(name, _expression_l)| name),
+        }
+    }
+
+    // `gem "sidekiq"` in a `Gemfile` or `spec.add_dependency "sidekiq"` in a
+    // gemspec, for finding gems with workspace symbols and linking to them
+    fn push_gem_dependency(
+        &mut self,
+        method_name: &str,
+        dependency: &Node,
+        documents: &mut Vec<FuzzyNode>,
+    ) {
+        let Some((name, line, start_column, end_column)) = literal_span(dependency, self.input)
+        else {
+            return;
+        };
+
+        // `gem` is already a link
+        if method_name != "gem" {
+            self.findings.required_paths.push(RequiredPath {
+                method_name: method_name.to_string(),
+                path: name.clone(),
+                line,
+                start_column,
+                end_column,
+            });
+        }
+
+        documents.push(FuzzyNode {
+            category: "assignment",
+            name,
+            node_type: "Gem",
+            line,
+            start_column,
+            end_column,
+            ..Default::default()
+        });
+    }
+
+    // The reader, writer, and query methods of each column in the
+    // `create_table` blocks of a schema, e.g. `email`, `email=`, and `email?`
+    // on `User` for `t.string "email"` in `create_table "users"`
+    fn push_model_columns(&self, body: &Node, documents: &mut Vec<FuzzyNode>) {
+        let input = self.input;
+
+        for statement in statements(body) {
+            let Node::Block(Block {
+                call,
+                args: Some(block_args),
+                body: Some(table_body),
+                ..
+            }) = statement
+            else {
+                continue;
+            };
+
+            let Node::Send(Send {
+                recv: None,
+                method_name,
+                args: table_args,
+                ..
+            }) = call.as_ref()
+            else {
+                continue;
+            };
+
+            if method_name != "create_table" {
+                continue;
+            }
+
+            let table_name = match table_args.first() {
+                Some(Node::Str(Str { value, .. })) => value.to_string_lossy(),
+                Some(Node::Sym(Sym { name, .. })) => name.to_string_lossy(),
+                _ => continue,
+            };
+
+            // A lone block argument like `|t|` is a `Procarg0`
+            let table_variable = match block_args.as_ref() {
+                Node::Args(Args { args, .. }) => match args.first() {
+                    Some(Node::Procarg0(Procarg0 { args, .. })) => match args.first() {
+                        Some(Node::Arg(Arg { name, .. })) => name.to_string(),
+                        _ => continue,
+                    },
+                    Some(Node::Arg(Arg { name, .. })) => name.to_string(),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+
+            let model_name = camelize(&singularize(&table_name));
+
+            for column in statements(table_body) {
+                let Node::Send(Send {
+                    recv: Some(recv),
+                    method_name: column_type,
+                    args: column_args,
+                    selector_l,
+                    ..
+                }) = column
+                else {
+                    continue;
+                };
+
+                if !matches!(recv.as_ref(), Node::Lvar(Lvar { name, .. }) if *name == table_variable)
+                {
+                    continue;
+                }
+
+                let columns: Vec<(String, Loc)> = match column_type.as_str() {
+                    "index" | "check_constraint" | "exclusion_constraint" | "unique_constraint" => {
+                        vec![]
+                    }
+                    "timestamps" => match selector_l {
+                        Some(selector_l) => vec![
+                            ("created_at".to_string(), *selector_l),
+                            ("updated_at".to_string(), *selector_l),
+                        ],
+                        None => vec![],
+                    },
+                    _ => column_args
+                        .iter()
+                        .flat_map(|column_arg| match column_arg {
+                            Node::Str(Str {
+                                value,
+                                expression_l,
+                                ..
+                            }) => Some((value.to_string_lossy(), *expression_l)),
+                            Node::Sym(Sym {
+                                name, expression_l, ..
+                            }) => Some((name.to_string_lossy(), *expression_l)),
+                            _ => None,
+                        })
+                        .map(|(name, expression_l)| match column_type.as_str() {
+                            "references" | "belongs_to" => (format!("{}_id", name), expression_l),
+                            _ => (name, expression_l),
+                        })
+                        .collect(),
+                };
+
+                for (column_name, expression_l) in columns {
+                    let (lineno, begin_pos) = input.line_col_for_pos(expression_l.begin).unwrap();
+                    let (_lineno, end_pos) = input.line_col_for_pos(expression_l.end).unwrap();
+
+                    for name in [
+                        column_name.clone(),
+                        format!("{}=", column_name),
+                        format!("{}?", column_name),
+                    ] {
+                        documents.push(FuzzyNode {
+                            category: "assignment",
+                            fuzzy_ruby_scope: vec![model_name.clone()],
+                            class_scope: vec![],
+                            name,
+                            node_type: "Def",
+                            line: lineno,
+                            start_column: begin_pos,
+                            end_column: end_pos,
+                            documentation: Some(format!(
+                                "`{}` column of `{}`",
+                                column_type, table_name
+                            )),
+                            ..Default::default()
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    // `module_function` makes the instance method private and adds a module
+    // method with the same name, so both `Helpers.format` and `format` resolve
+    fn push_module_function(&self, documents: &mut Vec<FuzzyNode>, method_index: usize) {
+        documents[method_index].visibility = Some("private");
+
+        let method_document = &documents[method_index];
+        let module_function = FuzzyNode {
+            category: "assignment",
+            fuzzy_ruby_scope: method_document.fuzzy_ruby_scope.clone(),
+            class_scope: vec![],
+            name: method_document.name.clone(),
+            node_type: "Defs",
+            line: method_document.line,
+            start_column: method_document.start_column,
+            end_column: method_document.end_column,
+            visibility: Some("public"),
+            documentation: method_document.documentation.clone(),
+            parameters: method_document.parameters.clone(),
+            ..Default::default()
+        };
+
+        documents.push(module_function);
+    }
+
+    // `x += 1` reads `x`, but only an Lvasgn is serialized for it
+    fn mark_op_assigned_local(&mut self, recv: &Node) {
+        if let Node::Lvasgn(Lvasgn { name, .. }) = recv {
+            self.findings.op_assigned_locals.insert(name.to_string());
+        }
+    }
+
+    // `obj.name += 1` calls `name=`, so the usage for `name` is renamed to
+    // resolve to the setter
+    fn mark_setter_usage(&self, recv: &Node, documents: &mut [FuzzyNode]) {
+        let (method_name, selector_l) = match recv {
+            Node::Send(Send {
+                recv: Some(_),
+                method_name,
+                selector_l: Some(selector_l),
+                ..
+            }) => (method_name, selector_l),
+            _ => return,
+        };
+
+        if !identifier_name(method_name) {
+            return;
+        }
+
+        let (lineno, begin_pos) = self.input.line_col_for_pos(selector_l.begin).unwrap();
+
+        let usage_document = documents.iter_mut().rev().find(|document| {
+            document.category == "usage"
+                && document.node_type == "Send"
+                && document.line == lineno
+                && document.start_column == begin_pos
+        });
+
+        if let Some(usage_document) = usage_document {
+            usage_document.name = format!("{}=", method_name);
+        }
+    }
+
+    // The name and location of a method name given as a symbol or string
+    fn method_name_literal(&self, node: &Node) -> Option<(String, Loc)> {
+        match node {
+            Node::Sym(Sym {
+                name, expression_l, ..
+            }) => Some((name.to_string_lossy(), *expression_l)),
+            Node::Str(Str {
+                value,
+                expression_l,
+                ..
+            }) => Some((value.to_string_lossy(), *expression_l)),
+            _ => None,
+        }
+    }
+
+    // A class, module, or method named the way it's written, returning its
+    // full range for the document indexed for it
+    fn push_scope_span(
+        &mut self,
+        name_l: &Loc,
+        node_type: &'static str,
+        expression_l: &Loc,
+    ) -> Option<Range> {
+        let name = name_l.source(self.input)?;
+        let range = loc_range(expression_l, self.input)?;
+        let selection_range = loc_range(name_l, self.input)?;
+
+        self.findings.scope_spans.push(ScopeSpan {
+            name,
+            node_type,
+            range,
+            selection_range,
+        });
+
+        Some(range)
+    }
+
+    // RSpec blocks like `it "works" do` and `describe User do`, and Minitest
+    // blocks like `test "it works" do`, which defines `test_it_works`
+    fn test_block(&self, call: &Node) -> Option<TestBlock> {
+        match call {
+            Node::Send(Send {
+                recv,
+                method_name,
+                args,
+                selector_l: Some(selector_l),
+                ..
+            }) => {
+                let (lineno, _begin_pos) = self.input.line_col_for_pos(selector_l.begin)?;
+                let description = match args.first() {
+                    Some(Node::Const(const_node)) => const_node.name.to_string(),
+                    Some(Node::Str(Str { value, .. })) => value.to_string_lossy(),
+                    _ => String::new(),
+                };
+
+                if method_name == "test" && recv.is_none() && !description.is_empty() {
+                    let words: Vec<&str> = description.split_whitespace().collect();
+
+                    return Some(TestBlock {
+                        framework: "test",
+                        name: format!("test_{}", words.join("_")),
+                        group: false,
+                        line: lineno,
+                    });
+                }
+
+                let rspec_receiver = match recv {
+                    None => true,
+                    Some(recv_node) => {
+                        matches!(recv_node.as_ref(), Node::Const(Const { name, .. }) if name == "RSpec")
+                    }
+                };
+
+                let group = RSPEC_RUNNABLE_BLOCKS.get(method_name.as_str())?;
+
+                rspec_receiver.then_some(TestBlock {
+                    framework: "spec",
+                    name: description,
+                    group: *group,
+                    line: lineno,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    // The class of values like `User.new` or `User.find(id)`, in the same
+    // format as a `Send` usage's class scope
+    fn inferred_class_scope(&self, value: &Node) -> Option<Vec<String>> {
+        match value {
+            Node::Send(Send {
+                recv: Some(recv_node),
+                method_name,
+                ..
+            }) if INSTANCE_RETURNING_METHODS.contains(method_name.as_str()) => {
+                self.receiver_const(recv_node).map(|const_node| {
+                    let mut full_class_scope = vec![const_node.name.to_string()];
+                    full_class_scope.append(self.build_class_scope(const_node).as_mut());
+                    full_class_scope
+                })
+            }
+            _ => None,
+        }
+    }
+
+    // The constant a receiver names, where `described_class` names the class
+    // of the innermost `describe` around it
+    fn receiver_const<'n>(&'n self, node: &'n Node) -> Option<&'n Const> {
+        match node {
+            Node::Const(const_node) => Some(const_node),
+            Node::Send(Send {
+                recv: None,
+                method_name,
+                args,
+                ..
+            }) if method_name == "described_class" && args.is_empty() => self.described_class(),
+            _ => None,
+        }
+    }
+
+    // True for constants prefixed with `::`, e.g. `::Foo` or `::Foo::Bar`
+    fn top_level_const(&self, const_node: &Const) -> bool {
+        let mut current_node = &const_node.scope;
+
+        while let Some(node) = current_node {
+            match node.as_ref() {
+                Node::Const(Const { scope, .. }) => current_node = scope,
+                Node::Cbase(Cbase { .. }) => return true,
+                _ => return false,
+            }
+        }
+
+        false
+    }
+
+    fn build_class_scope(&self, const_node: &Const) -> Vec<String> {
+        let mut node_class_scope = vec![];
+        let mut current_node = &const_node.scope;
+
+        loop {
+            match current_node {
+                Some(node) => {
+                    match node.as_ref() {
+                        Node::Const(Const { name, scope, .. }) => {
+                            node_class_scope.push(name.to_string());
+                            current_node = scope;
+                        }
+                        Node::Cbase(Cbase { .. }) => {
+                            // let mut root_prefixed_scope = vec!["^^^".to_string()];
+                            // root_prefixed_scope.append(&mut node_class_scope);
+
+                            // node_class_scope = root_prefixed_scope;
+                            break;
+                        }
+                        Node::Send(Send { .. }) => break,
+                        Node::Self_(Self_ { expression_l: _ }) => break,
+                        _ => {
+                            info!("unknown node in build_class_scope");
+                            info!("{:#?}", node);
+                            break;
+                        }
+                    }
+                }
+                None => {
+                    // node_class_scope.should = self.class_scope.clone();
+                    break;
+                }
+            }
+        }
+
+        node_class_scope
+    }
+}
@@ -0,0 +1,2409 @@
+use log::info;
+use phf::phf_map;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str;
+use tantivy::collector::{DocSetCollector, TopDocs};
+use tantivy::query::{
+    BooleanQuery, BoostQuery, ConstScoreQuery, Occur, Query, QueryClone, RegexQuery, TermQuery,
+};
+use tantivy::{schema::*, Document};
+use tantivy::{DocAddress, Score, Searcher};
+use tower_lsp::lsp_types::{Location, Position, Range, TextDocumentPositionParams, Url};
+
+use super::indexer::RequiredPath;
+use super::{
+    controller_class_name, full_translation_key, fuzzy_match_score, gem_dependency,
+    identifier_name, setter_reader_name, Persistence, AUTOLOAD_DOCUMENTATION,
+    SINGLETON_RECEIVER_SCOPE, TOP_LEVEL_SCOPE,
+};
+use crate::session;
+use crate::telemetry::MeasuredSearcher;
+use crate::workspace_path::{self, uri_path};
+
+static USAGE_TYPE_RESTRICTIONS: phf::Map<&'static str, &[&str]> = phf_map! {
+    "Alias" => &[
+        "Alias", "Def", "Defs",
+        "CSend", "Send", "Super", "ZSuper",
+    ],
+    "Const" => &[
+        "Casgn", "Class", "Module",
+        "Const"
+    ],
+    "CSend" => &[
+        "Alias", "Def", "Defs",
+        "CSend", "Send", "Super", "ZSuper",
+    ],
+    "Cvar" => &[
+        "Cvasgn",
+        "Cvar"
+    ],
+    "Gvar" => &[
+        "Gvasgn",
+        "Gvar"
+    ],
+    "Ivar" => &[
+        "Ivasgn",
+        "Ivar"
+    ],
+    "Lvar" => &[
+        "Arg", "Blockarg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg",
+        "Shadowarg",
+        "Lvar"
+    ],
+    "Send" => &[
+        "Alias", "Def", "Defs",
+        "CSend", "Send", "Super", "ZSuper",
+    ],
+    "Super" => &[
+        "Alias", "Def", "Defs",
+        "CSend", "Send", "Super", "ZSuper",
+    ],
+    "ZSuper" => &[
+        "Alias", "Def", "Defs",
+        "CSend", "Send", "Super", "ZSuper",
+    ],
+};
+
+static ASSIGNMENT_TYPE_RESTRICTIONS: phf::Map<&'static str, &[&str]> = phf_map! {
+    "Alias" => &[
+        "Alias", "CSend", "Send", "Super", "ZSuper",
+        "Def", "Defs"
+    ],
+    "Arg" => &[
+        "Lvar",
+        "Arg", "Blockarg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg",
+        "Shadowarg"
+    ],
+    "Blockarg" => &[
+        "Lvar",
+        "Arg", "Blockarg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg",
+        "Shadowarg"
+    ],
+    "Casgn" => &[
+        "Const",
+        "Casgn", "Class", "Module"
+    ],
+    "Class" => &[
+        "Const",
+        "Casgn", "Class", "Module"
+    ],
+    "Cvasgn" => &[
+        "Cvar",
+        "Cvasgn"
+    ],
+    "Def" => &[
+        "Alias", "CSend", "Send", "Super", "ZSuper",
+        "Def"
+    ],
+    "Defs" => &[
+        "Alias", "CSend", "Send", "Super", "ZSuper",
+        "Defs"
+    ],
+    "Gvasgn" => &[
+        "Gvar",
+        "Gvasgn"
+    ],
+    "Ivasgn" => &[
+        "Ivar",
+        "Ivasgn"
+    ],
+    "Kwarg" => &[
+        "Lvar",
+        "Arg", "Blockarg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg",
+        "Shadowarg"
+    ],
+    "Kwoptarg" => &[
+        "Lvar",
+        "Arg", "Blockarg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg",
+        "Shadowarg"
+    ],
+    "Kwrestarg" => &[
+        "Lvar",
+        "Arg", "Blockarg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg",
+        "Shadowarg"
+    ],
+    "Lvasgn" => &[
+        "Lvar",
+        "Arg", "Blockarg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg",
+        "Shadowarg"
+    ],
+    "MatchVar" => &[
+        "Lvar",
+        "Arg", "Blockarg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg",
+        "Shadowarg"
+    ],
+    "Module" => &[
+        "Const",
+        "Casgn", "Class", "Module"
+    ],
+    "Optarg" => &[
+        "Lvar",
+        "Arg", "Blockarg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg",
+        "Shadowarg"
+    ],
+    "Restarg" => &[
+        "Lvar",
+        "Arg", "Blockarg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg",
+        "Shadowarg"
+    ],
+    "Shadowarg" => &[
+        "Lvar",
+        "Arg", "Blockarg", "Kwarg", "Kwoptarg", "Kwrestarg", "Lvasgn", "MatchVar", "Optarg", "Restarg",
+        "Shadowarg"
+    ],
+};
+
+static SYMBOL_QUERY_FILTERS: phf::Map<&'static str, &[&str]> = phf_map! {
+    "class" => &["Class"],
+    "const" => &["Casgn"],
+    "def" => &["Alias", "Def", "Defs"],
+    "gem" => &["Gem"],
+    "gvar" => &["Gvasgn"],
+    "mod" => &["Module"],
+    "module" => &["Module"],
+};
+
+static SYMBOL_TYPES: &[&str] = &[
+    "Alias", "Casgn", "Class", "Def", "Defs", "Gem", "Gvasgn", "Module",
+];
+
+impl Persistence {
+    pub(super) fn definition_name(&self, definition_doc: &Document) -> String {
+        definition_doc
+            .get_first(self.schema_fields.name_field)
+            .and_then(Value::as_text)
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    // The enclosing classes and modules, e.g. `Admin::UsersController`
+    pub(super) fn definition_container(&self, definition_doc: &Document) -> String {
+        definition_doc
+            .get_all(self.schema_fields.fuzzy_ruby_scope_field)
+            .flat_map(Value::as_text)
+            .filter(|scope_name| scope_name.starts_with(char::is_uppercase))
+            .collect::<Vec<&str>>()
+            .join("::")
+    }
+
+    // Only for user-space documents
+    pub(super) fn definition_location(&self, definition_doc: &Document) -> Location {
+        let file_path: String = definition_doc
+            .get_all(self.schema_fields.file_path)
+            .flat_map(Value::as_text)
+            .collect::<Vec<&str>>()
+            .join("/");
+        let user_space = definition_doc
+            .get_first(self.schema_fields.user_space_field)
+            .and_then(Value::as_bool)
+            .unwrap_or(true);
+        let absolute_file_path = if user_space {
+            self.workspace_path.absolute_path(&file_path)
+        } else {
+            workspace_path::absolute_path(&file_path)
+        };
+        let doc_uri = self.document_uri(&absolute_file_path);
+
+        let line = definition_doc
+            .get_first(self.schema_fields.line_field)
+            .and_then(Value::as_u64)
+            .unwrap_or(0) as u32;
+        let start_column = definition_doc
+            .get_first(self.schema_fields.start_column_field)
+            .and_then(Value::as_u64)
+            .unwrap_or(0) as u32;
+        let end_column = definition_doc
+            .get_first(self.schema_fields.end_column_field)
+            .and_then(Value::as_u64)
+            .unwrap_or(0) as u32;
+
+        Location::new(
+            doc_uri,
+            Range::new(
+                Position::new(line, start_column),
+                Position::new(line, end_column),
+            ),
+        )
+    }
+
+    // From `class`, `module`, or `def` to its `end`, for definitions of those
+    pub(super) fn definition_full_range(&self, definition_doc: &Document) -> Option<Range> {
+        let field = |field| definition_doc.get_first(field).and_then(Value::as_u64);
+
+        Some(Range::new(
+            Position::new(
+                field(self.schema_fields.full_start_line_field)? as u32,
+                field(self.schema_fields.full_start_column_field)? as u32,
+            ),
+            Position::new(
+                field(self.schema_fields.full_end_line_field)? as u32,
+                field(self.schema_fields.full_end_column_field)? as u32,
+            ),
+        ))
+    }
+
+    pub fn find_definitions(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> tantivy::Result<Vec<Location>> {
+        if let Some(route_locations) = self.route_definitions(&params)? {
+            return Ok(route_locations);
+        }
+
+        if let Some(view_locations) = self.rendered_view_definitions(&params) {
+            return Ok(view_locations);
+        }
+
+        if let Some(translation_locations) = self.translation_definitions(&params) {
+            return Ok(translation_locations);
+        }
+
+        if let Some(factory_locations) = self.factory_definitions(&params)? {
+            return Ok(factory_locations);
+        }
+
+        if let Some(shared_group_locations) = self.shared_group_definitions(&params)? {
+            return Ok(shared_group_locations);
+        }
+
+        if let Some(gem_locations) = self.gem_definitions(&params) {
+            return Ok(gem_locations);
+        }
+
+        let path = uri_path(&params.text_document.uri);
+        let relative_path = self.workspace_path.relative_path(&path);
+
+        let position = params.position;
+
+        if let Some(reader) = &self.reader {
+            let searcher = reader.searcher();
+            let character_position = position.character;
+            let character_line = position.line;
+            let file_path_id = workspace_path::file_path_id(&relative_path);
+
+            let file_path_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(self.schema_fields.file_path_id, &file_path_id),
+                IndexRecordOption::Basic,
+            ));
+            let category_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(self.schema_fields.category_field, "usage"),
+                IndexRecordOption::Basic,
+            ));
+            let line_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_u64(self.schema_fields.line_field, character_line.into()),
+                IndexRecordOption::Basic,
+            ));
+            let column_query = self.column_query(character_position);
+
+            let query = BooleanQuery::new(vec![
+                (Occur::Must, file_path_query),
+                (Occur::Must, category_query),
+                (Occur::Must, line_query),
+                (Occur::Must, column_query),
+            ]);
+
+            let usage_top_docs = searcher.measured_search(&query, &TopDocs::with_limit(1))?;
+
+            let mut locations = Vec::new();
+
+            if usage_top_docs.len() == 0 {
+                info!("No usages docs found");
+                return Ok(locations);
+            }
+
+            let doc_address = usage_top_docs[0].1;
+            let retrieved_doc = searcher.measured_doc(doc_address)?;
+
+            let category_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(self.schema_fields.category_field, "assignment"),
+                IndexRecordOption::Basic,
+            ));
+
+            let usage_name = retrieved_doc
+                .get_first(self.schema_fields.name_field)
+                .unwrap()
+                .as_text()
+                .unwrap();
+            let usage_type = retrieved_doc
+                .get_first(self.schema_fields.node_type_field)
+                .unwrap()
+                .as_text()
+                .unwrap();
+
+            let name_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(self.schema_fields.name_field, usage_name),
+                IndexRecordOption::Basic,
+            ));
+
+            let mut assignment_type_queries = vec![];
+
+            for possible_assignment_type in USAGE_TYPE_RESTRICTIONS.get(usage_type).unwrap().iter()
+            {
+                let assignment_type_query: Box<dyn Query> = Box::new(TermQuery::new(
+                    Term::from_field_text(
+                        self.schema_fields.node_type_field,
+                        possible_assignment_type,
+                    ),
+                    IndexRecordOption::Basic,
+                ));
+
+                assignment_type_queries.push((Occur::Should, assignment_type_query));
+            }
+
+            let assignment_type_query = BooleanQuery::new(assignment_type_queries);
+
+            let mut queries = vec![
+                (Occur::Must, category_query),
+                (Occur::Must, name_query),
+                (Occur::Must, Box::new(assignment_type_query)),
+            ];
+
+            let usage_fuzzy_scope =
+                retrieved_doc.get_all(self.schema_fields.fuzzy_ruby_scope_field);
+
+            match usage_type {
+                // "Alias" => {},
+                "Const" => {
+                    for scope_name in usage_fuzzy_scope {
+                        let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
+                            Term::from_field_text(
+                                self.schema_fields.fuzzy_ruby_scope_field,
+                                scope_name.as_text().unwrap(),
+                            ),
+                            IndexRecordOption::Basic,
+                        ));
+
+                        queries.push((Occur::Should, scope_query));
+                    }
+
+                    let class_scope = retrieved_doc.get_all(self.schema_fields.class_scope_field);
+
+                    for scope_name in class_scope {
+                        if scope_name.as_text() == Some(TOP_LEVEL_SCOPE) {
+                            continue;
+                        }
+
+                        let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
+                            Term::from_field_text(
+                                self.schema_fields.fuzzy_ruby_scope_field,
+                                scope_name.as_text().unwrap(),
+                            ),
+                            IndexRecordOption::Basic,
+                        ));
+
+                        queries.push((Occur::Must, scope_query));
+                    }
+                }
+                // "CSend" => {},
+                // todo: improved indexed scopes so there is a separate class scope, etc
+                // "Cvar" => {},
+                // "Gvar" => {},
+                // todo: improved indexed scopes so there is a separate class scope, etc
+                // "Ivar" => {},
+                // todo: improved to be more accurate
+                "Arg" | "Blockarg" | "Kwarg" | "Kwoptarg" | "Kwrestarg" | "Lvasgn" | "MatchVar"
+                | "Optarg" | "Restarg" | "Shadowarg" | "Lvar" => {
+                    for scope_name in usage_fuzzy_scope {
+                        let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
+                            Term::from_field_text(
+                                self.schema_fields.fuzzy_ruby_scope_field,
+                                scope_name.as_text().unwrap(),
+                            ),
+                            IndexRecordOption::Basic,
+                        ));
+
+                        queries.push((Occur::Must, scope_query));
+                    }
+                }
+                //
+                "Send" => {
+                    let class_scope = retrieved_doc.get_all(self.schema_fields.class_scope_field);
+
+                    let mut usage_scope_fallback = true;
+
+                    for scope_name in class_scope {
+                        if scope_name.as_text() == Some(SINGLETON_RECEIVER_SCOPE) {
+                            continue;
+                        }
+
+                        usage_scope_fallback = false;
+
+                        let scope_query = Box::new(TermQuery::new(
+                            Term::from_field_text(
+                                self.schema_fields.fuzzy_ruby_scope_field,
+                                scope_name.as_text().unwrap(),
+                            ),
+                            IndexRecordOption::Basic,
+                        ));
+
+                        let boosted_scope_query: Box<dyn Query> =
+                            Box::new(BoostQuery::new(scope_query, 10000.0));
+
+                        // queries.push((Occur::Should, scope_query));
+                        // queries.push((Occur::Should, boosted_scope_query));
+
+                        // This probably would be better as just a boosted
+                        // query, but it's not working for some reason.
+                        queries.push((Occur::Must, boosted_scope_query));
+                    }
+
+                    if usage_scope_fallback {
+                        for scope_name in usage_fuzzy_scope {
+                            let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
+                                Term::from_field_text(
+                                    self.schema_fields.fuzzy_ruby_scope_field,
+                                    scope_name.as_text().unwrap(),
+                                ),
+                                IndexRecordOption::Basic,
+                            ));
+
+                            queries.push((Occur::Should, scope_query));
+                        }
+                    }
+                }
+                // "Super" => {},
+                // "ZSuper" => {},
+                _ => {
+                    for scope_name in usage_fuzzy_scope {
+                        let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
+                            Term::from_field_text(
+                                self.schema_fields.fuzzy_ruby_scope_field,
+                                scope_name.as_text().unwrap(),
+                            ),
+                            IndexRecordOption::Basic,
+                        ));
+
+                        queries.push((Occur::Should, scope_query));
+                    }
+                }
+            };
+
+            // Keeps indexed `let` definitions out of results for app code
+            if self.settings.index_rspec_let && !relative_path.ends_with("_spec.rb") {
+                let spec_file_query: Box<dyn Query> = Box::new(RegexQuery::from_pattern(
+                    ".*_spec\\.rb",
+                    self.schema_fields.file_path,
+                )?);
+
+                queries.push((Occur::MustNot, spec_file_query));
+            }
+
+            let query = BooleanQuery::new(queries);
+            let mut assignments_top_docs =
+                searcher.measured_search(&query, &TopDocs::with_limit(50))?;
+
+            if usage_type == "Const" {
+                let assignment_types = USAGE_TYPE_RESTRICTIONS.get(usage_type).unwrap();
+                let const_top_docs =
+                    self.resolve_constant(&searcher, &retrieved_doc, usage_name, assignment_types)?;
+
+                if !const_top_docs.is_empty() {
+                    assignments_top_docs = const_top_docs;
+                }
+            }
+
+            if usage_type == "Super" || usage_type == "ZSuper" {
+                // The name search usually finds the method `super` is called
+                // from, so look for the overridden method in the ancestors
+                if let Some(enclosing_class) = self.enclosing_class_name(&retrieved_doc) {
+                    let super_top_docs = self.find_ancestor_definitions(
+                        &searcher,
+                        &enclosing_class,
+                        usage_name,
+                        &["Alias", "Def", "Defs"],
+                    )?;
+
+                    if !super_top_docs.is_empty() {
+                        assignments_top_docs = super_top_docs;
+                    }
+                }
+            }
+
+            if usage_type == "Send" {
+                let receiver_class = retrieved_doc
+                    .get_first(self.schema_fields.class_scope_field)
+                    .and_then(Value::as_text);
+                let assignment_types = USAGE_TYPE_RESTRICTIONS.get(usage_type).unwrap();
+
+                match receiver_class {
+                    Some(receiver_class) => {
+                        // `Foo.new` runs `Foo#initialize`, so prefer it over
+                        // any `self.new` definitions
+                        if usage_name == "new" {
+                            let initialize_query =
+                                self.definition_query("initialize", &["Def"], Some(receiver_class));
+                            let mut initialize_top_docs = searcher
+                                .measured_search(&initialize_query, &TopDocs::with_limit(50))?;
+
+                            if initialize_top_docs.is_empty() {
+                                initialize_top_docs = self.find_ancestor_definitions(
+                                    &searcher,
+                                    receiver_class,
+                                    "initialize",
+                                    &["Def"],
+                                )?;
+                            }
+
+                            if !initialize_top_docs.is_empty() {
+                                assignments_top_docs = initialize_top_docs;
+                            }
+                        }
+
+                        // The receiver class doesn't define the method, so try
+                        // its ancestors before matching the name anywhere
+                        if assignments_top_docs.is_empty() {
+                            assignments_top_docs = self.find_ancestor_definitions(
+                                &searcher,
+                                receiver_class,
+                                usage_name,
+                                assignment_types,
+                            )?;
+                        }
+
+                        if assignments_top_docs.is_empty() {
+                            let name_query =
+                                self.definition_query(usage_name, assignment_types, None);
+                            assignments_top_docs =
+                                searcher.measured_search(&name_query, &TopDocs::with_limit(50))?;
+                        }
+                    }
+                    None => {
+                        // Calls without a receiver go to the enclosing class,
+                        // then its ancestors, before the scored name search
+                        let enclosing_class = self.enclosing_class_name(&retrieved_doc);
+
+                        if let Some(enclosing_class) = enclosing_class {
+                            let class_query = self.definition_query(
+                                usage_name,
+                                assignment_types,
+                                Some(&enclosing_class),
+                            );
+                            let mut class_top_docs =
+                                searcher.measured_search(&class_query, &TopDocs::with_limit(50))?;
+
+                            if class_top_docs.is_empty() {
+                                class_top_docs = self.find_ancestor_definitions(
+                                    &searcher,
+                                    &enclosing_class,
+                                    usage_name,
+                                    assignment_types,
+                                )?;
+                            }
+
+                            // Methods called from a module are often defined
+                            // by the classes that include it
+                            if class_top_docs.is_empty() {
+                                for includer in self.find_includers(&searcher, &enclosing_class)? {
+                                    let includer_query = self.definition_query(
+                                        usage_name,
+                                        assignment_types,
+                                        Some(&includer),
+                                    );
+
+                                    class_top_docs.append(&mut searcher.measured_search(
+                                        &includer_query,
+                                        &TopDocs::with_limit(50),
+                                    )?);
+                                }
+                            }
+
+                            if !class_top_docs.is_empty() {
+                                assignments_top_docs = class_top_docs;
+                            }
+                        }
+                    }
+                }
+            }
+            // Templates have no class of their own, so their calls go to
+            // helpers and the controller rendering them, and instance
+            // variables to what the controller assigned
+            if let Some((controller, action)) = template_controller(&relative_path) {
+                assignments_top_docs = self.template_definitions(
+                    &searcher,
+                    &retrieved_doc,
+                    &controller,
+                    action.as_deref(),
+                    assignments_top_docs,
+                )?;
+            }
+
+            if usage_type == "Send" {
+                assignments_top_docs = self.prefer_method_kind(
+                    &searcher,
+                    assignments_top_docs,
+                    self.class_method_call(&retrieved_doc),
+                )?;
+            }
+
+            // A `let` is only visible inside its own example group
+            if self.settings.index_rspec_let {
+                let usage_scope: Vec<&str> = retrieved_doc
+                    .get_all(self.schema_fields.fuzzy_ruby_scope_field)
+                    .flat_map(Value::as_text)
+                    .collect();
+
+                let mut visible_top_docs = vec![];
+
+                for (score, doc_address) in assignments_top_docs {
+                    let assignment_doc = searcher.measured_doc(doc_address)?;
+                    let visible = assignment_doc
+                        .get_all(self.schema_fields.fuzzy_ruby_scope_field)
+                        .flat_map(Value::as_text)
+                        .filter(|scope_name| self.example_group_scope(scope_name))
+                        .all(|scope_name| usage_scope.contains(&scope_name));
+
+                    if visible {
+                        visible_top_docs.push((score, doc_address));
+                    }
+                }
+
+                assignments_top_docs = visible_top_docs;
+            }
+
+            // Not indexed yet, e.g. while indexing, but where Rails would
+            // autoload it from
+            if usage_type == "Const" && assignments_top_docs.is_empty() {
+                return Ok(self.autoload_locations(&retrieved_doc, usage_name));
+            }
+
+            let mut ranked_locations = Vec::new();
+
+            for (score, doc_address) in assignments_top_docs {
+                let retrieved_doc = searcher.measured_doc(doc_address)?;
+
+                let file_path: String = retrieved_doc
+                    .get_all(self.schema_fields.file_path)
+                    .flat_map(Value::as_text)
+                    .collect::<Vec<&str>>()
+                    .join("/");
+
+                let absolute_file_path: String;
+
+                let user_space = retrieved_doc
+                    .get_first(self.schema_fields.user_space_field)
+                    .unwrap()
+                    .as_bool()
+                    .unwrap() as bool;
+
+                if user_space {
+                    absolute_file_path = self.workspace_path.absolute_path(&file_path);
+                } else {
+                    absolute_file_path = workspace_path::absolute_path(&file_path);
+                }
+
+                let doc_uri = self.document_uri(&absolute_file_path);
+
+                let start_line = retrieved_doc
+                    .get_first(self.schema_fields.line_field)
+                    .unwrap()
+                    .as_u64()
+                    .unwrap() as u32;
+                let start_column = retrieved_doc
+                    .get_first(self.schema_fields.start_column_field)
+                    .unwrap()
+                    .as_u64()
+                    .unwrap() as u32;
+                let start_position = Position::new(start_line, start_column);
+                let end_column = retrieved_doc
+                    .get_first(self.schema_fields.end_column_field)
+                    .unwrap()
+                    .as_u64()
+                    .unwrap() as u32;
+                let end_position = Position::new(start_line, end_column);
+
+                let doc_range = Range::new(start_position, end_position);
+                let location = Location::new(doc_uri, doc_range);
+
+                if locations.contains(&location) {
+                    continue;
+                }
+
+                let proximity = self.definition_proximity(&relative_path, &file_path, user_space);
+                let autoloaded =
+                    usage_type == "Const" && self.autoloaded(&file_path, &retrieved_doc);
+                let autoload_declaration = self.autoload_declaration(&retrieved_doc);
+                let stdlib = retrieved_doc
+                    .get_first(self.schema_fields.stdlib_field)
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false);
+                let refinement = retrieved_doc
+                    .get_first(self.schema_fields.refinement_field)
+                    .is_some();
+
+                locations.push(location.clone());
+                ranked_locations.push((
+                    (!autoloaded, autoload_declaration, stdlib, refinement),
+                    proximity,
+                    score,
+                    location,
+                ));
+            }
+
+            // The standard library reopens core classes and defines common
+            // methods like `map` all over, so it's only searched when nothing
+            // else matches
+            if ranked_locations
+                .iter()
+                .any(|((_autoloaded, _declaration, stdlib, _refinement), ..)| !stdlib)
+            {
+                ranked_locations
+                    .retain(|((_autoloaded, _declaration, stdlib, _refinement), ..)| !stdlib);
+            }
+
+            // Constants in the file Rails would autoload them from first and
+            // `autoload` declarations last, methods from refinements after
+            // the ones the class always has, then the closest definitions,
+            // then by how well the scope matched
+            ranked_locations.sort_by(
+                |(a_autoloaded, a_proximity, a_score, _),
+                 (b_autoloaded, b_proximity, b_score, _)| {
+                    a_autoloaded
+                        .cmp(b_autoloaded)
+                        .then(a_proximity.cmp(b_proximity))
+                        .then(b_score.total_cmp(a_score))
+                },
+            );
+
+            let locations = ranked_locations
+                .into_iter()
+                .map(|(_autoloaded, _proximity, _score, location)| location)
+                .collect();
+
+            Ok(locations)
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    // Helper methods from `app/helpers` and methods of the controller or its
+    // ancestors for a call in a template, or the controller's assignments to
+    // an instance variable, those in the template's action first. The name
+    // search's results when there are none.
+    fn template_definitions(
+        &self,
+        searcher: &Searcher,
+        usage_doc: &Document,
+        controller: &str,
+        action: Option<&str>,
+        top_docs: Vec<(Score, DocAddress)>,
+    ) -> tantivy::Result<Vec<(Score, DocAddress)>> {
+        let name = self.definition_name(usage_doc);
+        let usage_type = usage_doc
+            .get_first(self.schema_fields.node_type_field)
+            .and_then(Value::as_text)
+            .unwrap_or_default();
+        let receiverless = usage_doc
+            .get_first(self.schema_fields.class_scope_field)
+            .is_none();
+        let class_name = controller.rsplit("::").next().unwrap_or(controller);
+
+        let mut template_top_docs = vec![];
+
+        match usage_type {
+            "Send" if receiverless => {
+                // Every helper is available in every template
+                for (score, doc_address) in &top_docs {
+                    let definition_doc = searcher.measured_doc(*doc_address)?;
+                    let file_path: Vec<&str> = definition_doc
+                        .get_all(self.schema_fields.file_path)
+                        .flat_map(Value::as_text)
+                        .collect();
+
+                    if file_path.starts_with(&["app", "helpers"]) {
+                        template_top_docs.push((*score, *doc_address));
+                    }
+                }
+            }
+            "Ivar" => {}
+            _ => return Ok(top_docs),
+        }
+
+        let assignment_types = USAGE_TYPE_RESTRICTIONS.get(usage_type).unwrap();
+        let controller_query = self.definition_query(&name, assignment_types, Some(class_name));
+        let mut controller_top_docs =
+            searcher.measured_search(&controller_query, &TopDocs::with_limit(50))?;
+
+        if controller_top_docs.is_empty() {
+            controller_top_docs =
+                self.find_ancestor_definitions(searcher, class_name, &name, assignment_types)?;
+        }
+
+        if let Some(action) = action {
+            for (score, doc_address) in &mut controller_top_docs {
+                let in_action = searcher
+                    .measured_doc(*doc_address)?
+                    .get_all(self.schema_fields.fuzzy_ruby_scope_field)
+                    .flat_map(Value::as_text)
+                    .any(|scope_name| scope_name == action);
+
+                if in_action {
+                    *score += 1000.0;
+                }
+            }
+        }
+
+        template_top_docs.append(&mut controller_top_docs);
+
+        if template_top_docs.is_empty() {
+            Ok(top_docs)
+        } else {
+            Ok(template_top_docs)
+        }
+    }
+
+    // The templates a `render` at the position could mean, or `None` when
+    // there's none there
+    fn rendered_view_definitions(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> Option<Vec<Location>> {
+        let parsed_document = self.open_document(&params.text_document.uri)?;
+        let position = params.position;
+
+        let rendered_view =
+            parsed_document
+                .findings
+                .rendered_views
+                .iter()
+                .find(|rendered_view| {
+                    rendered_view.line == position.line as usize
+                        && rendered_view.start_column <= position.character as usize
+                        && position.character as usize <= rendered_view.end_column
+                })?;
+
+        let locations = self
+            .rendered_view_paths(&uri_path(&params.text_document.uri), rendered_view)
+            .iter()
+            .flat_map(|path| Url::from_file_path(path).ok())
+            .map(|uri| Location::new(uri, Range::default()))
+            .collect();
+
+        Some(locations)
+    }
+
+    // The factory or trait whose symbol is at the position, or `None` when
+    // there's none there. Traits are looked up in the factory, then the
+    // factories it inherits from, then outside of any factory.
+    fn factory_definitions(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> tantivy::Result<Option<Vec<Location>>> {
+        let (Some(parsed_document), Some(reader)) =
+            (self.open_document(&params.text_document.uri), &self.reader)
+        else {
+            return Ok(None);
+        };
+
+        let position = params.position;
+        let Some(factory_reference) =
+            parsed_document
+                .findings
+                .factory_references
+                .iter()
+                .find(|factory_reference| {
+                    factory_reference.line == position.line as usize
+                        && factory_reference.start_column <= position.character as usize
+                        && position.character as usize <= factory_reference.end_column
+                })
+        else {
+            return Ok(None);
+        };
+
+        let searcher = reader.searcher();
+
+        let definition_docs = match &factory_reference.trait_name {
+            Some(trait_name) => {
+                let traits = self.factory_traits(&searcher, &factory_reference.factory)?;
+
+                traits
+                    .into_iter()
+                    .filter(|trait_doc| self.definition_name(trait_doc) == *trait_name)
+                    .collect()
+            }
+            None => self.factory_docs(&searcher, Some(&factory_reference.factory))?,
+        };
+
+        Ok(Some(
+            definition_docs
+                .iter()
+                .map(|definition_doc| self.definition_location(definition_doc))
+                .collect(),
+        ))
+    }
+
+    // The `shared_examples` or `shared_context` included by name at the
+    // position, closest first, or `None` when there's no name there
+    fn shared_group_definitions(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> tantivy::Result<Option<Vec<Location>>> {
+        let (Some(parsed_document), Some(reader)) =
+            (self.open_document(&params.text_document.uri), &self.reader)
+        else {
+            return Ok(None);
+        };
+
+        let position = params.position;
+        let Some(shared_group_reference) = parsed_document
+            .findings
+            .shared_group_references
+            .iter()
+            .find(|shared_group_reference| {
+                shared_group_reference.line == position.line as usize
+                    && shared_group_reference.start_column <= position.character as usize
+                    && position.character as usize <= shared_group_reference.end_column
+            })
+        else {
+            return Ok(None);
+        };
+
+        let searcher = reader.searcher();
+        let query = self.definition_query(
+            &shared_group_reference.name,
+            &["SharedExamples", "SharedContext"],
+            None,
+        );
+        let relative_path = self
+            .workspace_path
+            .relative_path(&uri_path(&params.text_document.uri));
+        let mut ranked_locations = vec![];
+
+        for doc_address in searcher.measured_search(&query, &DocSetCollector)? {
+            let definition_doc = searcher.measured_doc(doc_address)?;
+            let file_path = definition_doc
+                .get_all(self.schema_fields.file_path)
+                .flat_map(Value::as_text)
+                .collect::<Vec<&str>>()
+                .join("/");
+            let proximity = self.definition_proximity(&relative_path, &file_path, true);
+
+            ranked_locations.push((proximity, self.definition_location(&definition_doc)));
+        }
+
+        ranked_locations.sort_by(|(a_proximity, a_location), (b_proximity, b_location)| {
+            a_proximity
+                .cmp(b_proximity)
+                .then(a_location.uri.as_str().cmp(b_location.uri.as_str()))
+                .then(
+                    a_location
+                        .range
+                        .start
+                        .line
+                        .cmp(&b_location.range.start.line),
+                )
+        });
+
+        Ok(Some(
+            ranked_locations
+                .into_iter()
+                .map(|(_proximity, location)| location)
+                .collect(),
+        ))
+    }
+
+    // Every factory, or the ones with a name
+    pub(super) fn factory_docs(
+        &self,
+        searcher: &Searcher,
+        name: Option<&str>,
+    ) -> tantivy::Result<Vec<Document>> {
+        let query = match name {
+            Some(name) => self.definition_query(name, &["Factory"], None),
+            None => self.node_type_query("Factory"),
+        };
+
+        let mut factory_docs = vec![];
+
+        for doc_address in searcher.measured_search(&query, &DocSetCollector)? {
+            factory_docs.push(searcher.measured_doc(doc_address)?);
+        }
+
+        Ok(factory_docs)
+    }
+
+    // The traits a factory can use, closest first: its own, those of the
+    // factories it inherits from, and those defined outside of any factory
+    pub(super) fn factory_traits(
+        &self,
+        searcher: &Searcher,
+        factory: &str,
+    ) -> tantivy::Result<Vec<Document>> {
+        let mut trait_docs = vec![];
+
+        for doc_address in
+            searcher.measured_search(&self.node_type_query("Trait"), &DocSetCollector)?
+        {
+            trait_docs.push(searcher.measured_doc(doc_address)?);
+        }
+
+        let mut factory_chain = vec![factory.to_string()];
+
+        while factory_chain.len() < 10 {
+            let parent = self
+                .factory_docs(searcher, factory_chain.last().map(String::as_str))?
+                .iter()
+                .find_map(|factory_doc| {
+                    factory_doc
+                        .get_first(self.schema_fields.superclass_field)
+                        .and_then(Value::as_text)
+                        .map(str::to_string)
+                });
+
+            match parent {
+                Some(parent) if !factory_chain.contains(&parent) => factory_chain.push(parent),
+                _ => break,
+            }
+        }
+
+        // Where the trait was defined, with the ones outside of a factory last
+        let trait_owner = |trait_doc: &Document| {
+            let owner = trait_doc
+                .get_all(self.schema_fields.fuzzy_ruby_scope_field)
+                .flat_map(Value::as_text)
+                .last();
+
+            match owner {
+                Some(owner) => factory_chain.iter().position(|factory| factory == owner),
+                None => Some(factory_chain.len()),
+            }
+        };
+
+        trait_docs.retain(|trait_doc| trait_owner(trait_doc).is_some());
+        trait_docs.sort_by_key(|trait_doc| trait_owner(trait_doc));
+
+        Ok(trait_docs)
+    }
+
+    fn node_type_query(&self, node_type: &str) -> BooleanQuery {
+        let category_query: Box<dyn Query> = Box::new(TermQuery::new(
+            Term::from_field_text(self.schema_fields.category_field, "assignment"),
+            IndexRecordOption::Basic,
+        ));
+        let node_type_query: Box<dyn Query> = Box::new(TermQuery::new(
+            Term::from_field_text(self.schema_fields.node_type_field, node_type),
+            IndexRecordOption::Basic,
+        ));
+
+        BooleanQuery::new(vec![
+            (Occur::Must, category_query),
+            (Occur::Must, node_type_query),
+        ])
+    }
+
+    // Where the key passed to `t` at the position is translated, in every
+    // locale, or `None` when there's no key there
+    fn translation_definitions(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> Option<Vec<Location>> {
+        let parsed_document = self.open_document(&params.text_document.uri)?;
+        let position = params.position;
+
+        let translation_key =
+            parsed_document
+                .findings
+                .translation_keys
+                .iter()
+                .find(|translation_key| {
+                    translation_key.line == position.line as usize
+                        && translation_key.start_column <= position.character as usize
+                        && position.character as usize <= translation_key.end_column
+                })?;
+
+        let relative_path = self
+            .workspace_path
+            .relative_path(&uri_path(&params.text_document.uri));
+        let key = full_translation_key(&relative_path, &translation_key.key)?;
+
+        let locations = self
+            .locales
+            .get(&key)
+            .iter()
+            .flat_map(|locale_key| {
+                let uri = Url::from_file_path(&locale_key.path).ok()?;
+                let range = Range::new(
+                    Position::new(locale_key.line as u32, locale_key.start_column as u32),
+                    Position::new(locale_key.line as u32, locale_key.end_column as u32),
+                );
+
+                Some(Location::new(uri, range))
+            })
+            .collect();
+
+        Some(locations)
+    }
+
+    // The main file of the gem named by `gem "sidekiq"` in a `Gemfile` or
+    // `add_dependency` in a gemspec. `None` elsewhere.
+    fn gem_definitions(&self, params: &TextDocumentPositionParams) -> Option<Vec<Location>> {
+        let gem_declaration = self.gem_declaration(params)?;
+
+        Some(
+            self.gem_location(&gem_declaration.path)
+                .into_iter()
+                .collect(),
+        )
+    }
+
+    pub(super) fn gem_declaration(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> Option<&RequiredPath> {
+        let parsed_document = self.open_document(&params.text_document.uri)?;
+        let position = params.position;
+
+        parsed_document
+            .findings
+            .required_paths
+            .iter()
+            .find(|required_path| {
+                gem_dependency(&required_path.method_name)
+                    && required_path.line == position.line as usize
+                    && required_path.start_column <= position.character as usize
+                    && position.character as usize <= required_path.end_column
+            })
+    }
+
+    // The action a route in `config/routes.rb` goes to, or its controller
+    // for `resources` and actions without a method. `None` outside of routes.
+    fn route_definitions(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> tantivy::Result<Option<Vec<Location>>> {
+        let path = uri_path(&params.text_document.uri);
+
+        if !path.ends_with("/config/routes.rb") && !path.contains("/config/routes/") {
+            return Ok(None);
+        }
+
+        let (Some(parsed_document), Some(reader)) =
+            (self.open_document(&params.text_document.uri), &self.reader)
+        else {
+            return Ok(None);
+        };
+
+        let position = params.position;
+        let Some(route_target) =
+            parsed_document
+                .findings
+                .route_targets
+                .iter()
+                .find(|route_target| {
+                    route_target.line == position.line as usize
+                        && route_target.start_column <= position.character as usize
+                        && position.character as usize <= route_target.end_column
+                })
+        else {
+            return Ok(None);
+        };
+
+        let searcher = reader.searcher();
+        let controller = &route_target.controller;
+        let class_name = controller.rsplit("::").next().unwrap_or(controller);
+        let mut definition_docs = vec![];
+
+        if let Some(action) = &route_target.action {
+            let method_query = self.definition_query(action, &["Def"], Some(class_name));
+            let mut top_docs = searcher.measured_search(&method_query, &TopDocs::with_limit(50))?;
+
+            // e.g. from a base controller or a concern
+            if top_docs.is_empty() {
+                top_docs =
+                    self.find_ancestor_definitions(&searcher, class_name, action, &["Def"])?;
+            }
+
+            for (_score, doc_address) in top_docs {
+                definition_docs.push(searcher.measured_doc(doc_address)?);
+            }
+        }
+
+        if definition_docs.is_empty() {
+            let class_query = self.definition_query(class_name, &["Class"], None);
+
+            for (_score, doc_address) in
+                searcher.measured_search(&class_query, &TopDocs::with_limit(50))?
+            {
+                definition_docs.push(searcher.measured_doc(doc_address)?);
+            }
+        }
+
+        // Controllers with the same name in other namespaces only when
+        // there's none in the route's
+        let in_namespace = |definition_doc: &Document| {
+            let mut qualified_name = self.definition_container(definition_doc);
+
+            if self.definition_name(definition_doc) == class_name {
+                qualified_name = format!("{}::{}", qualified_name, class_name);
+            }
+
+            qualified_name.trim_start_matches("::") == controller
+        };
+
+        if definition_docs.iter().any(in_namespace) {
+            definition_docs.retain(in_namespace);
+        }
+
+        Ok(Some(
+            definition_docs
+                .iter()
+                .map(|definition_doc| self.definition_location(definition_doc))
+                .collect(),
+        ))
+    }
+
+    pub(super) fn definition_query(
+        &self,
+        name: &str,
+        assignment_types: &[&str],
+        class_name: Option<&str>,
+    ) -> BooleanQuery {
+        let category_query: Box<dyn Query> = Box::new(TermQuery::new(
+            Term::from_field_text(self.schema_fields.category_field, "assignment"),
+            IndexRecordOption::Basic,
+        ));
+        let name_query: Box<dyn Query> = Box::new(TermQuery::new(
+            Term::from_field_text(self.schema_fields.name_field, name),
+            IndexRecordOption::Basic,
+        ));
+
+        let mut assignment_type_queries = vec![];
+
+        for assignment_type in assignment_types {
+            let assignment_type_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(self.schema_fields.node_type_field, assignment_type),
+                IndexRecordOption::Basic,
+            ));
+
+            assignment_type_queries.push((Occur::Should, assignment_type_query));
+        }
+
+        let mut queries = vec![
+            (Occur::Must, category_query),
+            (Occur::Must, name_query),
+            (
+                Occur::Must,
+                Box::new(BooleanQuery::new(assignment_type_queries)) as Box<dyn Query>,
+            ),
+        ];
+
+        if let Some(class_name) = class_name {
+            let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(self.schema_fields.fuzzy_ruby_scope_field, class_name),
+                IndexRecordOption::Basic,
+            ));
+
+            queries.push((Occur::Must, scope_query));
+        }
+
+        BooleanQuery::new(queries)
+    }
+
+    fn find_superclass(
+        &self,
+        searcher: &Searcher,
+        class_name: &str,
+    ) -> tantivy::Result<Option<String>> {
+        let class_query = self.definition_query(class_name, &["Class"], None);
+        let class_top_docs = searcher.measured_search(&class_query, &TopDocs::with_limit(10))?;
+
+        for (_score, doc_address) in class_top_docs {
+            let class_doc = searcher.measured_doc(doc_address)?;
+
+            if let Some(superclass) = class_doc
+                .get_first(self.schema_fields.superclass_field)
+                .and_then(Value::as_text)
+            {
+                return Ok(Some(superclass.to_string()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Modules mixed into `class_name` with include, extend, or prepend
+    fn find_mixins(&self, searcher: &Searcher, class_name: &str) -> tantivy::Result<Vec<String>> {
+        let category_query: Box<dyn Query> = Box::new(TermQuery::new(
+            Term::from_field_text(self.schema_fields.category_field, "mixin"),
+            IndexRecordOption::Basic,
+        ));
+        let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
+            Term::from_field_text(self.schema_fields.fuzzy_ruby_scope_field, class_name),
+            IndexRecordOption::Basic,
+        ));
+
+        let query = BooleanQuery::new(vec![
+            (Occur::Must, category_query),
+            (Occur::Must, scope_query),
+        ]);
+
+        let mut mixins = vec![];
+
+        for (_score, doc_address) in searcher.measured_search(&query, &TopDocs::with_limit(100))? {
+            let mixin_doc = searcher.measured_doc(doc_address)?;
+
+            // The scope includes outer namespaces, so only keep mixins that
+            // were declared directly in `class_name`
+            if self.enclosing_class_name(&mixin_doc).as_deref() != Some(class_name) {
+                continue;
+            }
+
+            if let Some(mixin_name) = mixin_doc
+                .get_first(self.schema_fields.name_field)
+                .and_then(Value::as_text)
+            {
+                mixins.push(mixin_name.to_string());
+            }
+        }
+
+        Ok(mixins)
+    }
+
+    // Classes and modules that include, extend, or prepend `module_name`
+    fn find_includers(
+        &self,
+        searcher: &Searcher,
+        module_name: &str,
+    ) -> tantivy::Result<Vec<String>> {
+        let category_query: Box<dyn Query> = Box::new(TermQuery::new(
+            Term::from_field_text(self.schema_fields.category_field, "mixin"),
+            IndexRecordOption::Basic,
+        ));
+        let name_query: Box<dyn Query> = Box::new(TermQuery::new(
+            Term::from_field_text(self.schema_fields.name_field, module_name),
+            IndexRecordOption::Basic,
+        ));
+
+        let query = BooleanQuery::new(vec![
+            (Occur::Must, category_query),
+            (Occur::Must, name_query),
+        ]);
+
+        let mut includers = vec![];
+
+        for (_score, doc_address) in searcher.measured_search(&query, &TopDocs::with_limit(100))? {
+            let mixin_doc = searcher.measured_doc(doc_address)?;
+
+            if let Some(includer) = self.enclosing_class_name(&mixin_doc) {
+                if !includers.contains(&includer) {
+                    includers.push(includer);
+                }
+            }
+        }
+
+        Ok(includers)
+    }
+
+    fn enclosing_class_name(&self, document: &Document) -> Option<String> {
+        document
+            .get_all(self.schema_fields.fuzzy_ruby_scope_field)
+            .flat_map(Value::as_text)
+            .filter(|scope_name| scope_name.starts_with(char::is_uppercase))
+            .last()
+            .map(str::to_string)
+    }
+
+    // Approximates Ruby's ancestor chain: each class's mixins followed by its
+    // superclass, breadth first
+    pub(super) fn find_ancestors(
+        &self,
+        searcher: &Searcher,
+        class_name: &str,
+    ) -> tantivy::Result<Vec<String>> {
+        let mut ancestors = vec![class_name.to_string()];
+        let mut position = 0;
+
+        while position < ancestors.len() && ancestors.len() < 25 {
+            let current_class = ancestors[position].clone();
+            let mixins = self.find_mixins(searcher, &current_class)?;
+            let superclass = self.find_superclass(searcher, &current_class)?;
+
+            for ancestor in mixins.into_iter().chain(superclass) {
+                if !ancestors.contains(&ancestor) {
+                    ancestors.push(ancestor);
+                }
+            }
+
+            position += 1;
+        }
+
+        ancestors.remove(0);
+
+        Ok(ancestors)
+    }
+
+    // Returns the definitions of `name` from the closest ancestor of
+    // `class_name` that has any
+    pub(super) fn find_ancestor_definitions(
+        &self,
+        searcher: &Searcher,
+        class_name: &str,
+        name: &str,
+        assignment_types: &[&str],
+    ) -> tantivy::Result<Vec<(Score, DocAddress)>> {
+        for ancestor_name in self.find_ancestors(searcher, class_name)? {
+            let ancestor_query =
+                self.definition_query(name, assignment_types, Some(&ancestor_name));
+            let ancestor_top_docs =
+                searcher.measured_search(&ancestor_query, &TopDocs::with_limit(50))?;
+
+            if !ancestor_top_docs.is_empty() {
+                return Ok(ancestor_top_docs);
+            }
+        }
+
+        Ok(vec![])
+    }
+
+    // Emulates Ruby's constant lookup: the innermost lexical scope outward,
+    // then the ancestors of the enclosing class. `::Foo` only looks at the top
+    // level. Returns nothing when no candidate is in a matching scope.
+    fn resolve_constant(
+        &self,
+        searcher: &Searcher,
+        usage_doc: &Document,
+        name: &str,
+        assignment_types: &[&str],
+    ) -> tantivy::Result<Vec<(Score, DocAddress)>> {
+        let lexical_scope: Vec<&str> = usage_doc
+            .get_all(self.schema_fields.fuzzy_ruby_scope_field)
+            .flat_map(Value::as_text)
+            .filter(|scope_name| scope_name.starts_with(char::is_uppercase))
+            .collect();
+        let class_scope: Vec<&str> = usage_doc
+            .get_all(self.schema_fields.class_scope_field)
+            .flat_map(Value::as_text)
+            .collect();
+
+        let top_level = class_scope.contains(&TOP_LEVEL_SCOPE);
+
+        // `A::B::Name` is stored innermost first as ["B", "A"]
+        let namespace: Vec<&str> = class_scope
+            .iter()
+            .rev()
+            .filter(|scope_name| **scope_name != TOP_LEVEL_SCOPE)
+            .copied()
+            .collect();
+
+        let candidate_query = self.definition_query(name, assignment_types, None);
+        let mut candidates = vec![];
+
+        for (score, doc_address) in
+            searcher.measured_search(&candidate_query, &TopDocs::with_limit(500))?
+        {
+            let candidate_doc = searcher.measured_doc(doc_address)?;
+            let candidate_scope: Vec<String> = candidate_doc
+                .get_all(self.schema_fields.fuzzy_ruby_scope_field)
+                .flat_map(Value::as_text)
+                .filter(|scope_name| scope_name.starts_with(char::is_uppercase))
+                .map(str::to_string)
+                .collect();
+
+            candidates.push((score, doc_address, candidate_scope));
+        }
+
+        let matching = |expected_scope: &[&str]| -> Vec<(Score, DocAddress)> {
+            candidates
+                .iter()
+                .filter(|(_score, _doc_address, candidate_scope)| candidate_scope == expected_scope)
+                .map(|(score, doc_address, _candidate_scope)| (*score, *doc_address))
+                .collect()
+        };
+
+        let nesting_depths: Vec<usize> = if top_level {
+            vec![0]
+        } else {
+            (0..=lexical_scope.len()).rev().collect()
+        };
+
+        for depth in nesting_depths {
+            let mut expected_scope = lexical_scope[..depth].to_vec();
+            expected_scope.extend(&namespace);
+
+            let matches = matching(&expected_scope);
+
+            if !matches.is_empty() {
+                return Ok(matches);
+            }
+        }
+
+        if top_level {
+            return Ok(vec![]);
+        }
+
+        if let Some(enclosing_class) = lexical_scope.last() {
+            for ancestor in self.find_ancestors(searcher, enclosing_class)? {
+                let matches: Vec<(Score, DocAddress)> = candidates
+                    .iter()
+                    .filter(|(_score, _doc_address, candidate_scope)| {
+                        let mut ancestor_scope = vec![ancestor.as_str()];
+                        ancestor_scope.extend(&namespace);
+
+                        candidate_scope.ends_with(
+                            &ancestor_scope
+                                .iter()
+                                .map(|scope_name| scope_name.to_string())
+                                .collect::<Vec<String>>(),
+                        )
+                    })
+                    .map(|(score, doc_address, _candidate_scope)| (*score, *doc_address))
+                    .collect();
+
+                if !matches.is_empty() {
+                    return Ok(matches);
+                }
+            }
+        }
+
+        Ok(vec![])
+    }
+
+    // Calls on a constant, or without a receiver inside a class method, call
+    // class methods
+    pub(super) fn class_method_call(&self, usage_doc: &Document) -> bool {
+        let class_scope: Vec<&str> = usage_doc
+            .get_all(self.schema_fields.class_scope_field)
+            .flat_map(Value::as_text)
+            .collect();
+
+        if !class_scope.is_empty() {
+            return class_scope.contains(&SINGLETON_RECEIVER_SCOPE);
+        }
+
+        usage_doc
+            .get_all(self.schema_fields.fuzzy_ruby_scope_field)
+            .flat_map(Value::as_text)
+            .filter(|scope_name| !scope_name.starts_with(char::is_uppercase))
+            .last()
+            .is_some_and(|method_scope| method_scope.starts_with(SINGLETON_RECEIVER_SCOPE))
+    }
+
+    // Keeps only class methods or only instance methods when there are
+    // definitions of both, e.g. `def self.fetch` and `def fetch`
+    fn prefer_method_kind(
+        &self,
+        searcher: &Searcher,
+        top_docs: Vec<(Score, DocAddress)>,
+        class_methods: bool,
+    ) -> tantivy::Result<Vec<(Score, DocAddress)>> {
+        let mut preferred = vec![];
+        let mut others = vec![];
+
+        for (score, doc_address) in top_docs {
+            let node_type = searcher
+                .measured_doc(doc_address)?
+                .get_first(self.schema_fields.node_type_field)
+                .and_then(Value::as_text)
+                .map(str::to_string);
+
+            if (node_type.as_deref() == Some("Defs")) == class_methods {
+                preferred.push((score, doc_address));
+            } else {
+                others.push((score, doc_address));
+            }
+        }
+
+        if preferred.is_empty() {
+            Ok(others)
+        } else {
+            Ok(preferred)
+        }
+    }
+
+    // Whether a class or module is defined in the file Rails would autoload
+    // it from, e.g. `Admin::UsersController` in
+    // `app/controllers/admin/users_controller.rb`
+    fn autoloaded(&self, file_path: &str, definition_doc: &Document) -> bool {
+        let node_type = definition_doc
+            .get_first(self.schema_fields.node_type_field)
+            .and_then(Value::as_text);
+
+        if !matches!(node_type, Some("Class" | "Module")) {
+            return false;
+        }
+
+        let container = self.definition_container(definition_doc);
+        let name = self.definition_name(definition_doc);
+        let qualified_name = if container.is_empty() {
+            name
+        } else {
+            format!("{}::{}", container, name)
+        };
+
+        let constant_path = format!("{}.rb", underscore(&qualified_name));
+
+        match file_path.strip_suffix(&constant_path) {
+            Some("") => true,
+            Some(root) => match root.strip_suffix('/') {
+                Some(root) => self
+                    .settings
+                    .autoload_paths
+                    .iter()
+                    .any(|autoload_path| autoload_root(autoload_path, root)),
+                None => false,
+            },
+            None => false,
+        }
+    }
+
+    // A constant indexed from `autoload :Parser, "my_gem/parser"` rather
+    // than where it's defined
+    fn autoload_declaration(&self, definition_doc: &Document) -> bool {
+        definition_doc
+            .get_first(self.schema_fields.documentation_field)
+            .and_then(Value::as_text)
+            .is_some_and(|documentation| documentation.starts_with(AUTOLOAD_DOCUMENTATION))
+    }
+
+    // The files Rails would autoload a constant from, trying the same
+    // namespaces as `resolve_constant`, innermost first
+    fn autoload_locations(&self, usage_doc: &Document, name: &str) -> Vec<Location> {
+        let lexical_scope: Vec<&str> = usage_doc
+            .get_all(self.schema_fields.fuzzy_ruby_scope_field)
+            .flat_map(Value::as_text)
+            .filter(|scope_name| scope_name.starts_with(char::is_uppercase))
+            .collect();
+        let class_scope: Vec<&str> = usage_doc
+            .get_all(self.schema_fields.class_scope_field)
+            .flat_map(Value::as_text)
+            .collect();
+
+        let nesting_depths: Vec<usize> = if class_scope.contains(&TOP_LEVEL_SCOPE) {
+            vec![0]
+        } else {
+            (0..=lexical_scope.len()).rev().collect()
+        };
+
+        let namespace: Vec<&str> = class_scope
+            .iter()
+            .rev()
+            .filter(|scope_name| **scope_name != TOP_LEVEL_SCOPE)
+            .copied()
+            .collect();
+
+        let workspace_path = self.workspace_path.as_path();
+
+        if workspace_path.as_os_str().is_empty() {
+            return vec![];
+        }
+
+        let roots: Vec<PathBuf> = self
+            .settings
+            .autoload_paths
+            .iter()
+            .flat_map(|autoload_path| expand_autoload_path(workspace_path, autoload_path))
+            .collect();
+
+        for depth in nesting_depths {
+            let mut qualified_name = lexical_scope[..depth].to_vec();
+            qualified_name.extend(&namespace);
+            qualified_name.push(name);
+
+            let constant_path = format!("{}.rb", underscore(&qualified_name.join("::")));
+
+            let locations: Vec<Location> = roots
+                .iter()
+                .map(|root| root.join(&constant_path))
+                .filter(|file_path| file_path.is_file())
+                .map(|file_path| {
+                    let uri = self.document_uri(&file_path.to_string_lossy());
+                    Location::new(uri, Range::default())
+                })
+                .collect();
+
+            if !locations.is_empty() {
+                return locations;
+            }
+        }
+
+        vec![]
+    }
+
+    // Lower is closer: same file, same directory, user space, then gems
+    fn definition_proximity(&self, relative_path: &str, file_path: &str, user_space: bool) -> u8 {
+        if !user_space {
+            return 3;
+        }
+
+        let relative_path = relative_path.trim_start_matches("/");
+
+        if relative_path == file_path {
+            return 0;
+        }
+
+        let usage_dir = relative_path
+            .rsplit_once("/")
+            .map(|(dir, _)| dir)
+            .unwrap_or("");
+        let definition_dir = file_path.rsplit_once("/").map(|(dir, _)| dir).unwrap_or("");
+
+        if usage_dir == definition_dir {
+            1
+        } else {
+            2
+        }
+    }
+
+    pub fn find_references(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> tantivy::Result<Vec<Document>> {
+        let path = uri_path(&params.text_document.uri);
+        let relative_path = self.workspace_path.relative_path(&path);
+
+        let position = params.position;
+
+        if let Some(reader) = &self.reader {
+            let searcher = reader.searcher();
+            let character_position = position.character;
+            let character_line = position.line;
+            let file_path_id = workspace_path::file_path_id(&relative_path);
+
+            let file_path_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(self.schema_fields.file_path_id, &file_path_id),
+                IndexRecordOption::Basic,
+            ));
+            let line_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_u64(self.schema_fields.line_field, character_line.into()),
+                IndexRecordOption::Basic,
+            ));
+            let column_query = self.column_query(character_position);
+
+            // Mixin documents share a position with the module's Const usage
+            let mixin_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(self.schema_fields.category_field, "mixin"),
+                IndexRecordOption::Basic,
+            ));
+
+            let query = BooleanQuery::new(vec![
+                (Occur::Must, file_path_query),
+                (Occur::Must, line_query),
+                (Occur::Must, column_query),
+                (Occur::MustNot, mixin_query),
+            ]);
+
+            let usage_top_docs = searcher.measured_search(&query, &TopDocs::with_limit(1))?;
+
+            if usage_top_docs.len() == 0 {
+                info!("No highlight usages docs found");
+                return Ok(Vec::new());
+            }
+
+            let doc_address = usage_top_docs[0].1;
+            let retrieved_doc = searcher.measured_doc(doc_address)?;
+
+            let usage_name = retrieved_doc
+                .get_first(self.schema_fields.name_field)
+                .unwrap()
+                .as_text()
+                .unwrap();
+            let token_type = retrieved_doc
+                .get_first(self.schema_fields.node_type_field)
+                .unwrap()
+                .as_text()
+                .unwrap();
+
+            let file_path_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(self.schema_fields.file_path_id, &file_path_id),
+                IndexRecordOption::Basic,
+            ));
+
+            let mut name_queries = vec![];
+
+            // Readers and setters are renamed together, e.g. `name` and `name=`
+            let mut names = vec![usage_name.to_string()];
+
+            if matches!(token_type, "Alias" | "CSend" | "Def" | "Defs" | "Send") {
+                if let Some(reader_name) = setter_reader_name(usage_name) {
+                    names.push(reader_name.to_string());
+                } else if identifier_name(usage_name) {
+                    names.push(format!("{}=", usage_name));
+                }
+            }
+
+            for name in &names {
+                let name_query: Box<dyn Query> = Box::new(TermQuery::new(
+                    Term::from_field_text(self.schema_fields.name_field, name),
+                    IndexRecordOption::Basic,
+                ));
+
+                name_queries.push((Occur::Should, name_query));
+            }
+
+            let name_query: Box<dyn Query> = Box::new(BooleanQuery::new(name_queries));
+
+            let mut highlight_token_queries = vec![];
+
+            for possible_assignment_type in USAGE_TYPE_RESTRICTIONS
+                .get(token_type)
+                .unwrap_or(&[].as_slice())
+                .iter()
+            {
+                let assignment_type_query: Box<dyn Query> = Box::new(TermQuery::new(
+                    Term::from_field_text(
+                        self.schema_fields.node_type_field,
+                        possible_assignment_type,
+                    ),
+                    IndexRecordOption::Basic,
+                ));
+
+                highlight_token_queries.push((Occur::Should, assignment_type_query));
+            }
+            for possible_usage_type in ASSIGNMENT_TYPE_RESTRICTIONS
+                .get(token_type)
+                .unwrap_or(&[].as_slice())
+                .iter()
+            {
+                let usage_type_query: Box<dyn Query> = Box::new(TermQuery::new(
+                    Term::from_field_text(self.schema_fields.node_type_field, possible_usage_type),
+                    IndexRecordOption::Basic,
+                ));
+
+                highlight_token_queries.push((Occur::Should, usage_type_query));
+            }
+
+            let token_type_query = BooleanQuery::new(highlight_token_queries);
+
+            let mut queries = vec![
+                (Occur::Must, file_path_query),
+                (Occur::Must, name_query),
+                (Occur::Must, Box::new(token_type_query)),
+            ];
+
+            let usage_fuzzy_scope =
+                retrieved_doc.get_all(self.schema_fields.fuzzy_ruby_scope_field);
+
+            match token_type {
+                // "Alias" => {},
+                // "Const" => {},
+                // "CSend" => {},
+                // todo: improved indexed scopes so there is a separate class scope, etc
+                // "Cvar" => {},
+                // "Gvar" => {},
+                // todo: improved indexed scopes so there is a separate class scope, etc
+                // "Ivar" => {},
+                // todo: improved to be more accurate
+
+                // same values as local assignment type restrictions, for
+                // example "Lvasgn" in ASSIGNMENT_TYPE_RESTRICTIONS
+                "Arg" | "Blockarg" | "Kwarg" | "Kwoptarg" | "Kwrestarg" | "Lvasgn" | "MatchVar"
+                | "Optarg" | "Restarg" | "Shadowarg" | "Lvar" => {
+                    for scope_name in usage_fuzzy_scope {
+                        let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
+                            Term::from_field_text(
+                                self.schema_fields.fuzzy_ruby_scope_field,
+                                scope_name.as_text().unwrap(),
+                            ),
+                            IndexRecordOption::Basic,
+                        ));
+
+                        queries.push((Occur::Must, scope_query));
+                    }
+                }
+                // "Send" => {},
+                // "Super" => {},
+                // "ZSuper" => {},
+                _ => {
+                    for scope_name in usage_fuzzy_scope {
+                        let scope_query: Box<dyn Query> = Box::new(TermQuery::new(
+                            Term::from_field_text(
+                                self.schema_fields.fuzzy_ruby_scope_field,
+                                scope_name.as_text().unwrap(),
+                            ),
+                            IndexRecordOption::Basic,
+                        ));
+
+                        queries.push((Occur::Should, scope_query));
+                    }
+                }
+            };
+
+            let results =
+                searcher.measured_search(&BooleanQuery::new(queries), &TopDocs::with_limit(100))?;
+
+            let mut documents = Vec::new();
+
+            for (_score, doc_address) in results {
+                documents.push(searcher.measured_doc(doc_address).unwrap())
+            }
+
+            Ok(documents)
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    pub fn find_references_in_workspace(&self, query: String) -> tantivy::Result<Vec<Document>> {
+        let mut documents = Vec::new();
+
+        for batch in self.workspace_symbol_batches(query)? {
+            documents.extend(batch?);
+        }
+
+        Ok(documents)
+    }
+
+    // Names are ranked before any documents are fetched so the best matches
+    // can't be dropped by the result limit, then fetched a batch at a time so
+    // callers can stream each batch as soon as it's found
+    pub fn workspace_symbol_batches(&self, query: String) -> tantivy::Result<SymbolBatches<'_>> {
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(SymbolBatches::empty(self)),
+        };
+        let searcher = reader.searcher();
+
+        // Queries like `models/user create` match the leading terms against
+        // the file path and the last term against the name
+        let (path_query, query) = match query.trim().rsplit_once(char::is_whitespace) {
+            Some((path_query, name_query)) => (path_query, name_query),
+            None => ("", query.trim()),
+        };
+
+        // Queries like `class:User` or `def:perform` restrict the node types
+        let (query, allowed_types) = match query.split_once(":") {
+            Some((prefix, name_query)) if SYMBOL_QUERY_FILTERS.contains_key(prefix) => {
+                (name_query.trim(), SYMBOL_QUERY_FILTERS[prefix])
+            }
+            _ => (query, SYMBOL_TYPES),
+        };
+
+        // Only names containing the query characters in order are visited,
+        // e.g. `usrctrl` => `[^\0]*u[^\0]*s[^\0]*r...\0.*` over the lowercase
+        // half of each `name_lowercase` term
+        let subsequence_pattern: String = query
+            .to_lowercase()
+            .chars()
+            .map(|query_char| format!("{}[^\\x00]*", regex::escape(&query_char.to_string())))
+            .collect();
+        let name_automaton =
+            tantivy_fst::Regex::new(&format!("[^\\x00]*{}\\x00.*", subsequence_pattern))
+                .map_err(|error| tantivy::TantivyError::InvalidArgument(error.to_string()))?;
+
+        let mut name_scores = HashMap::new();
+
+        for segment_reader in searcher.segment_readers() {
+            let inverted_index =
+                segment_reader.inverted_index(self.schema_fields.name_lowercase_field)?;
+            let mut terms = inverted_index
+                .terms()
+                .search(&name_automaton)
+                .into_stream()?;
+
+            while terms.advance() {
+                let name = match str::from_utf8(terms.key()) {
+                    Ok(term) => term.split_once('\0').map_or(term, |(_, name)| name),
+                    Err(_) => continue,
+                };
+
+                if name_scores.contains_key(name) {
+                    continue;
+                }
+
+                if let Some(score) = fuzzy_match_score(query, name) {
+                    name_scores.insert(name.to_string(), score);
+                }
+            }
+        }
+
+        let mut ranked_names: Vec<(String, i64)> = name_scores.into_iter().collect();
+        ranked_names.sort_by(|(a_name, a_score), (b_name, b_score)| {
+            b_score.cmp(a_score).then(a_name.cmp(b_name))
+        });
+
+        let mut allowed_type_queries = vec![];
+
+        for allowed_type in allowed_types {
+            let assignment_type_query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(self.schema_fields.node_type_field, allowed_type),
+                IndexRecordOption::Basic,
+            ));
+
+            allowed_type_queries.push((Occur::Should, assignment_type_query));
+        }
+
+        let user_space_query: Box<dyn Query> = Box::new(TermQuery::new(
+            Term::from_field_bool(self.schema_fields.user_space_field, true),
+            IndexRecordOption::Basic,
+        ));
+
+        let mut filter_queries = vec![
+            (Occur::Must, user_space_query),
+            (
+                Occur::Must,
+                Box::new(BooleanQuery::new(allowed_type_queries)) as Box<dyn Query>,
+            ),
+        ];
+
+        for path_part in path_query.split(|c: char| c == '/' || c.is_whitespace()) {
+            if !path_part.is_empty() {
+                let path_part_query: Box<dyn Query> = Box::new(RegexQuery::from_pattern(
+                    format!("{}.*", regex::escape(path_part)).as_str(),
+                    self.schema_fields.file_path,
+                )?);
+
+                filter_queries.push((Occur::Must, path_part_query));
+            }
+        }
+
+        let filter_query: Box<dyn Query> = Box::new(ConstScoreQuery::new(
+            Box::new(BooleanQuery::new(filter_queries)),
+            0.0,
+        ));
+
+        Ok(SymbolBatches {
+            persistence: self,
+            searcher: Some(searcher),
+            filter_query,
+            ranked_names,
+            next_name_index: 0,
+            remaining: self.settings.symbol_search_limit,
+        })
+    }
+
+    // Documents that aren't files, like unsaved editor tabs in single-file
+    // mode, are indexed by their URI's path, so they're looked up again to
+    // point back at them
+    pub(super) fn document_uri(&self, path: &str) -> Url {
+        let open_document_uri = self
+            .open_documents
+            .get(&session::current())
+            .into_iter()
+            .flat_map(|open_documents| open_documents.keys())
+            .find(|uri| uri.scheme() != "file" && uri.path() == path.trim_start_matches('/'));
+
+        match open_document_uri {
+            Some(uri) => uri.clone(),
+            None => Url::from_file_path(path).unwrap(),
+        }
+    }
+
+    pub fn documents_to_locations(&self, path: &str, documents: Vec<Document>) -> Vec<Location> {
+        let mut locations = Vec::new();
+
+        for document in documents {
+            let doc_uri = self.document_uri(path);
+
+            let start_line = document
+                .get_first(self.schema_fields.line_field)
+                .unwrap()
+                .as_u64()
+                .unwrap() as u32;
+            let start_column = document
+                .get_first(self.schema_fields.start_column_field)
+                .unwrap()
+                .as_u64()
+                .unwrap() as u32;
+            let start_position = Position::new(start_line, start_column);
+            let end_column = document
+                .get_first(self.schema_fields.end_column_field)
+                .unwrap()
+                .as_u64()
+                .unwrap() as u32;
+            let end_position = Position::new(start_line, end_column);
+
+            let doc_range = Range::new(start_position, end_position);
+            let location = Location::new(doc_uri, doc_range);
+
+            locations.push(location);
+        }
+
+        locations
+    }
+
+    // Definitions matching every part of a structured query, for
+    // `fuzzy/search`: an exact name, any of the node types, e.g. `["Def",
+    // "Defs"]`, the classes and modules they're in, e.g.
+    // `Admin::UsersController`, and only the workspace's own. Each part is
+    // optional, and at most `limit` are returned, sorted by file and line.
+    pub fn search(
+        &self,
+        name: Option<&str>,
+        node_types: &[String],
+        scope: Option<&str>,
+        user_space_only: bool,
+        limit: Option<usize>,
+    ) -> tantivy::Result<Vec<serde_json::Value>> {
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(vec![]),
+        };
+
+        let term_query = |term: Term| -> Box<dyn Query> {
+            Box::new(TermQuery::new(term, IndexRecordOption::Basic))
+        };
+
+        let mut queries = vec![(
+            Occur::Must,
+            term_query(Term::from_field_text(
+                self.schema_fields.category_field,
+                "assignment",
+            )),
+        )];
+
+        if let Some(name) = name {
+            queries.push((
+                Occur::Must,
+                term_query(Term::from_field_text(self.schema_fields.name_field, name)),
+            ));
+        }
+
+        if !node_types.is_empty() {
+            let node_type_queries = node_types
+                .iter()
+                .map(|node_type| {
+                    (
+                        Occur::Should,
+                        term_query(Term::from_field_text(
+                            self.schema_fields.node_type_field,
+                            node_type,
+                        )),
+                    )
+                })
+                .collect();
+
+            queries.push((Occur::Must, Box::new(BooleanQuery::new(node_type_queries))));
+        }
+
+        // Narrowed down by each name in the scope, then compared whole below
+        for scope_name in scope.iter().flat_map(|scope| scope.split("::")) {
+            queries.push((
+                Occur::Must,
+                term_query(Term::from_field_text(
+                    self.schema_fields.fuzzy_ruby_scope_field,
+                    scope_name,
+                )),
+            ));
+        }
+
+        if user_space_only {
+            queries.push((
+                Occur::Must,
+                term_query(Term::from_field_bool(
+                    self.schema_fields.user_space_field,
+                    true,
+                )),
+            ));
+        }
+
+        let searcher = reader.searcher();
+        let limit = limit.unwrap_or(self.settings.symbol_search_limit);
+        let doc_addresses =
+            searcher.measured_search(&BooleanQuery::new(queries), &DocSetCollector)?;
+        let mut records = vec![];
+
+        for doc_address in doc_addresses {
+            let definition_doc = searcher.measured_doc(doc_address)?;
+            let container = self.definition_container(&definition_doc);
+
+            if scope.is_some_and(|scope| scope != container) {
+                continue;
+            }
+
+            let location = self.definition_location(&definition_doc);
+            let node_type = definition_doc
+                .get_first(self.schema_fields.node_type_field)
+                .and_then(Value::as_text)
+                .unwrap_or_default();
+
+            records.push((
+                location.uri.to_string(),
+                location.range.start.line,
+                json!({
+                    "name": self.definition_name(&definition_doc),
+                    "nodeType": node_type,
+                    "scope": container,
+                    "uri": location.uri,
+                    "range": location.range,
+                    "fullRange": self.definition_full_range(&definition_doc),
+                }),
+            ));
+        }
+
+        records.sort_by(|(uri, line, _), (other_uri, other_line, _)| {
+            (uri, line).cmp(&(other_uri, other_line))
+        });
+
+        // Cut after sorting so `limit` keeps the first matches by file
+        Ok(records
+            .into_iter()
+            .take(limit)
+            .map(|(_uri, _line, record)| record)
+            .collect())
+    }
+}
+
+// The reverse of `camelize`, e.g. `admin/users_controller` for
+// `Admin::UsersController` and `html_parser` for `HTMLParser`
+fn underscore(constant_name: &str) -> String {
+    let chars: Vec<char> = constant_name.replace("::", "/").chars().collect();
+    let mut path = String::new();
+
+    for (index, c) in chars.iter().enumerate() {
+        if !c.is_uppercase() {
+            path.push(*c);
+            continue;
+        }
+
+        let previous = index.checked_sub(1).map(|index| chars[index]);
+        let next = chars.get(index + 1);
+
+        let word_start = match previous {
+            Some(previous) if previous.is_lowercase() || previous.is_ascii_digit() => true,
+            Some(previous) if previous.is_uppercase() => {
+                next.is_some_and(|next| next.is_lowercase())
+            }
+            _ => false,
+        };
+
+        if word_start {
+            path.push('_');
+        }
+        path.extend(c.to_lowercase());
+    }
+
+    path
+}
+
+// Whether a directory relative to the workspace is one of `autoloadPaths`,
+// e.g. `app/models` for `app/*`
+fn autoload_root(autoload_path: &str, root: &str) -> bool {
+    let pattern: Vec<&str> = autoload_path.trim_matches('/').split('/').collect();
+    let root: Vec<&str> = root.split('/').collect();
+
+    pattern.len() == root.len()
+        && pattern
+            .iter()
+            .zip(&root)
+            .all(|(pattern_part, root_part)| *pattern_part == "*" || pattern_part == root_part)
+}
+
+// The directories in the workspace an `autoloadPaths` entry matches
+fn expand_autoload_path(workspace_path: &Path, autoload_path: &str) -> Vec<PathBuf> {
+    let mut directories = vec![workspace_path.to_path_buf()];
+
+    for part in autoload_path.split('/').filter(|part| !part.is_empty()) {
+        directories = directories
+            .iter()
+            .flat_map(|directory| {
+                if part == "*" {
+                    fs::read_dir(directory)
+                        .into_iter()
+                        .flatten()
+                        .flatten()
+                        .map(|entry| entry.path())
+                        .filter(|path| path.is_dir())
+                        .collect()
+                } else {
+                    vec![directory.join(part)]
+                }
+            })
+            .filter(|directory| directory.is_dir())
+            .collect();
+    }
+
+    directories
+}
+
+// The controller that renders a template by Rails' conventions, and the
+// action for templates that aren't partials, e.g. `UsersController` and
+// `show` for `app/views/users/show.html.erb`. Layouts go with the controller
+// they're named after.
+fn template_controller(relative_path: &str) -> Option<(String, Option<String>)> {
+    let view_path = relative_path
+        .trim_start_matches('/')
+        .strip_prefix("app/views/")?;
+    let (directory, file_name) = view_path.rsplit_once('/')?;
+    let name = file_name.split('.').next()?;
+
+    if directory == "layouts" {
+        return Some((controller_class_name(name), None));
+    }
+
+    let action = (!name.starts_with('_')).then(|| name.to_string());
+
+    Some((controller_class_name(directory), action))
+}
+
+const SYMBOL_BATCH_SIZE: usize = 50;
+
+// The documents matching a workspace symbol query, searched for a batch of
+// ranked names at a time
+pub struct SymbolBatches<'a> {
+    persistence: &'a Persistence,
+    searcher: Option<Searcher>,
+    filter_query: Box<dyn Query>,
+    ranked_names: Vec<(String, i64)>,
+    next_name_index: usize,
+    remaining: usize,
+}
+
+impl<'a> SymbolBatches<'a> {
+    fn empty(persistence: &'a Persistence) -> Self {
+        SymbolBatches {
+            persistence,
+            searcher: None,
+            filter_query: Box::new(BooleanQuery::new(vec![])),
+            ranked_names: Vec::new(),
+            next_name_index: 0,
+            remaining: 0,
+        }
+    }
+
+    fn search_batch(&mut self, searcher: &Searcher) -> tantivy::Result<Vec<Document>> {
+        let schema_fields = &self.persistence.schema_fields;
+        let batch_end = (self.next_name_index + SYMBOL_BATCH_SIZE).min(self.ranked_names.len());
+        let mut name_queries = vec![];
+
+        for rank in self.next_name_index..batch_end {
+            let (name, _score) = &self.ranked_names[rank];
+            let name_query = Box::new(TermQuery::new(
+                Term::from_field_text(schema_fields.name_field, name),
+                IndexRecordOption::Basic,
+            ));
+            let rank_score = (self.ranked_names.len() - rank) as f32;
+
+            name_queries.push((
+                Occur::Should,
+                Box::new(ConstScoreQuery::new(name_query, rank_score)) as Box<dyn Query>,
+            ));
+        }
+
+        self.next_name_index = batch_end;
+
+        let query = BooleanQuery::new(vec![
+            (Occur::Must, self.filter_query.box_clone()),
+            (Occur::Must, Box::new(BooleanQuery::new(name_queries))),
+        ]);
+
+        let results = searcher.measured_search(&query, &TopDocs::with_limit(self.remaining))?;
+        let mut documents = Vec::new();
+
+        for (_score, doc_address) in results {
+            documents.push(searcher.measured_doc(doc_address)?);
+        }
+
+        self.remaining -= documents.len();
+
+        Ok(documents)
+    }
+}
+
+impl<'a> Iterator for SymbolBatches<'a> {
+    type Item = tantivy::Result<Vec<Document>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let searcher = self.searcher.take()?;
+
+        if self.remaining == 0 || self.next_name_index >= self.ranked_names.len() {
+            return None;
+        }
+
+        let batch = self.search_batch(&searcher);
+        self.searcher = Some(searcher);
+
+        Some(batch)
+    }
+}
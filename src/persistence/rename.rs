@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use tantivy::{schema::*, Document};
+use tower_lsp::lsp_types::{
+    AnnotatedTextEdit, ChangeAnnotation, DocumentChanges, OneOf,
+    OptionalVersionedTextDocumentIdentifier, Position, Range, TextDocumentEdit, TextEdit,
+    WorkspaceEdit,
+};
+
+use super::Persistence;
+
+impl Persistence {
+    // With `change_annotations`, the edits are grouped into the definition,
+    // usages, and possible dynamic usages for the editor to preview. Symbols
+    // and strings, e.g. `send(:name)`, might not be the method at all, so
+    // those need confirming.
+    pub fn rename_tokens(
+        &self,
+        path: &str,
+        documents: Vec<Document>,
+        new_name: &String,
+        change_annotations: bool,
+    ) -> WorkspaceEdit {
+        let uri = self.document_uri(path);
+        let lines: Vec<&str> = match self.open_document(&uri) {
+            Some(parsed_document) => parsed_document.text.lines().collect(),
+            None => vec![],
+        };
+        let mut edits = Vec::new();
+
+        for document in documents {
+            let start_line = document
+                .get_first(self.schema_fields.line_field)
+                .unwrap()
+                .as_u64()
+                .unwrap() as u32;
+            let mut start_column = document
+                .get_first(self.schema_fields.start_column_field)
+                .unwrap()
+                .as_u64()
+                .unwrap() as u32;
+            let mut end_column = document
+                .get_first(self.schema_fields.end_column_field)
+                .unwrap()
+                .as_u64()
+                .unwrap() as u32;
+            let name = self.definition_name(&document);
+            let category = document
+                .get_first(self.schema_fields.category_field)
+                .and_then(Value::as_text)
+                .unwrap_or_default();
+
+            let line: Vec<char> = lines
+                .get(start_line as usize)
+                .map(|line| line.chars().collect())
+                .unwrap_or_default();
+            let token: String = line
+                .get(start_column as usize..end_column as usize)
+                .map(|token| token.iter().collect())
+                .unwrap_or_default();
+            let quoted =
+                start_column > 0 && matches!(line.get(start_column as usize - 1), Some('"' | '\''));
+
+            // `described_class` stands for the class in a spec and is left
+            // alone
+            if token == "described_class" {
+                continue;
+            }
+
+            // Only the name in `:name` and `:"name"` is replaced
+            let dynamic = match token.find(&name) {
+                Some(offset) if token != name => {
+                    let offset = token[..offset].chars().count() as u32;
+                    start_column += offset;
+                    end_column = start_column + name.chars().count() as u32;
+                    true
+                }
+                _ => quoted,
+            };
+
+            let annotation_id = if category == "assignment" {
+                "definition"
+            } else if dynamic {
+                "dynamicUsages"
+            } else {
+                "usages"
+            };
+
+            edits.push((
+                annotation_id,
+                TextEdit::new(
+                    Range::new(
+                        Position::new(start_line, start_column),
+                        Position::new(start_line, end_column),
+                    ),
+                    new_name.clone(),
+                ),
+            ));
+        }
+
+        if !change_annotations {
+            let edits = edits.into_iter().map(|(_, edit)| edit).collect();
+
+            return WorkspaceEdit::new(HashMap::from([(uri, edits)]));
+        }
+
+        let mut annotations = HashMap::new();
+        let annotated_edits = edits
+            .into_iter()
+            .map(|(annotation_id, text_edit)| {
+                annotations
+                    .entry(annotation_id.to_string())
+                    .or_insert_with(|| rename_annotation(annotation_id));
+
+                OneOf::Right(AnnotatedTextEdit {
+                    text_edit,
+                    annotation_id: annotation_id.to_string(),
+                })
+            })
+            .collect();
+
+        WorkspaceEdit {
+            changes: None,
+            document_changes: Some(DocumentChanges::Edits(vec![TextDocumentEdit {
+                text_document: OptionalVersionedTextDocumentIdentifier { uri, version: None },
+                edits: annotated_edits,
+            }])),
+            change_annotations: Some(annotations),
+        }
+    }
+}
+
+// How each group of rename edits is shown in the editor's preview
+fn rename_annotation(annotation_id: &str) -> ChangeAnnotation {
+    let (label, needs_confirmation, description) = match annotation_id {
+        "definition" => ("Definition", false, "Where it's defined"),
+        "usages" => ("Usages", false, "Where it's used by name"),
+        _ => (
+            "Possible dynamic usages",
+            true,
+            "Symbols and strings with the same name, which may not refer to it",
+        ),
+    };
+
+    ChangeAnnotation {
+        label: label.to_string(),
+        needs_confirmation: Some(needs_confirmation),
+        description: Some(description.to_string()),
+    }
+}
@@ -0,0 +1,93 @@
+use tantivy::schema::*;
+
+// The fields of the documents indexed for each definition and usage
+#[derive(Clone)]
+pub(super) struct SchemaFields {
+    pub(super) file_path_id: Field,
+    pub(super) file_path: Field,
+    pub(super) category_field: Field,
+    pub(super) fuzzy_ruby_scope_field: Field,
+    pub(super) class_scope_field: Field,
+    pub(super) name_field: Field,
+    pub(super) name_lowercase_field: Field,
+    pub(super) node_type_field: Field,
+    pub(super) line_field: Field,
+    pub(super) start_column_field: Field,
+    pub(super) end_column_field: Field,
+    pub(super) full_start_line_field: Field,
+    pub(super) full_start_column_field: Field,
+    pub(super) full_end_line_field: Field,
+    pub(super) full_end_column_field: Field,
+    pub(super) user_space_field: Field,
+    pub(super) stdlib_field: Field,
+    pub(super) superclass_field: Field,
+    pub(super) visibility_field: Field,
+    pub(super) documentation_field: Field,
+    pub(super) parameters_field: Field,
+    pub(super) parameter_types_field: Field,
+    pub(super) return_type_field: Field,
+    pub(super) refinement_field: Field,
+}
+
+impl SchemaFields {
+    pub(super) fn build() -> (Schema, SchemaFields) {
+        let mut schema_builder = Schema::builder();
+        let schema_fields = SchemaFields {
+            file_path_id: schema_builder.add_text_field("file_path_id", keyword()),
+            file_path: schema_builder.add_text_field("file_path", keyword()),
+            category_field: schema_builder.add_text_field("category", keyword()),
+            fuzzy_ruby_scope_field: schema_builder.add_text_field("fuzzy_ruby_scope", keyword()),
+            class_scope_field: schema_builder.add_text_field("class_scope", keyword()),
+            name_field: schema_builder.add_text_field("name", keyword()),
+            // `name_lowercase\0Name`, so symbol search can match names case
+            // insensitively through the term dictionary and still rank them by
+            // their original case
+            name_lowercase_field: schema_builder.add_text_field(
+                "name_lowercase",
+                TextOptions::default().set_indexing_options(
+                    TextFieldIndexing::default()
+                        .set_tokenizer("raw")
+                        .set_index_option(IndexRecordOption::Basic),
+                ),
+            ),
+            node_type_field: schema_builder.add_text_field("node_type", keyword()),
+            line_field: schema_builder.add_u64_field("line", INDEXED | STORED),
+            start_column_field: schema_builder.add_u64_field("start_column", INDEXED | STORED),
+            end_column_field: schema_builder.add_u64_field("end_column", INDEXED | STORED),
+            // Classes, modules, and methods from their keyword to their `end`
+            full_start_line_field: schema_builder
+                .add_u64_field("full_start_line", INDEXED | STORED),
+            full_start_column_field: schema_builder
+                .add_u64_field("full_start_column", INDEXED | STORED),
+            full_end_line_field: schema_builder.add_u64_field("full_end_line", INDEXED | STORED),
+            full_end_column_field: schema_builder
+                .add_u64_field("full_end_column", INDEXED | STORED),
+            user_space_field: schema_builder.add_bool_field("user_space", INDEXED | STORED),
+            stdlib_field: schema_builder.add_bool_field("stdlib", INDEXED | STORED),
+            superclass_field: schema_builder.add_text_field("superclass", keyword()),
+            visibility_field: schema_builder.add_text_field("visibility", keyword()),
+            documentation_field: schema_builder.add_text_field("documentation", STORED),
+            parameters_field: schema_builder.add_text_field("parameters", STORED),
+            parameter_types_field: schema_builder.add_text_field("parameter_types", STORED),
+            return_type_field: schema_builder.add_text_field("return_type", STORED),
+            refinement_field: schema_builder.add_text_field("refinement", STORED),
+        };
+
+        (schema_builder.build(), schema_fields)
+    }
+}
+
+pub(super) fn name_lowercase_term(name: &str) -> String {
+    format!("{}\0{}", name.to_lowercase(), name)
+}
+
+// Matched as a whole rather than split into words, and stored
+fn keyword() -> TextOptions {
+    TextOptions::default()
+        .set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer("raw")
+                .set_index_option(IndexRecordOption::Basic),
+        )
+        .set_stored()
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use tantivy::collector::TopDocs;
+use tantivy::schema::*;
+use tower_lsp::lsp_types::{
+    Documentation, MarkupContent, MarkupKind, ParameterInformation, ParameterLabel, Position,
+    SignatureHelp, SignatureInformation, TextDocumentPositionParams,
+};
+
+use super::{Persistence, HOVER_DEFINITION_LIMIT};
+use crate::telemetry::MeasuredSearcher;
+use crate::workspace_path::uri_path;
+
+impl Persistence {
+    // The signatures of the method whose call the position is in the arguments
+    // of, with the argument the cursor is on as the active parameter
+    pub fn signature_help(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> tantivy::Result<Option<SignatureHelp>> {
+        let (Some(parsed_document), Some(reader)) =
+            (self.open_document(&params.text_document.uri), &self.reader)
+        else {
+            return Ok(None);
+        };
+
+        let Some((name, name_position, active_parameter)) =
+            open_call(&parsed_document.text, params.position)
+        else {
+            return Ok(None);
+        };
+
+        let searcher = reader.searcher();
+        let mut definition_docs = vec![];
+
+        for location in self
+            .find_definitions(TextDocumentPositionParams {
+                text_document: params.text_document,
+                position: name_position,
+            })?
+            .iter()
+            .take(HOVER_DEFINITION_LIMIT)
+        {
+            let file_path_id = self.workspace_path.file_path_id(&uri_path(&location.uri));
+
+            if let Some(definition_doc) = self.definition_doc_at(
+                &searcher,
+                &file_path_id,
+                location.range.start.line.into(),
+                location.range.start.character.into(),
+            )? {
+                definition_docs.push(definition_doc);
+            }
+        }
+
+        // The file usually doesn't parse while a call's arguments are being
+        // typed, so there are no usages to go by and the name is used instead
+        if definition_docs.is_empty() {
+            let query = self.definition_query(&name, &["Def", "Defs"], None);
+
+            for (_score, doc_address) in
+                searcher.measured_search(&query, &TopDocs::with_limit(HOVER_DEFINITION_LIMIT))?
+            {
+                definition_docs.push(searcher.measured_doc(doc_address)?);
+            }
+        }
+
+        let mut signatures = vec![];
+
+        for definition_doc in definition_docs {
+            let text_field = |field| definition_doc.get_first(field).and_then(Value::as_text);
+
+            if !matches!(
+                text_field(self.schema_fields.node_type_field),
+                Some("Def" | "Defs")
+            ) {
+                continue;
+            }
+
+            // `name: String` for each typed parameter, by its name
+            let parameter_types: HashMap<&str, &str> =
+                text_field(self.schema_fields.parameter_types_field)
+                    .map(|parameter_types| {
+                        top_level_split(parameter_types)
+                            .into_iter()
+                            .filter_map(|parameter_type| parameter_type.split_once(':'))
+                            .map(|(name, parameter_type)| (name.trim(), parameter_type.trim()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+            let parameters = text_field(self.schema_fields.parameters_field)
+                .map(|parameters| {
+                    top_level_split(parameters)
+                        .into_iter()
+                        .map(|parameter| {
+                            let name = parameter
+                                .trim_start_matches(['*', '&'])
+                                .split(|char: char| !(char.is_alphanumeric() || char == '_'))
+                                .next()
+                                .unwrap_or_default();
+
+                            ParameterInformation {
+                                label: ParameterLabel::Simple(parameter.to_string()),
+                                documentation: parameter_types.get(name).map(|parameter_type| {
+                                    Documentation::MarkupContent(MarkupContent {
+                                        kind: MarkupKind::Markdown,
+                                        value: format!("`{}`", parameter_type),
+                                    })
+                                }),
+                            }
+                        })
+                        .collect::<Vec<ParameterInformation>>()
+                })
+                .unwrap_or_default();
+
+            let mut documentation = vec![];
+            if let Some(sig) = self.definition_sig(&definition_doc) {
+                documentation.push(format!("```ruby\n{}\n```", sig));
+            }
+            if let Some(comment) = text_field(self.schema_fields.documentation_field) {
+                documentation.push(comment.to_string());
+            }
+
+            signatures.push(SignatureInformation {
+                label: self
+                    .definition_signature(&definition_doc)
+                    .trim_start_matches("def ")
+                    .to_string(),
+                documentation: (!documentation.is_empty()).then(|| {
+                    Documentation::MarkupContent(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value: documentation.join("\n\n"),
+                    })
+                }),
+                parameters: Some(parameters),
+                active_parameter: None,
+            });
+        }
+
+        if signatures.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(SignatureHelp {
+            signatures,
+            active_signature: Some(0),
+            active_parameter: Some(active_parameter),
+        }))
+    }
+}
+
+// The method name and where it starts of the call whose unclosed `(` comes
+// before the position, and how many arguments before the position it has, e.g.
+// `1` in `find(id, |`. Brackets in strings aren't told apart from the code's.
+fn open_call(text: &str, position: Position) -> Option<(String, Position, u32)> {
+    let mut before: Vec<&str> = text.lines().take(position.line as usize + 1).collect();
+    let line = before.pop()?;
+    let mut lines: Vec<String> = before.iter().map(|line| line.to_string()).collect();
+    lines.push(line.chars().take(position.character as usize).collect());
+
+    let mut depth = 0;
+    let mut commas = 0;
+
+    for (line_number, line) in lines.iter().enumerate().rev() {
+        let chars: Vec<char> = line.chars().collect();
+
+        for column in (0..chars.len()).rev() {
+            match chars[column] {
+                ')' | ']' | '}' => depth += 1,
+                '[' | '{' if depth > 0 => depth -= 1,
+                '[' | '{' => return None,
+                '(' if depth > 0 => depth -= 1,
+                ',' if depth == 0 => commas += 1,
+                '(' => {
+                    let name_start = chars[..column]
+                        .iter()
+                        .rposition(|char| {
+                            !(char.is_alphanumeric() || ['_', '?', '!'].contains(char))
+                        })
+                        .map_or(0, |index| index + 1);
+
+                    if name_start == column {
+                        return None;
+                    }
+
+                    let name = chars[name_start..column].iter().collect();
+                    let position = Position::new(line_number as u32, name_start as u32);
+                    return Some((name, position, commas));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    None
+}
+
+// Splits parameters or arguments on the commas that aren't inside brackets
+fn top_level_split(list: &str) -> Vec<&str> {
+    let mut items = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (index, char) in list.char_indices() {
+        match char {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(list[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+
+    items.push(list[start..].trim());
+    items.retain(|item| !item.is_empty());
+    items
+}
@@ -0,0 +1,250 @@
+use serde_json::json;
+use tantivy::{schema::*, Document};
+use tower_lsp::lsp_types::{
+    DocumentSymbol, FoldingRange, Location, Position, Range, SymbolInformation, SymbolKind,
+    TextDocumentPositionParams, Url,
+};
+
+use super::indexer::ScopeSpan;
+use super::Persistence;
+
+impl Persistence {
+    // The classes, modules, and method around a position, outermost first,
+    // and their names together, e.g. `Admin::UsersController#create`
+    pub fn where_am_i(&self, params: &TextDocumentPositionParams) -> Option<serde_json::Value> {
+        let parsed_document = self.open_document(&params.text_document.uri)?;
+        let position = params.position;
+
+        let scope_spans: Vec<&ScopeSpan> = parsed_document
+            .findings
+            .scope_spans
+            .iter()
+            .filter(|scope_span| {
+                scope_span.range.start <= position && position < scope_span.range.end
+            })
+            .collect();
+
+        if scope_spans.is_empty() {
+            return None;
+        }
+
+        let mut name = String::new();
+        let mut scopes = vec![];
+
+        for scope_span in scope_spans {
+            let separator = match scope_span.node_type {
+                _ if name.is_empty() => "",
+                "Def" => "#",
+                "Defs" => ".",
+                _ => "::",
+            };
+
+            // `class ::Foo` isn't nested in what's around it
+            match scope_span.name.strip_prefix("::") {
+                Some(top_level_name) => name = top_level_name.to_string(),
+                None => {
+                    name.push_str(separator);
+                    name.push_str(&scope_span.name);
+                }
+            }
+
+            let kind = match scope_span.node_type {
+                "Class" => SymbolKind::CLASS,
+                "Module" => SymbolKind::MODULE,
+                _ => SymbolKind::METHOD,
+            };
+
+            scopes.push(json!({
+                "name": scope_span.name,
+                "kind": kind,
+                "range": scope_span.range,
+            }));
+        }
+
+        Some(json!({ "name": name, "scopes": scopes }))
+    }
+
+    // The classes, modules, and methods in an open file, each nested in the
+    // one around it
+    pub fn document_symbols(&self, uri: &Url) -> Vec<DocumentSymbol> {
+        let parsed_document = match self.open_document(uri) {
+            Some(parsed_document) => parsed_document,
+            None => return vec![],
+        };
+
+        // Spans are in the order they start, so the symbols still open are
+        // the ones around the next span
+        let mut open_symbols: Vec<DocumentSymbol> = vec![];
+        let mut symbols = vec![];
+
+        for scope_span in &parsed_document.findings.scope_spans {
+            while open_symbols
+                .last()
+                .is_some_and(|symbol| symbol.range.end <= scope_span.range.start)
+            {
+                close_document_symbol(&mut open_symbols, &mut symbols);
+            }
+
+            let kind = match scope_span.node_type {
+                "Class" => SymbolKind::CLASS,
+                "Module" => SymbolKind::MODULE,
+                _ => SymbolKind::METHOD,
+            };
+            let name = match scope_span.node_type {
+                "Defs" => format!("self.{}", scope_span.name),
+                _ => scope_span.name.clone(),
+            };
+
+            #[allow(deprecated)]
+            open_symbols.push(DocumentSymbol {
+                name,
+                detail: None,
+                kind,
+                tags: None,
+                deprecated: None,
+                range: scope_span.range,
+                selection_range: scope_span.selection_range,
+                children: None,
+            });
+        }
+
+        while !open_symbols.is_empty() {
+            close_document_symbol(&mut open_symbols, &mut symbols);
+        }
+
+        symbols
+    }
+
+    // The classes, modules, and methods in an open file that span more than
+    // a line, folded up to their `end`
+    pub fn folding_ranges(&self, uri: &Url) -> Vec<FoldingRange> {
+        let parsed_document = match self.open_document(uri) {
+            Some(parsed_document) => parsed_document,
+            None => return vec![],
+        };
+
+        parsed_document
+            .findings
+            .scope_spans
+            .iter()
+            .filter(|scope_span| scope_span.range.end.line > scope_span.range.start.line + 1)
+            .map(|scope_span| FoldingRange {
+                start_line: scope_span.range.start.line,
+                end_line: scope_span.range.end.line - 1,
+                ..FoldingRange::default()
+            })
+            .collect()
+    }
+
+    pub fn documents_to_symbol_information(
+        &self,
+        documents: Vec<Document>,
+    ) -> Vec<SymbolInformation> {
+        let mut symbol_infos = Vec::new();
+
+        for document in documents {
+            let doc_path: Vec<&str> = document
+                .get_all(self.schema_fields.file_path)
+                .map(|v| v.as_text().unwrap())
+                .collect();
+            let doc_path = doc_path.join("/");
+            let absolute_file_path = self.workspace_path.absolute_path(&doc_path);
+            let doc_uri = self.document_uri(&absolute_file_path);
+
+            let name = document
+                .get_first(self.schema_fields.name_field)
+                .unwrap()
+                .as_text()
+                .unwrap();
+
+            let start_line = document
+                .get_first(self.schema_fields.line_field)
+                .unwrap()
+                .as_u64()
+                .unwrap() as u32;
+            let start_column = document
+                .get_first(self.schema_fields.start_column_field)
+                .unwrap()
+                .as_u64()
+                .unwrap() as u32;
+            let start_position = Position::new(start_line, start_column);
+            let end_column = document
+                .get_first(self.schema_fields.end_column_field)
+                .unwrap()
+                .as_u64()
+                .unwrap() as u32;
+            let end_position = Position::new(start_line, end_column);
+
+            let doc_type = document
+                .get_first(self.schema_fields.node_type_field)
+                .unwrap()
+                .as_text()
+                .unwrap();
+
+            let symbol_kind = match doc_type {
+                "Alias" => SymbolKind::METHOD,
+                "Casgn" => SymbolKind::CLASS,
+                "Class" => SymbolKind::CLASS,
+                "Def" => SymbolKind::METHOD,
+                "Defs" => SymbolKind::METHOD,
+                "Gem" => SymbolKind::PACKAGE,
+                "Gvasgn" => SymbolKind::VARIABLE,
+                "Module" => SymbolKind::MODULE,
+                _ => SymbolKind::VARIABLE,
+            };
+
+            let doc_range = Range::new(start_position, end_position);
+            let symbol_location = Location::new(doc_uri, doc_range);
+
+            // Only the class and module names, e.g. `Admin::UsersController`
+            let container_scope: Vec<&str> = document
+                .get_all(self.schema_fields.fuzzy_ruby_scope_field)
+                .flat_map(Value::as_text)
+                .filter(|scope_name| scope_name.starts_with(char::is_uppercase))
+                .collect();
+            let container_name = if container_scope.is_empty() {
+                None
+            } else {
+                Some(container_scope.join("::"))
+            };
+
+            // `module_function` indexes a method twice at the same location
+            let duplicate = symbol_infos.iter().any(|symbol_info: &SymbolInformation| {
+                symbol_info.name == name && symbol_info.location == symbol_location
+            });
+
+            if duplicate {
+                continue;
+            }
+
+            let symbol_info = SymbolInformation {
+                name: name.to_string(),
+                kind: symbol_kind,
+                tags: None,
+                deprecated: None,
+                location: symbol_location,
+                container_name,
+            };
+
+            symbol_infos.push(symbol_info);
+        }
+
+        symbol_infos
+    }
+}
+
+// Moves the innermost open document symbol into the one around it, or the
+// top level symbols when it isn't in one
+fn close_document_symbol(
+    open_symbols: &mut Vec<DocumentSymbol>,
+    symbols: &mut Vec<DocumentSymbol>,
+) {
+    let Some(symbol) = open_symbols.pop() else {
+        return;
+    };
+
+    match open_symbols.last_mut() {
+        Some(parent) => parent.children.get_or_insert_with(Vec::new).push(symbol),
+        None => symbols.push(symbol),
+    }
+}
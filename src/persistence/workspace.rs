@@ -0,0 +1,906 @@
+use filetime::FileTime;
+use jwalk::{WalkDir, WalkDirGeneric};
+use log::info;
+use rayon::prelude::*;
+use regex::Regex;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::str;
+use std::sync::Arc;
+use std::time::Instant;
+use tantivy::schema::*;
+use tower_lsp::lsp_types::{FileChangeType, FileEvent, TextDocumentPositionParams};
+
+use super::{template, Persistence};
+use crate::bundler;
+use crate::locales::Locales;
+use crate::snapshot;
+use crate::workspace_path::uri_path;
+use crate::write_queue::WriteQueue;
+
+#[derive(Clone)]
+pub struct IndexableDir {
+    pub(super) path: String,
+    pub(super) interface_only: bool,
+}
+
+// Ruby files the client's file watcher reported since the last reindex, so
+// the workspace doesn't need to be walked again
+#[derive(Default)]
+pub struct WatchedFiles {
+    watching: bool,
+    modified: HashSet<String>,
+    deleted: HashSet<String>,
+}
+
+impl WatchedFiles {
+    fn changed(&mut self, path: String, deleted: bool) {
+        if deleted {
+            self.modified.remove(&path);
+            self.deleted.insert(path);
+        } else {
+            self.deleted.remove(&path);
+            self.modified.insert(path);
+        }
+    }
+
+    // The modified and deleted files, or `None` when nothing is watching
+    fn take_changes(&mut self) -> Option<(Vec<String>, Vec<String>)> {
+        let modified = self.modified.drain().collect();
+        let deleted = self.deleted.drain().collect();
+
+        if self.watching {
+            Some((modified, deleted))
+        } else {
+            None
+        }
+    }
+}
+
+// How many files are indexed between commits during bulk indexing
+const INDEXING_CHUNK_SIZE: usize = 500;
+
+impl Persistence {
+    // Every indexing phase in order: the workspace's modified files, then
+    // `includeDirs`, gems, and the standard library. The server and `Indexer`
+    // both index with this, so the command line builds the same index as the
+    // editor. A phase that fails doesn't stop the ones after it.
+    pub fn index_all(&mut self) {
+        let _ = self.reindex_modified_files();
+        let _ = self.index_included_dirs_once();
+        let _ = self.index_gems_once();
+        let _ = self.index_stdlib_once();
+    }
+
+    pub fn reindex_modified_files(&mut self) -> tantivy::Result<()> {
+        if self.no_workspace {
+            return Ok(());
+        }
+
+        let timer = Instant::now();
+        let start_time = FileTime::from_unix_time(FileTime::now().unix_seconds(), 0).seconds() - 1;
+        let initial_indexing = self.last_reindex_time == 0;
+        let watched_changes = self.watched_files.lock().unwrap().take_changes();
+
+        // Once the workspace has been walked, the file watcher reports
+        // everything that changed since
+        let (modified_file_paths, deleted_file_paths) = match watched_changes {
+            Some((modified_file_paths, deleted_file_paths)) if !initial_indexing => {
+                for path in &deleted_file_paths {
+                    self.indexed_file_paths.remove(path);
+                }
+
+                self.indexed_file_paths
+                    .extend(modified_file_paths.iter().cloned());
+
+                (modified_file_paths, deleted_file_paths)
+            }
+            _ => self.walk_workspace(),
+        };
+        self.snapshot_file_hashes = Arc::default();
+        self.locales = Arc::new(Locales::load(self.workspace_path.as_path()));
+
+        if let Some(write_queue) = self.write_queue.clone() {
+            if !modified_file_paths.is_empty() || !deleted_file_paths.is_empty() {
+                self.indexing_progress
+                    .start("workspace", modified_file_paths.len());
+
+                let deleted_file_path_terms = deleted_file_paths
+                    .iter()
+                    .map(|path| {
+                        Term::from_field_text(
+                            self.schema_fields.file_path_id,
+                            &self.workspace_path.file_path_id(path),
+                        )
+                    })
+                    .collect();
+
+                write_queue.delete(deleted_file_path_terms);
+                self.index_files(&modified_file_paths, true, &write_queue);
+                write_queue.commit();
+
+                info!(
+                    "Reindexed {} modified and {} deleted workspace files in {:?}",
+                    modified_file_paths.len(),
+                    deleted_file_paths.len(),
+                    timer.elapsed()
+                );
+            } else {
+                info!("No file changes, skipping periodic reindexing.")
+            }
+        }
+
+        self.last_reindex_time = start_time;
+        self.reindex_durations.insert("workspace", timer.elapsed());
+
+        Ok(())
+    }
+
+    // Finds the Ruby files modified since the last reindex and the ones that
+    // were deleted
+    fn walk_workspace(&mut self) -> (Vec<String>, Vec<String>) {
+        let last_reindex_time = self.last_reindex_time;
+
+        // Files in a loaded snapshot that are gone now are deleted
+        if self.indexed_file_paths.is_empty() {
+            self.indexed_file_paths = self
+                .snapshot_file_hashes
+                .keys()
+                .map(|path| self.workspace_path.absolute_path(path))
+                .collect();
+        }
+
+        let workspace_path = self.workspace_path.clone();
+        let excluded_dirs = self.settings.excluded_dirs.clone();
+
+        let walk_dir = WalkDirGeneric::<(usize, bool)>::new(self.workspace_path.as_path())
+            .process_read_dir(move |_depth, _path, _read_dir_state, children| {
+                children.retain(|dir_entry_result| {
+                    dir_entry_result
+                        .as_ref()
+                        .map(|dir_entry| {
+                            if let Some(file_name) = dir_entry.file_name.to_str() {
+                                dir_entry.file_type.is_dir() || ruby_file(file_name)
+                            } else {
+                                false
+                            }
+                        })
+                        .unwrap_or(false)
+                });
+
+                children.iter_mut().for_each(|dir_entry_result| {
+                    if let Ok(dir_entry) = dir_entry_result {
+                        if let Some(file_name) = dir_entry.file_name.to_str() {
+                            if file_name.contains("node_modules")
+                                || file_name.contains("tmp")
+                                || file_name.contains(".git")
+                                || !workspace_path.contains(&dir_entry.path().to_string_lossy())
+                                || excluded_dirs
+                                    .iter()
+                                    .any(|excluded_dir| dir_entry.path() == *excluded_dir)
+                            {
+                                dir_entry.read_children_path = None;
+                            }
+                        }
+                    }
+                });
+            });
+
+        let mut modified_file_paths = vec![];
+        let mut indexed_file_paths = HashSet::new();
+
+        for entry in walk_dir {
+            let path = entry.unwrap().path();
+            let path = path.to_str().unwrap();
+            if ruby_file(path) {
+                indexed_file_paths.insert(path.to_string());
+                self.indexed_file_paths.remove(path);
+
+                let metadata = fs::metadata(path).unwrap();
+
+                let mtime = FileTime::from_last_modification_time(&metadata);
+                let recently_modified =
+                    mtime.seconds() >= last_reindex_time && !self.unchanged_since_snapshot(path);
+
+                if recently_modified {
+                    modified_file_paths.push(path.to_string());
+                }
+            }
+        }
+
+        let deleted_file_paths = self.indexed_file_paths.drain().collect();
+        self.indexed_file_paths = indexed_file_paths;
+
+        (modified_file_paths, deleted_file_paths)
+    }
+
+    // Checked by content, since checking out a snapshotted workspace gives
+    // every file a new modification time
+    fn unchanged_since_snapshot(&self, path: &str) -> bool {
+        match self
+            .snapshot_file_hashes
+            .get(&self.workspace_path.relative_path(path))
+        {
+            Some(snapshot_hash) => {
+                snapshot::file_hash(Path::new(path)).as_ref() == Some(snapshot_hash)
+            }
+            None => false,
+        }
+    }
+
+    // Records changes from the client's file watcher for the next reindex
+    pub fn files_changed(&self, changes: &[FileEvent]) {
+        if self.no_workspace {
+            return;
+        }
+
+        let mut watched_files = self.watched_files.lock().unwrap();
+
+        for change in changes {
+            let path = match change.uri.to_file_path() {
+                Ok(path) => path.to_string_lossy().to_string(),
+                Err(_) => continue,
+            };
+
+            let relative_path = self.workspace_path.relative_path(&path);
+            let excluded = relative_path.split('/').any(|path_part| {
+                path_part.contains("node_modules")
+                    || path_part.contains("tmp")
+                    || path_part.contains(".git")
+            });
+
+            let excluded = excluded
+                || self
+                    .settings
+                    .excluded_dirs
+                    .iter()
+                    .any(|excluded_dir| Path::new(&path).starts_with(excluded_dir));
+
+            if self.workspace_path.contains(&path) && ruby_file(&path) && !excluded {
+                watched_files.changed(path, change.typ == FileChangeType::DELETED);
+            }
+        }
+    }
+
+    pub fn watch_files(&self) {
+        self.watched_files.lock().unwrap().watching = true;
+    }
+
+    pub fn index_included_dirs_once(&mut self) -> tantivy::Result<()> {
+        if self.include_dirs_indexed {
+            return Ok(());
+        }
+
+        let timer = Instant::now();
+        self.index_interface_only = true;
+
+        if !self.settings.include_dirs.is_empty() {
+            let write_queue = match self.write_queue.clone() {
+                Some(write_queue) => write_queue,
+                None => {
+                    info!("missing index");
+                    quit::with_code(1);
+                }
+            };
+
+            // Walk everything first so progress can be reported as a percentage
+            let mut indexable_dirs = vec![];
+
+            for indexable_dir in self.settings.include_dirs.clone() {
+                let walk_dir = WalkDirGeneric::<(usize, bool)>::new(indexable_dir.path.clone())
+                    .process_read_dir(move |_depth, _path, _read_dir_state, children| {
+                        children.retain(|dir_entry_result| {
+                            dir_entry_result
+                                .as_ref()
+                                .map(|dir_entry| {
+                                    if let Some(file_name) = dir_entry.file_name.to_str() {
+                                        let ruby_file = file_name.ends_with(".rb");
+                                        dir_entry.file_type.is_dir() || ruby_file
+                                    } else {
+                                        false
+                                    }
+                                })
+                                .unwrap_or(false)
+                        });
+
+                        children.iter_mut().for_each(|dir_entry_result| {
+                            if let Ok(dir_entry) = dir_entry_result {
+                                if let Some(file_name) = dir_entry.file_name.to_str() {
+                                    if file_name.contains("node_modules")
+                                        || file_name.contains("vendor")
+                                        || file_name.contains("tmp")
+                                        || file_name.contains(".git")
+                                    {
+                                        dir_entry.read_children_path = None;
+                                    }
+                                }
+                            }
+                        });
+                    });
+
+                let mut indexable_file_paths = Vec::new();
+
+                for entry in walk_dir {
+                    let path = entry.unwrap().path();
+                    let path = path.to_str().unwrap();
+                    let ruby_file = path.ends_with(".rb");
+
+                    if ruby_file {
+                        indexable_file_paths.push(path.to_string());
+                    }
+                }
+
+                indexable_dirs.push((indexable_dir.interface_only, indexable_file_paths));
+            }
+
+            let file_count = indexable_dirs
+                .iter()
+                .map(|(_interface_only, indexable_file_paths)| indexable_file_paths.len())
+                .sum();
+            self.indexing_progress.start("include dirs", file_count);
+
+            for (interface_only, indexable_file_paths) in indexable_dirs {
+                self.index_interface_only = interface_only;
+                self.include_dir_file_count += indexable_file_paths.len();
+                self.index_files(&indexable_file_paths, false, &write_queue);
+            }
+
+            write_queue.commit();
+        }
+
+        self.include_dirs_indexed = true;
+        self.index_interface_only = false;
+        self.reindex_durations
+            .insert("includeDirs", timer.elapsed());
+
+        Ok(())
+    }
+
+    pub fn index_gems_once(&mut self) -> tantivy::Result<()> {
+        if self.gems_indexed || self.no_workspace {
+            return Ok(());
+        }
+
+        let timer = Instant::now();
+        self.index_interface_only = true;
+
+        // Four leading spaces dictates that it's a gem version
+        // https://github.com/rubygems/bundler/blob/v2.1.4/lib/bundler/lockfile_parser.rb#L174-L181
+        let gem_version = Regex::new(r"^\s{4}([a-zA-Z\d\.\-_]+)\s\(([\d\w\.\-_]+)\)").unwrap();
+        if !self.bundle_dirs.is_empty() {
+            let mut gem_paths: Vec<(String, String)> = vec![];
+            let mut stdlib_path = None;
+            let mut default_gem_file_paths = vec![];
+
+            // Engines in a monorepo can lock other gems, or other versions of
+            // them, so each `Gemfile.lock` is read with its own gem home
+            for bundle_dir in &self.bundle_dirs {
+                let gemfile_contents =
+                    match fs::read_to_string(Path::new(bundle_dir).join("Gemfile.lock")) {
+                        Ok(gemfile_contents) => gemfile_contents,
+                        Err(_) => continue,
+                    };
+
+                // Gems only needed by the groups that aren't indexed
+                let excluded_gems = match &self.settings.index_gem_groups {
+                    Some(groups) => bundler::excluded_gems(
+                        &fs::read_to_string(Path::new(bundle_dir).join("Gemfile"))
+                            .unwrap_or_default(),
+                        &gemfile_contents,
+                        groups,
+                    ),
+                    None => HashSet::new(),
+                };
+
+                let mut base_gem_path = "unset";
+                let vendored_gem_homes = vendored_gem_homes(Path::new(bundle_dir));
+
+                let gem_home_path_result = gem_environment_home(bundle_dir);
+
+                if let Ok(gem_home_path) = gem_home_path_result {
+                    if let Ok(gem_home_path) = str::from_utf8(gem_home_path.stdout.as_slice()) {
+                        base_gem_path = gem_home_path;
+                    }
+
+                    // Index Ruby
+                    let ruby_source_path = base_gem_path.replace("gems/", "").replace("\n", "");
+
+                    // Only when it's found next to the gems, so gems aren't
+                    // mistaken for it
+                    if ruby_source_path != base_gem_path.trim() {
+                        stdlib_path.get_or_insert(ruby_source_path.clone());
+                    }
+
+                    if !gem_paths
+                        .iter()
+                        .any(|(_name, path)| *path == ruby_source_path)
+                    {
+                        info!("Added Ruby source path: {}", ruby_source_path);
+                        gem_paths.push(("ruby".to_string(), ruby_source_path.clone()));
+                    }
+
+                    // Index Gems
+                    for line in gemfile_contents.lines() {
+                        if let Some(captures) = gem_version.captures(line) {
+                            let name = captures[1].to_string();
+                            let version = captures[2].to_string();
+
+                            if excluded_gems.contains(&name) {
+                                info!("Skipping {}, it's not in indexGemGroups", name);
+                                continue;
+                            }
+
+                            let gem_folder_name =
+                                format!("{}/gems/{}-{}", base_gem_path, name, version);
+                            // Not 100% sure where this newline is coming from. `gemfile_contents.lines()` I think.
+                            let gem_folder_name = gem_folder_name.replace("\n", "");
+
+                            // Installed into the project, e.g. `vendor/bundle`
+                            let gem_folder_name = vendored_gem_homes
+                                .iter()
+                                .map(|gem_home| format!("{}/gems/{}-{}", gem_home, name, version))
+                                .find(|gem_path| Path::new(gem_path).is_dir())
+                                .unwrap_or(gem_folder_name);
+
+                            // Default gems like `json` are installed into the
+                            // standard library, which is indexed already
+                            // unless `indexStdlib` is off
+                            if !self.settings.index_stdlib && !Path::new(&gem_folder_name).is_dir()
+                            {
+                                default_gem_file_paths.extend(default_gem_files(
+                                    base_gem_path.trim(),
+                                    &ruby_source_path,
+                                    &name,
+                                    &version,
+                                ));
+                            }
+
+                            if gem_paths
+                                .iter()
+                                .any(|(_name, path)| *path == gem_folder_name)
+                            {
+                                continue;
+                            }
+
+                            info!("gem folder name: {}", gem_folder_name);
+
+                            gem_paths.push((name, gem_folder_name))
+                        }
+                    }
+                }
+            }
+
+            let write_queue = match self.write_queue.clone() {
+                Some(write_queue) => write_queue,
+                None => {
+                    info!("missing index");
+                    quit::with_code(1);
+                }
+            };
+
+            self.gem_paths = Arc::new(
+                gem_paths
+                    .iter()
+                    .map(|(_name, gem_path)| gem_path.to_string())
+                    .collect(),
+            );
+
+            // Walk everything first so progress can be reported as a percentage
+            let mut indexable_file_paths = Vec::new();
+
+            self.stdlib_path = stdlib_path;
+
+            for (name, gem_path) in gem_paths {
+                // Still listed above so `require "set"` finds it
+                if name == "ruby" && !self.settings.index_stdlib {
+                    continue;
+                }
+
+                // Ruby itself is always indexed up front
+                // Indexed on demand by name, so only the version locked
+                // first, usually the workspace's, is kept
+                if self.settings.lazy_gem_indexing && name != "ruby" {
+                    self.unindexed_gems.entry(name).or_insert(gem_path);
+                    continue;
+                }
+
+                indexable_file_paths.extend(gem_file_paths(&gem_path));
+            }
+
+            indexable_file_paths.extend(default_gem_file_paths);
+
+            self.gem_file_count += indexable_file_paths.len();
+            self.indexing_progress
+                .start("gems", indexable_file_paths.len());
+
+            self.index_files(&indexable_file_paths, false, &write_queue);
+
+            write_queue.commit();
+        } else {
+            info!("Gemfile not found, skipping indexing workspace gems.");
+        }
+
+        self.gems_indexed = true;
+        self.index_interface_only = false;
+        self.reindex_durations.insert("gems", timer.elapsed());
+
+        Ok(())
+    }
+
+    // Single-file mode has no Gemfile to find Ruby through, so the standard
+    // library of the `ruby` on the PATH is used
+    pub fn index_stdlib_once(&mut self) -> tantivy::Result<()> {
+        if self.stdlib_indexed {
+            return Ok(());
+        }
+
+        let timer = Instant::now();
+        self.index_interface_only = true;
+
+        match stdlib_path() {
+            Some(stdlib_path) => {
+                info!("Indexing the Ruby standard library: {}", stdlib_path);
+                Arc::make_mut(&mut self.gem_paths).push(stdlib_path.clone());
+                self.stdlib_path = Some(stdlib_path.clone());
+
+                if let Some(write_queue) = self.write_queue.clone() {
+                    let indexable_file_paths = gem_file_paths(&stdlib_path);
+
+                    self.gem_file_count += indexable_file_paths.len();
+                    self.indexing_progress
+                        .start("stdlib", indexable_file_paths.len());
+
+                    self.index_files(&indexable_file_paths, false, &write_queue);
+
+                    write_queue.commit();
+                }
+            }
+            None => info!("Couldn't find the Ruby standard library, skipping it."),
+        }
+
+        self.stdlib_indexed = true;
+        self.index_interface_only = false;
+        self.reindex_durations.insert("stdlib", timer.elapsed());
+
+        Ok(())
+    }
+
+    // Takes the gems among `names` that aren't indexed yet when
+    // `lazyGemIndexing` is on, so each is only indexed once. They're indexed
+    // with `index_claimed_gems` on a copy from `indexer`.
+    pub fn claim_unindexed_gems(&mut self, names: &[String]) -> Vec<(String, String)> {
+        names
+            .iter()
+            .filter_map(|name| {
+                self.unindexed_gems
+                    .remove(name)
+                    .map(|gem_path| (name.to_string(), gem_path))
+            })
+            .collect()
+    }
+
+    // Indexes the gems from `claim_unindexed_gems` and commits them. Returns
+    // the names of the gems that were indexed.
+    pub fn index_claimed_gems(&mut self, gems: &[(String, String)]) -> Vec<String> {
+        let write_queue = match self.write_queue.clone() {
+            Some(write_queue) => write_queue,
+            None => return vec![],
+        };
+
+        let mut indexable_file_paths = vec![];
+
+        for (name, gem_path) in gems {
+            info!("Indexing gem on demand: {}", name);
+
+            indexable_file_paths.extend(gem_file_paths(gem_path));
+        }
+
+        self.index_interface_only = true;
+        self.gem_file_count += indexable_file_paths.len();
+        self.index_files(&indexable_file_paths, false, &write_queue);
+        self.index_interface_only = false;
+
+        write_queue.commit();
+
+        gems.iter()
+            .map(|(name, _gem_path)| name.to_string())
+            .collect()
+    }
+
+    // The unindexed gems a failed definition lookup in the workspace might be
+    // looking for, going by gem names matching the constant under the cursor
+    // or the receiver's class, e.g. `Sidekiq::Worker` and `sidekiq`
+    pub fn gems_for_definition(&self, params: &TextDocumentPositionParams) -> Vec<String> {
+        if self.unindexed_gems.is_empty()
+            || !self
+                .workspace_path
+                .contains(&uri_path(&params.text_document.uri))
+        {
+            return vec![];
+        }
+
+        let constant_names = self.usage_constant_names(params).unwrap_or_default();
+
+        self.unindexed_gems
+            .keys()
+            .filter(|gem_name| {
+                constant_names
+                    .iter()
+                    .any(|constant_name| gem_name_matches(gem_name, constant_name))
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn unindexed_gem_names(&self) -> Vec<String> {
+        self.unindexed_gems.keys().cloned().collect()
+    }
+
+    // Parses files on every core and sends their documents to the write
+    // queue. Parsing keeps what it finds on the `Persistence` doing it, so
+    // each thread parses with its own copy.
+    fn index_files(&self, paths: &[String], user_space: bool, write_queue: &WriteQueue) {
+        paths.par_iter().for_each_init(
+            || {
+                let mut parser = self.indexer().ok();
+
+                if let Some(parser) = &mut parser {
+                    parser.index_interface_only = self.index_interface_only;
+                }
+
+                parser
+            },
+            |parser, path| {
+                if let (Some(parser), Ok(text)) = (parser, fs::read_to_string(path)) {
+                    let relative_path = self.workspace_path.relative_path(path);
+
+                    let _ = parser.reindex_modified_file_without_commit(
+                        &text,
+                        relative_path,
+                        user_space,
+                    );
+                }
+
+                self.file_indexed(write_queue);
+            },
+        );
+    }
+
+    // Commits every `INDEXING_CHUNK_SIZE` files so searches can use what's
+    // been indexed so far
+    fn file_indexed(&self, write_queue: &WriteQueue) {
+        if self.indexing_progress.advance() % INDEXING_CHUNK_SIZE == 0 {
+            write_queue.commit();
+        }
+    }
+}
+
+// Files indexed in the workspace: Ruby, ERB templates, and the `Gemfile` and
+// gemspecs for their dependencies
+fn ruby_file(path: &str) -> bool {
+    path.ends_with(".rb")
+        || path.ends_with(".gemspec")
+        || Path::new(path).file_name() == Some("Gemfile".as_ref())
+        || template(path)
+}
+
+// The workspace if it has a `Gemfile.lock`, then the engines and packages of
+// a monorepo that have their own, e.g. `engines/billing`
+pub(super) fn bundle_dirs(workspace_path: &Path) -> Vec<String> {
+    let mut bundle_dirs = vec![];
+
+    if workspace_path.as_os_str().is_empty() {
+        return bundle_dirs;
+    }
+
+    if workspace_path.join("Gemfile.lock").is_file() {
+        bundle_dirs.push(workspace_path.to_string_lossy().to_string());
+    }
+
+    for subprojects_dir in ["engines", "components", "packs"] {
+        let mut subproject_dirs: Vec<String> = WalkDir::new(workspace_path.join(subprojects_dir))
+            .max_depth(3)
+            .into_iter()
+            .flatten()
+            .filter(|entry| entry.file_name() == "Gemfile.lock")
+            .filter_map(|entry| Some(entry.path().parent()?.to_string_lossy().to_string()))
+            .collect();
+        subproject_dirs.sort();
+
+        bundle_dirs.extend(subproject_dirs);
+    }
+
+    bundle_dirs
+}
+
+// Where gems are installed into the project, e.g. `vendor/bundle` after
+// `bundle config set path vendor/bundle`. The project's `.bundle/config` takes
+// precedence over the environment, as with Bundler.
+pub(super) fn bundle_path(bundle_dir: &Path) -> Option<PathBuf> {
+    let bundle_path = fs::read_to_string(bundle_dir.join(".bundle").join("config"))
+        .ok()
+        .and_then(|config| {
+            config.lines().find_map(|line| {
+                let bundle_path = line.strip_prefix("BUNDLE_PATH:")?.trim();
+                Some(
+                    bundle_path
+                        .trim_matches(|c| c == '"' || c == '\'')
+                        .to_string(),
+                )
+            })
+        })
+        .or_else(|| std::env::var("BUNDLE_PATH").ok())
+        .filter(|bundle_path| !bundle_path.is_empty())?;
+
+    // Relative to the project, unless it's absolute
+    Some(bundle_dir.join(bundle_path))
+}
+
+fn gem_environment_home(bundle_dir: &str) -> std::io::Result<Output> {
+    Command::new("sh")
+        .arg("-c")
+        // .arg(format!("eval \"$(/usr/local/bin/rbenv init -)\" && cd {} && gem environment home", &self.workspace_path))
+        .arg("gem environment home")
+        .current_dir(bundle_dir)
+        .output()
+}
+
+// Where the Ruby used in each bundle installs gems, or its `BUNDLE_PATH` when
+// they're vendored, which decide where the indexed gems were found
+pub(super) fn gem_homes(bundle_dirs: &[String]) -> Vec<String> {
+    bundle_dirs
+        .iter()
+        .map(|bundle_dir| match bundle_path(Path::new(bundle_dir)) {
+            Some(bundle_path) => bundle_path.to_string_lossy().to_string(),
+            None => gem_environment_home(bundle_dir)
+                .ok()
+                .and_then(|output| String::from_utf8(output.stdout).ok())
+                .map(|gem_home| gem_home.trim().to_string())
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+// The gem homes under `BUNDLE_PATH`, e.g. `vendor/bundle/ruby/3.2.0`, newest
+// Ruby first
+fn vendored_gem_homes(bundle_dir: &Path) -> Vec<String> {
+    let ruby_dir = match bundle_path(bundle_dir) {
+        Some(bundle_path) => bundle_path.join("ruby"),
+        None => return vec![],
+    };
+
+    let mut gem_homes: Vec<PathBuf> = fs::read_dir(ruby_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|gem_home| gem_home.join("gems").is_dir())
+        .collect();
+
+    // By version, so `3.10.0` comes before `3.2.0`
+    gem_homes.sort_by_key(|gem_home| {
+        let ruby_version = gem_home.file_name().unwrap_or_default().to_string_lossy();
+
+        Reverse(
+            ruby_version
+                .split('.')
+                .map(|part| part.parse::<u32>().unwrap_or_default())
+                .collect::<Vec<u32>>(),
+        )
+    });
+
+    gem_homes
+        .iter()
+        .map(|gem_home| gem_home.to_string_lossy().to_string())
+        .collect()
+}
+
+// The standard library directory of the `ruby` on the PATH
+pub fn stdlib_path() -> Option<String> {
+    let output = Command::new("ruby")
+        .arg("-e")
+        .arg("print RbConfig::CONFIG['rubylibdir']")
+        .output()
+        .ok()?;
+
+    if output.status.success() {
+        Some(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        None
+    }
+}
+
+// The Ruby files of a default gem, which are listed relative to the standard
+// library in its gemspec under `specifications/default`
+fn default_gem_files(gem_home: &str, stdlib_path: &str, name: &str, version: &str) -> Vec<String> {
+    let gemspec_path = format!(
+        "{}/specifications/default/{}-{}.gemspec",
+        gem_home, name, version
+    );
+    let gemspec = match fs::read_to_string(gemspec_path) {
+        Ok(gemspec) => gemspec,
+        Err(_) => return vec![],
+    };
+
+    let ruby_file_name = Regex::new(r#""([^"]+\.rb)""#).unwrap();
+
+    gemspec
+        .lines()
+        .filter(|line| line.trim_start().starts_with("s.files"))
+        .flat_map(|line| ruby_file_name.captures_iter(line))
+        .map(|captures| format!("{}/{}", stdlib_path, &captures[1]))
+        .filter(|path| Path::new(path).is_file())
+        .collect()
+}
+
+fn gem_file_paths(gem_path: &str) -> Vec<String> {
+    let walk_dir = WalkDirGeneric::<(usize, bool)>::new(gem_path).process_read_dir(
+        move |_depth, _path, _read_dir_state, children| {
+            children.retain(|dir_entry_result| {
+                dir_entry_result
+                    .as_ref()
+                    .map(|dir_entry| {
+                        if let Some(file_name) = dir_entry.file_name.to_str() {
+                            let ruby_file = file_name.ends_with(".rb");
+                            dir_entry.file_type.is_dir() || ruby_file
+                        } else {
+                            false
+                        }
+                    })
+                    .unwrap_or(false)
+            });
+
+            children.iter_mut().for_each(|dir_entry_result| {
+                if let Ok(dir_entry) = dir_entry_result {
+                    if let Some(file_name) = dir_entry.file_name.to_str() {
+                        if file_name.contains("node_modules")
+                            || file_name.contains("vendor")
+                            || file_name.contains("tmp")
+                            || file_name.contains(".git")
+                        {
+                            dir_entry.read_children_path = None;
+                        }
+                    }
+                }
+            });
+        },
+    );
+
+    let mut file_paths = vec![];
+
+    for entry in walk_dir {
+        let path = entry.unwrap().path();
+        let path = path.to_str().unwrap();
+
+        if path.ends_with(".rb") {
+            file_paths.push(path.to_string());
+        }
+    }
+
+    file_paths
+}
+
+// The name and version of the gem a file is in from the gem's directory,
+// e.g. `gems/rails-html-sanitizer-1.6.0/lib/...`
+pub(super) fn gem_name_and_version(file_path: &str) -> Option<(String, String)> {
+    let gem_dir = Regex::new(r"/gems/([^/]+?)-(\d[^/-]*(?:-[^/]+)?)/").unwrap();
+    let captures = gem_dir.captures(file_path)?;
+
+    Some((captures[1].to_string(), captures[2].to_string()))
+}
+
+// Whether a gem is named after a constant, ignoring case and separators, e.g.
+// `active_model_serializers` and `ActiveModelSerializers`
+fn gem_name_matches(gem_name: &str, constant_name: &str) -> bool {
+    let normalize = |name: &str| -> String {
+        name.chars()
+            .filter(|char| *char != '_' && *char != '-')
+            .flat_map(char::to_lowercase)
+            .collect()
+    };
+
+    normalize(gem_name) == normalize(constant_name)
+}
@@ -0,0 +1,187 @@
+use lib_ruby_parser::source::token_rewriter::{
+    LexStateAction, RewriteAction, TokenRewriter, TokenRewriterResult,
+};
+use lib_ruby_parser::source::{Decoder, DecoderResult};
+use lib_ruby_parser::{Bytes, Lexer, ParserOptions, Token};
+use regex::Regex;
+use std::cell::Cell;
+use std::fs;
+use std::path::Path;
+
+// The version of Ruby a project runs on, e.g. `3.2` for `3.2.2`. Patch
+// versions don't change the syntax.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RubyVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+// Anonymous `*` and `**` arguments, like `def log(*) = logger.info(*)`, are
+// newer than the Ruby 3.1 syntax lib-ruby-parser knows
+const ANONYMOUS_ARGS_VERSION: RubyVersion = RubyVersion { major: 3, minor: 2 };
+
+// The files at the workspace root the version is detected from
+pub const VERSION_FILES: &[&str] = &[".ruby-version", ".tool-versions", "Gemfile"];
+
+impl RubyVersion {
+    // `3.2.2`, `3.3`, or `ruby-3.4.1`, like in `.ruby-version`. Other Rubies,
+    // like `jruby-9.4`, have versions of their own and aren't recognized.
+    pub fn parse(version: &str) -> Option<RubyVersion> {
+        captured_version(r"^\s*(?:ruby-)?(\d+)\.(\d+)", version)
+    }
+
+    // From `.ruby-version`, then the `ruby` line of `.tool-versions`, then the
+    // `ruby "3.2.2"` directive in the `Gemfile`
+    pub fn detect(workspace_path: &Path) -> Option<RubyVersion> {
+        let read = |name: &str| fs::read_to_string(workspace_path.join(name)).ok();
+
+        if let Some(ruby_version) = read(".ruby-version") {
+            return RubyVersion::parse(&ruby_version);
+        }
+
+        read(".tool-versions")
+            .and_then(|tool_versions| captured_version(r"(?m)^ruby\s+(\d+)\.(\d+)", &tool_versions))
+            .or_else(|| {
+                read("Gemfile").and_then(|gemfile| {
+                    captured_version(
+                        r#"(?m)^\s*ruby\s*\(?\s*["'][~><=\s]*(\d+)\.(\d+)"#,
+                        &gemfile,
+                    )
+                })
+            })
+    }
+}
+
+// Files are read as UTF-8 before they're parsed, so magic comments like
+// `# encoding: us-ascii` are accepted as is instead of failing the parse. The
+// syntax of Rubies newer than the parser's is rewritten into what it knows,
+// and left as a syntax error for the versions without it. When the version
+// isn't known, the newest syntax is accepted.
+pub fn parser_options(buffer_name: &str, ruby_version: Option<RubyVersion>) -> ParserOptions {
+    let token_rewriter = match ruby_version {
+        Some(ruby_version) if ruby_version < ANONYMOUS_ARGS_VERSION => None,
+        _ => Some(anonymous_args_rewriter()),
+    };
+
+    ParserOptions {
+        buffer_name: buffer_name.to_string(),
+        decoder: Some(Decoder::new(Box::new(|_encoding, input| {
+            DecoderResult::Ok(input)
+        }))),
+        token_rewriter,
+        record_tokens: false,
+    }
+}
+
+// Passes `nil` in place of an anonymous `*` or `**` argument, e.g.
+// `logger.info(nil)` for `logger.info(*)`, which keeps every location the
+// same. The `*` and `**` in the method's parameters are already understood,
+// so the parentheses after `def` are left alone.
+fn anonymous_args_rewriter() -> TokenRewriter {
+    // How many parentheses deep the parameters of a `def` are, while in them
+    let def_depth: Cell<Option<usize>> = Cell::new(None);
+
+    TokenRewriter::new(Box::new(move |mut token: Box<Token>, input: &[u8]| {
+        let token_type = token.token_type;
+
+        match def_depth.get() {
+            _ if token_type == Lexer::kDEF => def_depth.set(Some(0)),
+            Some(depth)
+                if [Lexer::tLPAREN, Lexer::tLPAREN2, Lexer::tLPAREN_ARG].contains(&token_type) =>
+            {
+                def_depth.set(Some(depth + 1))
+            }
+            Some(depth) if token_type == Lexer::tRPAREN => {
+                def_depth.set(depth.checked_sub(1).filter(|depth| *depth > 0))
+            }
+            Some(0) if [Lexer::tNL, Lexer::tSEMI].contains(&token_type) => def_depth.set(None),
+            None if [Lexer::tSTAR, Lexer::tDSTAR].contains(&token_type) => {
+                let next_byte = input
+                    .get(token.loc.end..)
+                    .unwrap_or_default()
+                    .iter()
+                    .find(|byte| !byte.is_ascii_whitespace());
+
+                if matches!(next_byte, Some(b')' | b',')) {
+                    token.token_type = Lexer::kNIL;
+                    token.token_value = Bytes::new(b"nil".to_vec());
+                }
+            }
+            _ => {}
+        }
+
+        TokenRewriterResult {
+            rewritten_token: token,
+            token_action: RewriteAction::Keep,
+            lex_state_action: LexStateAction::Keep,
+        }
+    }))
+}
+
+fn captured_version(pattern: &str, text: &str) -> Option<RubyVersion> {
+    let captures = Regex::new(pattern).unwrap().captures(text)?;
+
+    Some(RubyVersion {
+        major: captures[1].parse().ok()?,
+        minor: captures[2].parse().ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TestWorkspace;
+    use lib_ruby_parser::{ErrorLevel, Parser};
+
+    fn version(major: u32, minor: u32) -> Option<RubyVersion> {
+        Some(RubyVersion { major, minor })
+    }
+
+    #[test]
+    fn versions_are_parsed_without_their_patch() {
+        assert_eq!(RubyVersion::parse("3.2.2\n"), version(3, 2));
+        assert_eq!(RubyVersion::parse("ruby-3.4.1"), version(3, 4));
+        assert_eq!(RubyVersion::parse("3.3"), version(3, 3));
+        assert_eq!(RubyVersion::parse("jruby-9.4.5.0"), None);
+    }
+
+    #[test]
+    fn ruby_version_comes_first_then_tool_versions_then_the_gemfile() {
+        let gemfile = (
+            "Gemfile",
+            "source \"https://rubygems.org\"\n\nruby \"~> 3.1.0\"\n",
+        );
+        let tool_versions = (".tool-versions", "nodejs 20.9.0\nruby 3.2.2\n");
+        let ruby_version = (".ruby-version", "3.3.0\n");
+
+        let detect = |files: &[(&str, &str)]| {
+            let workspace = TestWorkspace::new(files).unwrap();
+            RubyVersion::detect(workspace.path())
+        };
+
+        assert_eq!(
+            detect(&[gemfile, tool_versions, ruby_version]),
+            version(3, 3)
+        );
+        assert_eq!(detect(&[gemfile, tool_versions]), version(3, 2));
+        assert_eq!(detect(&[gemfile]), version(3, 1));
+        assert_eq!(detect(&[]), None);
+    }
+
+    #[test]
+    fn anonymous_args_are_only_accepted_from_ruby_3_2() {
+        let parses = |ruby_version: Option<RubyVersion>| {
+            let options = parser_options("(eval)", ruby_version);
+            let result = Parser::new("def log(*) = info(*)\n", options).do_parse();
+
+            result
+                .diagnostics
+                .iter()
+                .all(|diagnostic| diagnostic.level != ErrorLevel::Error)
+        };
+
+        assert!(!parses(version(3, 1)));
+        assert!(parses(version(3, 2)));
+        assert!(parses(None));
+    }
+}
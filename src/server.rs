@@ -0,0 +1,1434 @@
+use log::{debug, info};
+use serde::Deserialize;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::*;
+use tower_lsp::jsonrpc::Result;
+use tower_lsp::lsp_types::notification::Notification;
+use tower_lsp::lsp_types::*;
+use tower_lsp::{Client, ClientSocket, LanguageServer, LspService};
+
+use crate::config::Features;
+use crate::daemon::Workspaces;
+use crate::linter::Linter;
+use crate::logging;
+use crate::persistence::{IndexingProgress, Persistence};
+use crate::telemetry;
+use crate::test_runner::TestRunner;
+use crate::workspace_config::{project_root, WorkspaceConfig, CONFIG_FILE_NAMES};
+use crate::workspace_path::uri_path;
+
+enum PartialResultProgress {}
+
+impl Notification for PartialResultProgress {
+    type Params = serde_json::Value;
+
+    const METHOD: &'static str = "$/progress";
+}
+
+enum TelemetryNotification {}
+
+impl Notification for TelemetryNotification {
+    type Params = serde_json::Value;
+
+    const METHOD: &'static str = "fuzzy/telemetry";
+}
+
+enum IndexingStatusNotification {}
+
+impl Notification for IndexingStatusNotification {
+    type Params = serde_json::Value;
+
+    const METHOD: &'static str = "fuzzy/indexingStatus";
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RelatedFilesParams {
+    text_document: TextDocumentIdentifier,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SearchParams {
+    name: Option<String>,
+    #[serde(default)]
+    node_types: Vec<String>,
+    scope: Option<String>,
+    #[serde(default)]
+    user_space_only: bool,
+    limit: Option<usize>,
+}
+
+// One editor session. A daemon shares the index between the sessions of the
+// same workspace, so everything else about the editor lives here.
+pub struct Backend {
+    client: Client,
+    // Set in `initialize`, where a daemon looks up the workspace's index
+    persistence: OnceLock<Arc<RwLock<Persistence>>>,
+    workspaces: Option<Workspaces>,
+    // Whether another session of the daemon is already indexing the workspace
+    shared_index: AtomicBool,
+    linter: Mutex<Linter>,
+    test_runner: Mutex<TestRunner>,
+    workspace_config: Mutex<WorkspaceConfig>,
+    features: OnceLock<Features>,
+    telemetry: AtomicBool,
+    work_done_progress: AtomicBool,
+    snippet_support: AtomicBool,
+    // Whether quick fixes can be sent without their edits, which are made
+    // once one is picked
+    code_action_resolve: AtomicBool,
+    show_document: AtomicBool,
+    change_annotations: AtomicBool,
+    watch_files: AtomicBool,
+}
+
+// The language server for one editor session. A daemon passes its `workspaces` so sessions share indexes.
+pub fn language_service(workspaces: Option<Workspaces>) -> (LspService<Backend>, ClientSocket) {
+    LspService::build(|client| Backend {
+        client,
+        persistence: OnceLock::new(),
+        workspaces,
+        shared_index: AtomicBool::new(false),
+        linter: Mutex::new(Linter::default()),
+        test_runner: Mutex::new(TestRunner::default()),
+        workspace_config: Mutex::new(WorkspaceConfig::default()),
+        features: OnceLock::new(),
+        telemetry: AtomicBool::new(false),
+        work_done_progress: AtomicBool::new(false),
+        snippet_support: AtomicBool::new(false),
+        code_action_resolve: AtomicBool::new(false),
+        show_document: AtomicBool::new(false),
+        change_annotations: AtomicBool::new(false),
+        watch_files: AtomicBool::new(false),
+    })
+    .custom_method("fuzzy/indexStats", Backend::index_stats)
+    .custom_method("fuzzy/relatedFiles", Backend::related_files)
+    .custom_method("fuzzy/whereAmI", Backend::where_am_i)
+    .custom_method("fuzzy/search", Backend::search)
+    .finish()
+}
+
+impl Backend {
+    fn persistence(&self) -> &Arc<RwLock<Persistence>> {
+        self.persistence
+            .get_or_init(|| Arc::new(RwLock::new(Persistence::new().unwrap())))
+    }
+
+    // Times a request and what it spent parsing and searching, logged at the
+    // debug level and sent as a `fuzzy/telemetry` notification when enabled
+    async fn measured<F: Future>(&self, method: &str, request: F) -> F::Output {
+        let timer = Instant::now();
+        let (output, stats) = telemetry::measure(request).await;
+        let duration = timer.elapsed();
+
+        debug!(
+            "{} took {:?} (parse {:?}, {} searches in {:?}, {} docs scanned)",
+            method, duration, stats.parse, stats.searches, stats.search, stats.docs_scanned
+        );
+
+        if self.telemetry.load(Ordering::Relaxed) {
+            self.client
+                .send_notification::<TelemetryNotification>(serde_json::json!({
+                    "method": method,
+                    "durationMs": duration.as_secs_f64() * 1000.0,
+                    "parseMs": stats.parse.as_secs_f64() * 1000.0,
+                    "searchMs": stats.search.as_secs_f64() * 1000.0,
+                    "searches": stats.searches,
+                    "docsScanned": stats.docs_scanned,
+                }))
+                .await;
+        }
+
+        output
+    }
+
+    // Set in `initialize`, since capabilities can't change afterwards
+    fn features(&self) -> Features {
+        self.features.get().copied().unwrap_or_default()
+    }
+
+    // Only settings that don't need a reindex are applied right away
+    async fn reload_workspace_config(&self) {
+        let mut workspace_config = self.workspace_config.lock().await;
+        workspace_config.load();
+        let config = workspace_config.config();
+        drop(workspace_config);
+
+        // A shared index was configured by the session that created it, with
+        // the same index settings
+        if !self.shared_index.load(Ordering::Relaxed) {
+            self.persistence().write().await.configure(&config);
+        }
+
+        self.linter.lock().await.configure(&config);
+        self.test_runner.lock().await.configure(&config);
+        self.telemetry.store(config.telemetry, Ordering::Relaxed);
+        self.show_config_errors().await;
+    }
+
+    // Indexes the workspace in the background and reindexes modified files
+    // every 10 minutes, or sooner when a reindex is requested
+    fn start_indexing(&self) {
+        let background_persistence = Arc::clone(self.persistence());
+        let client = self.client.clone();
+        let work_done_progress = self.work_done_progress.load(Ordering::Relaxed);
+
+        tokio::spawn(async move {
+            client
+                .send_notification::<IndexingStatusNotification>(serde_json::json!({
+                    "state": "queued",
+                }))
+                .await;
+
+            // Indexing runs on its own copy, off the async runtime, so queries
+            // never wait for it
+            let mut indexer = background_persistence.read().await.indexer().unwrap();
+            let reindex_requests = background_persistence.read().await.reindex_requests();
+            let mut initial_indexing = true;
+
+            loop {
+                // Settings may have changed since the last reindex
+                background_persistence
+                    .read()
+                    .await
+                    .refresh_indexer(&mut indexer);
+
+                let indexing_progress = indexer.indexing_progress();
+                let status_reporting = initial_indexing.then(|| {
+                    tokio::spawn(report_indexing_status(
+                        client.clone(),
+                        Arc::clone(&indexing_progress),
+                    ))
+                });
+                let indexing = tokio::task::spawn_blocking(move || {
+                    indexer.index_all();
+                    indexer
+                });
+
+                indexer = if initial_indexing && work_done_progress {
+                    report_indexing_progress(&client, indexing_progress, indexing).await
+                } else {
+                    indexing.await.unwrap()
+                };
+
+                // So `fuzzy/indexStats` only reports what searches can see
+                indexer.wait_for_commit().await;
+
+                background_persistence
+                    .write()
+                    .await
+                    .sync_indexing_status(&indexer);
+
+                if let Some(status_reporting) = status_reporting {
+                    status_reporting.abort();
+
+                    client
+                        .send_notification::<IndexingStatusNotification>(serde_json::json!({
+                            "state": "ready",
+                            "indexedFiles": indexer.indexed_file_counts(),
+                        }))
+                        .await;
+                }
+                initial_indexing = false;
+
+                let _ = tokio::time::timeout(Duration::from_secs(600), reindex_requests.notified())
+                    .await;
+            }
+        });
+    }
+
+    // Indexes unindexed gems when `lazyGemIndexing` is on, on a copy off the
+    // async runtime like `start_indexing`. Returns the names of the gems that
+    // were indexed once searches can see them.
+    async fn index_gems(&self, names: &[String]) -> Vec<String> {
+        let (mut indexer, gems) = {
+            let mut persistence = self.persistence().write().await;
+            let gems = persistence.claim_unindexed_gems(names);
+
+            if gems.is_empty() {
+                return vec![];
+            }
+
+            (persistence.indexer().unwrap(), gems)
+        };
+
+        let (indexer, indexed_gems) = tokio::task::spawn_blocking(move || {
+            let indexed_gems = indexer.index_claimed_gems(&gems);
+            (indexer, indexed_gems)
+        })
+        .await
+        .unwrap();
+
+        indexer.wait_for_commit().await;
+
+        self.persistence().write().await.sync_claimed_gems(&indexer);
+
+        indexed_gems
+    }
+
+    // For clients that didn't send a workspace folder, the project of the
+    // first file opened from disk is used. A file outside of any project puts
+    // the server in single-file mode, as do documents that aren't files until
+    // one is opened.
+    async fn open_workspace_for(&self, uri: &Url) {
+        if uri.scheme() != "file" {
+            return;
+        }
+
+        let mut persistence = self.persistence().write().await;
+
+        if !persistence.awaiting_workspace() {
+            return;
+        }
+
+        let path = uri_path(uri);
+        let workspace_path = match project_root(&path) {
+            Some(workspace_path) => workspace_path,
+            None => {
+                info!("{} isn't in a project, using single-file mode", path);
+
+                let config = self.workspace_config.lock().await.config();
+                persistence.open_single_file(&config);
+                drop(persistence);
+
+                self.show_config_errors().await;
+
+                if config.index_stdlib.unwrap_or(false) {
+                    self.start_indexing();
+                }
+
+                return;
+            }
+        };
+
+        info!(
+            "Using {} as the workspace",
+            workspace_path.as_path().display()
+        );
+
+        let mut workspace_config = self.workspace_config.lock().await;
+        workspace_config.open_workspace(workspace_path.as_path());
+        let config = workspace_config.config();
+        drop(workspace_config);
+
+        persistence.open_workspace(&workspace_path, &config);
+        persistence.configure(&config);
+        drop(persistence);
+
+        let mut linter = self.linter.lock().await;
+        linter.open_workspace(workspace_path.as_path());
+        linter.configure(&config);
+        drop(linter);
+
+        let mut test_runner = self.test_runner.lock().await;
+        test_runner.open_workspace(workspace_path.as_path());
+        test_runner.configure(&config);
+        drop(test_runner);
+
+        self.telemetry.store(config.telemetry, Ordering::Relaxed);
+        self.show_config_errors().await;
+        self.start_indexing();
+    }
+
+    // Settings are read before the client is ready for messages, so problems
+    // are shown once it is
+    async fn show_config_errors(&self) {
+        let errors = self.workspace_config.lock().await.take_errors();
+
+        for message in errors {
+            self.client
+                .show_message(MessageType::WARNING, message)
+                .await;
+        }
+    }
+
+    // Opens a location in the editor, when the client supports being asked to
+    async fn show_location(&self, location: &Location) -> bool {
+        if !self.show_document.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let params = ShowDocumentParams {
+            uri: location.uri.clone(),
+            external: None,
+            take_focus: Some(true),
+            selection: Some(location.range),
+        };
+
+        self.client.show_document(params).await.unwrap_or(false)
+    }
+
+    // Lints with a copy of the linter's command, so it isn't locked while the
+    // linter runs
+    async fn lint(&self, uri: &Url, text: &str) -> Vec<Diagnostic> {
+        let linter_command = {
+            let mut linter = self.linter.lock().await;
+            linter.update_text(uri, text);
+            linter.command()
+        };
+
+        let offenses = linter_command.lint(uri, text).await;
+        self.linter.lock().await.set_offenses(uri, offenses.clone());
+
+        offenses
+    }
+
+    // Runs the test a code lens points at, given its file and line. The
+    // output goes to the log and whether it passed is shown in the editor.
+    async fn run_test(&self, arguments: &[serde_json::Value]) -> Option<serde_json::Value> {
+        let uri: Url = serde_json::from_value(arguments.first()?.clone()).ok()?;
+        let line = arguments.get(1)?.as_u64()? as u32;
+
+        let (framework, name) = self.persistence().read().await.test_at(&uri, line)?;
+        // A copy runs the test, so settings can change while it runs
+        let test_runner = self.test_runner.lock().await.clone();
+        let command = test_runner.command(framework, &uri, line, &name);
+        let result = test_runner.run(&command).await;
+
+        let result = match result {
+            Some(result) => result,
+            None => {
+                self.client
+                    .show_message(MessageType::ERROR, format!("Couldn't run `{}`", command))
+                    .await;
+                return None;
+            }
+        };
+
+        self.client
+            .log_message(
+                MessageType::INFO,
+                format!("$ {}\n{}", command, result.output),
+            )
+            .await;
+
+        let (message_type, status) = if result.passed {
+            (MessageType::INFO, "Passed")
+        } else {
+            (MessageType::ERROR, "Failed")
+        };
+        self.client
+            .show_message(message_type, format!("{}: `{}`", status, command))
+            .await;
+
+        Some(serde_json::json!({
+            "command": command,
+            "passed": result.passed,
+            "output": result.output,
+        }))
+    }
+
+    async fn related_files(&self, params: RelatedFilesParams) -> Result<Vec<serde_json::Value>> {
+        self.measured("fuzzy/relatedFiles", async {
+            let persistence = self.persistence().read().await;
+
+            Ok(persistence.related_files(&params.text_document.uri))
+        })
+        .await
+    }
+
+    async fn where_am_i(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> Result<Option<serde_json::Value>> {
+        self.measured("fuzzy/whereAmI", async {
+            let persistence = self.persistence().read().await;
+
+            Ok(persistence.where_am_i(&params))
+        })
+        .await
+    }
+
+    async fn search(&self, params: SearchParams) -> Result<Vec<serde_json::Value>> {
+        self.measured("fuzzy/search", async {
+            let persistence = self.persistence().read().await;
+            let records = persistence
+                .search(
+                    params.name.as_deref(),
+                    &params.node_types,
+                    params.scope.as_deref(),
+                    params.user_space_only,
+                    params.limit,
+                )
+                .unwrap_or_default();
+
+            Ok(records)
+        })
+        .await
+    }
+
+    async fn index_stats(&self) -> Result<serde_json::Value> {
+        let persistence = self.persistence().read().await;
+        let stats = persistence
+            .index_stats()
+            .unwrap_or_else(|_| serde_json::json!({}));
+
+        Ok(stats)
+    }
+}
+
+// Sends a `fuzzy/indexingStatus` notification whenever the phase or its
+// count changes, until aborted once indexing is done
+async fn report_indexing_status(client: Client, indexing_progress: Arc<IndexingProgress>) {
+    let mut last_counts = None;
+
+    loop {
+        let counts = indexing_progress.counts();
+
+        if last_counts != Some(counts) && !counts.0.is_empty() {
+            let (phase, indexed, total) = counts;
+
+            client
+                .send_notification::<IndexingStatusNotification>(serde_json::json!({
+                    "state": "indexing",
+                    "phase": phase,
+                    "indexed": indexed,
+                    "total": total,
+                }))
+                .await;
+            last_counts = Some(counts);
+        }
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+// Shows "Indexing gems: 40%" in the editor until `indexing` finishes
+async fn report_indexing_progress(
+    client: &Client,
+    indexing_progress: Arc<IndexingProgress>,
+    mut indexing: tokio::task::JoinHandle<Persistence>,
+) -> Persistence {
+    let token = NumberOrString::String("fuzzy/indexing".to_string());
+    let progress = |value: WorkDoneProgress| ProgressParams {
+        token: token.clone(),
+        value: ProgressParamsValue::WorkDone(value),
+    };
+
+    let create_token =
+        client.send_request::<request::WorkDoneProgressCreate>(WorkDoneProgressCreateParams {
+            token: token.clone(),
+        });
+
+    if !matches!(
+        tokio::time::timeout(Duration::from_secs(5), create_token).await,
+        Ok(Ok(()))
+    ) {
+        return indexing.await.unwrap();
+    }
+
+    client
+        .send_notification::<notification::Progress>(progress(WorkDoneProgress::Begin(
+            WorkDoneProgressBegin {
+                title: "Indexing".to_string(),
+                percentage: Some(0),
+                ..WorkDoneProgressBegin::default()
+            },
+        )))
+        .await;
+
+    let indexer = loop {
+        tokio::select! {
+            indexer = &mut indexing => break indexer.unwrap(),
+            _ = tokio::time::sleep(Duration::from_millis(500)) => {
+                let (phase, percentage) = indexing_progress.status();
+
+                client
+                    .send_notification::<notification::Progress>(progress(
+                        WorkDoneProgress::Report(WorkDoneProgressReport {
+                            message: Some(format!("{}: {}%", phase, percentage)),
+                            percentage: Some(percentage),
+                            ..WorkDoneProgressReport::default()
+                        }),
+                    ))
+                    .await;
+            }
+        }
+    };
+
+    client
+        .send_notification::<notification::Progress>(progress(WorkDoneProgress::End(
+            WorkDoneProgressEnd::default(),
+        )))
+        .await;
+
+    indexer
+}
+
+#[tower_lsp::async_trait]
+impl LanguageServer for Backend {
+    async fn initialize(&self, mut params: InitializeParams) -> Result<InitializeResult> {
+        // `root_uri` is deprecated in favour of `workspace_folders`, so some
+        // clients only send the latter. Without either, the workspace is taken
+        // from the first file that's opened.
+        if params.root_uri.is_none() {
+            params.root_uri = params
+                .workspace_folders
+                .as_ref()
+                .and_then(|workspace_folders| workspace_folders.first())
+                .map(|workspace_folder| workspace_folder.uri.clone());
+        }
+
+        let mut workspace_config = self.workspace_config.lock().await;
+        workspace_config.initialize(&params);
+        let config = workspace_config.config();
+
+        // Logging is set up here since it's configured by the client
+        let workspace_path = params.root_uri.as_ref().map(uri_path).unwrap_or_default();
+        if let Err(message) = logging::init(&config, &workspace_path) {
+            workspace_config.add_error(message);
+        }
+        drop(workspace_config);
+
+        let shared_persistence = match &self.workspaces {
+            Some(workspaces) => workspaces.persistence(&params, &config).await,
+            None => None,
+        };
+
+        match shared_persistence {
+            Some((persistence, created)) => {
+                self.shared_index.store(!created, Ordering::Relaxed);
+                let _ = self.persistence.set(persistence);
+            }
+            None => self
+                .persistence()
+                .write()
+                .await
+                .initialize(&params, &config),
+        }
+
+        self.linter.lock().await.initialize(&params, &config);
+        self.test_runner.lock().await.initialize(&params, &config);
+
+        let features = config.features;
+        let _ = self.features.set(features);
+        self.telemetry.store(config.telemetry, Ordering::Relaxed);
+
+        // A daemon outlives its editors
+        if self.workspaces.is_none() {
+            let editor_process_id = params.process_id.unwrap_or_else(|| quit::with_code(1));
+
+            tokio::spawn(async move {
+                loop {
+                    let editor_process_running = psutil::process::processes()
+                        .unwrap()
+                        .into_iter()
+                        .filter_map(|process| process.ok())
+                        .find(|process| process.pid() == editor_process_id);
+
+                    if let None = editor_process_running {
+                        quit::with_code(1);
+                    }
+
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                }
+            });
+        }
+
+        let work_done_progress = params
+            .capabilities
+            .window
+            .as_ref()
+            .and_then(|window| window.work_done_progress)
+            .unwrap_or(false);
+        self.work_done_progress
+            .store(work_done_progress, Ordering::Relaxed);
+
+        let snippet_support = params
+            .capabilities
+            .text_document
+            .as_ref()
+            .and_then(|text_document| text_document.completion.as_ref())
+            .and_then(|completion| completion.completion_item.as_ref())
+            .and_then(|completion_item| completion_item.snippet_support)
+            .unwrap_or(false);
+        self.snippet_support
+            .store(snippet_support, Ordering::Relaxed);
+
+        let code_action_resolve = params
+            .capabilities
+            .text_document
+            .as_ref()
+            .and_then(|text_document| text_document.code_action.as_ref())
+            .and_then(|code_action| code_action.resolve_support.as_ref())
+            .is_some_and(|resolve_support| {
+                resolve_support
+                    .properties
+                    .iter()
+                    .any(|property| property == "edit")
+            });
+        self.code_action_resolve
+            .store(code_action_resolve, Ordering::Relaxed);
+
+        let show_document = params
+            .capabilities
+            .window
+            .as_ref()
+            .and_then(|window| window.show_document.as_ref())
+            .is_some_and(|show_document| show_document.support);
+        self.show_document.store(show_document, Ordering::Relaxed);
+
+        // Annotated edits only fit in `documentChanges`
+        let document_changes = params
+            .capabilities
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.workspace_edit.as_ref())
+            .and_then(|workspace_edit| workspace_edit.document_changes)
+            .unwrap_or(false);
+        let honors_change_annotations = params
+            .capabilities
+            .text_document
+            .as_ref()
+            .and_then(|text_document| text_document.rename.as_ref())
+            .and_then(|rename| rename.honors_change_annotations)
+            .unwrap_or(false);
+        self.change_annotations.store(
+            document_changes && honors_change_annotations,
+            Ordering::Relaxed,
+        );
+
+        let watch_files = params
+            .capabilities
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.did_change_watched_files.as_ref())
+            .and_then(|did_change_watched_files| did_change_watched_files.dynamic_registration)
+            .unwrap_or(false);
+        self.watch_files.store(watch_files, Ordering::Relaxed);
+
+        Ok(InitializeResult {
+            server_info: None,
+            capabilities: ServerCapabilities {
+                text_document_sync: Some(TextDocumentSyncCapability::Options(
+                    TextDocumentSyncOptions {
+                        open_close: Some(true),
+                        change: Some(TextDocumentSyncKind::FULL), // todo: incremental
+                        will_save: Some(false),
+                        will_save_wait_until: Some(false),
+                        save: Some(TextDocumentSyncSaveOptions::SaveOptions(SaveOptions {
+                            include_text: Some(true),
+                        })),
+                    },
+                )),
+                definition_provider: features.definitions.then_some(OneOf::Left(true)),
+                document_highlight_provider: features.highlights.then_some(OneOf::Left(true)),
+                hover_provider: features
+                    .hover
+                    .then_some(HoverProviderCapability::Simple(true)),
+                signature_help_provider: features.signature_help.then_some(SignatureHelpOptions {
+                    trigger_characters: Some(vec!["(".to_string(), ",".to_string()]),
+                    ..SignatureHelpOptions::default()
+                }),
+                code_lens_provider: features.code_lens.then_some(CodeLensOptions {
+                    resolve_provider: Some(false),
+                }),
+                moniker_provider: features.monikers.then_some(OneOf::Left(true)),
+                document_symbol_provider: features.document_symbols.then_some(OneOf::Left(true)),
+                folding_range_provider: features
+                    .folding_ranges
+                    .then_some(FoldingRangeProviderCapability::Simple(true)),
+                references_provider: features.references.then_some(OneOf::Left(true)),
+                rename_provider: features.rename.then_some(OneOf::Left(true)),
+                workspace_symbol_provider: features.symbols.then_some(OneOf::Left(true)),
+                code_action_provider: features.code_actions.then_some(
+                    CodeActionProviderCapability::Options(CodeActionOptions {
+                        code_action_kinds: Some(vec![
+                            CodeActionKind::QUICKFIX,
+                            CodeActionKind::SOURCE_ORGANIZE_IMPORTS,
+                        ]),
+                        resolve_provider: Some(true),
+                        ..CodeActionOptions::default()
+                    }),
+                ),
+                completion_provider: features.completion.then_some(CompletionOptions {
+                    trigger_characters: Some(vec![
+                        "/".to_string(),
+                        "\"".to_string(),
+                        "'".to_string(),
+                        ":".to_string(),
+                        "@".to_string(),
+                        "$".to_string(),
+                    ]),
+                    resolve_provider: Some(true),
+                    ..CompletionOptions::default()
+                }),
+                document_link_provider: features.document_links.then_some(DocumentLinkOptions {
+                    resolve_provider: Some(false),
+                    work_done_progress_options: WorkDoneProgressOptions::default(),
+                }),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![
+                        "fuzzy.unusedMethods".to_string(),
+                        "fuzzy.unusedSymbols".to_string(),
+                        "fuzzy.exportCallGraph".to_string(),
+                        "fuzzy.indexGems".to_string(),
+                        "fuzzy.runTest".to_string(),
+                        "fuzzy.goToSuperMethod".to_string(),
+                        "fuzzy.openGem".to_string(),
+                    ],
+                    ..ExecuteCommandOptions::default()
+                }),
+                ..ServerCapabilities::default()
+            },
+        })
+    }
+
+    // Indexing starts once the client is ready for progress notifications, so
+    // `initialize` returns right away and queries use whatever has been
+    // indexed so far
+    async fn initialized(&self, _: InitializedParams) {
+        self.show_config_errors().await;
+
+        if !self.persistence().read().await.no_workspace()
+            && !self.shared_index.load(Ordering::Relaxed)
+        {
+            self.start_indexing();
+        }
+
+        // With a file watcher, periodic reindexing only needs to look at the
+        // files it reports instead of walking the workspace, and the config
+        // file is read again when it changes
+        if self.watch_files.load(Ordering::Relaxed) {
+            let registration_options = DidChangeWatchedFilesRegistrationOptions {
+                watchers: vec![
+                    FileSystemWatcher {
+                        glob_pattern: GlobPattern::String("**/*.rb".to_string()),
+                        kind: None,
+                    },
+                    FileSystemWatcher {
+                        glob_pattern: GlobPattern::String("**/*.erb".to_string()),
+                        kind: None,
+                    },
+                    FileSystemWatcher {
+                        glob_pattern: GlobPattern::String("**/Gemfile".to_string()),
+                        kind: None,
+                    },
+                    FileSystemWatcher {
+                        glob_pattern: GlobPattern::String("**/*.gemspec".to_string()),
+                        kind: None,
+                    },
+                    FileSystemWatcher {
+                        glob_pattern: GlobPattern::String("**/.ruby-version".to_string()),
+                        kind: None,
+                    },
+                    FileSystemWatcher {
+                        glob_pattern: GlobPattern::String("**/.tool-versions".to_string()),
+                        kind: None,
+                    },
+                    FileSystemWatcher {
+                        glob_pattern: GlobPattern::String(format!("**/{}", CONFIG_FILE_NAMES[0])),
+                        kind: None,
+                    },
+                    FileSystemWatcher {
+                        glob_pattern: GlobPattern::String(format!("**/{}", CONFIG_FILE_NAMES[1])),
+                        kind: None,
+                    },
+                ],
+            };
+            let registration = Registration {
+                id: "fuzzy-watched-files".to_string(),
+                method: "workspace/didChangeWatchedFiles".to_string(),
+                register_options: serde_json::to_value(registration_options).ok(),
+            };
+
+            if self
+                .client
+                .register_capability(vec![registration])
+                .await
+                .is_ok()
+            {
+                self.persistence().read().await.watch_files();
+            }
+        }
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.persistence().write().await.close_session();
+
+        Ok(())
+    }
+
+    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        self.open_workspace_for(&params.text_document.uri).await;
+
+        self.measured("textDocument/didOpen", async {
+            let linter_diagnostics = self
+                .lint(&params.text_document.uri, &params.text_document.text)
+                .await;
+
+            let mut persistence = self.persistence().write().await;
+
+            // Without a workspace there's no background indexing, so open
+            // files are indexed as they're opened
+            if persistence.no_workspace() {
+                persistence
+                    .reindex_modified_file(
+                        &self.client,
+                        &params.text_document.text,
+                        &params.text_document.uri,
+                        linter_diagnostics,
+                    )
+                    .await;
+                return;
+            }
+
+            let mut diagnostics: Vec<tower_lsp::lsp_types::Diagnostic> = vec![];
+
+            let change_diagnostics =
+                persistence.diagnostics(&params.text_document.text, &params.text_document.uri);
+
+            for diagnostic in change_diagnostics {
+                for unwrapped_diagnostic in diagnostic {
+                    if let Some(finally_diagnostic) = unwrapped_diagnostic {
+                        diagnostics.push(finally_diagnostic.to_owned());
+                    }
+                }
+            }
+
+            diagnostics.extend(linter_diagnostics);
+
+            if persistence.report_diagnostics() {
+                self.client
+                    .publish_diagnostics(
+                        params.text_document.uri,
+                        diagnostics,
+                        Some(params.text_document.version),
+                    )
+                    .await;
+            }
+        })
+        .await
+    }
+
+    async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        self.measured("textDocument/didChange", async {
+            let mut linter = self.linter.lock().await;
+
+            // Offenses are only refreshed on save, so the last ones are kept
+            for content_change in &params.content_changes {
+                linter.update_text(&params.text_document.uri, &content_change.text);
+            }
+
+            let linter_diagnostics = linter.offenses(&params.text_document.uri);
+            drop(linter);
+
+            let mut persistence = self.persistence().write().await;
+
+            for content_change in &params.content_changes {
+                persistence
+                    .reindex_modified_file(
+                        &self.client,
+                        &content_change.text,
+                        &params.text_document.uri,
+                        linter_diagnostics.clone(),
+                    )
+                    .await;
+            }
+        })
+        .await
+    }
+
+    async fn did_save(&self, params: DidSaveTextDocumentParams) {
+        self.measured("textDocument/didSave", async {
+            let text = params.text.unwrap();
+
+            let linter_diagnostics = self.lint(&params.text_document.uri, &text).await;
+
+            let mut persistence = self.persistence().write().await;
+            persistence
+                .reindex_modified_file(
+                    &self.client,
+                    &text,
+                    &params.text_document.uri,
+                    linter_diagnostics,
+                )
+                .await;
+        })
+        .await
+    }
+
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        let workspace_config = self.workspace_config.lock().await;
+        let config_file_changed = params
+            .changes
+            .iter()
+            .any(|change| workspace_config.is_config_file(&change.uri));
+        drop(workspace_config);
+
+        // The Ruby version is detected again along with the other settings
+        let persistence = self.persistence().read().await;
+        let ruby_version_changed = params
+            .changes
+            .iter()
+            .any(|change| persistence.ruby_version_file(&change.uri));
+        drop(persistence);
+
+        if config_file_changed || ruby_version_changed {
+            self.reload_workspace_config().await;
+        }
+
+        self.persistence()
+            .read()
+            .await
+            .files_changed(&params.changes);
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        self.persistence()
+            .write()
+            .await
+            .close_document(&params.text_document.uri);
+
+        self.client
+            .log_message(MessageType::INFO, "file closed!")
+            .await;
+    }
+
+    async fn goto_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> Result<Option<GotoDefinitionResponse>> {
+        if !self.features().definitions {
+            return Ok(None);
+        }
+
+        self.measured("textDocument/definition", async {
+            let text_position = params.text_document_position_params;
+            let persistence = self.persistence().read().await;
+            let mut locations = persistence.find_definitions(text_position.clone()).unwrap();
+            drop(persistence);
+
+            // With `lazyGemIndexing`, a lookup that comes up empty indexes the
+            // gems it might be looking for and tries again
+            if locations.is_empty() {
+                let names = self
+                    .persistence()
+                    .read()
+                    .await
+                    .gems_for_definition(&text_position);
+
+                if !self.index_gems(&names).await.is_empty() {
+                    locations = self
+                        .persistence()
+                        .read()
+                        .await
+                        .find_definitions(text_position)
+                        .unwrap();
+                }
+            }
+
+            Ok(Some(GotoDefinitionResponse::Array(locations)))
+        })
+        .await
+    }
+
+    async fn document_highlight(
+        &self,
+        params: DocumentHighlightParams,
+    ) -> Result<Option<Vec<DocumentHighlight>>> {
+        if !self.features().highlights {
+            return Ok(None);
+        }
+
+        self.measured("textDocument/documentHighlight", async {
+            let persistence = self.persistence().read().await;
+
+            let highlights_response = || -> Option<Vec<DocumentHighlight>> {
+                let highlights = persistence.find_highlights(params.text_document_position_params);
+                let highlights = highlights.unwrap();
+
+                Some(highlights)
+            }();
+
+            Ok(highlights_response)
+        })
+        .await
+    }
+
+    async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
+        if !self.features().references {
+            return Ok(None);
+        }
+
+        self.measured("textDocument/references", async {
+            let persistence = self.persistence().read().await;
+            let text_position = params.clone().text_document_position;
+            let text_document = &params.text_document_position.text_document;
+
+            let locations_response = || -> Option<Vec<Location>> {
+                let documents = persistence.find_references(text_position).unwrap();
+                let locations =
+                    persistence.documents_to_locations(&uri_path(&text_document.uri), documents);
+
+                Some(locations)
+            }();
+
+            Ok(locations_response)
+        })
+        .await
+    }
+
+    async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
+        if !self.features().rename {
+            return Ok(None);
+        }
+
+        self.measured("textDocument/rename", async {
+            let persistence = self.persistence().read().await;
+            let text_position = params.clone().text_document_position;
+            let text_document = &params.text_document_position.text_document;
+            let new_name = &params.new_name;
+
+            let change_annotations = self.change_annotations.load(Ordering::Relaxed);
+
+            let workspace_edit = || -> Option<WorkspaceEdit> {
+                let references = persistence.find_references(text_position).unwrap();
+                let workspace_edit = persistence.rename_tokens(
+                    &uri_path(&text_document.uri),
+                    references,
+                    new_name,
+                    change_annotations,
+                );
+
+                Some(workspace_edit)
+            }();
+
+            Ok(workspace_edit)
+        })
+        .await
+    }
+
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        if !self.features().hover {
+            return Ok(None);
+        }
+
+        self.measured("textDocument/hover", async {
+            let persistence = self.persistence().read().await;
+            let hover = persistence
+                .hover(params.text_document_position_params)
+                .unwrap_or_default();
+
+            Ok(hover)
+        })
+        .await
+    }
+
+    async fn signature_help(&self, params: SignatureHelpParams) -> Result<Option<SignatureHelp>> {
+        if !self.features().signature_help {
+            return Ok(None);
+        }
+
+        self.measured("textDocument/signatureHelp", async {
+            let persistence = self.persistence().read().await;
+            let signature_help = persistence
+                .signature_help(params.text_document_position_params)
+                .unwrap_or_default();
+
+            Ok(signature_help)
+        })
+        .await
+    }
+
+    async fn code_lens(&self, params: CodeLensParams) -> Result<Option<Vec<CodeLens>>> {
+        if !self.features().code_lens {
+            return Ok(None);
+        }
+
+        self.measured("textDocument/codeLens", async {
+            let persistence = self.persistence().read().await;
+            let code_lenses = persistence.code_lenses(&params.text_document.uri);
+
+            Ok(Some(code_lenses))
+        })
+        .await
+    }
+
+    async fn moniker(&self, params: MonikerParams) -> Result<Option<Vec<Moniker>>> {
+        if !self.features().monikers {
+            return Ok(None);
+        }
+
+        self.measured("textDocument/moniker", async {
+            let persistence = self.persistence().read().await;
+            let monikers = persistence
+                .monikers(params.text_document_position_params)
+                .unwrap_or_default();
+
+            Ok(Some(monikers))
+        })
+        .await
+    }
+
+    async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        if !self.features().completion {
+            return Ok(None);
+        }
+
+        self.measured("textDocument/completion", async {
+            let persistence = self.persistence().read().await;
+            let snippet_support = self.snippet_support.load(Ordering::Relaxed);
+            let completions = persistence
+                .completions(&params.text_document_position, snippet_support)
+                .unwrap_or_default();
+
+            Ok(Some(CompletionResponse::Array(completions)))
+        })
+        .await
+    }
+
+    async fn completion_resolve(&self, params: CompletionItem) -> Result<CompletionItem> {
+        self.measured("completionItem/resolve", async {
+            let persistence = self.persistence().read().await;
+            let item = persistence
+                .resolve_completion(params.clone())
+                .unwrap_or(params);
+
+            Ok(item)
+        })
+        .await
+    }
+
+    async fn document_link(&self, params: DocumentLinkParams) -> Result<Option<Vec<DocumentLink>>> {
+        if !self.features().document_links {
+            return Ok(None);
+        }
+
+        self.measured("textDocument/documentLink", async {
+            let persistence = self.persistence().read().await;
+            let links = persistence.document_links(&params.text_document.uri);
+
+            Ok(Some(links))
+        })
+        .await
+    }
+
+    async fn document_symbol(
+        &self,
+        params: DocumentSymbolParams,
+    ) -> Result<Option<DocumentSymbolResponse>> {
+        if !self.features().document_symbols {
+            return Ok(None);
+        }
+
+        self.measured("textDocument/documentSymbol", async {
+            let persistence = self.persistence().read().await;
+            let symbols = persistence.document_symbols(&params.text_document.uri);
+
+            Ok(Some(DocumentSymbolResponse::Nested(symbols)))
+        })
+        .await
+    }
+
+    async fn folding_range(&self, params: FoldingRangeParams) -> Result<Option<Vec<FoldingRange>>> {
+        if !self.features().folding_ranges {
+            return Ok(None);
+        }
+
+        self.measured("textDocument/foldingRange", async {
+            let persistence = self.persistence().read().await;
+            let folding_ranges = persistence.folding_ranges(&params.text_document.uri);
+
+            Ok(Some(folding_ranges))
+        })
+        .await
+    }
+
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        if !self.features().code_actions {
+            return Ok(None);
+        }
+
+        let (linter_command, linter_text, linter_code_actions) = {
+            let linter = self.linter.lock().await;
+
+            (
+                linter.command(),
+                linter.text(&params.text_document.uri),
+                linter.code_actions(&params),
+            )
+        };
+
+        let mut code_actions = vec![];
+
+        // Clients that can't resolve quick fixes need their edits up front
+        if self.code_action_resolve.load(Ordering::Relaxed) {
+            code_actions.extend(
+                linter_code_actions
+                    .into_iter()
+                    .map(CodeActionOrCommand::CodeAction),
+            );
+        } else if let Some(text) = linter_text {
+            for code_action in linter_code_actions {
+                if let Some(code_action) =
+                    linter_command.resolve_code_action(code_action, &text).await
+                {
+                    code_actions.push(CodeActionOrCommand::CodeAction(code_action));
+                }
+            }
+        }
+
+        let persistence = self.persistence().read().await;
+        if let Ok(method_stubs) = persistence.code_actions(&params) {
+            code_actions.extend(
+                method_stubs
+                    .into_iter()
+                    .map(CodeActionOrCommand::CodeAction),
+            );
+        }
+
+        Ok(Some(code_actions))
+    }
+
+    async fn code_action_resolve(&self, params: CodeAction) -> Result<CodeAction> {
+        self.measured("codeAction/resolve", async {
+            let (linter_command, text) = {
+                let linter = self.linter.lock().await;
+
+                (linter.command(), linter.code_action_text(&params))
+            };
+
+            let code_action = match text {
+                Some(text) => linter_command
+                    .resolve_code_action(params.clone(), &text)
+                    .await
+                    .unwrap_or(params),
+                None => params,
+            };
+
+            Ok(code_action)
+        })
+        .await
+    }
+
+    async fn execute_command(
+        &self,
+        params: ExecuteCommandParams,
+    ) -> Result<Option<serde_json::Value>> {
+        self.measured("workspace/executeCommand", async {
+            if params.command == "fuzzy.indexGems" {
+                let names = match params.arguments.first().and_then(|names| names.as_array()) {
+                    Some(names) => names
+                        .iter()
+                        .filter_map(|name| name.as_str().map(str::to_string))
+                        .collect(),
+                    None => self.persistence().read().await.unindexed_gem_names(),
+                };
+                let indexed_gems = self.index_gems(&names).await;
+
+                return Ok(Some(serde_json::json!(indexed_gems)));
+            }
+
+            if params.command == "fuzzy.runTest" {
+                return Ok(self.run_test(&params.arguments).await);
+            }
+
+            let persistence = self.persistence().read().await;
+
+            // Commands that lead somewhere open it in the editor, and return
+            // the location for clients that can't be told to
+            let location = match params.command.as_str() {
+                "fuzzy.goToSuperMethod" => {
+                    serde_json::from_value(params.arguments.first().cloned().unwrap_or_default())
+                        .ok()
+                        .and_then(|position_params| {
+                            persistence
+                                .super_method(&position_params)
+                                .unwrap_or_default()
+                        })
+                }
+                "fuzzy.openGem" => params
+                    .arguments
+                    .first()
+                    .and_then(|name| name.as_str())
+                    .and_then(|name| persistence.gem_location(name)),
+                _ => None,
+            };
+
+            if let Some(location) = location {
+                drop(persistence);
+                self.show_location(&location).await;
+
+                return Ok(Some(serde_json::json!(location)));
+            }
+
+            match params.command.as_str() {
+                "fuzzy.unusedMethods" => {
+                    let unused_methods = persistence.unused_methods().unwrap_or_default();
+
+                    Ok(Some(serde_json::json!(unused_methods)))
+                }
+                "fuzzy.unusedSymbols" => {
+                    let unused_symbols = persistence.unused_symbols().unwrap_or_default();
+
+                    Ok(Some(serde_json::json!(unused_symbols)))
+                }
+                "fuzzy.exportCallGraph" => {
+                    let default_options = serde_json::json!({});
+                    let options = params.arguments.first().unwrap_or(&default_options);
+                    let call_graph = persistence
+                        .export_call_graph(options)
+                        .unwrap_or(serde_json::Value::Null);
+
+                    Ok(Some(call_graph))
+                }
+                _ => Ok(None),
+            }
+        })
+        .await
+    }
+
+    async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> Result<Option<Vec<SymbolInformation>>> {
+        if !self.features().symbols {
+            return Ok(None);
+        }
+
+        self.measured("workspace/symbol", async {
+            let persistence = self.persistence().read().await;
+            let partial_result_token = params.partial_result_params.partial_result_token;
+
+            // Stream each batch as soon as it's found when the client supports
+            // partial results, in which case the final response must be empty
+            if let Some(token) = partial_result_token {
+                let batches = match persistence.workspace_symbol_batches(params.query) {
+                    Ok(batches) => batches,
+                    Err(_) => return Ok(Some(vec![])),
+                };
+
+                for batch in batches {
+                    let documents = match batch {
+                        Ok(documents) if !documents.is_empty() => documents,
+                        Ok(_) => continue,
+                        Err(_) => break,
+                    };
+
+                    self.client
+                        .send_notification::<PartialResultProgress>(serde_json::json!({
+                            "token": token,
+                            "value": persistence.documents_to_symbol_information(documents),
+                        }))
+                        .await;
+                }
+
+                return Ok(Some(vec![]));
+            }
+
+            let documents = persistence
+                .find_references_in_workspace(params.query)
+                .unwrap_or_else(|_| Vec::new());
+            let symbol_info = persistence.documents_to_symbol_information(documents);
+
+            Ok(Some(symbol_info))
+        })
+        .await
+    }
+}
@@ -0,0 +1,55 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use tower::Service;
+
+// An editor session. The sessions of a daemon share its indexes, but each has
+// open documents of its own, so two editors with the same file open don't
+// overwrite each other's text. Outside of a daemon's sessions, like for the
+// server over stdio, messages are handled in the first session.
+pub type SessionId = usize;
+
+tokio::task_local! {
+    static SESSION: SessionId;
+}
+
+// The session the current message is handled in
+pub fn current() -> SessionId {
+    SESSION.try_with(|session| *session).unwrap_or_default()
+}
+
+// Handles every message of a service in a session of its own
+pub struct SessionService<S> {
+    service: S,
+    session: SessionId,
+}
+
+impl<S> SessionService<S> {
+    pub fn new(service: S) -> SessionService<S> {
+        static NEXT_SESSION: AtomicUsize = AtomicUsize::new(1);
+
+        SessionService {
+            service,
+            session: NEXT_SESSION.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+impl<S, Request> Service<Request> for SessionService<S>
+where
+    S: Service<Request>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, context: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.service.poll_ready(context)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        Box::pin(SESSION.scope(self.session, self.service.call(request)))
+    }
+}
@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use tantivy::directory::Directory;
+use tantivy::schema::Schema;
+use tantivy::Index;
+
+// Bumped whenever the format or what's indexed changes, so older snapshots
+// are reindexed instead of trusted
+pub const SNAPSHOT_VERSION: u32 = 11;
+
+// A snapshot is a line of JSON with this, followed by each of the index's
+// files as a `<name>\t<length>` line and its bytes
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotMetadata {
+    pub version: u32,
+    pub schema_hash: String,
+    // The hash of the `Gemfile.lock`s, since the gems they lock are in the
+    // index
+    pub gemfile_lock_hash: Option<String>,
+    // Content hashes of the workspace files by their path in the index, so
+    // files that are unchanged since the snapshot aren't reindexed even
+    // though checking them out gave them new modification times
+    pub file_hashes: HashMap<String, String>,
+    // Where the gems were installed, since `gem_paths` are absolute paths
+    // that are only valid where the snapshot was made
+    pub gem_homes: Vec<String>,
+    pub unindexed_gems: HashMap<String, String>,
+    pub gem_paths: Vec<String>,
+    pub gem_file_count: usize,
+}
+
+pub fn schema_hash(schema: &Schema) -> String {
+    let schema = serde_json::to_string(schema).unwrap_or_default();
+
+    blake3::hash(schema.as_bytes()).to_string()
+}
+
+// `None` when the file can't be read, like a workspace without a
+// `Gemfile.lock`
+pub fn file_hash(path: &Path) -> Option<String> {
+    let contents = std::fs::read(path).ok()?;
+
+    Some(blake3::hash(&contents).to_string())
+}
+
+// The hashes of every `Gemfile.lock` in the workspace and its engines
+// together, or `None` when there aren't any
+pub fn gemfile_lock_hash(bundle_dirs: &[String]) -> Option<String> {
+    if bundle_dirs.is_empty() {
+        return None;
+    }
+
+    // Without the directories, so snapshots work from other checkouts
+    let file_hashes: Vec<String> = bundle_dirs
+        .iter()
+        .map(|bundle_dir| {
+            file_hash(&Path::new(bundle_dir).join("Gemfile.lock")).unwrap_or_default()
+        })
+        .collect();
+
+    Some(blake3::hash(file_hashes.join("\n").as_bytes()).to_string())
+}
+
+pub fn write(path: &Path, metadata: &SnapshotMetadata, index: &Index) -> tantivy::Result<()> {
+    let directory = index.directory();
+    let index_meta = index.load_metas()?;
+
+    // `meta.json` is written from the same metas the segment files are
+    // listed from, so a commit while exporting can't mix up the two
+    let mut files = vec![(
+        PathBuf::from("meta.json"),
+        serde_json::to_vec_pretty(&index_meta).map_err(io::Error::from)?,
+    )];
+
+    for segment_meta in &index_meta.segments {
+        // Every file a segment could have, e.g. deletes only once there are some
+        for file_path in segment_meta.list_files() {
+            if !directory.exists(&file_path)? {
+                continue;
+            }
+
+            // As stored, with the footer `open_read` would check and strip
+            let contents = directory.atomic_read(&file_path)?;
+            files.push((file_path, contents));
+        }
+    }
+
+    let mut snapshot = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(&mut snapshot, metadata).map_err(io::Error::from)?;
+    snapshot.write_all(b"\n")?;
+
+    for (file_path, contents) in files {
+        writeln!(snapshot, "{}\t{}", file_path.display(), contents.len())?;
+        snapshot.write_all(&contents)?;
+    }
+
+    snapshot.flush()?;
+
+    Ok(())
+}
+
+pub fn read_metadata(path: &Path) -> io::Result<SnapshotMetadata> {
+    let mut snapshot = BufReader::new(File::open(path)?);
+
+    read_metadata_line(&mut snapshot)
+}
+
+// Copies the index's files into `directory`, which should be empty
+pub fn read_index(path: &Path, directory: &dyn Directory) -> io::Result<()> {
+    let mut snapshot = BufReader::new(File::open(path)?);
+    read_metadata_line(&mut snapshot)?;
+
+    let mut file_paths = vec![];
+
+    loop {
+        let mut line = String::new();
+        if snapshot.read_line(&mut line)? == 0 {
+            break;
+        }
+
+        let (file_path, length) = line
+            .trim_end()
+            .split_once('\t')
+            .and_then(|(file_path, length)| Some((file_path, length.parse::<usize>().ok()?)))
+            .ok_or_else(|| invalid_data("Malformed snapshot"))?;
+
+        // Read only as far as the snapshot goes, rather than trusting the
+        // length to allocate up front
+        let mut contents = vec![];
+        (&mut snapshot)
+            .take(length as u64)
+            .read_to_end(&mut contents)?;
+
+        if contents.len() != length {
+            return Err(invalid_data("Truncated snapshot"));
+        }
+
+        directory.atomic_write(Path::new(file_path), &contents)?;
+
+        if file_path != "meta.json" {
+            file_paths.push(file_path.to_string());
+        }
+    }
+
+    // Lets tantivy clean up the segments once they're merged
+    let managed_paths = serde_json::to_vec(&file_paths).map_err(io::Error::from)?;
+    directory.atomic_write(Path::new(".managed.json"), &managed_paths)
+}
+
+fn read_metadata_line(snapshot: &mut impl BufRead) -> io::Result<SnapshotMetadata> {
+    let mut line = String::new();
+    snapshot.read_line(&mut line)?;
+
+    serde_json::from_str(&line).map_err(|_| invalid_data("Not a fuzzy snapshot"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,77 @@
+use std::cell::Cell;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tantivy::collector::Collector;
+use tantivy::query::Query;
+use tantivy::{DocAddress, Document, Searcher};
+
+// What a request spent its time on. Recorded while a request is being
+// measured, and ignored elsewhere, e.g. by background indexing.
+#[derive(Clone, Copy, Default)]
+pub struct RequestStats {
+    pub parse: Duration,
+    pub search: Duration,
+    pub searches: usize,
+    pub docs_scanned: usize,
+}
+
+tokio::task_local! {
+    static REQUEST_STATS: Cell<RequestStats>;
+}
+
+pub async fn measure<F: Future>(future: F) -> (F::Output, RequestStats) {
+    REQUEST_STATS
+        .scope(Cell::new(RequestStats::default()), async move {
+            let output = future.await;
+
+            (output, REQUEST_STATS.with(Cell::get))
+        })
+        .await
+}
+
+fn record(update: impl FnOnce(&mut RequestStats)) {
+    let _ = REQUEST_STATS.try_with(|request_stats| {
+        let mut stats = request_stats.get();
+        update(&mut stats);
+        request_stats.set(stats);
+    });
+}
+
+pub fn record_parse(duration: Duration) {
+    record(|stats| stats.parse += duration);
+}
+
+// `search` and `doc` that count towards the current request's stats
+pub trait MeasuredSearcher {
+    fn measured_search<C: Collector>(
+        &self,
+        query: &dyn Query,
+        collector: &C,
+    ) -> tantivy::Result<C::Fruit>;
+
+    fn measured_doc(&self, doc_address: DocAddress) -> tantivy::Result<Document>;
+}
+
+impl MeasuredSearcher for Searcher {
+    fn measured_search<C: Collector>(
+        &self,
+        query: &dyn Query,
+        collector: &C,
+    ) -> tantivy::Result<C::Fruit> {
+        let timer = Instant::now();
+        let fruit = self.search(query, collector);
+
+        record(|stats| {
+            stats.search += timer.elapsed();
+            stats.searches += 1;
+        });
+
+        fruit
+    }
+
+    fn measured_doc(&self, doc_address: DocAddress) -> tantivy::Result<Document> {
+        record(|stats| stats.docs_scanned += 1);
+
+        self.doc(doc_address)
+    }
+}
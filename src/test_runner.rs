@@ -0,0 +1,110 @@
+use log::info;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tower_lsp::lsp_types::{InitializeParams, Url};
+
+use crate::config::{Config, TestCommands};
+use crate::workspace_path::uri_path;
+
+// Runs the tests under the code lenses in spec and test files, with the
+// command configured for their framework
+#[derive(Clone, Default)]
+pub struct TestRunner {
+    commands: TestCommands,
+    workspace_path: PathBuf,
+}
+
+// How a test run went, with stdout and stderr together
+pub struct TestResult {
+    pub passed: bool,
+    pub output: String,
+}
+
+impl TestRunner {
+    pub fn initialize(&mut self, params: &InitializeParams, config: &Config) {
+        if let Some(uri) = &params.root_uri {
+            self.open_workspace(Path::new(&uri_path(uri)));
+        }
+
+        self.configure(config);
+    }
+
+    // Tests run from here, like the linter
+    pub fn open_workspace(&mut self, workspace_path: &Path) {
+        self.workspace_path = workspace_path.to_path_buf();
+    }
+
+    pub fn configure(&mut self, config: &Config) {
+        self.commands = config.test_commands.clone();
+    }
+
+    // The command for a test in `uri`, where `framework` is `spec` or `test`
+    // and `line` starts at 0
+    pub fn command(&self, framework: &str, uri: &Url, line: u32, name: &str) -> String {
+        let file_path = uri_path(uri);
+        let relative_path = Path::new(&file_path)
+            .strip_prefix(&self.workspace_path)
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or(file_path);
+
+        let template = if framework == "spec" {
+            &self.commands.spec
+        } else {
+            &self.commands.test
+        };
+
+        template
+            .replace("{file}", &shell_word(&relative_path))
+            .replace("{line}", &(line + 1).to_string())
+            .replace("{name}", &shell_word(name))
+    }
+
+    pub async fn run(&self, command: &str) -> Option<TestResult> {
+        let mut shell = if cfg!(windows) {
+            let mut shell = Command::new("cmd");
+            shell.arg("/C");
+            shell
+        } else {
+            let mut shell = Command::new("sh");
+            shell.arg("-c");
+            shell
+        };
+
+        if !self.workspace_path.as_os_str().is_empty() {
+            shell.current_dir(&self.workspace_path);
+        }
+
+        let output = match shell.arg(command).output().await {
+            Ok(output) => output,
+            Err(error) => {
+                info!("Couldn't run `{}`: {}", command, error);
+                return None;
+            }
+        };
+
+        let mut combined_output = String::from_utf8_lossy(&output.stdout).to_string();
+        combined_output.push_str(&String::from_utf8_lossy(&output.stderr));
+
+        Some(TestResult {
+            passed: output.status.success(),
+            output: combined_output,
+        })
+    }
+}
+
+// Quoted for the shell unless it's only made of characters that don't need
+// it, like most paths and method names
+fn shell_word(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_alphanumeric() || "/._-:=+@".contains(c));
+
+    if plain {
+        word.to_string()
+    } else if cfg!(windows) {
+        format!("\"{}\"", word.replace('"', "\"\""))
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
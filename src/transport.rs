@@ -0,0 +1,131 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+
+// How the editor talks to the server. VS Code's language client passes
+// `--stdio`, `--socket=<port>`, or `--pipe=<name>` depending on the transport
+// it's configured with, and for sockets and pipes it's already listening, so
+// the server connects to it.
+pub enum Transport {
+    Stdio,
+    // A TCP port on localhost, or the path of a Unix domain socket
+    Socket(String),
+    // The path of a Unix domain socket, or a named pipe like
+    // `\\.\pipe\fuzzy` on Windows
+    Pipe(String),
+}
+
+pub type Input = Box<dyn AsyncRead + Unpin + Send>;
+pub type Output = Box<dyn AsyncWrite + Unpin + Send>;
+
+impl Transport {
+    // Accepts both `--socket <path>` and `--socket=<path>`
+    pub fn from_args(args: &[String]) -> Result<Transport, String> {
+        let mut transport = Transport::Stdio;
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+
+            transport = match flag {
+                "--stdio" => Transport::Stdio,
+                "--socket" | "--pipe" => {
+                    let value = value
+                        .or_else(|| args.next().cloned())
+                        .ok_or_else(|| format!("`{}` needs a value", flag))?;
+
+                    if flag == "--socket" {
+                        Transport::Socket(value)
+                    } else {
+                        Transport::Pipe(value)
+                    }
+                }
+                _ => return Err(format!("Unknown argument `{}`", arg)),
+            };
+        }
+
+        Ok(transport)
+    }
+
+    pub async fn connect(&self) -> io::Result<(Input, Output)> {
+        match self {
+            Transport::Stdio => Ok((Box::new(tokio::io::stdin()), Box::new(tokio::io::stdout()))),
+            Transport::Socket(address) => match address.parse::<u16>() {
+                Ok(port) => Ok(split(TcpStream::connect(("127.0.0.1", port)).await?)),
+                Err(_) => connect_pipe(address).await,
+            },
+            Transport::Pipe(name) => connect_pipe(name).await,
+        }
+    }
+}
+
+// Where a daemon accepts editors: a TCP port on localhost, or the path of a
+// Unix domain socket
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    pub async fn bind(address: &str) -> io::Result<Listener> {
+        if let Ok(port) = address.parse::<u16>() {
+            return Ok(Listener::Tcp(TcpListener::bind(("127.0.0.1", port)).await?));
+        }
+
+        bind_socket(address).await
+    }
+
+    pub async fn accept(&self) -> io::Result<(Input, Output)> {
+        match self {
+            Listener::Tcp(listener) => Ok(split(listener.accept().await?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(split(listener.accept().await?.0)),
+        }
+    }
+}
+
+fn split<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S) -> (Input, Output) {
+    let (input, output) = tokio::io::split(stream);
+
+    (Box::new(input), Box::new(output))
+}
+
+#[cfg(unix)]
+async fn connect_pipe(path: &str) -> io::Result<(Input, Output)> {
+    Ok(split(tokio::net::UnixStream::connect(path).await?))
+}
+
+#[cfg(windows)]
+async fn connect_pipe(name: &str) -> io::Result<(Input, Output)> {
+    let pipe = tokio::net::windows::named_pipe::ClientOptions::new().open(name)?;
+
+    Ok(split(pipe))
+}
+
+// A socket file left behind by a daemon that didn't exit cleanly is replaced,
+// but not one that's still accepting connections
+#[cfg(unix)]
+async fn bind_socket(path: &str) -> io::Result<Listener> {
+    if tokio::net::UnixStream::connect(path).await.is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is already in use", path),
+        ));
+    }
+
+    let _ = std::fs::remove_file(path);
+
+    Ok(Listener::Unix(tokio::net::UnixListener::bind(path)?))
+}
+
+#[cfg(windows)]
+async fn bind_socket(_path: &str) -> io::Result<Listener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the daemon listens on a TCP port on Windows",
+    ))
+}
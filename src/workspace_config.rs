@@ -0,0 +1,172 @@
+use log::info;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tower_lsp::lsp_types::{InitializeParams, Url};
+
+use crate::config::Config;
+use crate::workspace_path::{uri_path, WorkspacePath};
+
+// Checked into the workspace root so a team can share settings across
+// editors. Uses the same keys as `initializationOptions`. When both exist,
+// the first one is read.
+pub const CONFIG_FILE_NAMES: [&str; 2] = [".fuzzy.toml", "fuzzy.yml"];
+
+// Any of these in a directory marks it as the root of a project
+const PROJECT_ROOT_FILE_NAMES: [&str; 4] = [
+    CONFIG_FILE_NAMES[0],
+    CONFIG_FILE_NAMES[1],
+    "Gemfile",
+    ".git",
+];
+
+// The closest directory above a file that's the root of a project, if any
+pub fn project_root(file_path: &str) -> Option<WorkspacePath> {
+    Path::new(file_path)
+        .ancestors()
+        .skip(1)
+        .find(|dir| {
+            PROJECT_ROOT_FILE_NAMES
+                .iter()
+                .any(|file_name| dir.join(file_name).exists())
+        })
+        .map(WorkspacePath::new)
+}
+
+// The editor's settings along with the workspace's config file, which takes
+// precedence over them
+#[derive(Default)]
+pub struct WorkspaceConfig {
+    workspace_path: PathBuf,
+    config_file_path: PathBuf,
+    initialization_options: Option<Value>,
+    config_file: Option<Value>,
+    errors: Vec<String>,
+}
+
+impl WorkspaceConfig {
+    pub fn initialize(&mut self, params: &InitializeParams) {
+        self.initialization_options = params.initialization_options.clone();
+
+        if let Some(uri) = &params.root_uri {
+            self.open_workspace(Path::new(&uri_path(uri)));
+        }
+    }
+
+    pub fn open_workspace(&mut self, workspace_path: &Path) {
+        self.workspace_path = workspace_path.to_path_buf();
+        self.load();
+    }
+
+    // Reads the config file again. An invalid file keeps the last settings
+    // read from it.
+    pub fn load(&mut self) {
+        self.config_file_path = CONFIG_FILE_NAMES
+            .iter()
+            .map(|file_name| self.workspace_path.join(file_name))
+            .find(|config_file_path| config_file_path.exists())
+            .unwrap_or_default();
+
+        let contents = match fs::read_to_string(&self.config_file_path) {
+            Ok(contents) => contents,
+            Err(_) => {
+                self.config_file = None;
+                return;
+            }
+        };
+
+        let yaml = self
+            .config_file_path
+            .extension()
+            .is_some_and(|extension| extension == "yml");
+        let config_file = if yaml {
+            serde_yaml::from_str::<Value>(&contents).map_err(|error| error.to_string())
+        } else {
+            toml::from_str::<toml::Value>(&contents)
+                .map(|config_file| serde_json::to_value(config_file).unwrap_or_default())
+                .map_err(|error| error.to_string())
+        };
+        let file_name = self
+            .config_file_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+
+        match config_file {
+            Ok(config_file) => {
+                info!("Read settings from {}", self.config_file_path.display());
+
+                self.config_file = Some(config_file);
+            }
+            Err(error) => {
+                info!(
+                    "Couldn't parse {}: {}",
+                    self.config_file_path.display(),
+                    error
+                );
+
+                self.errors
+                    .push(format!("Couldn't read {}: {}", file_name, error));
+            }
+        }
+    }
+
+    // Either name, so creating the other file or deleting the one that's read
+    // is picked up too
+    pub fn is_config_file(&self, uri: &Url) -> bool {
+        let path = PathBuf::from(uri_path(uri));
+
+        !self.workspace_path.as_os_str().is_empty()
+            && CONFIG_FILE_NAMES
+                .iter()
+                .any(|file_name| path == self.workspace_path.join(file_name))
+    }
+
+    pub fn add_error(&mut self, message: String) {
+        self.errors.push(message);
+    }
+
+    // Problems reading the settings since they were last shown in the editor
+    pub fn take_errors(&mut self) -> Vec<String> {
+        self.errors.drain(..).collect()
+    }
+
+    pub fn config(&mut self) -> Config {
+        let (config, errors) = Config::from_value(&self.user_config());
+        self.errors.extend(errors);
+
+        config
+    }
+
+    fn user_config(&self) -> Value {
+        let mut user_config = self
+            .initialization_options
+            .clone()
+            .unwrap_or_else(|| json!({}));
+
+        if let Some(config_file) = &self.config_file {
+            merge(&mut user_config, config_file);
+        }
+
+        user_config
+    }
+}
+
+// Overwrites `base` with `overrides`, merging nested tables like `diagnostics`
+// key by key
+fn merge(base: &mut Value, overrides: &Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(key) {
+                    Some(base_value) => merge(base_value, value),
+                    None => {
+                        base.insert(key.to_string(), value.clone());
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides.clone(),
+    }
+}
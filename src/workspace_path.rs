@@ -0,0 +1,246 @@
+use std::path::{Component, Path, PathBuf, Prefix};
+use tower_lsp::lsp_types::Url;
+
+// The directory files are indexed relative to. Paths from the editor are
+// percent-decoded before they're compared with it, so workspaces with spaces
+// or non-ASCII characters match the paths found walking the file system.
+//
+// Paths are stored in the index with `/` separators on every platform, e.g.
+// `/app/models/user.rb` in the workspace or `C:/Ruby32/lib/ruby/3.2.0/set.rb`
+// outside of it on Windows.
+#[derive(Clone, Default)]
+pub struct WorkspacePath {
+    path: PathBuf,
+    // Directories in the workspace that count as outside of it, like gems
+    // installed into `vendor/bundle`
+    excluded_paths: Vec<PathBuf>,
+}
+
+impl WorkspacePath {
+    pub fn new(path: &Path) -> WorkspacePath {
+        WorkspacePath {
+            path: path.to_path_buf(),
+            excluded_paths: vec![],
+        }
+    }
+
+    pub fn from_uri(uri: &Url) -> WorkspacePath {
+        WorkspacePath::new(Path::new(&uri_path(uri)))
+    }
+
+    pub fn as_path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn exclude(&mut self, excluded_paths: Vec<PathBuf>) {
+        self.excluded_paths = excluded_paths;
+    }
+
+    // Without a workspace, every open file counts as being in it
+    pub fn contains(&self, path: &str) -> bool {
+        self.path.as_os_str().is_empty()
+            || (Path::new(path).starts_with(&self.path) && !self.excluded(path))
+    }
+
+    // e.g. `/app/models/user.rb`, or the whole path when it's outside of the
+    // workspace
+    pub fn relative_path(&self, path: &str) -> String {
+        match Path::new(path).strip_prefix(&self.path) {
+            Ok(relative_path) if !self.path.as_os_str().is_empty() && !self.excluded(path) => {
+                format!("/{}", index_path(relative_path))
+            }
+            _ => index_path(Path::new(path)),
+        }
+    }
+
+    // The reverse of `relative_path` for files in the workspace
+    pub fn absolute_path(&self, relative_path: &str) -> String {
+        if self.path.as_os_str().is_empty() {
+            return absolute_path(relative_path);
+        }
+
+        let mut absolute_path = self.path.clone();
+        absolute_path.extend(relative_path.split('/').filter(|part| !part.is_empty()));
+
+        absolute_path.to_string_lossy().to_string()
+    }
+
+    fn excluded(&self, path: &str) -> bool {
+        self.excluded_paths
+            .iter()
+            .any(|excluded_path| Path::new(path).starts_with(excluded_path))
+    }
+
+    // Identifies the documents of a file in the index
+    pub fn file_path_id(&self, path: &str) -> String {
+        file_path_id(&self.relative_path(path))
+    }
+}
+
+pub fn file_path_id(relative_path: &str) -> String {
+    blake3::hash(relative_path.as_bytes()).to_string()
+}
+
+// The path of a file outside of the workspace from its path in the index.
+// Those are stored whole, keeping the leading `/` on Unix, so one is only
+// added to paths without a root.
+pub fn absolute_path(index_path: &str) -> String {
+    if Path::new(index_path).is_absolute() {
+        index_path.to_string()
+    } else {
+        format!("/{}", index_path)
+    }
+}
+
+// The file system path of a URI with percent-encoding decoded. Documents that
+// aren't files, like unsaved editor tabs, keep their URI's path.
+pub fn uri_path(uri: &Url) -> String {
+    match uri.to_file_path() {
+        Ok(path) => path.to_string_lossy().to_string(),
+        Err(_) => uri.path().to_string(),
+    }
+}
+
+fn index_path(path: &Path) -> String {
+    let mut index_path = String::new();
+
+    for component in path.components() {
+        // Drives and UNC shares are written with `/` too, as `C:` and
+        // `//server/share`, without the `\\?\` canonicalized paths start
+        // with, so they match the paths from the editor
+        if let Component::Prefix(prefix) = component {
+            let prefix = match prefix.kind() {
+                Prefix::Disk(drive) | Prefix::VerbatimDisk(drive) => format!("{}:", drive as char),
+                Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => {
+                    format!("//{}/{}", server.to_string_lossy(), share.to_string_lossy())
+                }
+                _ => prefix.as_os_str().to_string_lossy().to_string(),
+            };
+
+            index_path.push_str(&prefix);
+            continue;
+        }
+
+        if component == Component::RootDir {
+            index_path.push('/');
+            continue;
+        }
+
+        if !index_path.is_empty() && !index_path.ends_with('/') {
+            index_path.push('/');
+        }
+
+        index_path.push_str(&component.as_os_str().to_string_lossy());
+    }
+
+    index_path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn paths_outside_of_the_workspace_keep_their_leading_slash() {
+        let workspace_path = WorkspacePath::new(Path::new("/app"));
+        let gem_path = "/gems/rack-3.0.8/lib/rack.rb";
+
+        assert_eq!(workspace_path.relative_path(gem_path), gem_path);
+        assert_eq!(
+            absolute_path(&workspace_path.relative_path(gem_path)),
+            gem_path
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn workspace_paths_are_relative_to_it() {
+        let mut workspace_path = WorkspacePath::new(Path::new("/app"));
+        workspace_path.exclude(vec![PathBuf::from("/app/vendor/bundle")]);
+
+        assert_eq!(
+            workspace_path.relative_path("/app/models/user.rb"),
+            "/models/user.rb"
+        );
+        assert_eq!(
+            workspace_path.absolute_path("/models/user.rb"),
+            "/app/models/user.rb"
+        );
+        assert_eq!(
+            workspace_path.relative_path("/app/vendor/bundle/rack.rb"),
+            "/app/vendor/bundle/rack.rb"
+        );
+        assert!(!workspace_path.contains("/app/vendor/bundle/rack.rb"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn uri_paths_are_percent_decoded() {
+        let uri = Url::parse("file:///home/me/my%20app/caf%C3%A9.rb").unwrap();
+
+        assert_eq!(uri_path(&uri), "/home/me/my app/café.rb");
+        assert!(
+            WorkspacePath::from_uri(&Url::parse("file:///home/me/my%20app").unwrap())
+                .contains(&uri_path(&uri))
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn drive_paths_use_forward_slashes() {
+        let workspace_path = WorkspacePath::new(Path::new(r"C:\app"));
+        let stdlib_path = r"C:\Ruby32\lib\ruby\3.2.0\set.rb";
+
+        assert_eq!(
+            workspace_path.relative_path(r"C:\app\models\user.rb"),
+            "/models/user.rb"
+        );
+        assert_eq!(
+            workspace_path.absolute_path("/models/user.rb"),
+            r"C:\app\models\user.rb"
+        );
+        assert_eq!(
+            workspace_path.relative_path(stdlib_path),
+            "C:/Ruby32/lib/ruby/3.2.0/set.rb"
+        );
+        assert_eq!(
+            workspace_path.relative_path(r"\\?\C:\Ruby32\lib\ruby\3.2.0\set.rb"),
+            "C:/Ruby32/lib/ruby/3.2.0/set.rb"
+        );
+        assert_eq!(
+            absolute_path(&workspace_path.relative_path(stdlib_path)),
+            "C:/Ruby32/lib/ruby/3.2.0/set.rb"
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn unc_paths_use_forward_slashes() {
+        let workspace_path = WorkspacePath::new(Path::new(r"\\server\share\app"));
+        let gem_path = r"\\server\share\gems\rack.rb";
+
+        assert_eq!(
+            workspace_path.relative_path(r"\\server\share\app\models\user.rb"),
+            "/models/user.rb"
+        );
+        assert_eq!(
+            workspace_path.relative_path(gem_path),
+            "//server/share/gems/rack.rb"
+        );
+        assert_eq!(
+            absolute_path(&workspace_path.relative_path(gem_path)),
+            "//server/share/gems/rack.rb"
+        );
+    }
+
+    #[test]
+    fn every_path_is_in_a_missing_workspace() {
+        assert!(WorkspacePath::default().contains("/gems/rack.rb"));
+    }
+
+    #[test]
+    fn absolute_path_adds_a_root_to_paths_without_one() {
+        assert_eq!(absolute_path("gems/rack.rb"), "/gems/rack.rb");
+    }
+}
@@ -0,0 +1,134 @@
+use log::info;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+use tantivy::{Document, Index, IndexReader, IndexWriter, Term};
+use tokio::sync::oneshot;
+
+// How long to wait for more changes before committing, so a burst of edits
+// is committed once
+const COMMIT_DEBOUNCE: Duration = Duration::from_millis(250);
+
+enum WriteOperation {
+    // Deletes the documents matching the term, e.g. a file's previous
+    // documents, before adding the new ones
+    Replace {
+        delete_term: Option<Term>,
+        documents: Vec<Document>,
+    },
+    // Deletes the documents matching any of the terms, e.g. every file that
+    // disappeared since the last reindex
+    Delete(Vec<Term>),
+    // Commits right away, acknowledging once searches can see the changes
+    Commit(Option<oneshot::Sender<()>>),
+}
+
+// The only index writer, owned by a dedicated thread. Changes are sent over a
+// channel and committed after `COMMIT_DEBOUNCE` passes without new ones, then
+// the shared reader is reloaded.
+#[derive(Clone)]
+pub struct WriteQueue {
+    sender: Sender<WriteOperation>,
+}
+
+impl WriteQueue {
+    pub fn spawn(index: &Index, reader: IndexReader) -> tantivy::Result<Self> {
+        let mut index_writer: IndexWriter = index.writer(256_000_000)?;
+        let (sender, receiver) = channel();
+
+        thread::spawn(move || {
+            let mut uncommitted = false;
+
+            loop {
+                let operation = if uncommitted {
+                    match receiver.recv_timeout(COMMIT_DEBOUNCE) {
+                        Ok(operation) => operation,
+                        Err(RecvTimeoutError::Timeout) => WriteOperation::Commit(None),
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                } else {
+                    match receiver.recv() {
+                        Ok(operation) => operation,
+                        Err(_) => break,
+                    }
+                };
+
+                match operation {
+                    WriteOperation::Replace {
+                        delete_term,
+                        documents,
+                    } => {
+                        if let Some(delete_term) = delete_term {
+                            index_writer.delete_term(delete_term);
+                        }
+
+                        for document in documents {
+                            if let Err(error) = index_writer.add_document(document) {
+                                info!("Couldn't add a document to the index: {}", error);
+                            }
+                        }
+
+                        uncommitted = true;
+                    }
+                    WriteOperation::Delete(delete_terms) => {
+                        for delete_term in delete_terms {
+                            index_writer.delete_term(delete_term);
+                        }
+
+                        uncommitted = true;
+                    }
+                    WriteOperation::Commit(acknowledgement) => {
+                        if uncommitted {
+                            if let Err(error) = index_writer.commit() {
+                                info!("Couldn't commit the index: {}", error);
+                            }
+
+                            if let Err(error) = reader.reload() {
+                                info!("Couldn't reload the index reader: {}", error);
+                            }
+
+                            uncommitted = false;
+                        }
+
+                        if let Some(acknowledgement) = acknowledgement {
+                            let _ = acknowledgement.send(());
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Self { sender })
+    }
+
+    pub fn replace(&self, delete_term: Option<Term>, documents: Vec<Document>) {
+        let _ = self.sender.send(WriteOperation::Replace {
+            delete_term,
+            documents,
+        });
+    }
+
+    pub fn delete(&self, delete_terms: Vec<Term>) {
+        if !delete_terms.is_empty() {
+            let _ = self.sender.send(WriteOperation::Delete(delete_terms));
+        }
+    }
+
+    // Commits without waiting for the debounce
+    pub fn commit(&self) {
+        let _ = self.sender.send(WriteOperation::Commit(None));
+    }
+
+    // Commits and waits until searches can see every change sent so far
+    pub async fn commit_and_wait(&self) {
+        let (acknowledgement, committed) = oneshot::channel();
+
+        if self
+            .sender
+            .send(WriteOperation::Commit(Some(acknowledgement)))
+            .is_ok()
+        {
+            let _ = committed.await;
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use fuzzy::daemon::Workspaces;
+use fuzzy::harness::{TestServer, TestWorkspace};
+use serde_json::{json, Value};
+use std::fs;
+use std::time::Duration;
+
+const USER: &str =
+    "class User\n  def greet\n  end\n\n  def greet_twice\n    greet\n    greet\n  end\nend\n";
+const GREETER: &str = "class Greeter\n  def run\n    User.new.greet\n  end\nend\n";
+// Passing on an anonymous `*` is a syntax error before Ruby 3.2, which leaves
+// nothing to index
+const LOGGER: &str = "class Logger\n  def log(*) = info(*)\n\n  def log_twice\n  end\nend\n";
+
+async fn start(workspace: &TestWorkspace) -> TestServer {
+    let mut server = TestServer::start(workspace.path(), json!({ "indexGems": false }))
+        .await
+        .unwrap();
+    server.wait_for_indexing().await.unwrap();
+    server.open("user.rb").await.unwrap();
+    server.open("greeter.rb").await.unwrap();
+
+    server
+}
+
+// `(uri, line, character)` of each location, sorted
+fn positions(server: &TestServer, locations: &Value) -> Vec<(String, u64, u64)> {
+    let mut positions: Vec<(String, u64, u64)> = locations
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|location| {
+            let uri = location["uri"].as_str().unwrap();
+            let relative_path = uri
+                .strip_prefix(server.uri("").as_str())
+                .unwrap()
+                .to_string();
+            let start = &location["range"]["start"];
+
+            (
+                relative_path,
+                start["line"].as_u64().unwrap(),
+                start["character"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+
+    positions.sort();
+    positions
+}
+
+#[tokio::test]
+async fn definition_of_a_method_in_another_file() {
+    let workspace = TestWorkspace::new(&[("user.rb", USER), ("greeter.rb", GREETER)]).unwrap();
+    let mut server = start(&workspace).await;
+
+    let definitions = server
+        .request(
+            "textDocument/definition",
+            json!({
+                "textDocument": { "uri": server.uri("greeter.rb") },
+                "position": { "line": 2, "character": 15 },
+            }),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(
+        positions(&server, &definitions),
+        vec![("user.rb".to_string(), 1, 6)]
+    );
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn references_include_the_definition_and_usages() {
+    let workspace = TestWorkspace::new(&[("user.rb", USER), ("greeter.rb", GREETER)]).unwrap();
+    let mut server = start(&workspace).await;
+
+    let references = server
+        .request(
+            "textDocument/references",
+            json!({
+                "textDocument": { "uri": server.uri("user.rb") },
+                "position": { "line": 5, "character": 5 },
+                "context": { "includeDeclaration": true },
+            }),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(
+        positions(&server, &references),
+        vec![
+            ("user.rb".to_string(), 1, 6),
+            ("user.rb".to_string(), 5, 4),
+            ("user.rb".to_string(), 6, 4),
+        ]
+    );
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn rename_edits_the_definition_and_usages() {
+    let workspace = TestWorkspace::new(&[("user.rb", USER), ("greeter.rb", GREETER)]).unwrap();
+    let mut server = start(&workspace).await;
+
+    let edit = server
+        .request(
+            "textDocument/rename",
+            json!({
+                "textDocument": { "uri": server.uri("user.rb") },
+                "position": { "line": 5, "character": 5 },
+                "newName": "welcome",
+            }),
+        )
+        .await
+        .unwrap();
+
+    // `(uri, line, character, new text)` of each edit, sorted
+    let mut edits: Vec<(String, u64, u64, String)> = vec![];
+
+    for (uri, file_edits) in edit["changes"].as_object().unwrap() {
+        for file_edit in file_edits.as_array().unwrap() {
+            let start = &file_edit["range"]["start"];
+
+            edits.push((
+                uri.clone(),
+                start["line"].as_u64().unwrap(),
+                start["character"].as_u64().unwrap(),
+                file_edit["newText"].as_str().unwrap().to_string(),
+            ));
+        }
+    }
+    edits.sort();
+
+    let uri = server.uri("user.rb").to_string();
+    assert_eq!(
+        edits,
+        vec![
+            (uri.clone(), 1, 6, "welcome".to_string()),
+            (uri.clone(), 5, 4, "welcome".to_string()),
+            (uri, 6, 4, "welcome".to_string()),
+        ]
+    );
+
+    server.shutdown().await.unwrap();
+}
+
+// The names of the workspace symbols found for a query
+async fn symbol_names(server: &mut TestServer, query: &str) -> Vec<String> {
+    let symbols = server
+        .request("workspace/symbol", json!({ "query": query }))
+        .await
+        .unwrap();
+
+    symbols
+        .as_array()
+        .map(|symbols| {
+            symbols
+                .iter()
+                .map(|symbol| symbol["name"].as_str().unwrap().to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[tokio::test]
+async fn workspace_symbols_are_fuzzy_matched() {
+    let workspace = TestWorkspace::new(&[("user.rb", USER), ("greeter.rb", GREETER)]).unwrap();
+    let mut server = start(&workspace).await;
+
+    // Shorter names that match from their start come first
+    assert_eq!(
+        symbol_names(&mut server, "gret").await,
+        vec!["greet", "Greeter", "greet_twice"]
+    );
+
+    let symbols = server
+        .request("workspace/symbol", json!({ "query": "greet_twice" }))
+        .await
+        .unwrap();
+    let locations: Value = symbols
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|symbol| symbol["location"].clone())
+        .collect();
+
+    assert_eq!(
+        positions(&server, &locations),
+        vec![("user.rb".to_string(), 4, 6)]
+    );
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn hover_shows_the_definition() {
+    let workspace = TestWorkspace::new(&[("user.rb", USER), ("greeter.rb", GREETER)]).unwrap();
+    let mut server = start(&workspace).await;
+
+    let hover = server
+        .request(
+            "textDocument/hover",
+            json!({
+                "textDocument": { "uri": server.uri("greeter.rb") },
+                "position": { "line": 2, "character": 15 },
+            }),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(hover["contents"]["kind"], "markdown");
+
+    let contents = hover["contents"]["value"].as_str().unwrap();
+    assert!(contents.starts_with("```ruby\ndef greet\n"), "{}", contents);
+    assert!(contents.ends_with("`user.rb:2`"), "{}", contents);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn completion_finishes_the_constant_being_typed() {
+    let workspace = TestWorkspace::new(&[
+        ("user.rb", USER),
+        ("greeter.rb", GREETER),
+        ("typing.rb", "class Typing\n  def run\n    Us\n  end\nend\n"),
+    ])
+    .unwrap();
+    let mut server = start(&workspace).await;
+    server.open("typing.rb").await.unwrap();
+
+    let params = json!({
+        "textDocument": { "uri": server.uri("typing.rb") },
+        "position": { "line": 2, "character": 6 },
+    });
+
+    // `didOpen` is a notification, so the file may not be open yet for the
+    // first requests
+    let opened = async {
+        loop {
+            let completions = server
+                .request("textDocument/completion", params.clone())
+                .await
+                .unwrap();
+
+            if completions
+                .as_array()
+                .is_some_and(|items| !items.is_empty())
+            {
+                return completions;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    let completions = tokio::time::timeout(Duration::from_secs(30), opened)
+        .await
+        .expect("No completions");
+
+    let completions = completions.as_array().unwrap();
+    assert_eq!(completions.len(), 1);
+    assert_eq!(completions[0]["label"], "User");
+    assert_eq!(
+        completions[0]["textEdit"]["range"]["start"],
+        json!({ "line": 2, "character": 4 })
+    );
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn changing_the_ruby_version_parses_the_workspace_again() {
+    let workspace =
+        TestWorkspace::new(&[(".ruby-version", "3.1.4\n"), ("logger.rb", LOGGER)]).unwrap();
+    let mut server = TestServer::start(workspace.path(), json!({ "indexGems": false }))
+        .await
+        .unwrap();
+    server.wait_for_indexing().await.unwrap();
+
+    assert!(symbol_names(&mut server, "log_twice").await.is_empty());
+
+    fs::write(workspace.path().join(".ruby-version"), "3.2.2\n").unwrap();
+    server
+        .notify(
+            "workspace/didChangeWatchedFiles",
+            json!({ "changes": [{ "uri": server.uri(".ruby-version"), "type": 2 }] }),
+        )
+        .await
+        .unwrap();
+
+    // `logger.rb` wasn't modified, so it's only found once every file is
+    // parsed again
+    let reindexed = async {
+        while symbol_names(&mut server, "log_twice").await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(30), reindexed)
+        .await
+        .expect("Not parsed again as Ruby 3.2");
+
+    server.shutdown().await.unwrap();
+}
+
+// The names of the symbols in an open file, outermost first
+async fn document_symbol_names(server: &mut TestServer, relative_path: &str) -> Vec<String> {
+    let symbols = server
+        .request(
+            "textDocument/documentSymbol",
+            json!({ "textDocument": { "uri": server.uri(relative_path) } }),
+        )
+        .await
+        .unwrap();
+
+    let mut names = vec![];
+    let mut symbols: Vec<&Value> = symbols.as_array().unwrap().iter().collect();
+
+    while let Some(symbol) = symbols.pop() {
+        names.push(symbol["name"].as_str().unwrap().to_string());
+
+        if let Some(children) = symbol["children"].as_array() {
+            symbols.extend(children.iter().rev());
+        }
+    }
+
+    names
+}
+
+#[tokio::test]
+async fn daemon_sessions_keep_their_own_open_documents() {
+    let workspace = TestWorkspace::new(&[("user.rb", USER)]).unwrap();
+    let workspaces = Workspaces::default();
+    let options = json!({ "indexGems": false });
+
+    let mut first = TestServer::start_session(&workspaces, workspace.path(), options.clone())
+        .await
+        .unwrap();
+    first.wait_for_indexing().await.unwrap();
+    let mut second = TestServer::start_session(&workspaces, workspace.path(), options)
+        .await
+        .unwrap();
+
+    first.open("user.rb").await.unwrap();
+    second
+        .notify(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": {
+                    "uri": second.uri("user.rb"),
+                    "languageId": "ruby",
+                    "version": 1,
+                    "text": "class User\n  def welcome\n  end\nend\n",
+                },
+            }),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(
+        document_symbol_names(&mut second, "user.rb").await,
+        vec!["User", "welcome"]
+    );
+    assert_eq!(
+        document_symbol_names(&mut first, "user.rb").await,
+        vec!["User", "greet", "greet_twice"]
+    );
+
+    // Closing the file in one session leaves it open in the other
+    second
+        .notify(
+            "textDocument/didClose",
+            json!({ "textDocument": { "uri": second.uri("user.rb") } }),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(
+        document_symbol_names(&mut first, "user.rb").await,
+        vec!["User", "greet", "greet_twice"]
+    );
+
+    first.shutdown().await.unwrap();
+    second.shutdown().await.unwrap();
+}